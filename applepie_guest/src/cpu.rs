@@ -0,0 +1,81 @@
+/// Wrappers around x86 instructions
+///
+/// Things which need CPL0 are only available with the `kernel` feature
+
+/// Performs a rdtsc instruction, returns 64-bit TSC value
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+
+    unsafe {
+        asm!("rdtsc" :
+             "={edx}"(high), "={eax}"(low) :::
+             "volatile", "intel");
+    }
+
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Performs a cpuid instruction with `leaf` in EAX and `subleaf` in ECX,
+/// returns EAX, EBX, ECX and EDX
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+
+    unsafe {
+        asm!("cpuid" :
+             "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx) :
+             "{eax}"(leaf), "{ecx}"(subleaf) ::
+             "volatile", "intel");
+    }
+
+    (eax, ebx, ecx, edx)
+}
+
+/// Spin loop hint
+#[inline]
+pub fn pause() {
+    unsafe { asm!("pause" :::: "volatile"); }
+}
+
+/// Output the byte `val` to `port`
+#[cfg(feature = "kernel")]
+#[inline]
+pub unsafe fn out8(port: u16, val: u8) {
+    asm!("out dx, al" :: "{al}"(val), "{dx}"(port) :: "intel", "volatile");
+}
+
+/// Input a byte from `port`
+#[cfg(feature = "kernel")]
+#[inline]
+pub unsafe fn in8(port: u16) -> u8 {
+    let ret: u8;
+    asm!("in al, dx" : "={al}"(ret) : "{dx}"(port) :: "intel", "volatile");
+    ret
+}
+
+/// Output the dword `val` to `port`
+#[cfg(feature = "kernel")]
+#[inline]
+pub unsafe fn out32(port: u16, val: u32) {
+    asm!("out dx, eax" :: "{eax}"(val), "{dx}"(port) :: "intel", "volatile");
+}
+
+/// Input a dword from `port`
+#[cfg(feature = "kernel")]
+#[inline]
+pub unsafe fn in32(port: u16) -> u32 {
+    let ret: u32;
+    asm!("in eax, dx" : "={eax}"(ret) : "{dx}"(port) :: "intel", "volatile");
+    ret
+}
+
+/// Disable interrupts and halt forever
+#[cfg(feature = "kernel")]
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli ; hlt" :::: "volatile");
+        }
+    }
+}
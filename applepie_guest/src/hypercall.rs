@@ -0,0 +1,181 @@
+/// Typed wrappers for the bochservisor hypercalls
+///
+/// These are just CPUIDs so they work from user mode as well, as long as the
+/// OS hasn't turned on CPUID faulting. The hypervisor walks the page tables
+/// itself and can't page anything in, so buffers are touched before they're
+/// handed over.
+
+use core::fmt;
+use crate::abi::*;
+
+/// Size of a page, used to touch buffers
+const PAGE_SIZE: usize = 4096;
+
+/// Errors the hypervisor can return from a hypercall
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The hypervisor doesn't know this hypercall
+    InvalidOpcode,
+
+    /// The hypervisor could not access a buffer we passed
+    BadAddress,
+
+    /// The hypercall can't be used right now, for example snapshotting while
+    /// already running from a snapshot
+    Unsupported,
+
+    /// A status we don't know about
+    Unknown(u64),
+}
+
+/// Result of a hypercall
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Make hypercall `opcode` with `args` in RCX, RDX, RSI and RDI. Returns the
+/// status and the return value.
+///
+/// # Safety
+///
+/// The hypervisor reads from and writes to whatever memory `args` describe
+pub unsafe fn raw(opcode: u32, args: [u64; 4]) -> (u64, u64) {
+    let rax = ((opcode as u64) << 32) | HYPERCALL_LEAF as u64;
+
+    loop {
+        let (status, signature, ret, _rdx): (u64, u64, u64, u64);
+        asm!("cpuid" :
+             "={rax}"(status), "={rbx}"(signature), "={rcx}"(ret),
+             "={rdx}"(_rdx) :
+             "{rax}"(rax), "{rcx}"(args[0]), "{rdx}"(args[1]),
+             "{rsi}"(args[2]), "{rdi}"(args[3]) :
+             "memory" :
+             "volatile", "intel");
+
+        // If Bochs was emulating when we ran the CPUID it answered it like a
+        // normal CPUID, try again until the hypervisor sees it
+        if signature as u32 == HYPERCALL_SIGNATURE {
+            return (status, ret);
+        }
+    }
+}
+
+/// Make hypercall `opcode` and convert the status into a `Result`
+unsafe fn call(opcode: u32, args: [u64; 4]) -> Result<u64> {
+    match raw(opcode, args) {
+        (HC_STATUS_SUCCESS, ret)        => Ok(ret),
+        (HC_STATUS_INVALID_OPCODE, _)   => Err(Error::InvalidOpcode),
+        (HC_STATUS_BAD_ADDRESS, _)      => Err(Error::BadAddress),
+        (HC_STATUS_UNSUPPORTED, _)      => Err(Error::Unsupported),
+        (status, _)                     => Err(Error::Unknown(status)),
+    }
+}
+
+/// Touch every page of the `len` bytes at `ptr` so they're paged in. If
+/// `write` is set the pages are written to as well, to break copy-on-write
+unsafe fn touch(ptr: *mut u8, len: usize, write: bool) {
+    if len == 0 { return; }
+
+    let end = ptr as usize + len;
+    let mut addr = ptr as usize;
+    while addr < end {
+        let byte = core::ptr::read_volatile(addr as *const u8);
+        if write {
+            core::ptr::write_volatile(addr as *mut u8, byte);
+        }
+
+        // Next page
+        addr = (addr & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    }
+}
+
+/// Print `msg` on the host. Anything past `HYPERCALL_MAX_PRINT` bytes is
+/// dropped
+pub fn print(msg: &[u8]) -> Result<()> {
+    unsafe {
+        touch(msg.as_ptr() as *mut u8, msg.len(), false);
+        call(HC_PRINT, [msg.as_ptr() as u64, msg.len() as u64, 0, 0])
+            .map(|_| ())
+    }
+}
+
+/// Take a snapshot. This returns once when the snapshot is taken, and again
+/// every time the snapshot is resumed
+pub fn snapshot() -> Result<()> {
+    unsafe { call(HC_SNAPSHOT, [0; 4]).map(|_| ()) }
+}
+
+/// Copy the current fuzz input into `buf`. Returns the full length of the
+/// input, if this is larger than `buf` the input was truncated
+pub fn get_input(buf: &mut [u8]) -> Result<usize> {
+    unsafe {
+        touch(buf.as_mut_ptr(), buf.len(), true);
+        call(HC_GET_INPUT, [buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0])
+            .map(|x| x as usize)
+    }
+}
+
+/// Report a crash with an OS specific `code` (like an exception code) at
+/// `addr`
+pub fn report_crash(code: u64, addr: u64) -> Result<()> {
+    unsafe { call(HC_REPORT_CRASH, [code, addr, 0, 0]).map(|_| ()) }
+}
+
+/// Report the result of the current fuzz case
+pub fn report_result(result: u64) -> Result<()> {
+    unsafe { call(HC_REPORT_RESULT, [result, 0, 0, 0]).map(|_| ()) }
+}
+
+/// Print formatted text on the host with the `hprint!` macro
+#[macro_export]
+macro_rules! hprint {
+    ( $($arg:tt)* ) => ({
+        use core::fmt::Write;
+        let mut writer = $crate::hypercall::Writer::new();
+        let _ = write!(&mut writer, $($arg)*);
+        writer.flush();
+    })
+}
+
+/// Writer used by `hprint!`. Text is buffered so a message is printed with as
+/// few hypercalls as possible
+pub struct Writer {
+    /// Text which hasn't been printed yet
+    buf: [u8; 256],
+
+    /// Number of bytes used in `buf`
+    len: usize,
+}
+
+impl Writer {
+    /// Create a new empty writer
+    pub fn new() -> Self {
+        Writer { buf: [0; 256], len: 0 }
+    }
+
+    /// Print everything buffered
+    pub fn flush(&mut self) {
+        if self.len > 0 {
+            let _ = print(&self.buf[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Writer::new()
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == self.buf.len() {
+                self.flush();
+            }
+
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
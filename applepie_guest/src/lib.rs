@@ -0,0 +1,23 @@
+#![feature(asm)]
+#![no_std]
+
+//! Guest side support for running under applepie
+//!
+//! Everything a test kernel or fuzzing harness needs to talk to bochservisor,
+//! so harnesses link this rather than hand writing inline assembly for each
+//! target. Only x86-64 guests are supported.
+//!
+//! Without features this only contains what works in user mode, which is the
+//! hypercalls and a few unprivileged instructions. This is what Windows
+//! harness DLLs should use. The `kernel` feature adds bare metal support like
+//! port I/O and printing to the screen.
+
+/// The hypercall ABI, shared with bochservisor
+#[path = "../../bochservisor/src/hypercall.rs"]
+pub mod abi;
+
+#[macro_use] pub mod hypercall;
+pub mod cpu;
+
+#[cfg(feature = "kernel")]
+#[macro_use] pub mod disp;
@@ -1,16 +1,5 @@
-/// Performs a rdtsc instruction, returns 64-bit TSC value
-#[inline]
-pub fn rdtsc() -> u64 {
-	let (high, low): (u32, u32);
-
-    unsafe {
-        asm!("rdtsc" :
-             "={edx}"(high), "={eax}"(low) :::
-             "volatile", "intel");
-    }
-
-	((high as u64) << 32) | (low as u64)
-}
+/// Port I/O, rdtsc and halting live in the `applepie_guest` crate
+pub use applepie_guest::cpu::{rdtsc, out8, in8, out32, in32, halt};
 
 #[inline]
 pub unsafe fn invlpg(addr: usize)
@@ -18,43 +7,6 @@ pub unsafe fn invlpg(addr: usize)
     asm!("invlpg [$0]" :: "r"(addr) : "memory" : "volatile", "intel");
 }
 
-/// Output the byte `val` to `port`
-#[inline]
-pub unsafe fn out8(port: u16, val: u8) {
-    asm!("out dx, al" :: "{al}"(val), "{dx}"(port) :: "intel", "volatile");
-}
-
-/// Input a byte from `port`
-#[inline]
-pub unsafe fn in8(port: u16) -> u8 {
-    let ret: u8;
-    asm!("in al, dx" : "={al}"(ret) : "{dx}"(port) :: "intel", "volatile");
-    ret
-}
-
-/// Output the dword `val` to `port`
-#[inline]
-pub unsafe fn out32(port: u16, val: u32) {
-    asm!("out dx, eax" :: "{eax}"(val), "{dx}"(port) :: "intel", "volatile");
-}
-
-/// Input a dword from `port`
-#[inline]
-pub unsafe fn in32(port: u16) -> u32 {
-    let ret: u32;
-    asm!("in eax, dx" : "={eax}"(ret) : "{dx}"(port) :: "intel", "volatile");
-    ret
-}
-
-/// Disable interrupts and halt forever
-pub fn halt() -> ! {
-    loop {
-        unsafe {
-            asm!("cli ; hlt" :::: "volatile");
-        }
-    }
-}
-
 /// Load the interrupt table specified by vaddr
 #[inline]
 pub unsafe fn lidt(vaddr: *const u8)
@@ -4,9 +4,9 @@
 #![no_main]
 
 #[macro_use] extern crate alloc;
+#[macro_use] extern crate applepie_guest;
 
 pub mod core_reqs;
-#[macro_use] pub mod disp;
 pub mod cpu;
 pub mod time;
 pub mod mm;
@@ -37,7 +37,7 @@ pub fn panic(info: &PanicInfo) -> ! {
 
     if let Some(&args) = info.message() {
         use core::fmt::write;
-        let _ = write(&mut disp::Writer, args);
+        let _ = write(&mut applepie_guest::disp::Writer, args);
         print!("\n");
     } else {
         print!("No arguments\n");
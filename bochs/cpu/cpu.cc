@@ -29,8 +29,8 @@
 
 #ifdef BOCHSERVISOR
 extern struct _bochs_routines routines;
-extern void (*bochs_cpu_loop)(struct _bochs_routines*, Bit64u, void*, void*,
-  void*, void*);
+extern void (*bochs_cpu_loop)(struct _bochs_routines*, Bit32u, Bit64u, void*,
+  void*, void*, void*);
 
 extern Bit64u dirty_bits_l1[(4ULL * 1024 * 1024 * 1024) / (1024 * 1024 * 64)];
 extern Bit64u dirty_bits_l2[(4ULL * 1024 * 1024 * 1024) / (4096 * 64)];
@@ -77,12 +77,13 @@ void BX_CPU_C::cpu_loop(void)
   // Jump into the Rust CPU loop implementation
   // Here we pass it:
   // - Callbacks for invoking different things in rust
+  // - Number of processors, the Rust loop drives all of them
   // - Size of physical memory in bytes
   // - Pointer to the L1 dirty bits table
   // - Pointer to the L2 dirty bits table
   // - Pointer to the start of the linear memory mapping
   // - Pointer to original memory (NULL if we're not restoring from a snapshot)
-  (*bochs_cpu_loop)(&routines, BX_MEM(0)->get_memory_len(),
+  (*bochs_cpu_loop)(&routines, BX_SMP_PROCESSORS, BX_MEM(0)->get_memory_len(),
     (void*)dirty_bits_l1, (void*)dirty_bits_l2,
     (void*)BX_MEM_THIS get_vector(0), original_memory);
   return;
@@ -24,6 +24,11 @@
 #include "cpu.h"
 #define LOG_THIS BX_CPU_THIS_PTR
 
+#ifdef BOCHSERVISOR
+extern bool (*report_breakpoint)(Bit64u, Bit64u, Bit64u, int, Bit64u, Bit16u,
+    Bit64u);
+#endif
+
 void BX_CPP_AttrRegparmN(1) BX_CPU_C::BOUND_GwMa(bxInstruction_c *i)
 {
   Bit16s op1_16 = BX_READ_16BIT_REG(i->dst());
@@ -92,6 +97,24 @@ void BX_CPP_AttrRegparmN(1) BX_CPU_C::INT1(bxInstruction_c *i)
 
 void BX_CPP_AttrRegparmN(1) BX_CPU_C::INT3(bxInstruction_c *i)
 {
+#ifdef BOCHSERVISOR
+  // Coverage breakpoints are taken out by bochservisor, in which case we go
+  // back and run the instruction the breakpoint replaced
+  if (report_breakpoint && (*report_breakpoint)(
+        BX_CPU_THIS_PTR cr0.get32(),
+        BX_CPU_THIS_PTR cr3,
+        BX_CPU_THIS_PTR cr4.get32(),
+        (BX_CPU_THIS_PTR efer.get32() & (1 << 10)) != 0,
+        BX_CPU_THIS_PTR sregs[BX_SEG_REG_GS].cache.u.segment.base,
+        BX_CPU_THIS_PTR sregs[BX_SEG_REG_CS].selector.value,
+        BX_CPU_THIS_PTR sregs[BX_SEG_REG_CS].cache.u.segment.base + PREV_RIP))
+  {
+    RIP = PREV_RIP;
+    flushICaches();
+    BX_NEXT_TRACE(i);
+  }
+#endif
+
   BX_INSTR_FAR_BRANCH_ORIGIN();
 
   // INT 3 is not IOPL sensitive
@@ -399,20 +399,33 @@ Bit64u dirty_bits_l2[(4ULL * 1024 * 1024 * 1024) / (4096 * 64)] = { 0 };
 
 // Function pointers passed to the Rust DLL for accessing things they need in
 // the Bochs environment
+//
+// Routines which touch CPU state take the index of the Bochs processor to
+// operate on as `cpu`
 struct _bochs_routines {
-  void  (*set_context)(const struct _whvp_context*);
-  void  (*get_context)(struct _whvp_context*);
-  void  (*step_device)(Bit64u steps);
-  void  (*step_cpu)(Bit64u steps);
+  void  (*set_context)(Bit32u cpu, const struct _whvp_context*);
+  void  (*get_context)(Bit32u cpu, struct _whvp_context*);
+  void  (*step_device)(Bit32u cpu, Bit64u steps);
+  void  (*step_cpu)(Bit32u cpu, Bit64u steps);
   void* (*get_memory_backing)(Bit64u address, int type);
-  void  (*cpuid)(Bit32u leaf, Bit32u subleaf, Bit32u *eax,
+  void  (*cpuid)(Bit32u cpu, Bit32u leaf, Bit32u subleaf, Bit32u *eax,
     Bit32u *ebx, Bit32u *ecx, Bit32u *edx);
-  void  (*write_msr)(Bit32u index, Bit64u value);
+  void  (*write_msr)(Bit32u cpu, Bit32u index, Bit64u value);
   void  (*after_restore)(void);
   void  (*reset_all)(void);
   void  (*take_snapshot)(const char *folder_name);
+  unsigned (*activity_state)(Bit32u cpu);
 };
 
+// All of the glue below is written as if it were a member of the CPU class.
+// Point it at the processor selected by the `cpu` argument instead. This also
+// makes it work with SMP builds where `BX_CPU_THIS_PTR` is `this->`
+#undef  BX_CPU_THIS_PTR
+#define BX_CPU_THIS_PTR BX_CPU(cpu)->
+#undef  BX_CPU_CALL_METHOD
+#define BX_CPU_CALL_METHOD(func, args) \
+  (BX_CPU(cpu)->*((BxExecutePtr_tR) (func))) args
+
 // Take a Bochs snapshot, save it to `folder_name` and then exit Bochs cleanly
 void take_snapshot(const char *folder_name) {
   SIM->save_state(folder_name);
@@ -420,12 +433,25 @@ void take_snapshot(const char *folder_name) {
 }
 
 // Write an MSR into Bochs state
-void write_msr(Bit32u index, Bit64u value) {
+void write_msr(Bit32u cpu, Bit32u index, Bit64u value) {
+  // A rejected write raises #GP, see `step_cpu()` for why secondary processors
+  // need a landing spot of their own
+  if (cpu != 0) {
+    if (setjmp(BX_CPU_THIS_PTR jmp_buf_env)) return;
+  }
+
   BX_CPU_THIS_PTR wrmsr(index, value);
 }
 
+// Get the activity state of a processor. Anything other than
+// `BX_ACTIVITY_STATE_ACTIVE` means the processor is halted or waiting for a
+// SIPI, and must be left to Bochs to wake up
+unsigned activity_state(Bit32u cpu) {
+  return BX_CPU_THIS_PTR activity_state;
+}
+
 // Perform a Bochs CPUID and return the result to the caller
-void do_cpuid(Bit32u leaf, Bit32u subleaf, Bit32u *eax,
+void do_cpuid(Bit32u cpu, Bit32u leaf, Bit32u subleaf, Bit32u *eax,
     Bit32u *ebx, Bit32u *ecx, Bit32u *edx)
 {
   struct cpuid_function_t result = { 0 };
@@ -469,8 +495,9 @@ void bochservisor_after_restore(void) {
 
 // get_memory_backing implementation for Rust which allows Rust to get access to
 // memory backings for certain physical addresses
+// Memory is shared between all processors so we always ask processor 0
 void* get_memory_backing(Bit64u address, int type) {
-  return (void*)BX_CPU_THIS_PTR getHostMemAddr(address, type);
+  return (void*)BX_CPU(0)->getHostMemAddr(address, type);
 }
 
 // Number of hypervisor context switches. This is used to track the age of
@@ -485,7 +512,7 @@ Bit64u hypervisor_context_switches = 0x12ad1fef77be846aULL;
 
 // set_context implementation that allows Rust to provide a new CPU context for
 // Bochs to use internally
-void set_context(const struct _whvp_context* context) {
+void set_context(Bit32u cpu, const struct _whvp_context* context) {
   // Update number of context switches, this will cause icache entires to be
   // flushed conditionally if they are older than this number
   hypervisor_context_switches++;
@@ -642,7 +669,7 @@ void set_context(const struct _whvp_context* context) {
 
 // get_context implementation to allow Rust to get access to all of the CPU
 // state internal to Bochs
-void get_context(struct _whvp_context* context) {
+void get_context(Bit32u cpu, struct _whvp_context* context) {
   context->rax.Reg64 = RAX;
   context->rcx.Reg64 = RCX;
   context->rdx.Reg64 = RDX;
@@ -758,18 +785,66 @@ void get_context(struct _whvp_context* context) {
   context->tsc_aux.Reg64 = BX_CPU_THIS_PTR msr.tsc_aux;
 }
 
+// Check if processor `cpu` is about to run an instruction with an enabled
+// execute breakpoint in DR0-DR3. If it is the breakpoints hit are put in DR6
+static bool code_breakpoint_hit(Bit32u cpu) {
+  Bit32u dr7 = BX_CPU_THIS_PTR dr7.get32();
+  Bit64u rip = BX_CPU_THIS_PTR sregs[BX_SEG_REG_CS].cache.u.segment.base + RIP;
+
+  Bit32u hit = 0;
+  for (int ii = 0; ii < 4; ii++) {
+    // Enabled locally or globally, with a zero type which is execute
+    if (((dr7 >> (ii * 2)) & 3) != 0 && ((dr7 >> (16 + ii * 4)) & 3) == 0 &&
+        BX_CPU_THIS_PTR dr[ii] == rip) {
+      hit |= 1 << ii;
+    }
+  }
+
+  if (!hit) return false;
+  BX_CPU_THIS_PTR dr6.set32((BX_CPU_THIS_PTR dr6.get32() & ~0xf) | hit);
+  return true;
+}
+
 // step_cpu() implementation which allows Rust to run a certain amount of
 // instructions (or chains with optimizations on).
 //
+// Bochs only checks DR0-DR3 when built with the x86 debugger, which would
+// hand the #DB to the guest. Instead stepping stops on an enabled execute
+// breakpoint with it in DR6, the same as a #DB exit from the hypervisor, and
+// Rust handles it from there. RF suppresses the breakpoint on the instruction
+// we resume at, like in hardware. Stopping sets it so the next call runs the
+// instruction whether or not Rust looked at DR6. With handler chaining a step
+// is a whole trace, so only breakpoints at the start of a trace are seen.
+// Traces start at branch targets, which is where the fuzzing breakpoints
+// usually are.
+//
 // This code is nearly directly copied and pasted from the actual Bochs CPU
 // loop
-void step_cpu(Bit64u steps) {
+void step_cpu(Bit32u cpu, Bit64u steps) {
+  // Secondary processors never run `cpu_loop()` so there is nothing for their
+  // exceptions to `longjmp()` back to. Set up a landing spot here instead, an
+  // exception on these processors just ends this round of stepping.
+  //
+  // The landing spot is only valid while this frame is live. Every routine
+  // which can raise an exception on a secondary processor (`step_cpu()`,
+  // `step_device()` and `write_msr()`) sets up its own on entry, so a
+  // `longjmp()` never lands in a frame which already returned
+  if (cpu != 0) {
+    if (setjmp(BX_CPU_THIS_PTR jmp_buf_env)) {
+      BX_CPU_THIS_PTR icount++;
+      return;
+    }
+  }
+
   // Flush TLBs, this also resets stack and prefetch cache
   // We do this here to make sure our dirty bits get updated. If TLBs are
   // present it's possible to execute in the emulator and skip the call to
   // write physical memory which would cause us to miss setting dirty bits.
   BX_CPU_THIS_PTR TLB_flush();
 
+  // Whether RF is set for the instruction we resume at
+  bool resume = BX_CPU_THIS_PTR get_RF() != 0;
+
   // Step while we have steps... duh
   while(steps) {
     // Completed a step
@@ -784,6 +859,15 @@ void step_cpu(Bit64u steps) {
       }
     }
 
+    // Stop on breakpoints, RF only covers the first instruction
+    if (resume) {
+      BX_CPU_THIS_PTR clear_RF();
+      resume = false;
+    } else if (code_breakpoint_hit(cpu)) {
+      BX_CPU_THIS_PTR assert_RF();
+      return;
+    }
+
     bxICacheEntry_c *entry = BX_CPU_THIS_PTR getICacheEntry();
     bxInstruction_c *i = entry->i;
 
@@ -844,7 +928,20 @@ void step_cpu(Bit64u steps) {
 // step_device() implementation. This steps the device and time emulation in
 // Bochs. This is used very frequently to make sure things like timer interrupts
 // are delivered to the guest.
-void step_device(Bit64u steps) {
+//
+// Only async events for processor `cpu` are handled here. Other processors may
+// be running in the hypervisor, making their Bochs state stale, so they pick
+// up their events when they are next synced
+void step_device(Bit32u cpu, Bit64u steps) {
+  // Delivering an async event can raise an exception, see `step_cpu()` for
+  // why secondary processors need a landing spot of their own
+  if (cpu != 0) {
+    if (setjmp(BX_CPU_THIS_PTR jmp_buf_env)) {
+      BX_CPU_THIS_PTR icount++;
+      return;
+    }
+  }
+
   // Flush TLBs, this also resets stack and prefetch cache
   // We do this here to make sure our dirty bits get updated. If TLBs are
   // present it's possible to execute in the emulator and skip the call to
@@ -877,10 +974,16 @@ void step_device(Bit64u steps) {
 struct _bochs_routines routines = { 0 };
 
 // Cached address of the Rust routine to call instead of the normal CPU loop
-void (*bochs_cpu_loop)(struct _bochs_routines*, Bit64u, void*, void*, void*, void*) = NULL;
+void (*bochs_cpu_loop)(struct _bochs_routines*, Bit32u, Bit64u, void*, void*, void*, void*) = NULL;
 
 // Cached address of the Rust code coverage callback
-void (*report_coverage)(Bit64u, int, Bit64u, Bit16u, Bit64u, Bit64u) = NULL;
+void (*report_coverage)(Bit64u, Bit64u, Bit64u, int, Bit64u, Bit64u, Bit16u,
+    Bit64u, Bit64u) = NULL;
+
+// Cached address of the Rust callback for `int3`s, which returns true for the
+// coverage breakpoints it placed
+bool (*report_breakpoint)(Bit64u, Bit64u, Bit64u, int, Bit64u, Bit16u,
+    Bit64u) = NULL;
 
 // Cached address of the Rust device state registration callback
 // Type is an enum from `enum _shadow_type` in paramtree.cc
@@ -892,8 +995,6 @@ void initialize_bochservisor()
 {
   static int initialized = 0;
 
-  BX_CPU_THIS_PTR cr3;
-
   if(initialized) {
     fprintf(stderr, "initialize_bochservisor() got called twice!?\n");
     exit(-1);
@@ -915,12 +1016,13 @@ void initialize_bochservisor()
     exit(-1);
   }
 
-  // We only support single core right now, enforce that
+  // Every processor gets a bit in the kicker mask on the Rust side, so we can
+  // support at most 64 of them
   Bit64u procs   = SIM->get_param_num(BXPN_CPU_NPROCESSORS)->get();
   Bit64u cores   = SIM->get_param_num(BXPN_CPU_NCORES)->get();
   Bit64u threads = SIM->get_param_num(BXPN_CPU_NTHREADS)->get();
-  if(procs != 1 || cores != 1 || threads != 1) {
-    fprintf(stderr, "Bochservisor requires procs=cores=threads=1 in your bochsrc!\n");
+  if(procs * cores * threads > 64) {
+    fprintf(stderr, "Bochservisor supports at most 64 processors in your bochsrc!\n");
     exit(-1);
   }
 
@@ -949,9 +1051,10 @@ void initialize_bochservisor()
   routines.after_restore      = bochservisor_after_restore;
   routines.reset_all          = bochservisor_reset;
   routines.take_snapshot      = take_snapshot;
+  routines.activity_state     = activity_state;
 
   // Lookup the address of the Rust CPU look implementation in the DLL
-  bochs_cpu_loop = (void (*)(struct _bochs_routines*, Bit64u, void*, void*, void*, void*))
+  bochs_cpu_loop = (void (*)(struct _bochs_routines*, Bit32u, Bit64u, void*, void*, void*, void*))
     GetProcAddress(module, "bochs_cpu_loop");
   if(!bochs_cpu_loop) {
     fprintf(stderr, "GetProcAddress() error : %d\n", GetLastError());
@@ -959,13 +1062,21 @@ void initialize_bochservisor()
   }
 
   // Lookup the address of the Rust coverage reporting routine
-  report_coverage = (void (*)(Bit64u, int, Bit64u, Bit16u, Bit64u, Bit64u))
-    GetProcAddress(module, "report_coverage");
+  report_coverage = (void (*)(Bit64u, Bit64u, Bit64u, int, Bit64u, Bit64u,
+    Bit16u, Bit64u, Bit64u))GetProcAddress(module, "report_coverage");
   if(!report_coverage) {
     fprintf(stderr, "GetProcAddress() error : %d\n", GetLastError());
     exit(-1);
   }
 
+  // Lookup the address of the Rust coverage breakpoint routine
+  report_breakpoint = (bool (*)(Bit64u, Bit64u, Bit64u, int, Bit64u, Bit16u,
+    Bit64u))GetProcAddress(module, "report_breakpoint");
+  if(!report_breakpoint) {
+    fprintf(stderr, "GetProcAddress() error : %d\n", GetLastError());
+    exit(-1);
+  }
+
   // Lookup the address of the routine to call in Rust to notify that we
   // have more device state to report
   register_state = (void (*)(const char *name, const char *label, void *data, size_t size, int type))
@@ -1742,7 +1853,12 @@ int bx_begin_simulation(int argc, char *argv[])
   else
 #endif
   {
+#ifdef BOCHSERVISOR
+    // The Rust CPU loop drives every processor from processor 0's `cpu_loop()`
+    if (1) {
+#else
     if (BX_SMP_PROCESSORS == 1) {
+#endif
       // only one processor, run as fast as possible by not messing with
       // quantums and loops.
       while (1) {
@@ -0,0 +1,424 @@
+/// Hypervisor backend abstraction
+///
+/// The CPU loop in `lib.rs` only talks to the hypervisor through the
+/// `HypervisorBackend` trait and only looks at `VmExit` values. This keeps the
+/// loop's exit handling, emulation hand-off, and restore logic independent of
+/// WHVP. `Whvp` is the real backend, `SoftwareBackend` runs everything in Bochs.
+///
+/// Backends report failures as `BackendError`s, it's up to the CPU loop to
+/// decide whether it's worth trying again or if we have to give up.
+
+use std::sync::mpsc::{channel, Sender, Receiver};
+use crate::context::VpContext;
+
+/// A failed call into a backend
+#[derive(Clone, Copy, Debug)]
+pub struct BackendError {
+    /// Name of the API which failed
+    pub api: &'static str,
+
+    /// Error code the API returned, an HRESULT for WHVP
+    pub code: i32,
+
+    /// Human readable description of `code`, if the backend knows it
+    pub description: Option<&'static str>,
+
+    /// Whether trying again (possibly after emulating for a bit) has a chance
+    /// of working
+    pub transient: bool,
+}
+
+impl BackendError {
+    /// Returns `true` if trying again (possibly after emulating for a bit)
+    /// has a chance of working. Errors like missing Admin access, missing
+    /// features, or a bad partition will never go away on their own.
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}() error: {:#x}", self.api, self.code)?;
+        if let Some(desc) = self.description {
+            write!(f, " ({})", desc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason a backend returned from `run()`. This is just the kind of exit
+/// without any of the associated information, it's used for statistics
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum VmExitReason {
+    None,
+    MemoryAccess,
+    IoPortAccess,
+    UnrecoverableException,
+    InvalidVpRegisterValue,
+    UnsupportedFeature,
+    InterruptWindow,
+    Halt,
+    ApicEoi,
+    MsrAccess,
+    Cpuid,
+    Exception,
+    Canceled,
+}
+
+/// A VM exit along with the information we need to handle it
+#[derive(Clone, Copy, Debug)]
+pub enum VmExit {
+    None,
+
+    /// Access to guest physical memory which is not mapped (MMIO)
+    MemoryAccess { gpa: u64, gva: u64 },
+
+    /// Access to an I/O port
+    IoPortAccess { port: u16 },
+
+    UnrecoverableException,
+    InvalidVpRegisterValue,
+
+    /// The backend can't run the guest in its current state, Bochs has to
+    UnsupportedFeature,
+
+    InterruptWindow,
+    Halt,
+    ApicEoi,
+
+    /// Access to MSR `msr`
+    MsrAccess { msr: u32 },
+
+    /// A CPUID instruction. `default` holds the rax, rbx, rcx, and rdx the
+    /// backend would have returned to the guest
+    Cpuid {
+        leaf:               u64,
+        subleaf:            u64,
+        default:            [u64; 4],
+        instruction_length: u8,
+    },
+
+    /// Exception `vector` was raised in the guest
+    Exception { vector: u8, error_code: Option<u32> },
+
+    /// The run was cancelled by the kicker
+    Canceled,
+}
+
+impl VmExit {
+    /// Get the kind of exit this is for statistics
+    pub fn reason(&self) -> VmExitReason {
+        match self {
+            VmExit::None                   => VmExitReason::None,
+            VmExit::MemoryAccess { .. }    => VmExitReason::MemoryAccess,
+            VmExit::IoPortAccess { .. }    => VmExitReason::IoPortAccess,
+            VmExit::UnrecoverableException =>
+                VmExitReason::UnrecoverableException,
+            VmExit::InvalidVpRegisterValue =>
+                VmExitReason::InvalidVpRegisterValue,
+            VmExit::UnsupportedFeature     => VmExitReason::UnsupportedFeature,
+            VmExit::InterruptWindow        => VmExitReason::InterruptWindow,
+            VmExit::Halt                   => VmExitReason::Halt,
+            VmExit::ApicEoi                => VmExitReason::ApicEoi,
+            VmExit::MsrAccess { .. }       => VmExitReason::MsrAccess,
+            VmExit::Cpuid { .. }           => VmExitReason::Cpuid,
+            VmExit::Exception { .. }       => VmExitReason::Exception,
+            VmExit::Canceled               => VmExitReason::Canceled,
+        }
+    }
+}
+
+/// Everything the CPU loop needs from a hypervisor
+///
+/// Virtual processors are numbered from 0 to `num_vps() - 1` and match the
+/// Bochs processor with the same index
+pub trait HypervisorBackend {
+    /// Number of virtual processors in the guest
+    fn num_vps(&self) -> u32;
+
+    /// Map `backing` into the guest at physical address `paddr` with `perms`,
+    /// a bitwise or-ed combination of `PERM_READ`, `PERM_WRITE`, and
+    /// `PERM_EXECUTE`
+    fn map_memory(&mut self, paddr: usize, backing: &mut [u8], perms: i32)
+        -> Result<(), BackendError>;
+
+    /// Get a routine which runs `vp` until it exits. This is handed to the
+    /// run thread of `vp`, so while it is running the state of `vp` must not
+    /// be touched from anywhere else
+    fn runner(&self, vp: u32)
+        -> Box<dyn Fn() -> Result<VmExit, BackendError> + Send>;
+
+    /// Get the entire register state of `vp`
+    fn get_context(&self, vp: u32) -> Result<VpContext, BackendError>;
+
+    /// Set the entire register state of `vp`
+    fn set_context(&mut self, vp: u32, context: &VpContext)
+        -> Result<(), BackendError>;
+
+    /// Or in the pages dirtied since the last call into the `dirty_bits_l1`
+    /// (1 MiB) and `dirty_bits_l2` (4 KiB) bitmaps
+    fn get_dirty_list(&mut self, dirty_bits_l1: &mut [u64],
+        dirty_bits_l2: &mut [u64]) -> Result<(), BackendError>;
+
+    /// Request an exit as soon as `vp` can take an interrupt
+    fn register_interrupt_window(&mut self, vp: u32)
+        -> Result<(), BackendError>;
+
+    /// Deliver exception `vector` to `vp`, with an optional error code
+    fn deliver_exception(&mut self, vp: u32, vector: u8,
+        error_code: Option<u32>) -> Result<(), BackendError>;
+
+    /// Clear a pending exception on `vp`
+    fn clear_pending_exception(&mut self, vp: u32) -> Result<(), BackendError>;
+
+    /// Number of cycles of overhead for a single run
+    fn overhead(&self) -> u64;
+
+    /// Get a routine which forces the run in progress on the VP it is passed
+    /// to exit with `VmExit::Canceled`. This is what the kicker thread calls.
+    ///
+    /// Backends which never run the guest themselves return `None`
+    fn canceller(&self) -> Option<Box<dyn Fn(u32) + Send>>;
+}
+
+/// Backend which never runs anything itself. Every run returns
+/// `VmExit::UnsupportedFeature` which hands execution to Bochs.
+///
+/// This is painfully slow but needs no hypervisor at all, which makes it
+/// useful for working on the CPU loop without Hyper-V
+pub struct SoftwareBackend {
+    /// Last context set by the CPU loop for each VP
+    contexts: Vec<VpContext>,
+}
+
+impl SoftwareBackend {
+    /// Create a new software backend with `num_vps` processors
+    pub fn new(num_vps: u32) -> Self {
+        SoftwareBackend {
+            contexts: vec![VpContext::default(); num_vps as usize]
+        }
+    }
+}
+
+impl HypervisorBackend for SoftwareBackend {
+    fn num_vps(&self) -> u32 {
+        self.contexts.len() as u32
+    }
+
+    fn map_memory(&mut self, _paddr: usize, _backing: &mut [u8],
+            _perms: i32) -> Result<(), BackendError> {
+        // Nothing to map, Bochs already has the memory
+        Ok(())
+    }
+
+    fn runner(&self, _vp: u32)
+            -> Box<dyn Fn() -> Result<VmExit, BackendError> + Send> {
+        Box::new(|| Ok(VmExit::UnsupportedFeature))
+    }
+
+    fn get_context(&self, vp: u32) -> Result<VpContext, BackendError> {
+        Ok(self.contexts[vp as usize])
+    }
+
+    fn set_context(&mut self, vp: u32, context: &VpContext)
+            -> Result<(), BackendError> {
+        self.contexts[vp as usize] = *context;
+        Ok(())
+    }
+
+    fn get_dirty_list(&mut self, _dirty_bits_l1: &mut [u64],
+            _dirty_bits_l2: &mut [u64]) -> Result<(), BackendError> {
+        // Bochs tracks dirty bits for everything it executes, and we never
+        // execute anything ourselves
+        Ok(())
+    }
+
+    fn register_interrupt_window(&mut self, _vp: u32)
+            -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn deliver_exception(&mut self, _vp: u32, _vector: u8,
+            _error_code: Option<u32>) -> Result<(), BackendError> {
+        // Nothing runs here, so there's nothing to deliver the exception to
+        Err(BackendError {
+            api:         "deliver_exception",
+            code:        -1,
+            description: Some("Exceptions can't be delivered by the software \
+                backend"),
+            transient:   false,
+        })
+    }
+
+    fn clear_pending_exception(&mut self, _vp: u32)
+            -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn overhead(&self) -> u64 { 0 }
+
+    fn canceller(&self) -> Option<Box<dyn Fn(u32) + Send>> { None }
+}
+
+/// Exit of a VP reported by its run thread
+pub type VpExit = (u32, Result<VmExit, BackendError>);
+
+/// Run thread for virtual processor `vp`. Every request on `requests` runs the
+/// VP with `run` until it exits, the exit is then reported on `exits`.
+///
+/// WHVP blocks the calling thread while a VP runs, so this is what lets all of
+/// the VPs run at the same time
+fn vp_runner(vp: u32, run: Box<dyn Fn() -> Result<VmExit, BackendError> + Send>,
+        requests: Receiver<()>, exits: Sender<VpExit>) {
+    while requests.recv().is_ok() {
+        let vmexit = run();
+
+        // CPU loop went away, nobody cares about this exit
+        if exits.send((vp, vmexit)).is_err() { break; }
+    }
+}
+
+/// Create a run thread for every VP of `backend`. Returns the senders to
+/// request a run of each VP, and the receiver all of their exits are reported
+/// on
+pub fn spawn_runners(backend: &dyn HypervisorBackend)
+        -> (Vec<Sender<()>>, Receiver<VpExit>) {
+    let (exit_sender, exit_receiver) = channel();
+    let requests = (0..backend.num_vps()).map(|vp| {
+        let (run_sender, run_receiver) = channel();
+        let runner = backend.runner(vp);
+        let exits  = exit_sender.clone();
+        std::thread::spawn(move || vp_runner(vp, runner, run_receiver, exits));
+        run_sender
+    }).collect();
+    (requests, exit_receiver)
+}
+
+/// Backend which runs from a script of exits for every VP. Every run advances
+/// RIP by one and returns the next exit, `VmExit::Halt` once out of exits
+#[cfg(test)]
+struct MockBackend {
+    contexts: std::sync::Arc<std::sync::Mutex<Vec<VpContext>>>,
+    scripts:  Vec<std::sync::Arc<std::sync::Mutex<
+        std::collections::VecDeque<Result<VmExit, BackendError>>>>>,
+}
+
+#[cfg(test)]
+impl HypervisorBackend for MockBackend {
+    fn num_vps(&self) -> u32 { self.scripts.len() as u32 }
+
+    fn map_memory(&mut self, _paddr: usize, _backing: &mut [u8],
+            _perms: i32) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn runner(&self, vp: u32)
+            -> Box<dyn Fn() -> Result<VmExit, BackendError> + Send> {
+        let contexts = self.contexts.clone();
+        let script   = self.scripts[vp as usize].clone();
+        Box::new(move || {
+            unsafe { contexts.lock().unwrap()[vp as usize].rip.Reg64 += 1; }
+            script.lock().unwrap().pop_front().unwrap_or(Ok(VmExit::Halt))
+        })
+    }
+
+    fn get_context(&self, vp: u32) -> Result<VpContext, BackendError> {
+        Ok(self.contexts.lock().unwrap()[vp as usize])
+    }
+
+    fn set_context(&mut self, vp: u32, context: &VpContext)
+            -> Result<(), BackendError> {
+        self.contexts.lock().unwrap()[vp as usize] = *context;
+        Ok(())
+    }
+
+    fn get_dirty_list(&mut self, _dirty_bits_l1: &mut [u64],
+            _dirty_bits_l2: &mut [u64]) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn register_interrupt_window(&mut self, _vp: u32)
+            -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn deliver_exception(&mut self, _vp: u32, _vector: u8,
+            _error_code: Option<u32>) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn clear_pending_exception(&mut self, _vp: u32)
+            -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn overhead(&self) -> u64 { 0 }
+
+    fn canceller(&self) -> Option<Box<dyn Fn(u32) + Send>> { None }
+}
+
+#[test]
+fn test_vp_runners() {
+    let transient = BackendError {
+        api: "run", code: 1, description: None, transient: true,
+    };
+    let scripts = vec![
+        vec![Ok(VmExit::IoPortAccess { port: 0x3f8 }), Err(transient),
+            Ok(VmExit::Exception { vector: 3, error_code: None })],
+        vec![Ok(VmExit::MsrAccess { msr: 0x10 })],
+    ];
+    let mut backend = MockBackend {
+        contexts: Default::default(),
+        scripts:  scripts.into_iter().map(|x|
+            std::sync::Arc::new(std::sync::Mutex::new(x.into()))).collect(),
+    };
+    *backend.contexts.lock().unwrap() =
+        vec![VpContext::default(); backend.num_vps() as usize];
+
+    // Every VP starts somewhere else
+    for vp in 0..backend.num_vps() {
+        let mut context = VpContext::default();
+        context.rip.Reg64 = 0x1000 * (vp as u64 + 1);
+        backend.set_context(vp, &context).unwrap();
+    }
+
+    // Run everything until it halts, transient errors just run again
+    let (requests, exits) = spawn_runners(&backend);
+    requests.iter().for_each(|x| x.send(()).unwrap());
+    let mut seen = vec![Vec::new(); requests.len()];
+    let mut runs = vec![0; requests.len()];
+    let mut running = requests.len();
+    while running > 0 {
+        let (vp, vmexit) = exits.recv().unwrap();
+        runs[vp as usize] += 1;
+
+        // The state is whatever the run thread left behind
+        let context = backend.get_context(vp).unwrap();
+        assert_eq!(unsafe { context.rip.Reg64 },
+            0x1000 * (vp as u64 + 1) + runs[vp as usize]);
+
+        match vmexit {
+            Ok(VmExit::Halt) => { running -= 1; continue; }
+            Ok(vmexit) => seen[vp as usize].push(vmexit.reason()),
+            Err(err)   => assert!(err.is_transient()),
+        }
+        requests[vp as usize].send(()).unwrap();
+    }
+    assert_eq!(seen, [
+        vec![VmExitReason::IoPortAccess, VmExitReason::Exception],
+        vec![VmExitReason::MsrAccess],
+    ]);
+    assert_eq!(runs, [4, 2]);
+
+    // The run threads go away with the requests
+    std::mem::drop(requests);
+    assert!(exits.recv().is_err());
+
+    // Bochs runs everything for the software backend, and it can't inject
+    let mut software = SoftwareBackend::new(1);
+    assert_eq!(software.runner(0)().unwrap().reason(),
+        VmExitReason::UnsupportedFeature);
+    let err = software.deliver_exception(0, 3, None).unwrap_err();
+    assert!(!err.is_transient());
+}
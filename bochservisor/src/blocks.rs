@@ -0,0 +1,397 @@
+/// Static basic block discovery for PE images
+///
+/// Breakpoint coverage and coverage percentages need the offset of every basic
+/// block in a module. On x64 every function which isn't a leaf has an entry in
+/// the exception directory (`.pdata`), so each of those ranges is linearly
+/// disassembled. Blocks start at function entries, at direct branch and jump
+/// targets, and after conditional branches. Call and jump targets outside of
+/// every `.pdata` range are leaf functions, these are followed by recursive
+/// descent instead.
+///
+/// A breakpoint in the middle of an instruction corrupts it, so this errs on
+/// the side of missing blocks. Code only reached indirectly (eg. through jump
+/// tables) isn't found, and targets are only kept if they're the start of an
+/// instruction we decoded. Only 64-bit images are supported.
+
+use std::collections::HashSet;
+use crate::memreader::MemReader;
+use crate::virtmem::Paging;
+use crate::x86::{self, Flow};
+
+/// `IMAGE_FILE_MACHINE_AMD64`
+const MACHINE_AMD64: u16 = 0x8664;
+
+/// `IMAGE_NT_OPTIONAL_HDR64_MAGIC`
+const OPTIONAL_MAGIC64: u16 = 0x20b;
+
+/// `IMAGE_SCN_MEM_EXECUTE`
+const SCN_MEM_EXECUTE: u32 = 0x20000000;
+
+/// Index of `IMAGE_DIRECTORY_ENTRY_EXCEPTION` in the data directories
+const DIRECTORY_EXCEPTION: usize = 3;
+
+/// Size of a `RUNTIME_FUNCTION`
+const RUNTIME_FUNCTION_SIZE: usize = 12;
+
+/// A section of a PE image
+struct Section {
+    /// RVA of the section
+    rva: u32,
+
+    /// Size of the section once mapped
+    size: u32,
+
+    /// Offset of the section's data in the file
+    raw_offset: u32,
+
+    /// Size of the section's data in the file
+    raw_size: u32,
+
+    /// Set if the section holds code
+    exec: bool,
+}
+
+/// The parts of the PE headers we care about
+struct Headers {
+    /// Size of the image once mapped
+    size_of_image: u32,
+
+    /// Size of the headers, which are mapped as-is at the start of the image
+    size_of_headers: u32,
+
+    /// RVA and size of the exception directory
+    pdata: (u32, u32),
+
+    /// Sections of the image
+    sections: Vec<Section>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Parse the PE headers at the start of `bytes`, which is either a file or a
+/// mapped image as the headers are the same in both
+fn parse_headers(bytes: &[u8]) -> Result<Headers, String> {
+    let truncated = || "Truncated PE headers".to_string();
+
+    if bytes.get(..2) != Some(b"MZ") {
+        return Err("Missing MZ signature".into());
+    }
+    let pe = read_u32(bytes, 0x3c).ok_or_else(truncated)? as usize;
+    if bytes.get(pe..pe + 4) != Some(b"PE\0\0") {
+        return Err("Missing PE signature".into());
+    }
+    if read_u16(bytes, pe + 4).ok_or_else(truncated)? != MACHINE_AMD64 {
+        return Err("Not an x64 image".into());
+    }
+
+    let num_sections = read_u16(bytes, pe + 6).ok_or_else(truncated)?;
+    let opt_size = read_u16(bytes, pe + 20).ok_or_else(truncated)? as usize;
+    let opt = pe + 24;
+    if read_u16(bytes, opt).ok_or_else(truncated)? != OPTIONAL_MAGIC64 {
+        return Err("Not a PE32+ image".into());
+    }
+
+    let size_of_image   = read_u32(bytes, opt + 56).ok_or_else(truncated)?;
+    let size_of_headers = read_u32(bytes, opt + 60).ok_or_else(truncated)?;
+    let num_dirs = read_u32(bytes, opt + 108).ok_or_else(truncated)? as usize;
+    let pdata = if num_dirs > DIRECTORY_EXCEPTION {
+        let dir = opt + 112 + DIRECTORY_EXCEPTION * 8;
+        (read_u32(bytes, dir).ok_or_else(truncated)?,
+         read_u32(bytes, dir + 4).ok_or_else(truncated)?)
+    } else {
+        (0, 0)
+    };
+
+    let mut sections = Vec::new();
+    for ii in 0..num_sections as usize {
+        let section = opt + opt_size + ii * 40;
+        let read = |offset| read_u32(bytes, section + offset)
+            .ok_or_else(truncated);
+        let raw_size = read(16)?;
+        let size = match read(8)? {
+            0    => raw_size,
+            size => size,
+        };
+
+        sections.push(Section {
+            rva: read(12)?,
+            size,
+            raw_offset: read(20)?,
+            raw_size,
+            exec: read(36)? & SCN_MEM_EXECUTE != 0,
+        });
+    }
+
+    Ok(Headers { size_of_image, size_of_headers, pdata, sections })
+}
+
+/// Map the PE file `file` the way the loader would, without relocations
+pub fn map_file(file: &[u8]) -> Result<Vec<u8>, String> {
+    let headers = parse_headers(file)?;
+
+    let mut image = vec![0u8; headers.size_of_image as usize];
+    let len = std::cmp::min(headers.size_of_headers as usize,
+        std::cmp::min(file.len(), image.len()));
+    image[..len].copy_from_slice(&file[..len]);
+
+    for section in &headers.sections {
+        let len = std::cmp::min(section.size, section.raw_size) as usize;
+        let src = file.get(section.raw_offset as usize..)
+            .and_then(|x| x.get(..len));
+        let dst = image.get_mut(section.rva as usize..)
+            .and_then(|x| x.get_mut(..len));
+        match (src, dst) {
+            (Some(src), Some(dst)) => dst.copy_from_slice(src),
+            _ => return Err("Section outside of the file or image".into()),
+        }
+    }
+
+    Ok(image)
+}
+
+/// Read the image of `size` bytes loaded at `base` from guest memory, using
+/// page table `cr3` of a processor in `paging` mode. Only the headers, the
+/// code and the exception directory are read, the rest is left zeroed. Fails
+/// if any page of those isn't resident
+pub fn read_image(memory: &mut MemReader, paging: Paging, cr3: usize,
+        base: usize, size: usize) -> Result<Vec<u8>, String> {
+    let mut image = vec![0u8; size];
+
+    let mut read_range = |image: &mut [u8], start: usize, end: usize| {
+        let end = std::cmp::min(end, image.len());
+        for page in (start & !0xfff..end).step_by(0x1000) {
+            let page_end = std::cmp::min(page + 0x1000, end);
+            let buf = &mut image[page..page_end];
+            if memory.read_virt_paging(paging, cr3, base + page, buf) !=
+                    buf.len() {
+                return Err(format!("Page {:#x} of the image isn't resident",
+                    base + page));
+            }
+        }
+        Ok(())
+    };
+
+    read_range(&mut image, 0, 0x1000)?;
+    let headers = parse_headers(&image)?;
+    read_range(&mut image, 0, headers.size_of_headers as usize)?;
+
+    for section in headers.sections.iter().filter(|x| x.exec) {
+        read_range(&mut image, section.rva as usize,
+            section.rva as usize + section.size as usize)?;
+    }
+    let (pdata, pdata_size) = headers.pdata;
+    read_range(&mut image, pdata as usize,
+        pdata as usize + pdata_size as usize)?;
+
+    Ok(image)
+}
+
+/// Find the basic blocks of the mapped image `image`. Returns the sorted
+/// offsets of every block found
+pub fn discover(image: &[u8]) -> Result<Vec<u32>, String> {
+    let headers = parse_headers(image)?;
+    let exec: Vec<(u32, u32)> = headers.sections.iter().filter(|x| x.exec)
+        .map(|x| (x.rva, x.rva.saturating_add(x.size))).collect();
+    let in_exec = |rva: u32| exec.iter().any(|&(start, end)|
+        rva >= start && rva < end);
+
+    // Function ranges from the exception directory, sorted by start
+    let (pdata, pdata_size) = headers.pdata;
+    let mut funcs: Vec<(u32, u32)> = image
+        .get(pdata as usize..pdata as usize + pdata_size as usize)
+        .ok_or("Exception directory outside of the image")?
+        .chunks_exact(RUNTIME_FUNCTION_SIZE)
+        .map(|x| (read_u32(x, 0).unwrap(), read_u32(x, 4).unwrap()))
+        .filter(|&(start, end)| start < end && in_exec(start))
+        .collect();
+    funcs.sort();
+    let in_func = |rva: u32| {
+        let idx = match funcs.binary_search_by_key(&rva, |x| x.0) {
+            Ok(idx)  => idx,
+            Err(0)   => return false,
+            Err(idx) => idx - 1,
+        };
+        rva < funcs[idx].1
+    };
+
+    // Start of every instruction decoded, and every candidate block start
+    let mut insns   = HashSet::new();
+    let mut targets = Vec::new();
+    let mut leaves  = Vec::new();
+
+    let add_target = |targets: &mut Vec<u32>, leaves: &mut Vec<u32>,
+            end: u32, rel: i64| {
+        let target = end as i64 + rel;
+        if target < 0 || target > u32::MAX as i64 { return; }
+        let target = target as u32;
+        targets.push(target);
+        if !in_func(target) && in_exec(target) {
+            leaves.push(target);
+        }
+    };
+
+    // Linear sweep of each function
+    for &(start, end) in &funcs {
+        targets.push(start);
+
+        let mut rva = start;
+        while rva < end {
+            let bytes = image.get(rva as usize..).unwrap_or(&[]);
+            let insn = match x86::decode(bytes) {
+                Some(insn) => insn,
+                None       => break,
+            };
+            insns.insert(rva);
+            rva += insn.len as u32;
+
+            match insn.flow {
+                Flow::Call(Some(rel)) | Flow::Jump(Some(rel)) =>
+                    add_target(&mut targets, &mut leaves, rva, rel),
+                Flow::Branch(rel) => {
+                    add_target(&mut targets, &mut leaves, rva, rel);
+                    targets.push(rva);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Recursive descent through leaf functions
+    let mut seen = HashSet::new();
+    while let Some(mut rva) = leaves.pop() {
+        while in_exec(rva) && !in_func(rva) && seen.insert(rva) {
+            let bytes = image.get(rva as usize..).unwrap_or(&[]);
+            let insn = match x86::decode(bytes) {
+                Some(insn) => insn,
+                None       => break,
+            };
+            insns.insert(rva);
+            rva += insn.len as u32;
+
+            match insn.flow {
+                Flow::Next | Flow::Call(None) => {}
+                Flow::Call(Some(rel)) =>
+                    add_target(&mut targets, &mut leaves, rva, rel),
+                Flow::Branch(rel) => {
+                    add_target(&mut targets, &mut leaves, rva, rel);
+                    targets.push(rva);
+                }
+                Flow::Jump(Some(rel)) => {
+                    add_target(&mut targets, &mut leaves, rva, rel);
+                    break;
+                }
+                Flow::Jump(None) | Flow::Stop => break,
+            }
+        }
+    }
+
+    targets.retain(|&x| insns.contains(&x) && in_exec(x));
+    targets.sort();
+    targets.dedup();
+    Ok(targets)
+}
+
+/// Get the file name of the block list for the module `name` with the
+/// `timedatestamp` and `sizeofimage` from its PE header
+pub fn list_name(name: &str, timedatestamp: u32, sizeofimage: u32) -> String {
+    format!("{}-{:08x}-{:x}.blocks", name, timedatestamp, sizeofimage)
+}
+
+/// Parse a block list, one offset per line. Offsets are hex if `0x` prefixed
+/// and decimal otherwise, blank lines and lines starting with `#` are ignored
+pub fn parse_list(list: &str) -> Result<Vec<u32>, String> {
+    let mut blocks = list.lines().map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            let ret = if let Some(hex) = x.strip_prefix("0x") {
+                u32::from_str_radix(hex, 16)
+            } else {
+                x.parse()
+            };
+            ret.map_err(|_| format!("Invalid block offset `{}`", x))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    blocks.sort();
+    blocks.dedup();
+    Ok(blocks)
+}
+
+/// Serialize `blocks` into a block list
+pub fn serialize_list(blocks: &[u32]) -> String {
+    blocks.iter().map(|x| format!("0x{:x}\n", x)).collect()
+}
+
+#[test]
+fn test_discover() {
+    let mut file = vec![0u8; 0x800];
+    let write = |file: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+        file[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+
+    // Headers with a .text section at 0x1000 and .pdata at 0x2000
+    write(&mut file, 0, b"MZ");
+    write(&mut file, 0x3c, &0x40u32.to_le_bytes());
+    write(&mut file, 0x40, b"PE\0\0");
+    write(&mut file, 0x44, &MACHINE_AMD64.to_le_bytes());
+    write(&mut file, 0x46, &2u16.to_le_bytes());
+    write(&mut file, 0x54, &240u16.to_le_bytes());
+    write(&mut file, 0x58, &OPTIONAL_MAGIC64.to_le_bytes());
+    write(&mut file, 0x58 + 56, &0x3000u32.to_le_bytes());
+    write(&mut file, 0x58 + 60, &0x200u32.to_le_bytes());
+    write(&mut file, 0x58 + 108, &16u32.to_le_bytes());
+    write(&mut file, 0x58 + 136, &0x2000u32.to_le_bytes());
+    write(&mut file, 0x58 + 140, &12u32.to_le_bytes());
+    for (ii, &(rva, offset, chars)) in [
+        (0x1000u32, 0x400u32, 0x60000020u32),
+        (0x2000,    0x600,    0x40000040),
+    ].iter().enumerate() {
+        let section = 0x148 + ii * 40;
+        write(&mut file, section + 8,  &0x200u32.to_le_bytes());
+        write(&mut file, section + 12, &rva.to_le_bytes());
+        write(&mut file, section + 16, &0x200u32.to_le_bytes());
+        write(&mut file, section + 20, &offset.to_le_bytes());
+        write(&mut file, section + 36, &chars.to_le_bytes());
+    }
+
+    // A function calling a leaf function which isn't in .pdata
+    write(&mut file, 0x400, &[
+        0x48, 0x83, 0xec, 0x28,       // sub  rsp, 0x28
+        0x85, 0xc9,                   // test ecx, ecx
+        0x74, 0x07,                   // je   0x100f
+        0xe8, 0x13, 0x00, 0x00, 0x00, // call 0x1020
+        0xeb, 0x05,                   // jmp  0x1014
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov  eax, 1
+        0x48, 0x83, 0xc4, 0x28,       // add  rsp, 0x28
+        0xc3,                         // ret
+        0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+        0x8d, 0x41, 0x01,             // lea  eax, [rcx+1]
+        0x85, 0xc0,                   // test eax, eax
+        0x75, 0x01,                   // jne  0x1028
+        0xc3,                         // ret
+        0xff, 0xc0,                   // inc  eax
+        0xc3,                         // ret
+    ]);
+    write(&mut file, 0x600, &0x1000u32.to_le_bytes());
+    write(&mut file, 0x604, &0x1019u32.to_le_bytes());
+
+    let image = map_file(&file).unwrap();
+    assert_eq!(image.len(), 0x3000);
+    assert_eq!(discover(&image).unwrap(),
+        [0x1000, 0x1008, 0x100f, 0x1014, 0x1020, 0x1027, 0x1028]);
+    assert!(discover(&file[..0x100]).is_err());
+
+    let list = serialize_list(&[0x10, 0x20]);
+    assert_eq!(parse_list(&format!("# blocks\n{}\n16\n", list)).unwrap(),
+        [0x10, 0x20]);
+    assert!(parse_list("0x10\nfoo\n").is_err());
+    assert_eq!(list_name("ntdll.dll", 0x5b1c3a2e, 0x1e0000),
+        "ntdll.dll-5b1c3a2e-1e0000.blocks");
+}
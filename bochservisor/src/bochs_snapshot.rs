@@ -0,0 +1,243 @@
+/// Reader for Bochs snapshot folders
+///
+/// When Bochs saves a snapshot it writes a folder with a text file per device
+/// describing its parameter tree, and binary parameters (like RAM) split out
+/// into their own files. We only care about physical memory and the register
+/// state, which is enough to walk page tables and OS structures without
+/// running anything.
+///
+/// This assumes Bochs was built without `BX_LARGE_RAMFILE`, which is how our
+/// `bochs_config` builds it.
+
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+use crate::cpustate::{CpuState, Segment, Table};
+use crate::memreader::{MemReader, MemoryRegion};
+
+/// Size of a Bochs memory block (`BX_MEM_BLOCK_LEN`)
+const BX_MEM_BLOCK_LEN: usize = 4 * 1024 * 1024;
+
+/// Create an `InvalidData` error with `msg`
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Parse a Bochs parameter tree file into a map from the dotted parameter
+/// path (for example `cpu0.CS.base`) to the value as it was written
+///
+/// Text data parameters (`name = [ ... ]`) are skipped as nothing we read
+/// uses them
+fn parse_param_tree(contents: &str) -> io::Result<HashMap<String, String>> {
+    let mut ret  = HashMap::new();
+    let mut path: Vec<&str> = Vec::new();
+    let mut in_data = false;
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        // Skip over the bytes of text data parameters
+        if in_data {
+            if line == "]" { in_data = false; }
+            continue;
+        }
+
+        if line.is_empty() { continue; }
+
+        if line == "}" {
+            if path.pop().is_none() {
+                return Err(invalid(
+                    format!("Unbalanced braces on line {}", lineno + 1)));
+            }
+            continue;
+        }
+
+        let mut split = line.splitn(2, " = ");
+        let (name, value) = match (split.next(), split.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => return Err(invalid(
+                format!("Malformed parameter on line {}", lineno + 1))),
+        };
+
+        match value {
+            "{" => path.push(name),
+            "[" => in_data = true,
+            _ => {
+                let mut key = path.join(".");
+                if !key.is_empty() { key.push('.'); }
+                key.push_str(name);
+                ret.insert(key, value.to_string());
+            }
+        }
+    }
+
+    if !path.is_empty() || in_data {
+        return Err(invalid("Parameter tree ended early".into()));
+    }
+
+    Ok(ret)
+}
+
+/// Parse a number as Bochs prints them, either `0x` prefixed hex or decimal
+fn parse_num(value: &str) -> Option<u64> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// A Bochs snapshot folder loaded into memory
+pub struct BochsSnapshot {
+    /// Contents of the RAM file. Blocks are in allocation order, not physical
+    /// order, `regions` maps them to physical addresses
+    ram: Vec<u8>,
+
+    /// Physically contiguous runs of RAM as (paddr, offset in `ram`, size)
+    regions: Vec<(usize, usize, usize)>,
+
+    /// Register state for each processor
+    pub cpus: Vec<CpuState>,
+}
+
+impl BochsSnapshot {
+    /// Load the Bochs snapshot folder at `folder`
+    pub fn load<P: AsRef<Path>>(folder: P) -> io::Result<Self> {
+        let folder = folder.as_ref();
+
+        // Parse the memory layout
+        let memory = parse_param_tree(
+            &std::fs::read_to_string(folder.join("memory"))?)?;
+        let get = |key: &str| -> io::Result<u64> {
+            memory.get(key).and_then(|x| parse_num(x)).ok_or_else(||
+                invalid(format!("Memory state is missing `{}`", key)))
+        };
+
+        let memory_size = get("memory.len")? as usize;
+        let ram = std::fs::read(folder.join(memory.get("memory.ram")
+            .ok_or_else(|| invalid("Memory state is missing `ram`".into()))?))?;
+
+        if memory_size % BX_MEM_BLOCK_LEN != 0 {
+            return Err(invalid("Memory size is not block aligned".into()));
+        }
+
+        // Blocks which were never allocated were never touched by the guest
+        // and are saved as -1, we leave them unmapped. Contiguous blocks are
+        // merged into one region to keep the region list short.
+        let mut regions: Vec<(usize, usize, usize)> = Vec::new();
+        for blk in 0..memory_size / BX_MEM_BLOCK_LEN {
+            let idx = get(&format!("memory.mapping.blk{}", blk))? as usize;
+            let offset = match idx.checked_mul(BX_MEM_BLOCK_LEN) {
+                Some(offset) if offset + BX_MEM_BLOCK_LEN <= ram.len() =>
+                    offset,
+                _ => continue,
+            };
+
+            let paddr = blk * BX_MEM_BLOCK_LEN;
+            if let Some(last) = regions.last_mut() {
+                if last.0 + last.2 == paddr && last.1 + last.2 == offset {
+                    last.2 += BX_MEM_BLOCK_LEN;
+                    continue;
+                }
+            }
+            regions.push((paddr, offset, BX_MEM_BLOCK_LEN));
+        }
+
+        // Load every processor until we run out of files
+        let mut cpus = Vec::new();
+        loop {
+            let name = format!("cpu{}", cpus.len());
+            let contents = match std::fs::read_to_string(folder.join(&name)) {
+                Ok(contents) => contents,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => break,
+                Err(err) => return Err(err),
+            };
+            cpus.push(parse_cpu(&name, &parse_param_tree(&contents)?)?);
+        }
+
+        if cpus.is_empty() {
+            return Err(invalid("Snapshot has no processors".into()));
+        }
+
+        Ok(BochsSnapshot { ram, regions, cpus })
+    }
+
+    /// Get a memory reader over the guest physical memory in this snapshot
+    ///
+    /// The reader points directly into the snapshot so it must not outlive it
+    pub fn mem_reader(&self) -> MemReader {
+        MemReader::new(self.regions.iter().map(|&(paddr, offset, size)| {
+            MemoryRegion {
+                paddr,
+                backing: self.ram[offset..].as_ptr() as usize,
+                perms:   0,
+                size,
+            }
+        }).collect())
+    }
+}
+
+/// Build the register state for processor `name` from its parameter tree
+fn parse_cpu(name: &str, params: &HashMap<String, String>)
+        -> io::Result<CpuState> {
+    // This is only present on 64-bit builds of Bochs, which we need as we
+    // expect 64-bit register state everywhere
+    if !params.contains_key(&format!("{}.RIP", name)) {
+        return Err(invalid(format!("{} has no RIP, Bochs must be built \
+            with x86-64 support", name)));
+    }
+
+    // Registers which depend on the CPU model or Bochs configuration may not
+    // be present, these are left as zero
+    let get = |key: &str| -> u64 {
+        params.get(&format!("{}.{}", name, key))
+            .and_then(|x| parse_num(x)).unwrap_or(0)
+    };
+    let seg = |seg: &str| -> Segment {
+        Segment {
+            selector: get(&format!("{}.selector", seg)) as u16,
+            base:     get(&format!("{}.base", seg)),
+            limit:    get(&format!("{}.limit_scaled", seg)) as u32,
+        }
+    };
+    let table = |table: &str| -> Table {
+        Table {
+            base:  get(&format!("{}.base", table)),
+            limit: get(&format!("{}.limit", table)) as u16,
+        }
+    };
+
+    Ok(CpuState {
+        rax: get("RAX"), rcx: get("RCX"), rdx: get("RDX"), rbx: get("RBX"),
+        rsp: get("RSP"), rbp: get("RBP"), rsi: get("RSI"), rdi: get("RDI"),
+        r8:  get("R8"),  r9:  get("R9"),  r10: get("R10"), r11: get("R11"),
+        r12: get("R12"), r13: get("R13"), r14: get("R14"), r15: get("R15"),
+        rip: get("RIP"),
+
+        rflags: get("EFLAGS"),
+
+        es: seg("ES"), cs: seg("CS"), ss: seg("SS"),
+        ds: seg("DS"), fs: seg("FS"), gs: seg("GS"),
+
+        gdtr: table("GDTR"),
+        idtr: table("IDTR"),
+
+        cr0: get("CR0"),
+        cr2: get("CR2"),
+        cr3: get("CR3"),
+        cr4: get("CR4"),
+
+        // CR8 lives in the local APIC TPR in Bochs, which we don't parse
+        cr8: 0,
+
+        dr0: get("DR0"),
+        dr1: get("DR1"),
+        dr2: get("DR2"),
+        dr3: get("DR3"),
+        dr6: get("DR6"),
+        dr7: get("DR7"),
+
+        efer:           get("MSR.EFER"),
+        kernel_gs_base: get("MSR.kernelgsbase"),
+    })
+}
@@ -0,0 +1,358 @@
+/// Breakpoint coverage
+///
+/// Coverage from the RIPs we see at exits and while stepping in Bochs is both
+/// slow and sparse. Instead, given the offset of every basic block in a
+/// module, an `int3` is placed on each block once the module shows up in a
+/// module list. The first time a block runs its breakpoint traps, the block is
+/// recorded and the original byte is put back, so every block costs a single
+/// exit and the guest otherwise runs at full speed.
+///
+/// Breakpoints are placed in guest physical memory. Blocks in pages which
+/// aren't resident yet are retried every time the module list is walked. A
+/// snapshot restore puts back the original contents of every page the guest
+/// dirtied, so the breakpoints which haven't been hit are placed again after
+/// every restore, unless their page holds something else by then.
+///
+/// Block lists are read from the directory in `APPLEPIE_COVERAGE_BLOCKS`. The
+/// list for a module is `<name>-<timedatestamp>-<sizeofimage>.blocks`, with
+/// both numbers in hex (eg. `ntdll.dll-5b1c3a2e-1e0000.blocks`), or just
+/// `<name>.blocks` if there's no list for that exact build. Lists hold one
+/// offset per line, hex if `0x` prefixed and decimal otherwise. Blank lines
+/// and lines starting with `#` are ignored.
+///
+/// Modules named in `APPLEPIE_COVERAGE_DISCOVER` (comma separated, or `*` for
+/// every module) which don't have a list get one from static block discovery,
+/// using the image in guest memory or the file from the symbol server if the
+/// guest's copy isn't fully resident. Discovered lists are saved in the block
+/// list directory under the exact name for that build.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::blocks;
+use crate::memreader::MemReader;
+use crate::virtmem::Paging;
+use crate::win32::{ModuleInfo, Ordinal};
+
+/// Environment variable holding the directory with the block lists
+const BLOCKS_ENV_VAR: &str = "APPLEPIE_COVERAGE_BLOCKS";
+
+/// Environment variable holding the modules to discover blocks for
+const DISCOVER_ENV_VAR: &str = "APPLEPIE_COVERAGE_DISCOVER";
+
+/// Opcode of `int3`
+const INT3: u8 = 0xcc;
+
+/// A breakpoint we placed and which hasn't been hit yet
+struct Patch {
+    /// Byte the breakpoint replaced
+    orig: u8,
+
+    /// Module the block is in
+    ordinal: Ordinal,
+
+    /// Base address of the module the breakpoint was placed with
+    base: usize,
+
+    /// Offset of the block in the module
+    offset: u32,
+}
+
+/// Coverage breakpoints for every module we have a block list for
+pub struct Breakpoints {
+    /// Directory holding the block lists
+    dir: PathBuf,
+
+    /// Lowercase names of the modules to discover blocks for if they don't
+    /// have a list, `*` for every module
+    discover: Vec<String>,
+
+    /// Gets the PE file of a module, for discovery when the image in the
+    /// guest isn't fully resident
+    image_file: Option<fn(&ModuleInfo) -> Option<Vec<u8>>>,
+
+    /// Block offsets of every module we looked for a block list for, `None`
+    /// if there isn't one
+    blocks: HashMap<Ordinal, Option<Vec<u32>>>,
+
+    /// Blocks which still need a breakpoint, by module and base address
+    unplaced: HashMap<(Ordinal, usize), Vec<u32>>,
+
+    /// Breakpoints which haven't been hit yet, by physical address
+    patches: HashMap<usize, Patch>,
+
+    /// Number of breakpoints placed so far
+    pub placed: u64,
+
+    /// Number of breakpoints hit so far
+    pub hits: u64,
+}
+
+impl Breakpoints {
+    /// Create breakpoint coverage with the block lists in `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        Breakpoints {
+            dir,
+            discover:   Vec::new(),
+            image_file: None,
+            blocks:   HashMap::new(),
+            unplaced: HashMap::new(),
+            patches:  HashMap::new(),
+            placed:   0,
+            hits:     0,
+        }
+    }
+
+    /// Get breakpoint coverage from the environment, using `image_file` to
+    /// get the PE files of modules for discovery. Returns `None` if it wasn't
+    /// requested
+    pub fn from_env(image_file: fn(&ModuleInfo) -> Option<Vec<u8>>)
+            -> Option<Self> {
+        let mut bps = Breakpoints::new(std::env::var(BLOCKS_ENV_VAR).ok()?
+            .into());
+        bps.image_file = Some(image_file);
+        if let Ok(discover) = std::env::var(DISCOVER_ENV_VAR) {
+            bps.discover = discover.split(',').map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty()).collect();
+        }
+        Some(bps)
+    }
+
+    /// Get the number of breakpoints placed which haven't been hit yet
+    pub fn pending(&self) -> usize {
+        self.patches.len()
+    }
+
+    /// Get the blocks of `module` if it has been seen and has a block list
+    pub fn blocks(&self, module: &ModuleInfo) -> Option<&[u32]> {
+        self.blocks.get(&module.ordinal())
+            .and_then(|x| x.as_ref().map(|x| x.as_slice()))
+    }
+
+    /// Load the block list for `module`, `None` if there isn't a usable one
+    fn load_blocks(&self, module: &ModuleInfo) -> Option<Vec<u32>> {
+        let exact = self.dir.join(blocks::list_name(module.name(),
+            module.time(), module.size()));
+        let path = if exact.exists() {
+            exact
+        } else {
+            self.dir.join(format!("{}.blocks", module.name()))
+        };
+
+        let list = std::fs::read_to_string(&path).ok()?;
+        match blocks::parse_list(&list) {
+            Ok(blocks) => {
+                print!("Loaded {} blocks for {} from {}\n", blocks.len(),
+                    module.name(), path.display());
+                Some(blocks)
+            }
+            Err(err) => {
+                print!("Ignoring block list {}: {}\n", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Discover the blocks of `module` loaded at `base` if it was requested,
+    /// and save them as its block list. `None` if it wasn't requested or
+    /// failed
+    fn discover_blocks(&self, memory: &mut MemReader, paging: Paging,
+            cr3: usize, module: &ModuleInfo, base: usize) -> Option<Vec<u32>> {
+        let name = module.name().to_lowercase();
+        if !self.discover.iter().any(|x| x == "*" || *x == name) {
+            return None;
+        }
+
+        // Prefer the guest's copy, the symbol server is slow
+        let image = blocks::read_image(memory, paging, cr3, base,
+                module.size() as usize)
+            .or_else(|err| {
+                let file = self.image_file.and_then(|x| x(module))
+                    .ok_or(err)?;
+                blocks::map_file(&file)
+            })
+            .and_then(|image| blocks::discover(&image));
+        let blocks = match image {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                print!("Failed to discover blocks for {}: {}\n",
+                    module.name(), err);
+                return None;
+            }
+        };
+
+        let path = self.dir.join(blocks::list_name(module.name(),
+            module.time(), module.size()));
+        if let Err(err) = std::fs::write(&path,
+                blocks::serialize_list(&blocks)) {
+            print!("Failed to save block list {}: {}\n", path.display(), err);
+        }
+        print!("Discovered {} blocks for {}\n", blocks.len(), module.name());
+        Some(blocks)
+    }
+
+    /// Place breakpoints on the blocks of `module` loaded at `base` which
+    /// don't have one yet, using page table `cr3` of a processor in `paging`
+    /// mode
+    pub fn place(&mut self, memory: &mut MemReader, paging: Paging,
+            cr3: usize, module: &ModuleInfo, base: usize) {
+        let ordinal = module.ordinal();
+
+        if !self.blocks.contains_key(&ordinal) {
+            let blocks = self.load_blocks(module).or_else(||
+                self.discover_blocks(memory, paging, cr3, module, base));
+            self.blocks.insert(ordinal, blocks);
+        }
+        let blocks = match &self.blocks[&ordinal] {
+            Some(blocks) => blocks,
+            None         => return,
+        };
+
+        let size     = module.size();
+        let patches  = &mut self.patches;
+        let placed   = &mut self.placed;
+        let unplaced = self.unplaced.entry((ordinal, base))
+            .or_insert_with(|| blocks.iter().cloned()
+                .filter(|&x| x < size).collect());
+
+        // Keep every block we couldn't place this time around
+        unplaced.retain(|&offset| {
+            let paddr = match memory.translate(paging, cr3,
+                    base + offset as usize) {
+                Some(paddr) => paddr,
+                None        => return true,
+            };
+
+            // Another mapping of the same page already has this one
+            if patches.contains_key(&paddr) { return false; }
+
+            let mut orig = [0u8];
+            if memory.read_phys(paddr, &mut orig) != 1 { return true; }
+
+            // A breakpoint of the guest's own, leave it alone
+            if orig[0] == INT3 { return false; }
+
+            if memory.write_phys(paddr, &[INT3]) != 1 { return true; }
+            patches.insert(paddr, Patch { orig: orig[0], ordinal, base,
+                offset });
+            *placed += 1;
+            false
+        });
+    }
+
+    /// Handle a breakpoint at `rip`, translated with page table `cr3` of a
+    /// processor in `paging` mode. If it's one of ours the original byte is
+    /// put back and the module, module base and offset of the block are
+    /// returned
+    pub fn hit(&mut self, memory: &mut MemReader, paging: Paging, cr3: usize,
+            rip: usize) -> Option<(Ordinal, usize, u32)> {
+        let paddr = memory.translate(paging, cr3, rip)?;
+        let patch = self.patches.remove(&paddr)?;
+        memory.write_phys(paddr, &[patch.orig]);
+        self.hits += 1;
+        Some((patch.ordinal, patch.base, patch.offset))
+    }
+
+    /// Put a block which was hit but not recorded back with the unplaced
+    /// ones, so it gets a breakpoint again the next time its module's
+    /// breakpoints are placed
+    pub fn requeue(&mut self, ordinal: Ordinal, base: usize, offset: u32) {
+        self.unplaced.entry((ordinal, base)).or_default().push(offset);
+    }
+
+    /// Place every breakpoint which hasn't been hit yet again after a
+    /// snapshot restore. Breakpoints in pages which hold something else now
+    /// go back to being unplaced
+    pub fn reapply(&mut self, memory: &mut MemReader) {
+        let unplaced = &mut self.unplaced;
+        self.patches.retain(|&paddr, patch| {
+            let mut current = [0u8];
+            if memory.read_phys(paddr, &mut current) == 1 {
+                if current[0] == INT3 { return true; }
+                if current[0] == patch.orig {
+                    return memory.write_phys(paddr, &[INT3]) == 1;
+                }
+            }
+
+            unplaced.entry((patch.ordinal, patch.base)).or_default()
+                .push(patch.offset);
+            false
+        });
+    }
+
+    /// Take out every breakpoint which hasn't been hit yet, putting back the
+    /// original bytes
+    pub fn remove_all(&mut self, memory: &mut MemReader) {
+        for (paddr, patch) in self.patches.drain() {
+            memory.write_phys(paddr, &[patch.orig]);
+            self.unplaced.entry((patch.ordinal, patch.base)).or_default()
+                .push(patch.offset);
+        }
+    }
+}
+
+#[test]
+fn test_breakpoints() {
+    use crate::memreader::MemoryRegion;
+
+    let dir = std::env::temp_dir().join(
+        format!("applepie_bpcov_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("bp.exe.blocks"), "0x10\n0x11\n0x12\n0x4000\n")
+        .unwrap();
+
+    // Without paging virtual addresses are physical ones
+    let mut backing = vec![0x90u8; 0x2000];
+    backing[0x1011] = INT3;
+    let mut memory = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: backing.as_mut_ptr() as usize,
+        perms:   0,
+        size:    backing.len(),
+    }]);
+    let read = |memory: &mut MemReader, paddr: usize| {
+        let mut byte = [0u8];
+        memory.read_phys(paddr, &mut byte);
+        byte[0]
+    };
+
+    let module = ModuleInfo::new("bp.exe".into(), 0x1234, 0x2000);
+    let mut bps = Breakpoints::new(dir.clone());
+    bps.place(&mut memory, Paging::Disabled, 0, &module, 0x1000);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The guest's own breakpoint and the block past the image are skipped
+    assert_eq!((bps.placed, bps.pending()), (2, 2));
+    assert_eq!(read(&mut memory, 0x1010), INT3);
+    assert_eq!(read(&mut memory, 0x1012), INT3);
+
+    // Breakpoints are one-shot
+    assert_eq!(bps.hit(&mut memory, Paging::Disabled, 0, 0x1010),
+        Some((module.ordinal(), 0x1000, 0x10)));
+    assert_eq!(read(&mut memory, 0x1010), 0x90);
+    assert_eq!(bps.hit(&mut memory, Paging::Disabled, 0, 0x1010), None);
+    assert_eq!(bps.hit(&mut memory, Paging::Disabled, 0, 0x1011), None);
+
+    // A hit which wasn't recorded gets its breakpoint back the next time
+    // the module's breakpoints are placed
+    bps.requeue(module.ordinal(), 0x1000, 0x10);
+    assert_eq!(read(&mut memory, 0x1010), 0x90);
+    bps.place(&mut memory, Paging::Disabled, 0, &module, 0x1000);
+    assert_eq!((bps.placed, read(&mut memory, 0x1010)), (3, INT3));
+    assert_eq!(bps.hit(&mut memory, Paging::Disabled, 0, 0x1010),
+        Some((module.ordinal(), 0x1000, 0x10)));
+
+    // A restore of the page puts the original byte back, something else in
+    // the page means the block has to be placed again
+    memory.write_phys(0x1012, &[0x90]);
+    bps.reapply(&mut memory);
+    assert_eq!(read(&mut memory, 0x1012), INT3);
+    memory.write_phys(0x1012, &[0x41]);
+    bps.reapply(&mut memory);
+    assert_eq!(bps.pending(), 0);
+    memory.write_phys(0x1012, &[0x90]);
+    bps.place(&mut memory, Paging::Disabled, 0, &module, 0x1000);
+    assert_eq!((bps.placed, bps.pending()), (4, 1));
+
+    bps.remove_all(&mut memory);
+    assert_eq!((bps.pending(), read(&mut memory, 0x1012)), (0, 0x90));
+}
@@ -0,0 +1,508 @@
+/// Register state of a virtual processor
+///
+/// This is what the CPU loop, the backends, and Bochs pass registers around
+/// in. Every register is a `RegisterValue`, which is laid out exactly like
+/// WHVP's `WHV_REGISTER_VALUE`, so the WHVP backend hands the context to the
+/// API as-is. Nothing in here needs the bindings though, so neither does
+/// anything which only deals with registers.
+
+use crate::cpustate::{CpuState, Segment, Table};
+use crate::virtmem::Paging;
+
+/// A 128-bit register
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct Reg128 {
+    pub Dword: [u32; 4],
+}
+
+/// A segment register
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct SegmentRegister {
+    pub Base:       u64,
+    pub Limit:      u32,
+    pub Selector:   u16,
+    pub Attributes: u16,
+}
+
+/// A descriptor table register
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct TableRegister {
+    pub Pad:   [u16; 3],
+    pub Limit: u16,
+    pub Base:  u64,
+}
+
+/// An x87 floating point register
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct FpRegister {
+    pub Mantissa: u64,
+
+    /// 15-bit biased exponent followed by the sign bit
+    pub ExponentSign: u64,
+}
+
+#[allow(non_snake_case)]
+impl FpRegister {
+    /// Get the biased exponent
+    pub fn BiasedExponent(&self) -> u64 {
+        self.ExponentSign & 0x7fff
+    }
+
+    /// Get the sign bit
+    pub fn Sign(&self) -> u64 {
+        (self.ExponentSign >> 15) & 1
+    }
+}
+
+/// x87 control and status
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct FpControlStatusRegister {
+    pub FpControl: u16,
+    pub FpStatus:  u16,
+    pub FpTag:     u8,
+    pub Reserved:  u8,
+    pub LastFpOp:  u16,
+    pub LastFpRip: u64,
+}
+
+/// SSE control and status
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub struct XmmControlStatusRegister {
+    pub LastFpRdp:            u64,
+    pub XmmStatusControl:     u32,
+    pub XmmStatusControlMask: u32,
+}
+
+/// Value of a single register. Which field is valid depends on the register
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+pub union RegisterValue {
+    pub Reg128:           Reg128,
+    pub Reg64:            u64,
+    pub Reg32:            u32,
+    pub Reg16:            u16,
+    pub Reg8:             u8,
+    pub Fp:               FpRegister,
+    pub FpControlStatus:  FpControlStatusRegister,
+    pub XmmControlStatus: XmmControlStatusRegister,
+    pub Segment:          SegmentRegister,
+    pub Table:            TableRegister,
+}
+
+/// Entire context structure for a processor using all possible fields WHVP
+/// allows access to, in the order of `WHVP_CONTEXT_NAMES` in `whvp.rs`
+/// 
+/// It seems internally there's some alignment requirements.
+/// We force this by using a 64-byte alignment
+/// 
+/// DO NOT CHANGE WITHOUT CHANGING THE C VERSION IN BOCHS!!!
+/// This structure crosses FFI boundaries!
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+pub struct VpContext {
+    pub rax: RegisterValue,
+    pub rcx: RegisterValue,
+    pub rdx: RegisterValue,
+    pub rbx: RegisterValue,
+    pub rsp: RegisterValue,
+    pub rbp: RegisterValue,
+    pub rsi: RegisterValue,
+    pub rdi: RegisterValue,
+    pub r8:  RegisterValue,
+    pub r9:  RegisterValue,
+    pub r10: RegisterValue,
+    pub r11: RegisterValue,
+    pub r12: RegisterValue,
+    pub r13: RegisterValue,
+    pub r14: RegisterValue,
+    pub r15: RegisterValue,
+    pub rip: RegisterValue,
+
+    pub rflags: RegisterValue,
+
+    pub es: RegisterValue,
+    pub cs: RegisterValue,
+    pub ss: RegisterValue,
+    pub ds: RegisterValue,
+    pub fs: RegisterValue,
+    pub gs: RegisterValue,
+
+    pub ldtr: RegisterValue,
+    pub tr:   RegisterValue,
+    pub idtr: RegisterValue,
+    pub gdtr: RegisterValue,
+
+    pub cr0: RegisterValue,
+    pub cr2: RegisterValue,
+    pub cr3: RegisterValue,
+    pub cr4: RegisterValue,
+    pub cr8: RegisterValue,
+
+    pub dr0: RegisterValue,
+    pub dr1: RegisterValue,
+    pub dr2: RegisterValue,
+    pub dr3: RegisterValue,
+    pub dr6: RegisterValue,
+    pub dr7: RegisterValue,
+
+    pub xmm0: RegisterValue,
+    pub xmm1: RegisterValue,
+    pub xmm2: RegisterValue,
+    pub xmm3: RegisterValue,
+    pub xmm4: RegisterValue,
+    pub xmm5: RegisterValue,
+    pub xmm6: RegisterValue,
+    pub xmm7: RegisterValue,
+    pub xmm8: RegisterValue,
+    pub xmm9: RegisterValue,
+    pub xmm10: RegisterValue,
+    pub xmm11: RegisterValue,
+    pub xmm12: RegisterValue,
+    pub xmm13: RegisterValue,
+    pub xmm14: RegisterValue,
+    pub xmm15: RegisterValue,
+
+    pub st0: RegisterValue,
+    pub st1: RegisterValue,
+    pub st2: RegisterValue,
+    pub st3: RegisterValue,
+    pub st4: RegisterValue,
+    pub st5: RegisterValue,
+    pub st6: RegisterValue,
+    pub st7: RegisterValue,
+
+    pub fp_control:  RegisterValue,
+    pub xmm_control: RegisterValue,
+
+    pub tsc: RegisterValue,
+    pub efer: RegisterValue,
+    pub kernel_gs_base: RegisterValue,
+    pub apic_base: RegisterValue,
+    pub pat: RegisterValue,
+    pub sysenter_cs: RegisterValue,
+    pub sysenter_eip: RegisterValue,
+    pub sysenter_esp: RegisterValue,
+    pub star: RegisterValue,
+    pub lstar: RegisterValue,
+    pub cstar: RegisterValue,
+    pub sfmask: RegisterValue,
+
+    pub tsc_aux: RegisterValue,
+    //pub spec_ctrl: RegisterValue, not yet supported by Windows 17763
+    //pub pred_cmd: RegisterValue, not yet supported by Windows 17763
+    //pub apic_id: RegisterValue, not yet supported by Windows 17763
+    //pub apic_version: RegisterValue, not yet supported by Windows 17763
+    //pub pending_interruption: RegisterValue,
+    //pub interrupt_state: RegisterValue,
+    //pub pending_event: RegisterValue,
+    //pub deliverability_notifications: RegisterValue,
+    //pub internal_activity_state: RegisterValue, unknown type
+
+    pub xcr0: RegisterValue,
+}
+
+impl VpContext {
+    /// Gets the linear address for RIP
+    pub fn rip(&self) -> u64 {
+        unsafe { self.cs.Segment.Base.wrapping_add(self.rip.Reg64) }
+    }
+
+    /// Gets the CR3 for the VM with the reserved and PCID bits masked off
+    pub fn cr3(&self) -> u64 {
+        self.paging().table_root(unsafe { self.cr3.Reg64 })
+    }
+
+    /// Gets the paging mode of the VM
+    pub fn paging(&self) -> Paging {
+        unsafe {
+            Paging::new(self.cr0.Reg64, self.cr4.Reg64,
+                (self.efer.Reg64 & (1 << 10)) != 0)
+        }
+    }
+}
+
+impl Default for VpContext {
+    /// Returns a zeroed out context structure
+    fn default() -> Self {
+        unsafe { std::mem::zeroed() }
+    }
+}
+
+impl From<&VpContext> for CpuState {
+    /// Pull the register state out of a context
+    fn from(context: &VpContext) -> Self {
+        let seg = |reg: &RegisterValue| unsafe {
+            Segment {
+                selector: reg.Segment.Selector,
+                base:     reg.Segment.Base,
+                limit:    reg.Segment.Limit,
+            }
+        };
+        let table = |reg: &RegisterValue| unsafe {
+            Table { base: reg.Table.Base, limit: reg.Table.Limit }
+        };
+
+        unsafe {
+            CpuState {
+                rax: context.rax.Reg64,
+                rcx: context.rcx.Reg64,
+                rdx: context.rdx.Reg64,
+                rbx: context.rbx.Reg64,
+                rsp: context.rsp.Reg64,
+                rbp: context.rbp.Reg64,
+                rsi: context.rsi.Reg64,
+                rdi: context.rdi.Reg64,
+                r8:  context.r8.Reg64,
+                r9:  context.r9.Reg64,
+                r10: context.r10.Reg64,
+                r11: context.r11.Reg64,
+                r12: context.r12.Reg64,
+                r13: context.r13.Reg64,
+                r14: context.r14.Reg64,
+                r15: context.r15.Reg64,
+                rip: context.rip.Reg64,
+
+                rflags: context.rflags.Reg64,
+
+                es: seg(&context.es),
+                cs: seg(&context.cs),
+                ss: seg(&context.ss),
+                ds: seg(&context.ds),
+                fs: seg(&context.fs),
+                gs: seg(&context.gs),
+
+                gdtr: table(&context.gdtr),
+                idtr: table(&context.idtr),
+
+                cr0: context.cr0.Reg64,
+                cr2: context.cr2.Reg64,
+                cr3: context.cr3.Reg64,
+                cr4: context.cr4.Reg64,
+                cr8: context.cr8.Reg64,
+
+                dr0: context.dr0.Reg64,
+                dr1: context.dr1.Reg64,
+                dr2: context.dr2.Reg64,
+                dr3: context.dr3.Reg64,
+                dr6: context.dr6.Reg64,
+                dr7: context.dr7.Reg64,
+
+                efer:           context.efer.Reg64,
+                kernel_gs_base: context.kernel_gs_base.Reg64,
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for VpContext {
+    /// Pretty prints the entire register state available
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        unsafe {
+            write!(f,
+                "rax {:016x} rcx {:016x} rdx {:016x} rbx {:016x}\n\
+                 rsp {:016x} rbp {:016x} rsi {:016x} rdi {:016x}\n\
+                 r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}\n\
+                 r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}\n\
+                 rip {:04x}:{:016x} (linear {:016x})\n\
+                 rfl {:016x}\n\
+                 es  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 cs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 ss  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 ds  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 fs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 gs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
+                 ldtr base {:016x} limit {:04x} attr {:04x}\n\
+                 tr   base {:016x} limit {:04x} attr {:04x}\n\
+                 idtr base {:016x} limit {:04x}\n\
+                 gdtr base {:016x} limit {:04x}\n\
+                 tsc {:016x} tsc aux {:016x} efer {:016x} kernel gs {:016x}\n\
+                 apic base {:016x} pat {:016x}\n\
+                 sysenter eip {:04x}:{:08x} esp {:08x}\n\
+                 star {:016x} lstar {:016x} cstar {:016x} sfmask {:016x}\n\
+                 cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}\n\
+                 cr8 {:016x}\n\
+                 dr0 {:016x} dr1 {:016x} dr2 {:016x} dr3 {:016x}\n\
+                 dr6 {:016x} dr7 {:016x}\n\
+                 xcr0 {:016x}\n\
+                 xmm control {:08x} mask {:08x}\n\
+                 xmm0  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm1  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm2  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm3  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm4  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm5  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm6  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm7  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm8  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm9  {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm10 {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm11 {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm12 {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm13 {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm14 {:08x} {:08x} {:08x} {:08x}\n\
+                 xmm15 {:08x} {:08x} {:08x} {:08x}\n\
+                 fp control {:04x} status {:04x} tag {:02x} last op {:04x}\n\
+                 fp0 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp1 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp2 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp3 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp4 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp5 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp6 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 fp7 mantissa {:016x} exponent {:04x} sign {:02x}\n\
+                 ",
+                 /*
+                 interrupt pending {:x} type {:x} deliver error {:x}\n    \
+                     inst len {:x} nested {:x} vector {:04x}\n    \
+                     error code {:08x}\n\
+                 interrupt state shadow {:x} nmi masked {:x}\n\
+                 pending event {:08x}\n\
+                 deliverability nmi {:x} interrupt {:x} priority {:x}\n\*/
+                self.rax.Reg64, self.rcx.Reg64, self.rdx.Reg64,
+                self.rbx.Reg64, self.rsp.Reg64, self.rbp.Reg64,
+                self.rsi.Reg64, self.rdi.Reg64, self.r8.Reg64,
+                self.r9.Reg64,  self.r10.Reg64, self.r11.Reg64,
+                self.r12.Reg64, self.r13.Reg64, self.r14.Reg64,
+                self.r15.Reg64,
+                self.cs.Segment.Selector, self.rip.Reg64,
+                self.rip.Reg64.wrapping_add(self.cs.Segment.Base),
+                self.rflags.Reg64,
+                self.es.Segment.Selector, self.es.Segment.Base,
+                self.es.Segment.Limit, self.es.Segment.Attributes,
+                self.cs.Segment.Selector, self.cs.Segment.Base,
+                self.cs.Segment.Limit, self.cs.Segment.Attributes,
+                self.ss.Segment.Selector, self.ss.Segment.Base,
+                self.ss.Segment.Limit, self.ss.Segment.Attributes,
+                self.ds.Segment.Selector, self.ds.Segment.Base,
+                self.ds.Segment.Limit, self.ds.Segment.Attributes,
+                self.fs.Segment.Selector, self.fs.Segment.Base,
+                self.fs.Segment.Limit, self.fs.Segment.Attributes,
+                self.gs.Segment.Selector, self.gs.Segment.Base,
+                self.gs.Segment.Limit, self.gs.Segment.Attributes,
+                self.ldtr.Segment.Base, self.ldtr.Segment.Limit,
+                self.ldtr.Segment.Attributes,
+                self.tr.Segment.Base, self.tr.Segment.Limit,
+                self.tr.Segment.Attributes,
+                self.idtr.Table.Base, self.idtr.Table.Limit,
+                self.gdtr.Table.Base, self.gdtr.Table.Limit,
+                self.tsc.Reg64, self.tsc_aux.Reg64, self.efer.Reg64,
+                self.kernel_gs_base.Reg64,
+                self.apic_base.Reg64, self.pat.Reg64,
+                self.sysenter_cs.Reg64, self.sysenter_eip.Reg64,
+                self.sysenter_esp.Reg64,
+                self.star.Reg64, self.lstar.Reg64, self.cstar.Reg64,
+                self.sfmask.Reg64,
+                self.cr0.Reg64, self.cr2.Reg64, self.cr3.Reg64,
+                self.cr4.Reg64, self.cr8.Reg64, self.dr0.Reg64,
+                self.dr1.Reg64, self.dr2.Reg64, self.dr3.Reg64,
+                self.dr6.Reg64, self.dr7.Reg64,
+                self.xcr0.Reg64,
+                self.xmm_control.XmmControlStatus.XmmStatusControl,
+                self.xmm_control.XmmControlStatus.XmmStatusControlMask,
+                // Missing LastFpRdp and LastFpDp depending on mode
+                self.xmm0.Reg128.Dword[0], self.xmm0.Reg128.Dword[1],
+                self.xmm0.Reg128.Dword[2], self.xmm0.Reg128.Dword[3],
+                self.xmm1.Reg128.Dword[0], self.xmm1.Reg128.Dword[1],
+                self.xmm1.Reg128.Dword[2], self.xmm1.Reg128.Dword[3],
+                self.xmm2.Reg128.Dword[0], self.xmm2.Reg128.Dword[1],
+                self.xmm2.Reg128.Dword[2], self.xmm2.Reg128.Dword[3],
+                self.xmm3.Reg128.Dword[0], self.xmm3.Reg128.Dword[1],
+                self.xmm3.Reg128.Dword[2], self.xmm3.Reg128.Dword[3],
+                self.xmm4.Reg128.Dword[0], self.xmm4.Reg128.Dword[1],
+                self.xmm4.Reg128.Dword[2], self.xmm4.Reg128.Dword[3],
+                self.xmm5.Reg128.Dword[0], self.xmm5.Reg128.Dword[1],
+                self.xmm5.Reg128.Dword[2], self.xmm5.Reg128.Dword[3],
+                self.xmm6.Reg128.Dword[0], self.xmm6.Reg128.Dword[1],
+                self.xmm6.Reg128.Dword[2], self.xmm6.Reg128.Dword[3],
+                self.xmm7.Reg128.Dword[0], self.xmm7.Reg128.Dword[1],
+                self.xmm7.Reg128.Dword[2], self.xmm7.Reg128.Dword[3],
+                self.xmm8.Reg128.Dword[0], self.xmm8.Reg128.Dword[1],
+                self.xmm8.Reg128.Dword[2], self.xmm8.Reg128.Dword[3],
+                self.xmm9.Reg128.Dword[0], self.xmm9.Reg128.Dword[1],
+                self.xmm9.Reg128.Dword[2], self.xmm9.Reg128.Dword[3],
+                self.xmm10.Reg128.Dword[0], self.xmm10.Reg128.Dword[1],
+                self.xmm10.Reg128.Dword[2], self.xmm10.Reg128.Dword[3],
+                self.xmm11.Reg128.Dword[0], self.xmm11.Reg128.Dword[1],
+                self.xmm11.Reg128.Dword[2], self.xmm11.Reg128.Dword[3],
+                self.xmm12.Reg128.Dword[0], self.xmm12.Reg128.Dword[1],
+                self.xmm12.Reg128.Dword[2], self.xmm12.Reg128.Dword[3],
+                self.xmm13.Reg128.Dword[0], self.xmm13.Reg128.Dword[1],
+                self.xmm13.Reg128.Dword[2], self.xmm13.Reg128.Dword[3],
+                self.xmm14.Reg128.Dword[0], self.xmm14.Reg128.Dword[1],
+                self.xmm14.Reg128.Dword[2], self.xmm14.Reg128.Dword[3],
+                self.xmm15.Reg128.Dword[0], self.xmm15.Reg128.Dword[1],
+                self.xmm15.Reg128.Dword[2], self.xmm15.Reg128.Dword[3],
+                self.fp_control.FpControlStatus.FpControl,
+                self.fp_control.FpControlStatus.FpStatus,
+                self.fp_control.FpControlStatus.FpTag,
+                self.fp_control.FpControlStatus.LastFpOp,
+                // TODO: Missing LastFpRip/LastFpEip based on processor mode
+                self.st0.Fp.Mantissa,
+                self.st0.Fp.BiasedExponent(),
+                self.st0.Fp.Sign(),
+                self.st1.Fp.Mantissa,
+                self.st1.Fp.BiasedExponent(),
+                self.st1.Fp.Sign(),
+                self.st2.Fp.Mantissa,
+                self.st2.Fp.BiasedExponent(),
+                self.st2.Fp.Sign(),
+                self.st3.Fp.Mantissa,
+                self.st3.Fp.BiasedExponent(),
+                self.st3.Fp.Sign(),
+                self.st4.Fp.Mantissa,
+                self.st4.Fp.BiasedExponent(),
+                self.st4.Fp.Sign(),
+                self.st5.Fp.Mantissa,
+                self.st5.Fp.BiasedExponent(),
+                self.st5.Fp.Sign(),
+                self.st6.Fp.Mantissa,
+                self.st6.Fp.BiasedExponent(),
+                self.st6.Fp.Sign(),
+                self.st7.Fp.Mantissa,
+                self.st7.Fp.BiasedExponent(),
+                self.st7.Fp.Sign(),
+            )
+        }
+    }
+}
+
+#[test]
+fn test_context_layout() {
+    // Must match an array of `WHV_REGISTER_VALUE`s, and Bochs' copy
+    assert_eq!(std::mem::size_of::<RegisterValue>(), 16);
+    assert_eq!(std::mem::size_of::<VpContext>() % 64, 0);
+
+    let mut context = VpContext::default();
+    context.cs.Segment = SegmentRegister {
+        Base: 0x1000, Limit: 0xffff, Selector: 0x10, Attributes: 0x209b,
+    };
+    context.rip.Reg64 = 0x234;
+    context.efer.Reg64 = 1 << 10;
+    context.cr0.Reg64 = 0x80000001;
+    context.cr4.Reg64 = 1 << 5;
+    context.cr3.Reg64 = 0x1ab000 | 0x5;
+    context.st0.Fp = FpRegister { Mantissa: 1, ExponentSign: 0xbfff };
+    assert_eq!(context.rip(), 0x1234);
+    assert_eq!(context.cr3(), 0x1ab000);
+    unsafe {
+        assert_eq!(context.st0.Fp.BiasedExponent(), 0x3fff);
+        assert_eq!(context.st0.Fp.Sign(), 1);
+    }
+
+    let state = CpuState::from(&context);
+    assert_eq!((state.rip, state.cs.selector, state.cs.base),
+        (0x234, 0x10, 0x1000));
+}
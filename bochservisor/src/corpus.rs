@@ -0,0 +1,241 @@
+/// On-disk corpus of inputs which found new coverage
+///
+/// Every input is saved in the corpus directory named by the hash of its
+/// contents, next to a `<hash>.cov` file listing the `module+offset` of the
+/// coverage it was the first to hit, and the edges it was the first to take
+/// as `edge:<index>:<bucket>` in edge coverage mode. The corpus is loaded back
+/// at startup so campaigns can be stopped and resumed.
+///
+/// Parents are picked weighted by energy. Inputs which found a lot of new
+/// coverage (or whose mutations did) get more energy, and energy drops the
+/// more often an input has been picked.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::mutator::Rng;
+
+/// Extension of the coverage listing saved next to every input
+const COVERAGE_EXTENSION: &str = "cov";
+
+/// Most coverage an input gets credit for when computing energy, so a single
+/// input which found a whole module doesn't starve everything else
+const MAX_ENERGY_COVERAGE: u64 = 64;
+
+/// Number of picks it takes to halve the energy of an input
+const PICKS_PER_HALVING: u64 = 256;
+
+/// FNV-1a hash of `data`, used to name corpus files
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325u64, |acc, &byte| {
+        (acc ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A single input in the corpus
+pub struct CorpusEntry {
+    /// Contents of the input
+    pub input: Vec<u8>,
+
+    /// Hash of `input`, this is also its file name
+    pub hash: u64,
+
+    /// Amount of new coverage this input and its mutations found
+    discovered: u64,
+
+    /// Number of times this input was picked as a parent
+    picks: u64,
+}
+
+impl CorpusEntry {
+    /// Weight of this entry when picking parents. Always non-zero
+    fn energy(&self) -> u64 {
+        let coverage = std::cmp::min(self.discovered, MAX_ENERGY_COVERAGE);
+        std::cmp::max(1,
+            (1 + coverage) * 1024 / (1 + self.picks / PICKS_PER_HALVING))
+    }
+}
+
+/// Corpus of inputs backed by a directory
+pub struct Corpus {
+    /// Directory holding the corpus
+    dir: PathBuf,
+
+    /// Every input in the corpus
+    entries: Vec<CorpusEntry>,
+
+    /// Hashes of every input in the corpus
+    hashes: HashSet<u64>,
+}
+
+impl Corpus {
+    /// Load the corpus in `dir`, creating the directory if it doesn't exist.
+    /// Entries are sorted by hash so runs with the same RNG seed pick the
+    /// same parents
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut entries = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if !path.is_file() ||
+                    path.extension().and_then(|x| x.to_str()) ==
+                    Some(COVERAGE_EXTENSION) {
+                continue;
+            }
+
+            // Coverage listings are one line per offset or edge discovered
+            let discovered = std::fs::read_to_string(
+                    path.with_extension(COVERAGE_EXTENSION))
+                .map(|x| x.lines().count() as u64).unwrap_or(0);
+
+            let input = std::fs::read(&path)?;
+            entries.push(CorpusEntry {
+                hash: hash(&input),
+                input,
+                discovered,
+                picks: 0,
+            });
+        }
+
+        entries.sort_by_key(|x| x.hash);
+        entries.dedup_by_key(|x| x.hash);
+        let hashes = entries.iter().map(|x| x.hash).collect();
+
+        Ok(Corpus { dir, entries, hashes })
+    }
+
+    /// Number of inputs in the corpus
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the input at `idx`
+    pub fn get(&self, idx: usize) -> &[u8] {
+        &self.entries[idx].input
+    }
+
+    /// Add `input` to the corpus and save it to disk along with the
+    /// `coverage` it discovered as (module, offset) and the `edges` as (edge,
+    /// bucket). Returns `false` if the input was already in the corpus
+    pub fn add(&mut self, input: &[u8], coverage: &[(String, usize)],
+            edges: &[(usize, u8)]) -> io::Result<bool> {
+        let hash = hash(input);
+        if self.hashes.contains(&hash) {
+            return Ok(false);
+        }
+
+        let path = self.dir.join(format!("{:016x}", hash));
+        std::fs::write(&path, input)?;
+
+        let mut listing =
+            File::create(path.with_extension(COVERAGE_EXTENSION))?;
+        for (module, offset) in coverage {
+            write!(listing, "{}+0x{:x}\n", module, offset)?;
+        }
+        for (edge, bucket) in edges {
+            write!(listing, "edge:{:#06x}:{:#04x}\n", edge, bucket)?;
+        }
+
+        self.hashes.insert(hash);
+        self.entries.push(CorpusEntry {
+            input: input.to_vec(),
+            hash,
+            discovered: (coverage.len() + edges.len()) as u64,
+            picks: 0,
+        });
+        Ok(true)
+    }
+
+    /// Give the entry at `idx` credit for `discovered` new coverage found by
+    /// one of its mutations
+    pub fn credit(&mut self, idx: usize, discovered: u64) {
+        self.entries[idx].discovered += discovered;
+    }
+
+    /// Pick a parent weighted by energy. The corpus must not be empty
+    pub fn pick(&mut self, rng: &mut Rng) -> usize {
+        let total: u64 = self.entries.iter().map(|x| x.energy()).sum();
+
+        let mut choice = rng.below(total);
+        let mut idx = 0;
+        for (ii, entry) in self.entries.iter().enumerate() {
+            let energy = entry.energy();
+            if choice < energy {
+                idx = ii;
+                break;
+            }
+            choice -= energy;
+        }
+
+        self.entries[idx].picks += 1;
+        idx
+    }
+}
+
+#[test]
+fn test_hash() {
+    // FNV-1a 64 test vectors
+    assert_eq!(hash(b""), 0xcbf29ce484222325);
+    assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+    assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+}
+
+#[test]
+fn test_corpus_round_trip() {
+    let dir = std::env::temp_dir().join(
+        format!("applepie_corpus_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut corpus = Corpus::load(&dir).unwrap();
+    assert_eq!(corpus.len(), 0);
+    assert!(corpus.add(b"one", &[("foo.sys".into(), 0x10)], &[(0x20, 2)])
+        .unwrap());
+    assert!(corpus.add(b"two", &[], &[]).unwrap());
+    assert!(!corpus.add(b"one", &[], &[]).unwrap());
+    assert_eq!(corpus.len(), 2);
+
+    let path = dir.join(format!("{:016x}", hash(b"one")));
+    assert_eq!(std::fs::read(&path).unwrap(), b"one");
+    assert_eq!(std::fs::read_to_string(path.with_extension(COVERAGE_EXTENSION))
+        .unwrap(), "foo.sys+0x10\nedge:0x0020:0x02\n");
+
+    // Entries come back sorted by hash, with credit for their coverage
+    let corpus = Corpus::load(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let mut expected = [(hash(b"one"), &b"one"[..], 2),
+        (hash(b"two"), b"two", 0)];
+    expected.sort();
+    let entries: Vec<_> = corpus.entries.iter()
+        .map(|x| (x.hash, &x.input[..], x.discovered)).collect();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn test_pick_energy() {
+    let entry = |discovered, picks| CorpusEntry {
+        input: Vec::new(), hash: 0, discovered, picks,
+    };
+    assert_eq!(entry(0, 0).energy(), 1024);
+    assert_eq!(entry(MAX_ENERGY_COVERAGE * 2, 0).energy(),
+        (1 + MAX_ENERGY_COVERAGE) * 1024);
+    assert_eq!(entry(0, PICKS_PER_HALVING).energy(), 512);
+
+    // 65 times the energy gets picked about 65 times as often, as long as
+    // the picks don't wear it down
+    let mut corpus = Corpus {
+        dir:     PathBuf::new(),
+        entries: vec![entry(0, 0), entry(64, 0)],
+        hashes:  HashSet::new(),
+    };
+    let mut rng = Rng::new(1);
+    let mut picks = [0u64; 2];
+    for _ in 0..6600 {
+        picks[corpus.pick(&mut rng)] += 1;
+        corpus.entries[1].picks = 0;
+    }
+    assert!(picks[0] > 50 && picks[0] < 200, "{:?}", picks);
+    assert_eq!(corpus.entries[0].picks, picks[0]);
+}
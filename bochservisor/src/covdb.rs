@@ -0,0 +1,119 @@
+/// Coverage database saved across runs
+///
+/// Module ordinals are only meaningful to the process which allocated them, so
+/// on disk every module is keyed by its name, TimeDateStamp and SizeOfImage
+/// instead, followed by its coverage bitmap with one bit per byte of the
+/// image. The database is merged with what we have in memory every time it's
+/// saved, so parallel workers sharing one database all end up with the
+/// coverage of the whole campaign.
+///
+/// The file is a magic followed by one record per module, all integers are
+/// little endian:
+///
+/// ```text
+/// u32 name length, name, u32 timedatestamp, u32 sizeofimage,
+/// u32 bitmap length, bitmap
+/// ```
+
+use std::convert::TryInto;
+
+/// Magic at the start of every coverage database
+const MAGIC: &[u8; 8] = b"APCOVDB1";
+
+/// A single module worth of coverage in the database
+#[derive(Debug, PartialEq)]
+pub struct Module<'a> {
+    /// Name of the module
+    pub name: &'a str,
+
+    /// TimeDateStamp from the PE header
+    pub timestamp: u32,
+
+    /// SizeOfImage from the PE header
+    pub size: u32,
+
+    /// Bitmap of covered offsets, one bit per byte of the image
+    pub bitmap: &'a [u8],
+}
+
+/// Parse the coverage database in `data`
+pub fn parse(data: &[u8]) -> Result<Vec<Module<'_>>, String> {
+    if data.get(..MAGIC.len()) != Some(&MAGIC[..]) {
+        return Err("Not a coverage database".into());
+    }
+
+    let mut modules = Vec::new();
+    let mut ptr = &data[MAGIC.len()..];
+
+    // Take `len` bytes from the database
+    fn take<'a>(ptr: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+        if ptr.len() < len {
+            return Err("Truncated coverage database".into());
+        }
+        let (ret, rest) = ptr.split_at(len);
+        *ptr = rest;
+        Ok(ret)
+    }
+
+    // Take a `u32` from the database
+    fn take_u32(ptr: &mut &[u8]) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(take(ptr, 4)?.try_into().unwrap()))
+    }
+
+    while !ptr.is_empty() {
+        let len  = take_u32(&mut ptr)? as usize;
+        let name = std::str::from_utf8(take(&mut ptr, len)?)
+            .map_err(|_| "Invalid module name in coverage database")?;
+        let timestamp = take_u32(&mut ptr)?;
+        let size      = take_u32(&mut ptr)?;
+        let len       = take_u32(&mut ptr)? as usize;
+        let bitmap    = take(&mut ptr, len)?;
+        modules.push(Module { name, timestamp, size, bitmap });
+    }
+
+    Ok(modules)
+}
+
+/// Serialize `modules` into a coverage database
+pub fn serialize(modules: &[Module]) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    for module in modules {
+        data.extend_from_slice(&(module.name.len() as u32).to_le_bytes());
+        data.extend_from_slice(module.name.as_bytes());
+        data.extend_from_slice(&module.timestamp.to_le_bytes());
+        data.extend_from_slice(&module.size.to_le_bytes());
+        data.extend_from_slice(&(module.bitmap.len() as u32).to_le_bytes());
+        data.extend_from_slice(module.bitmap);
+    }
+    data
+}
+
+/// Merge coverage `bitmap` into `into`, returning the number of offsets which
+/// were new to `into`
+pub fn merge(into: &mut [u8], bitmap: &[u8]) -> u64 {
+    into.iter_mut().zip(bitmap.iter()).map(|(into, &byte)| {
+        let new = byte & !*into;
+        *into |= new;
+        new.count_ones() as u64
+    }).sum()
+}
+
+#[test]
+fn test_covdb() {
+    let modules = [
+        Module { name: "ntdll.dll", timestamp: 0x1234, size: 9,
+            bitmap: &[0x81, 0x01] },
+        Module { name: "foo.exe", timestamp: 0, size: 0, bitmap: &[] },
+    ];
+
+    let data = serialize(&modules);
+    assert_eq!(parse(&data).unwrap(), modules);
+    assert!(parse(&data[..data.len() - 1]).is_err());
+    assert!(parse(b"garbage").is_err());
+    assert_eq!(parse(MAGIC).unwrap(), []);
+
+    let mut bitmap = [0x01, 0x00];
+    assert_eq!(merge(&mut bitmap, modules[0].bitmap), 2);
+    assert_eq!(bitmap, [0x81, 0x01]);
+    assert_eq!(merge(&mut bitmap, modules[0].bitmap), 0);
+}
@@ -0,0 +1,168 @@
+/// Plain register state for a processor
+///
+/// `VpContext` is laid out for WHVP and Bochs, every register is a union.
+/// Anything which only wants to look at registers, like the offline tools and
+/// dump writers, uses this instead so it doesn't need to know that layout.
+
+use crate::virtmem::Paging;
+
+/// A segment register
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Segment {
+    pub selector: u16,
+    pub base:     u64,
+    pub limit:    u32,
+}
+
+/// A descriptor table register
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Table {
+    pub base:  u64,
+    pub limit: u16,
+}
+
+/// Register state of a single processor
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CpuState {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8:  u64,
+    pub r9:  u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+
+    pub rflags: u64,
+
+    pub es: Segment,
+    pub cs: Segment,
+    pub ss: Segment,
+    pub ds: Segment,
+    pub fs: Segment,
+    pub gs: Segment,
+
+    pub gdtr: Table,
+    pub idtr: Table,
+
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+
+    pub dr0: u64,
+    pub dr1: u64,
+    pub dr2: u64,
+    pub dr3: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+
+    pub efer:           u64,
+    pub kernel_gs_base: u64,
+}
+
+impl CpuState {
+    /// Gets the linear address for RIP
+    pub fn linear_rip(&self) -> u64 {
+        self.cs.base.wrapping_add(self.rip)
+    }
+
+    /// Gets the CR3 with the reserved and PCID bits masked off
+    pub fn cr3(&self) -> u64 {
+        self.paging().table_root(self.cr3)
+    }
+
+    /// Returns `true` if the processor is in long mode
+    pub fn lma(&self) -> bool {
+        (self.efer & (1 << 10)) != 0
+    }
+
+    /// Returns `true` if 5-level paging is enabled
+    pub fn la57(&self) -> bool {
+        (self.cr4 & (1 << 12)) != 0
+    }
+
+    /// Paging mode of the processor
+    pub fn paging(&self) -> Paging {
+        Paging::new(self.cr0, self.cr4, self.lma())
+    }
+
+    /// Current privilege level
+    pub fn cpl(&self) -> u8 {
+        (self.cs.selector & 3) as u8
+    }
+
+    /// Gets the kernel GS base regardless of which mode we're in. In user
+    /// mode the kernel one is swapped out into the `KernelGsBase` MSR
+    pub fn kernel_gs(&self) -> u64 {
+        if self.cpl() == 0 { self.gs.base } else { self.kernel_gs_base }
+    }
+
+    /// Gets the address of the Windows `nt!_KPCR`. That's the kernel GS base
+    /// in long mode, and FS in kernel mode on 32-bit Windows. Zero if it
+    /// can't be found from this mode
+    pub fn kpcr(&self) -> u64 {
+        if self.lma() {
+            self.kernel_gs()
+        } else if self.cpl() == 0 {
+            self.fs.base
+        } else {
+            0
+        }
+    }
+}
+
+impl std::fmt::Display for CpuState {
+    /// Pretty prints the register state
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+            "rax {:016x} rcx {:016x} rdx {:016x} rbx {:016x}\n\
+             rsp {:016x} rbp {:016x} rsi {:016x} rdi {:016x}\n\
+             r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}\n\
+             r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}\n\
+             rip {:04x}:{:016x} (linear {:016x})\n\
+             rfl {:016x}\n\
+             es  {:04x} base {:016x} limit {:08x}\n\
+             cs  {:04x} base {:016x} limit {:08x}\n\
+             ss  {:04x} base {:016x} limit {:08x}\n\
+             ds  {:04x} base {:016x} limit {:08x}\n\
+             fs  {:04x} base {:016x} limit {:08x}\n\
+             gs  {:04x} base {:016x} limit {:08x}\n\
+             idtr base {:016x} limit {:04x}\n\
+             gdtr base {:016x} limit {:04x}\n\
+             efer {:016x} kernel gs {:016x}\n\
+             cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}\n\
+             cr8 {:016x}\n\
+             dr0 {:016x} dr1 {:016x} dr2 {:016x} dr3 {:016x}\n\
+             dr6 {:016x} dr7 {:016x}\n",
+            self.rax, self.rcx, self.rdx, self.rbx,
+            self.rsp, self.rbp, self.rsi, self.rdi,
+            self.r8,  self.r9,  self.r10, self.r11,
+            self.r12, self.r13, self.r14, self.r15,
+            self.cs.selector, self.rip, self.linear_rip(),
+            self.rflags,
+            self.es.selector, self.es.base, self.es.limit,
+            self.cs.selector, self.cs.base, self.cs.limit,
+            self.ss.selector, self.ss.base, self.ss.limit,
+            self.ds.selector, self.ds.base, self.ds.limit,
+            self.fs.selector, self.fs.base, self.fs.limit,
+            self.gs.selector, self.gs.base, self.gs.limit,
+            self.idtr.base, self.idtr.limit,
+            self.gdtr.base, self.gdtr.limit,
+            self.efer, self.kernel_gs_base,
+            self.cr0, self.cr2, self.cr3, self.cr4,
+            self.cr8,
+            self.dr0, self.dr1, self.dr2, self.dr3,
+            self.dr6, self.dr7)
+    }
+}
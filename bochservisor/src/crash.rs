@@ -0,0 +1,254 @@
+/// Crash detection and bucketing for fuzz cases
+///
+/// Crashes come from three places. Exceptions trapped with the exception
+/// bitmap, breakpoints on `nt!KeBugCheckEx` and
+/// `ntdll!KiUserExceptionDispatcher` in Windows guests, and guests reporting
+/// crashes themselves with a hypercall.
+///
+/// Crashes are bucketed by their kind, code, and symbolized faulting RIP plus
+/// a few return addresses found on the stack. The first input to hit a bucket
+/// is saved in the crashes directory as `<hash>`, next to a `<hash>.txt` with
+/// the bucket, where the input came from and the register state of the
+/// crashing processor. Hangs are kept the same way in their own directory,
+/// bucketed by their final RIP.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::corpus::hash;
+
+/// Extension of the crash information saved next to every crashing input
+const INFO_EXTENSION: &str = "txt";
+
+/// Number of symbolized frames in a bucket, including the faulting RIP
+pub const CRASH_FRAMES: usize = 4;
+
+/// Number of stack slots scanned for return addresses
+pub const CRASH_STACK_SCAN: usize = 256;
+
+/// Offset of the `EXCEPTION_RECORD` from RSP when entering
+/// `ntdll!KiUserExceptionDispatcher`. It follows the `CONTEXT` and a machine
+/// frame
+pub const USER_EXCEPTION_RECORD: usize = 0x4f0;
+
+/// Offset of `ExceptionAddress` in an `EXCEPTION_RECORD`
+pub const EXCEPTION_RECORD_ADDRESS: usize = 0x10;
+
+/// Offset of `Rsp` in a `CONTEXT`
+pub const CONTEXT_RSP: usize = 0x98;
+
+/// Exception names accepted in the trapped exception list, indexed by vector
+const EXCEPTION_NAMES: [&str; 20] = [
+    "de", "db", "nmi", "bp", "of", "br", "ud", "nm", "df", "cso",
+    "ts", "np", "ss", "gp", "pf", "res", "mf", "ac", "mc", "xm",
+];
+
+/// Vector of the debug exception, which is always trapped for breakpoints
+pub const DEBUG_VECTOR: u8 = 1;
+
+/// Vector of the breakpoint exception. Exception exits happen before the
+/// exception is delivered, so RIP is still on the `int3` when we see it
+pub const BREAKPOINT_VECTOR: u8 = 3;
+
+/// Where a crash was found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrashKind {
+    /// Exception `code` was trapped with the exception bitmap
+    Exception,
+
+    /// `nt!KeBugCheckEx` was called with bug check `code`
+    BugCheck,
+
+    /// User mode exception `code` reached `ntdll!KiUserExceptionDispatcher`
+    UserException,
+
+    /// The guest reported a crash with `code` itself
+    Guest,
+
+    /// The case ran over budget. There's no code, and the only frame is the
+    /// final RIP
+    Hang,
+}
+
+/// A single crash
+#[derive(Clone, Debug)]
+pub struct Crash {
+    /// Where the crash was found
+    pub kind: CrashKind,
+
+    /// Exception vector, bug check code, or status code depending on `kind`
+    pub code: u64,
+
+    /// Address of the faulting instruction
+    pub addr: u64,
+
+    /// Symbolized faulting RIP followed by symbolized return addresses
+    pub frames: Vec<String>,
+
+    /// Register state of the crashing processor
+    pub context: String,
+}
+
+impl Crash {
+    /// Get the bucket of this crash. Crashes with the same bucket are
+    /// considered the same bug
+    pub fn bucket(&self) -> String {
+        match self.kind {
+            CrashKind::Hang => format!("Hang at {}", self.frames.join(" <- ")),
+            _ => format!("{:?} {:#x} at {}", self.kind, self.code,
+                self.frames.join(" <- ")),
+        }
+    }
+}
+
+/// Parse a comma separated list of exception names or vector numbers into a
+/// bitmap. The debug exception can't be a crash as it's used for breakpoints
+pub fn parse_exceptions(list: &str) -> Result<u64, String> {
+    let mut bitmap = 0u64;
+
+    for name in list.split(',').map(|x| x.trim().to_lowercase()) {
+        if name.is_empty() { continue; }
+
+        let vector = EXCEPTION_NAMES.iter().position(|x| *x == name)
+            .or_else(|| name.parse::<usize>().ok())
+            .filter(|x| *x < 32)
+            .ok_or_else(|| format!("Unknown exception `{}`", name))?;
+
+        if vector == DEBUG_VECTOR as usize {
+            return Err("#DB is used for breakpoints and can't be trapped \
+                        as a crash".into());
+        }
+
+        bitmap |= 1 << vector;
+    }
+
+    Ok(bitmap)
+}
+
+/// Check if an `NTSTATUS` which reached `ntdll!KiUserExceptionDispatcher` is a
+/// crash. Only error severity system codes count, this skips things like
+/// debug prints, thread naming, and C++ exceptions which set the customer bit
+pub fn is_crash_status(code: u32) -> bool {
+    (code >> 30) == 3 && (code & (1 << 29)) == 0
+}
+
+/// Get the faulting address out of the parameters of bug check `code`, for
+/// the bug checks which have one
+pub fn bugcheck_address(code: u64, params: [u64; 4]) -> Option<u64> {
+    match code {
+        // KMODE_EXCEPTION_NOT_HANDLED, SYSTEM_SERVICE_EXCEPTION,
+        // SYSTEM_THREAD_EXCEPTION_NOT_HANDLED, KERNEL_MODE_EXCEPTION_NOT_HANDLED
+        0x1e | 0x3b | 0x7e | 0x8e => Some(params[1]),
+
+        // PAGE_FAULT_IN_NONPAGED_AREA
+        0x50 => Some(params[2]),
+
+        // IRQL_NOT_LESS_OR_EQUAL, DRIVER_IRQL_NOT_LESS_OR_EQUAL
+        0x0a | 0xd1 => Some(params[3]),
+
+        _ => None,
+    }.filter(|x| *x != 0)
+}
+
+/// Check if the 7 bytes in `prefix` preceding an address on the stack end with
+/// a call instruction, meaning the address is likely a return address
+pub fn is_return_address(prefix: &[u8; 7]) -> bool {
+    // Direct call rel32
+    if prefix[2] == 0xe8 { return true; }
+
+    // Indirect calls are `ff /2`, which are 2, 3, 4, 6, or 7 bytes long
+    // depending on the SIB byte and displacement
+    [5, 4, 3, 1, 0].iter().any(|&ii| {
+        prefix[ii] == 0xff && ((prefix[ii + 1] >> 3) & 7) == 2
+    })
+}
+
+/// Crashes found while fuzzing, backed by a directory
+pub struct Crashes {
+    /// Directory holding the crashes
+    dir: PathBuf,
+
+    /// Hashes of the bucket of every crash saved
+    buckets: HashSet<u64>,
+
+    /// Total number of crashes, including ones in known buckets
+    pub total: u64,
+}
+
+impl Crashes {
+    /// Load the known crash buckets in `dir`, creating the directory if it
+    /// doesn't exist
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut buckets = HashSet::new();
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if let Some(bucket) = path.file_name().and_then(|x| x.to_str())
+                    .and_then(|x| u64::from_str_radix(x, 16).ok()) {
+                buckets.insert(bucket);
+            }
+        }
+
+        Ok(Crashes { dir, buckets, total: 0 })
+    }
+
+    /// Number of unique crash buckets
+    pub fn unique(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Record `crash` caused by `input`, `origin` describes how the input
+    /// was made. If this is a new bucket the input and crash information are
+    /// saved and `true` is returned
+    pub fn add(&mut self, crash: &Crash, input: &[u8], origin: &str)
+            -> io::Result<bool> {
+        self.total += 1;
+
+        let bucket = crash.bucket();
+        let hash = hash(bucket.as_bytes());
+        if self.buckets.contains(&hash) {
+            return Ok(false);
+        }
+
+        let path = self.dir.join(format!("{:016x}", hash));
+        std::fs::write(&path, input)?;
+
+        let mut info = File::create(path.with_extension(INFO_EXTENSION))?;
+        write!(info, "{}\n{}\n\n{}", bucket, origin, crash.context)?;
+
+        self.buckets.insert(hash);
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_parse_exceptions() {
+    assert_eq!(parse_exceptions("pf, GP,ud,3"),
+        Ok((1 << 14) | (1 << 13) | (1 << 6) | (1 << 3)));
+    assert_eq!(parse_exceptions(""), Ok(0));
+    assert!(parse_exceptions("db").is_err());
+    assert!(parse_exceptions("1").is_err());
+    assert!(parse_exceptions("32").is_err());
+    assert!(parse_exceptions("segfault").is_err());
+}
+
+#[test]
+fn test_crash_filters() {
+    // Access violation and stack buffer overrun are crashes
+    assert!(is_crash_status(0xc0000005));
+    assert!(is_crash_status(0xc0000409));
+
+    // Breakpoints, debug prints, and C++ exceptions aren't
+    assert!(!is_crash_status(0x80000003));
+    assert!(!is_crash_status(0x40010006));
+    assert!(!is_crash_status(0xe06d7363));
+
+    // call rax, call [rip+x], call rel32
+    assert!(is_return_address(&[0, 0, 0, 0, 0, 0xff, 0xd0]));
+    assert!(is_return_address(&[0xff, 0x15, 0, 0, 0, 0, 0]));
+    assert!(is_return_address(&[0, 0, 0xe8, 0, 0, 0, 0]));
+    assert!(!is_return_address(&[0x48, 0x89, 0x5c, 0x24, 0x08, 0x55, 0x56]));
+}
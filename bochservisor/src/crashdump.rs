@@ -0,0 +1,349 @@
+/// Windows kernel crash dumps
+///
+/// Writes a 64-bit full memory dump (the `MEMORY.DMP` format) so a snapshot
+/// of the guest can be opened in WinDbg. A full dump is just a 0x2000 byte
+/// `DUMP_HEADER64` followed by every physical page described by the physical
+/// memory runs in the header, in order.
+///
+/// We don't locate `KdDebuggerDataBlock`, so it's left as zero. WinDbg will
+/// complain about it but still loads modules and symbols from
+/// `PsLoadedModuleList`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use crate::cpustate::CpuState;
+use crate::memreader::MemReader;
+
+/// Size of a page in the dump
+const PAGE_SIZE: usize = 4096;
+
+/// Size of `DUMP_HEADER64`
+const HEADER_SIZE: usize = 0x2000;
+
+/// Maximum number of physical memory runs which fit in the header
+const MAX_RUNS: usize = (0x2c0 - 0x10) / 0x10;
+
+/// Offsets into `DUMP_HEADER64`
+const HDR_SIGNATURE:             usize = 0x000;
+const HDR_VALID_DUMP:            usize = 0x004;
+const HDR_MAJOR_VERSION:         usize = 0x008;
+const HDR_MINOR_VERSION:         usize = 0x00c;
+const HDR_DIRECTORY_TABLE_BASE:  usize = 0x010;
+const HDR_PFN_DATABASE:          usize = 0x018;
+const HDR_PS_LOADED_MODULE_LIST: usize = 0x020;
+const HDR_PS_ACTIVE_PROCESS:     usize = 0x028;
+const HDR_MACHINE_IMAGE_TYPE:    usize = 0x030;
+const HDR_NUMBER_PROCESSORS:     usize = 0x034;
+const HDR_BUGCHECK_CODE:         usize = 0x038;
+const HDR_BUGCHECK_PARAMETERS:   usize = 0x040;
+const HDR_KD_DEBUGGER_DATA:      usize = 0x080;
+const HDR_PHYSICAL_MEMORY:       usize = 0x088;
+const HDR_CONTEXT:               usize = 0x348;
+const HDR_EXCEPTION:             usize = 0xf00;
+const HDR_DUMP_TYPE:             usize = 0xf98;
+const HDR_REQUIRED_DUMP_SPACE:   usize = 0xfa0;
+const HDR_SYSTEM_TIME:           usize = 0xfa8;
+const HDR_COMMENT:               usize = 0xfb0;
+const HDR_SYSTEM_UP_TIME:        usize = 0x1030;
+const HDR_MINI_DUMP_FIELDS:      usize = 0x1038;
+const HDR_SECONDARY_DATA_STATE:  usize = 0x103c;
+const HDR_PRODUCT_TYPE:          usize = 0x1040;
+const HDR_SUITE_MASK:            usize = 0x1044;
+const HDR_WRITER_STATUS:         usize = 0x1048;
+
+/// `DUMP_TYPE_FULL`
+const DUMP_TYPE_FULL: u32 = 1;
+
+/// `IMAGE_FILE_MACHINE_AMD64`
+const MACHINE_AMD64: u32 = 0x8664;
+
+/// `CONTEXT_AMD64 | CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS |
+/// CONTEXT_DEBUG_REGISTERS`
+const CONTEXT_FLAGS: u32 = 0x100000 | 0x1 | 0x2 | 0x4 | 0x10;
+
+/// `STATUS_BREAKPOINT`, used for the exception record when nothing crashed
+const STATUS_BREAKPOINT: u32 = 0x80000003;
+
+/// Bugcheck code for dumps we take ourselves, `MANUALLY_INITIATED_CRASH`
+pub const MANUALLY_INITIATED_CRASH: u32 = 0xe2;
+
+/// `nt!KUSER_SHARED_DATA.NtBuildNumber`, mapped at the same address in every
+/// 64-bit Windows
+const KUSER_NT_BUILD_NUMBER: usize = 0xfffff78000000000 + 0x260;
+
+/// Seconds between the Windows epoch (1601) and the Unix epoch (1970)
+const WINDOWS_EPOCH_OFFSET: u64 = 11644473600;
+
+/// Everything about the guest needed for a dump header, other than memory
+pub struct CrashDump<'a> {
+    /// State of the processor which "crashed". Its CR3 must map the kernel
+    pub cpu: &'a CpuState,
+
+    /// Number of processors in the guest
+    pub num_cpus: u32,
+
+    /// Address of `nt!PsLoadedModuleList`, see `win32::find_kernel_modlist`
+    pub ps_loaded_module_list: usize,
+
+    /// Bugcheck code and parameters to report
+    pub bugcheck: (u32, [u64; 4]),
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, val: u64) {
+    buf[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Get the physical memory runs in `memory` as (base page, page count),
+/// sorted and with adjacent runs merged. Partial pages are dropped
+fn physical_runs(memory: &MemReader) -> Vec<(u64, u64)> {
+    let mut regions: Vec<(u64, u64)> = memory.regions().iter()
+        .map(|x| {
+            let start = (x.paddr + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end   = (x.paddr + x.size) / PAGE_SIZE;
+            (start as u64, end.saturating_sub(start) as u64)
+        })
+        .filter(|x| x.1 > 0)
+        .collect();
+    regions.sort();
+
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for (base, count) in regions {
+        if let Some(last) = runs.last_mut() {
+            if last.0 + last.1 == base {
+                last.1 += count;
+                continue;
+            }
+        }
+        runs.push((base, count));
+    }
+
+    runs
+}
+
+/// Write the x64 `CONTEXT` structure for `cpu` into `buf`
+fn write_context(buf: &mut [u8], cpu: &CpuState) {
+    put_u32(buf, 0x30, CONTEXT_FLAGS);
+    put_u32(buf, 0x34, 0x1f80);
+
+    put_u16(buf, 0x38, cpu.cs.selector);
+    put_u16(buf, 0x3a, cpu.ds.selector);
+    put_u16(buf, 0x3c, cpu.es.selector);
+    put_u16(buf, 0x3e, cpu.fs.selector);
+    put_u16(buf, 0x40, cpu.gs.selector);
+    put_u16(buf, 0x42, cpu.ss.selector);
+    put_u32(buf, 0x44, cpu.rflags as u32);
+
+    let regs = [
+        cpu.dr0, cpu.dr1, cpu.dr2, cpu.dr3, cpu.dr6, cpu.dr7,
+        cpu.rax, cpu.rcx, cpu.rdx, cpu.rbx, cpu.rsp, cpu.rbp, cpu.rsi, cpu.rdi,
+        cpu.r8,  cpu.r9,  cpu.r10, cpu.r11, cpu.r12, cpu.r13, cpu.r14, cpu.r15,
+        cpu.rip,
+    ];
+    for (ii, &reg) in regs.iter().enumerate() {
+        put_u64(buf, 0x48 + ii * 8, reg);
+    }
+}
+
+impl<'a> CrashDump<'a> {
+    /// Write the dump to the file at `path`
+    pub fn save<P: AsRef<Path>>(&self, memory: &mut MemReader,
+                                path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.serialize(memory, &mut writer)?;
+        writer.flush()
+    }
+
+    /// Serialize the dump of `memory` into `w`
+    pub fn serialize<W: Write>(&self, memory: &mut MemReader,
+                               w: &mut W) -> io::Result<()> {
+        let runs = physical_runs(memory);
+        if runs.len() > MAX_RUNS {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                "Too many physical memory runs for a dump header"));
+        }
+        let num_pages: u64 = runs.iter().map(|x| x.1).sum();
+
+        let cpu = self.cpu;
+        let cr3 = cpu.cr3() as usize;
+
+        // Unused fields in real dumps are filled with the signature
+        let mut header = vec![0u8; HEADER_SIZE];
+        for chunk in header.chunks_mut(4) {
+            chunk.copy_from_slice(b"PAGE");
+        }
+
+        header[HDR_SIGNATURE..HDR_SIGNATURE + 4].copy_from_slice(b"PAGE");
+        header[HDR_VALID_DUMP..HDR_VALID_DUMP + 4].copy_from_slice(b"DU64");
+
+        // 0xf is a free build, the minor version is the build number
+        let build = memory.read_virt_u32(cr3, KUSER_NT_BUILD_NUMBER)
+            .unwrap_or(0) & 0xffff;
+        put_u32(&mut header, HDR_MAJOR_VERSION, 0xf);
+        put_u32(&mut header, HDR_MINOR_VERSION, build);
+
+        put_u64(&mut header, HDR_DIRECTORY_TABLE_BASE, cpu.cr3());
+        put_u64(&mut header, HDR_PFN_DATABASE, 0);
+        put_u64(&mut header, HDR_PS_LOADED_MODULE_LIST,
+            self.ps_loaded_module_list as u64);
+        put_u64(&mut header, HDR_PS_ACTIVE_PROCESS, 0);
+        put_u32(&mut header, HDR_MACHINE_IMAGE_TYPE, MACHINE_AMD64);
+        put_u32(&mut header, HDR_NUMBER_PROCESSORS, self.num_cpus);
+
+        put_u32(&mut header, HDR_BUGCHECK_CODE, self.bugcheck.0);
+        put_u32(&mut header, HDR_BUGCHECK_CODE + 4, 0);
+        for (ii, &param) in self.bugcheck.1.iter().enumerate() {
+            put_u64(&mut header, HDR_BUGCHECK_PARAMETERS + ii * 8, param);
+        }
+
+        put_u64(&mut header, HDR_KD_DEBUGGER_DATA, 0);
+
+        // PHYSICAL_MEMORY_DESCRIPTOR64
+        for byte in &mut header[HDR_PHYSICAL_MEMORY..HDR_CONTEXT] {
+            *byte = 0;
+        }
+        put_u32(&mut header, HDR_PHYSICAL_MEMORY, runs.len() as u32);
+        put_u64(&mut header, HDR_PHYSICAL_MEMORY + 8, num_pages);
+        for (ii, &(base, count)) in runs.iter().enumerate() {
+            let offset = HDR_PHYSICAL_MEMORY + 0x10 + ii * 0x10;
+            put_u64(&mut header, offset, base);
+            put_u64(&mut header, offset + 8, count);
+        }
+
+        // CONTEXT
+        for byte in &mut header[HDR_CONTEXT..HDR_EXCEPTION] {
+            *byte = 0;
+        }
+        write_context(&mut header[HDR_CONTEXT..HDR_EXCEPTION], cpu);
+
+        // EXCEPTION_RECORD64
+        for byte in &mut header[HDR_EXCEPTION..HDR_DUMP_TYPE] {
+            *byte = 0;
+        }
+        put_u32(&mut header, HDR_EXCEPTION, STATUS_BREAKPOINT);
+        put_u64(&mut header, HDR_EXCEPTION + 0x10, cpu.linear_rip());
+
+        put_u32(&mut header, HDR_DUMP_TYPE, DUMP_TYPE_FULL);
+        put_u32(&mut header, HDR_DUMP_TYPE + 4, 0);
+        put_u64(&mut header, HDR_REQUIRED_DUMP_SPACE,
+            HEADER_SIZE as u64 + num_pages * PAGE_SIZE as u64);
+
+        // System time is a FILETIME, 100ns intervals since 1601
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| (x.as_secs() + WINDOWS_EPOCH_OFFSET) * 10_000_000 +
+                 x.subsec_nanos() as u64 / 100)
+            .unwrap_or(0);
+        put_u64(&mut header, HDR_SYSTEM_TIME, now);
+
+        let comment = &mut header[HDR_COMMENT..HDR_SYSTEM_UP_TIME];
+        for byte in comment.iter_mut() { *byte = 0; }
+        let text = b"applepie snapshot";
+        comment[..text.len()].copy_from_slice(text);
+
+        put_u64(&mut header, HDR_SYSTEM_UP_TIME, 0);
+        put_u32(&mut header, HDR_MINI_DUMP_FIELDS, 0);
+        put_u32(&mut header, HDR_SECONDARY_DATA_STATE, 0);
+        put_u32(&mut header, HDR_PRODUCT_TYPE, 1);
+        put_u32(&mut header, HDR_SUITE_MASK, 0);
+        put_u32(&mut header, HDR_WRITER_STATUS, 0);
+
+        w.write_all(&header)?;
+
+        // Physical memory, every page of every run in order. Anything we
+        // can't read is written as zeros so the file layout stays intact
+        let mut page = [0u8; PAGE_SIZE];
+        for &(base, count) in &runs {
+            for pfn in base..base + count {
+                let bread = memory.read_phys(pfn as usize * PAGE_SIZE,
+                    &mut page);
+                for byte in &mut page[bread..] { *byte = 0; }
+                w.write_all(&page)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_serialize_dump() {
+    use crate::memreader::MemoryRegion;
+
+    // A page table at 0x1000 mapping KUSER_SHARED_DATA to 0x5000, then a
+    // region which adds a page and a half, and a lone page at 0x20000
+    let mut low = vec![0u8; 6 * PAGE_SIZE];
+    for &(entry, val) in &[(0x1000 + 0x1ef * 8, 0x2003u64), (0x2000, 0x3003),
+            (0x3000, 0x4003), (0x4000, 0x5003)] {
+        put_u64(&mut low, entry, val);
+    }
+    put_u32(&mut low, 0x5260, 0xf0000000 | 19041);
+    let mid  = vec![0u8; PAGE_SIZE + PAGE_SIZE / 2];
+    let high = vec![0x41u8; PAGE_SIZE];
+
+    let region = |paddr: usize, backing: &[u8]| MemoryRegion {
+        paddr,
+        backing: backing.as_ptr() as usize,
+        perms:   0,
+        size:    backing.len(),
+    };
+    let mut memory = MemReader::new(vec![region(0x20000, &high),
+        region(0, &low), region(0x6000, &mid)]);
+
+    let cpu = CpuState {
+        cr0:  1 << 31,
+        efer: 1 << 10,
+        cr3:  0x1000,
+        rip:  0xfffff80000001234,
+        ..Default::default()
+    };
+
+    let mut out = Vec::new();
+    CrashDump {
+        cpu: &cpu,
+        num_cpus: 2,
+        ps_loaded_module_list: 0xfffff80000005678,
+        bugcheck: (MANUALLY_INITIATED_CRASH, [1, 2, 3, 4]),
+    }.serialize(&mut memory, &mut out).unwrap();
+
+    let u32_at = |off: usize| {
+        let mut raw = [0u8; 4];
+        raw.copy_from_slice(&out[off..off + 4]);
+        u32::from_le_bytes(raw)
+    };
+    let u64_at = |off: usize| {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&out[off..off + 8]);
+        u64::from_le_bytes(raw)
+    };
+
+    assert_eq!(&out[HDR_SIGNATURE..HDR_SIGNATURE + 8], b"PAGEDU64");
+    assert_eq!(u32_at(HDR_MINOR_VERSION), 19041);
+    assert_eq!(u64_at(HDR_DIRECTORY_TABLE_BASE), 0x1000);
+    assert_eq!(u64_at(HDR_PS_LOADED_MODULE_LIST), 0xfffff80000005678);
+    assert_eq!(u32_at(HDR_NUMBER_PROCESSORS), 2);
+    assert_eq!(u32_at(HDR_BUGCHECK_CODE), MANUALLY_INITIATED_CRASH);
+    assert_eq!(u64_at(HDR_BUGCHECK_PARAMETERS + 24), 4);
+    assert_eq!(u64_at(HDR_CONTEXT + 0xf8), 0xfffff80000001234);
+    assert_eq!(u64_at(HDR_EXCEPTION + 0x10), 0xfffff80000001234);
+
+    // The first two regions merge and lose the half page
+    assert_eq!((u32_at(HDR_PHYSICAL_MEMORY), u64_at(HDR_PHYSICAL_MEMORY + 8)),
+        (2, 8));
+    assert_eq!((u64_at(HDR_PHYSICAL_MEMORY + 0x10),
+        u64_at(HDR_PHYSICAL_MEMORY + 0x18)), (0, 7));
+    assert_eq!((u64_at(HDR_PHYSICAL_MEMORY + 0x20),
+        u64_at(HDR_PHYSICAL_MEMORY + 0x28)), (0x20, 1));
+
+    assert_eq!(out.len(), HEADER_SIZE + 8 * PAGE_SIZE);
+    assert_eq!(u32_at(HEADER_SIZE + 0x5260), 0xf0000000 | 19041);
+    assert!(out[HEADER_SIZE + 7 * PAGE_SIZE..].iter().all(|&x| x == 0x41));
+}
@@ -31,6 +31,11 @@ struct VirtualDisk {
     /// If this store is `None`, the disk is not in volatile mode and thus
     /// writes go to disk.
     volatile_store: Option<HashMap<u64, [u8; 512]>>,
+
+    /// Sectors which differ from the backing file in the snapshot we booted
+    /// from. Unlike the volatile store these survive discarding changes, as
+    /// they're part of the state we restore to
+    snapshot_store: HashMap<u64, [u8; 512]>,
 }
 
 /// Discard all changes made to the vdisk. This is used to reset the disk to
@@ -70,6 +75,8 @@ pub fn vdisk_set_non_volatile() {
         // We could allow this in future if we wrote out the changes
         assert!(vdisk.volatile_store.as_ref().unwrap().len() == 0,
             "Cannot go non-volatile for vdisk with pending stores");
+        assert!(vdisk.snapshot_store.len() == 0,
+            "Cannot go non-volatile for vdisk loaded from a snapshot");
 
         // Disable the volatile store
         vdisk.volatile_store = None;
@@ -78,6 +85,59 @@ pub fn vdisk_set_non_volatile() {
     });
 }
 
+/// Get all sectors which differ from the backing file, sorted by sector number.
+/// This is what gets saved in a snapshot
+pub fn vdisk_changed_sectors() -> Vec<(u64, [u8; 512])> {
+    DISK_STATE.with(|x| {
+        let x = x.borrow();
+
+        // Handle cases where no vdisk is used
+        if x.is_none() { return Vec::new(); }
+
+        let vdisk = x.as_ref().unwrap();
+
+        // Start with the snapshot sectors and apply our changes on top
+        let mut changed = vdisk.snapshot_store.clone();
+        if let Some(volatile_store) = &vdisk.volatile_store {
+            for (&blk, sector) in volatile_store.iter() {
+                changed.insert(blk, *sector);
+            }
+        }
+
+        let mut changed: Vec<(u64, [u8; 512])> = changed.into_iter().collect();
+        changed.sort_by_key(|x| x.0);
+        changed
+    })
+}
+
+/// Load the changed sectors `sectors` from a snapshot. These become part of the
+/// state the disk is reset to when discarding changes
+pub fn vdisk_load_snapshot(sectors: &[(u64, [u8; 512])]) {
+    DISK_STATE.with(|x| {
+        let mut x = x.borrow_mut();
+
+        // Handle cases where no vdisk is used
+        if x.is_none() {
+            assert!(sectors.len() == 0,
+                "Snapshot has disk changes but there is no vdisk");
+            return;
+        }
+
+        let vdisk = x.as_mut().unwrap();
+
+        // Snapshots are only loaded before anything touched the disk
+        assert!(vdisk.volatile_store.as_ref()
+            .map(|x| x.len() == 0).unwrap_or(false),
+            "Can only load a snapshot into a clean volatile vdisk");
+
+        for &(blk, sector) in sectors {
+            assert!(blk.checked_mul(512).expect("Integer overflow on block") <
+                vdisk.length, "Snapshot sector is out of bounds of the disk");
+            vdisk.snapshot_store.insert(blk, sector);
+        }
+    });
+}
+
 #[no_mangle]
 pub extern "C" fn vdisk_open(path: *const u8, _flags: i32) -> i32 {
     // Just return out the file descriptor
@@ -114,6 +174,7 @@ pub extern "C" fn vdisk_open(path: *const u8, _flags: i32) -> i32 {
             backing:   backing,
             length,
             volatile_store: Some(HashMap::new()),
+            snapshot_store: HashMap::new(),
         });
 
         // Return the fd
@@ -146,6 +207,12 @@ pub extern "C" fn vdisk_read(_vuint: i32, blk: u64, buf: *mut u8) -> bool {
             }
         }
 
+        // Then sectors from the snapshot we booted from
+        if let Some(sector) = vdisk.snapshot_store.get(&blk) {
+            output_buf.copy_from_slice(sector);
+            return true;
+        }
+
         // Seek to block offset
         vdisk.backing.seek(SeekFrom::Start(lba)).expect("Failed to seek");
 
@@ -0,0 +1,94 @@
+/// drcov coverage files
+///
+/// drcov is the DynamoRIO coverage format, which Lighthouse (IDA and Binary
+/// Ninja) and friends load directly. We write version 2 with a `drcov` flavor.
+/// Modules don't have a base address in our coverage, so every module is
+/// listed at base zero and blocks are module offsets, which is all these tools
+/// look at anyway. Every covered offset is its own single byte block.
+
+use std::io::{self, Write};
+
+/// A single module worth of coverage
+pub struct Module<'a> {
+    /// Name of the module
+    pub name: &'a str,
+
+    /// SizeOfImage from the PE header
+    pub size: u32,
+
+    /// TimeDateStamp from the PE header
+    pub timestamp: u32,
+
+    /// Bitmap of covered offsets, one bit per byte of the image
+    pub bitmap: &'a [u8],
+}
+
+impl<'a> Module<'a> {
+    /// Get every covered offset in this module
+    pub fn offsets(&self) -> impl Iterator<Item = u32> + 'a {
+        let size = self.size;
+        self.bitmap.iter().enumerate()
+            .filter(|(_, &byte)| byte != 0)
+            .flat_map(|(ii, &byte)| (0..8).filter(move |bit|
+                byte & (1 << bit) != 0).map(move |bit| (ii * 8 + bit) as u32))
+            .filter(move |&offset| offset < size)
+    }
+}
+
+/// Write `modules` to `out` as a drcov file
+pub fn write<W: Write>(mut out: W, modules: &[Module]) -> io::Result<()> {
+    write!(out, "DRCOV VERSION: 2\n")?;
+    write!(out, "DRCOV FLAVOR: drcov\n")?;
+    write!(out, "Module Table: version 2, count {}\n", modules.len())?;
+    write!(out, "Columns: id, base, end, entry, checksum, timestamp, path\n")?;
+    for (id, module) in modules.iter().enumerate() {
+        write!(out, "{:3}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, {:#010x}, \
+                {}\n", id, 0, module.size, 0, 0, module.timestamp,
+            module.name)?;
+    }
+
+    let blocks: usize = modules.iter().map(|x| x.offsets().count()).sum();
+    write!(out, "BB Table: {} bbs\n", blocks)?;
+
+    // Blocks are `struct { u32 start; u16 size; u16 mod_id; }`
+    for (id, module) in modules.iter().enumerate() {
+        for offset in module.offsets() {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&1u16.to_le_bytes())?;
+            out.write_all(&(id as u16).to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_drcov() {
+    let bitmap = [0x81u8, 0x00, 0x02, 0xff];
+    let modules = [
+        Module { name: "ntdll.dll", size: 0x1000, timestamp: 0x1234,
+            bitmap: &[0x01] },
+        Module { name: "foo.exe", size: 28, timestamp: 0, bitmap: &bitmap },
+    ];
+    assert_eq!(modules[1].offsets().collect::<Vec<_>>(),
+        [0, 7, 17, 24, 25, 26, 27]);
+
+    let mut out = Vec::new();
+    write(&mut out, &modules).unwrap();
+
+    let header = b"BB Table: 8 bbs\n";
+    let split = out.windows(header.len()).position(|x| x == header).unwrap()
+        + header.len();
+    let text = std::str::from_utf8(&out[..split]).unwrap();
+    assert!(text.starts_with("DRCOV VERSION: 2\n"));
+    assert!(text.contains("Module Table: version 2, count 2\n"));
+    assert!(text.contains("  1, 0x0000000000000000, 0x000000000000001c, "));
+    assert!(text.contains(", 0x00001234, ntdll.dll\n"));
+
+    // First block is ntdll+0, second foo.exe+0, third foo.exe+7
+    let blocks = &out[split..];
+    assert_eq!(blocks.len(), 8 * 8);
+    assert_eq!(blocks[..8], [0, 0, 0, 0, 1, 0, 0, 0]);
+    assert_eq!(blocks[8..16], [0, 0, 0, 0, 1, 0, 1, 0]);
+    assert_eq!(blocks[16..24], [7, 0, 0, 0, 1, 0, 1, 0]);
+}
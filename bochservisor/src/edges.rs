@@ -0,0 +1,135 @@
+/// AFL style edge coverage
+///
+/// Every coverage event is a location, the hash of the module name and the
+/// offset in it, so locations are the same in every run and every worker.
+/// Each processor remembers its previous location, and the previous location
+/// shifted right by one xored with the current one indexes a map of hit
+/// counts for the case.
+///
+/// When a case is over its counts are bucketed (1, 2, 3, 4-7, 8-15, 16-31,
+/// 32-127, 128+) and compared against every bucket seen so far. A case is
+/// interesting if it took an edge nobody took before, or took a known edge a
+/// new number of times.
+
+use crate::corpus::hash;
+
+/// Number of entries in the edge map
+pub const EDGE_MAP_SIZE: usize = 1 << 16;
+
+/// Get the location of `offset` in `module`
+pub fn location(module: &str, offset: usize) -> u64 {
+    hash(module.as_bytes()) ^ hash(&offset.to_le_bytes())
+}
+
+/// Get the bucket bit of an edge taken `count` times
+pub fn bucket(count: u8) -> u8 {
+    match count {
+        0        => 0,
+        1        => 1 << 0,
+        2        => 1 << 1,
+        3        => 1 << 2,
+        4..=7    => 1 << 3,
+        8..=15   => 1 << 4,
+        16..=31  => 1 << 5,
+        32..=127 => 1 << 6,
+        _        => 1 << 7,
+    }
+}
+
+/// Edge hit counts of the current case and every bucket seen so far
+pub struct EdgeMap {
+    /// Hit counts of every edge in the current case, saturating
+    hits: Vec<u8>,
+
+    /// Bucket bits seen so far for every edge
+    seen: Vec<u8>,
+
+    /// Previous location of every processor in the current case
+    prev: Vec<u64>,
+
+    /// Number of edges taken so far
+    pub edges: u64,
+}
+
+impl EdgeMap {
+    /// Create a new empty edge map
+    pub fn new() -> Self {
+        EdgeMap {
+            hits:  vec![0; EDGE_MAP_SIZE],
+            seen:  vec![0; EDGE_MAP_SIZE],
+            prev:  Vec::new(),
+            edges: 0,
+        }
+    }
+
+    /// Forget the hit counts and previous locations of the current case
+    pub fn begin_case(&mut self) {
+        for hits in self.hits.iter_mut() { *hits = 0; }
+        self.prev.clear();
+    }
+
+    /// Record that processor `vp` reached `location`
+    pub fn hit(&mut self, vp: usize, location: u64) {
+        if vp >= self.prev.len() {
+            self.prev.resize(vp + 1, 0);
+        }
+
+        let idx = ((self.prev[vp] >> 1) ^ location) as usize % EDGE_MAP_SIZE;
+        self.hits[idx] = self.hits[idx].saturating_add(1);
+        self.prev[vp] = location;
+    }
+
+    /// Bucket the hit counts of the current case and merge them into the
+    /// buckets seen so far. Returns the index and bucket bit of every edge
+    /// which was taken a new number of times
+    pub fn finish_case(&mut self) -> Vec<(usize, u8)> {
+        let mut new = Vec::new();
+        for (idx, (&hits, seen)) in
+                self.hits.iter().zip(self.seen.iter_mut()).enumerate() {
+            let bucket = bucket(hits);
+            if bucket & !*seen != 0 {
+                if *seen == 0 { self.edges += 1; }
+                *seen |= bucket;
+                new.push((idx, bucket));
+            }
+        }
+        new
+    }
+}
+
+#[test]
+fn test_edges() {
+    let mut edges = EdgeMap::new();
+    let (a, b) = (location("foo.exe", 0x10), location("foo.exe", 0x20));
+    assert_ne!(a, b);
+
+    // a -> b once is a new edge (plus the entry edge into a)
+    edges.begin_case();
+    edges.hit(0, a);
+    edges.hit(0, b);
+    assert_eq!(edges.finish_case().len(), 2);
+    assert_eq!(edges.edges, 2);
+
+    // The same path again is nothing new
+    edges.begin_case();
+    edges.hit(0, a);
+    edges.hit(0, b);
+    assert!(edges.finish_case().is_empty());
+
+    // Taking a -> b four times is a new bucket for a known edge, and b -> a
+    // is a new edge. Other processors have their own previous location
+    edges.begin_case();
+    edges.hit(1, a);
+    for _ in 0..4 {
+        edges.hit(0, a);
+        edges.hit(0, b);
+    }
+    let new = edges.finish_case();
+    assert_eq!(new.len(), 3);
+    assert!(new.iter().any(|x| x.1 == bucket(4)));
+    assert_eq!(edges.edges, 3);
+
+    assert_eq!(bucket(3), 4);
+    assert_eq!(bucket(100), 64);
+    assert_eq!(bucket(255), 128);
+}
@@ -0,0 +1,375 @@
+/// ELF core files of guest memory
+///
+/// Writes an ELF64 core with a `NT_PRSTATUS` note per processor and a
+/// `PT_LOAD` segment per chunk of memory. This works for any guest, not just
+/// Windows, so generic tools like gdb can look at the guest.
+///
+/// Memory can be laid out two ways. The physical layout has a segment per
+/// physical memory region with the physical address as the virtual address.
+/// The virtual layout walks a page table and has a segment per contiguous
+/// virtual range, which is what gdb wants.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::cpustate::CpuState;
+use crate::memreader::MemReader;
+use crate::virtmem;
+
+/// Size of a page
+const PAGE_SIZE: u64 = 4096;
+
+/// Sizes of the ELF structures we write
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+/// `ET_CORE`
+const ET_CORE: u16 = 4;
+
+/// `EM_X86_64`
+const EM_X86_64: u16 = 62;
+
+/// Program header types
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// Program header flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// `NT_PRSTATUS`
+const NT_PRSTATUS: u32 = 1;
+
+/// Size of `struct elf_prstatus` on x86-64
+const PRSTATUS_SIZE: usize = 336;
+
+/// Offset of `pr_reg` in `struct elf_prstatus`
+const PRSTATUS_PR_REG: usize = 112;
+
+/// Offset of `pr_pid` in `struct elf_prstatus`
+const PRSTATUS_PR_PID: usize = 32;
+
+/// Which memory layout to write
+#[derive(Clone, Copy, Debug)]
+pub enum CoreLayout {
+    /// Physical memory regions at their physical addresses
+    Physical,
+
+    /// Everything mapped by the page table at this CR3
+    Virtual(usize),
+}
+
+/// A single `PT_LOAD` segment
+struct Segment {
+    /// Address the segment is loaded at
+    vaddr: u64,
+
+    /// Physical address of the start of the segment
+    paddr: u64,
+
+    /// Segment size in bytes
+    size: u64,
+
+    /// `PF_*` flags
+    flags: u32,
+
+    /// Physical memory making up this segment, in order, as (paddr, size)
+    chunks: Vec<(u64, u64)>,
+}
+
+/// Everything about the guest needed for a core, other than memory
+pub struct ElfCore<'a> {
+    /// State of every processor, each becomes a thread in the core
+    pub cpus: &'a [CpuState],
+
+    /// Memory layout to use
+    pub layout: CoreLayout,
+}
+
+/// Get a segment for every physical memory region
+fn physical_segments(memory: &MemReader) -> Vec<Segment> {
+    let mut ret: Vec<Segment> = memory.regions().iter().map(|x| {
+        Segment {
+            vaddr:  x.paddr as u64,
+            paddr:  x.paddr as u64,
+            size:   x.size as u64,
+            flags:  PF_R | PF_W | PF_X,
+            chunks: vec![(x.paddr as u64, x.size as u64)],
+        }
+    }).collect();
+    ret.sort_by_key(|x| x.vaddr);
+    ret
+}
+
+/// Get a segment for every virtually contiguous range with the same
+/// permissions in the page table at `cr3`. `la57` is set if the table is
+/// 5-level, which isn't supported
+fn virtual_segments(memory: &mut MemReader, cr3: usize, la57: bool)
+        -> io::Result<Vec<Segment>> {
+    let mut ret: Vec<Segment> = Vec::new();
+
+    let mut table = unsafe {
+        virtmem::PageTable::from_existing(cr3 as *mut u64, memory)
+    };
+    table.for_each_mapping(la57, |vaddr, paddr, size, writable, exec| {
+        let flags = PF_R |
+            if writable { PF_W } else { 0 } |
+            if exec     { PF_X } else { 0 };
+
+        // Extend the last segment if this directly follows it
+        if let Some(last) = ret.last_mut() {
+            if last.vaddr.wrapping_add(last.size) == vaddr &&
+                    last.flags == flags {
+                last.size += size;
+                match last.chunks.last_mut() {
+                    Some(chunk) if chunk.0 + chunk.1 == paddr => {
+                        chunk.1 += size;
+                    }
+                    _ => last.chunks.push((paddr, size)),
+                }
+                return;
+            }
+        }
+
+        ret.push(Segment {
+            vaddr,
+            paddr,
+            size,
+            flags,
+            chunks: vec![(paddr, size)],
+        });
+    }).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+
+    Ok(ret)
+}
+
+/// Build a `NT_PRSTATUS` note for processor number `idx`
+fn prstatus_note(idx: usize, cpu: &CpuState) -> Vec<u8> {
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+
+    // Processors become threads, give them a non-zero unique ID
+    desc[PRSTATUS_PR_PID..PRSTATUS_PR_PID + 4]
+        .copy_from_slice(&(idx as u32 + 1).to_le_bytes());
+
+    // `struct user_regs_struct`
+    let regs = [
+        cpu.r15, cpu.r14, cpu.r13, cpu.r12, cpu.rbp, cpu.rbx, cpu.r11,
+        cpu.r10, cpu.r9,  cpu.r8,  cpu.rax, cpu.rcx, cpu.rdx, cpu.rsi,
+        cpu.rdi, !0, cpu.rip, cpu.cs.selector as u64, cpu.rflags, cpu.rsp,
+        cpu.ss.selector as u64, cpu.fs.base, cpu.gs.base,
+        cpu.ds.selector as u64, cpu.es.selector as u64,
+        cpu.fs.selector as u64, cpu.gs.selector as u64,
+    ];
+    for (ii, &reg) in regs.iter().enumerate() {
+        let offset = PRSTATUS_PR_REG + ii * 8;
+        desc[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    let mut note = Vec::new();
+    note.extend_from_slice(&5u32.to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(&desc);
+    note
+}
+
+/// An ELF64 program header
+struct ProgramHeader {
+    p_type: u32,
+    flags:  u32,
+    offset: u64,
+    vaddr:  u64,
+    paddr:  u64,
+    size:   u64,
+    align:  u64,
+}
+
+impl ProgramHeader {
+    /// Write the program header. File and memory sizes are always the same
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.p_type.to_le_bytes())?;
+        w.write_all(&self.flags.to_le_bytes())?;
+        w.write_all(&self.offset.to_le_bytes())?;
+        w.write_all(&self.vaddr.to_le_bytes())?;
+        w.write_all(&self.paddr.to_le_bytes())?;
+        w.write_all(&self.size.to_le_bytes())?;
+        w.write_all(&self.size.to_le_bytes())?;
+        w.write_all(&self.align.to_le_bytes())
+    }
+}
+
+impl<'a> ElfCore<'a> {
+    /// Write the core to the file at `path`
+    pub fn save<P: AsRef<Path>>(&self, memory: &mut MemReader,
+                                path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.serialize(memory, &mut writer)?;
+        writer.flush()
+    }
+
+    /// Serialize the core of `memory` into `w`
+    pub fn serialize<W: Write>(&self, memory: &mut MemReader,
+                               w: &mut W) -> io::Result<()> {
+        let segments = match self.layout {
+            CoreLayout::Physical     => physical_segments(memory),
+            CoreLayout::Virtual(cr3) => virtual_segments(memory, cr3,
+                self.cpus.iter().any(|x| x.la57()))?,
+        };
+
+        let notes: Vec<u8> = self.cpus.iter().enumerate()
+            .flat_map(|(idx, cpu)| prstatus_note(idx, cpu)).collect();
+
+        // Headers, then notes, then page aligned segment data
+        let phnum      = segments.len() as u64 + 1;
+        let notes_off  = EHDR_SIZE + phnum * PHDR_SIZE;
+        let data_off   = (notes_off + notes.len() as u64 + PAGE_SIZE - 1) &
+            !(PAGE_SIZE - 1);
+
+        if phnum > 0xffff {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "Too many segments for an ELF core"));
+        }
+
+        // ELF header
+        w.write_all(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0")?;
+        w.write_all(&ET_CORE.to_le_bytes())?;
+        w.write_all(&EM_X86_64.to_le_bytes())?;
+        w.write_all(&1u32.to_le_bytes())?;
+        w.write_all(&0u64.to_le_bytes())?;
+        w.write_all(&EHDR_SIZE.to_le_bytes())?;
+        w.write_all(&0u64.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&(EHDR_SIZE as u16).to_le_bytes())?;
+        w.write_all(&(PHDR_SIZE as u16).to_le_bytes())?;
+        w.write_all(&(phnum as u16).to_le_bytes())?;
+        w.write_all(&64u16.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+
+        // Program headers
+        ProgramHeader {
+            p_type: PT_NOTE,
+            flags:  0,
+            offset: notes_off,
+            vaddr:  0,
+            paddr:  0,
+            size:   notes.len() as u64,
+            align:  4,
+        }.write(w)?;
+        let mut offset = data_off;
+        for segment in &segments {
+            ProgramHeader {
+                p_type: PT_LOAD,
+                flags:  segment.flags,
+                offset,
+                vaddr:  segment.vaddr,
+                paddr:  segment.paddr,
+                size:   segment.size,
+                align:  PAGE_SIZE,
+            }.write(w)?;
+            offset += segment.size;
+        }
+
+        // Notes
+        w.write_all(&notes)?;
+        let padding = data_off - notes_off - notes.len() as u64;
+        w.write_all(&vec![0u8; padding as usize])?;
+
+        // Segment data. Anything we can't read is written as zeros so the
+        // file layout stays intact
+        let mut page = [0u8; PAGE_SIZE as usize];
+        for segment in &segments {
+            for &(paddr, size) in &segment.chunks {
+                for off in (0..size).step_by(PAGE_SIZE as usize) {
+                    let len = std::cmp::min(PAGE_SIZE, size - off) as usize;
+                    let bread = memory.read_phys((paddr + off) as usize,
+                        &mut page[..len]);
+                    for byte in &mut page[bread..len] { *byte = 0; }
+                    w.write_all(&page[..len])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_serialize_core() {
+    use crate::memreader::MemoryRegion;
+
+    // PML4 at 0x1000 down to the PT at 0x4000, which maps 0x5000 and 0x6000
+    // writable at 0 and 0x5000 again read-only at 0x3000
+    let mut memory = vec![0u8; 7 * 4096];
+    for &(entry, val) in &[(0x1000, 0x2003u64), (0x2000, 0x3003),
+            (0x3000, 0x4003), (0x4000, 0x5003), (0x4008, 0x6003),
+            (0x4018, 0x5001)] {
+        memory[entry..entry + 8].copy_from_slice(&val.to_le_bytes());
+    }
+    memory[0x5000..0x5005].copy_from_slice(b"hello");
+    memory[0x6000..0x6005].copy_from_slice(b"world");
+
+    let mut reader = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: memory.as_ptr() as usize,
+        perms:   0,
+        size:    memory.len(),
+    }]);
+
+    let mut cpus = [CpuState::default()];
+    cpus[0].rip = 0x1234;
+
+    let mut out = Vec::new();
+    ElfCore { cpus: &cpus, layout: CoreLayout::Virtual(0x1000) }
+        .serialize(&mut reader, &mut out).unwrap();
+
+    let u16_at = |off: usize| u16::from_le_bytes([out[off], out[off + 1]]);
+    let u32_at = |off: usize| {
+        let mut raw = [0u8; 4];
+        raw.copy_from_slice(&out[off..off + 4]);
+        u32::from_le_bytes(raw)
+    };
+    let u64_at = |off: usize| {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&out[off..off + 8]);
+        u64::from_le_bytes(raw) as usize
+    };
+
+    assert_eq!(&out[..4], b"\x7fELF");
+    assert_eq!((u16_at(16), u16_at(18), u16_at(56)), (ET_CORE, EM_X86_64, 3));
+
+    // The note comes first, then a segment per virtual range
+    let phdr = |idx: usize| {
+        let off = (EHDR_SIZE + idx as u64 * PHDR_SIZE) as usize;
+        (u32_at(off), u32_at(off + 4), u64_at(off + 8), u64_at(off + 16),
+            u64_at(off + 24), u64_at(off + 32))
+    };
+    let (p_type, _, notes, ..) = phdr(0);
+    assert_eq!(p_type, PT_NOTE);
+    assert_eq!(u64_at(notes + 20 + PRSTATUS_PR_REG + 16 * 8), 0x1234);
+
+    let (p_type, flags, data, vaddr, paddr, size) = phdr(1);
+    assert_eq!((p_type, flags, vaddr, paddr, size),
+        (PT_LOAD, PF_R | PF_W | PF_X, 0, 0x5000, 0x2000));
+    assert_eq!(&out[data..data + 5], b"hello");
+    assert_eq!(&out[data + 0x1000..data + 0x1005], b"world");
+
+    let (p_type, flags, data, vaddr, paddr, size) = phdr(2);
+    assert_eq!((p_type, flags, vaddr, paddr, size),
+        (PT_LOAD, PF_R | PF_X, 0x3000, 0x5000, 0x1000));
+    assert_eq!(&out[data..data + 5], b"hello");
+    assert_eq!(out.len(), data + 0x1000);
+
+    // The physical layout is a segment for the one region
+    let mut out = Vec::new();
+    ElfCore { cpus: &cpus, layout: CoreLayout::Physical }
+        .serialize(&mut reader, &mut out).unwrap();
+    assert_eq!(u16::from_le_bytes([out[56], out[57]]), 2);
+
+    // 5-level tables aren't supported
+    cpus[0].cr4 |= 1 << 12;
+    assert!(ElfCore { cpus: &cpus, layout: CoreLayout::Virtual(0x1000) }
+        .serialize(&mut reader, &mut Vec::new()).is_err());
+}
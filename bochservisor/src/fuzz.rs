@@ -0,0 +1,824 @@
+/// Snapshot fuzzing configuration and per-case state
+///
+/// Fuzzing is enabled by booting from a snapshot with `APPLEPIE_FUZZ_END` set.
+/// Every case starts from the snapshot, runs until one of the configured end
+/// conditions is met, and then the guest is restored for the next case.
+///
+/// Environment variables:
+///
+/// * `APPLEPIE_FUZZ_END` - Comma separated list of end conditions, the first
+///   one met ends the case. `rip:<addr>` ends when any processor executes
+///   `addr`, `hypercall` ends when the guest reports a result or crash,
+///   `instructions:<n>` ends after `n` Bochs instructions worth of guest time
+///   and `time:<seconds>` ends after that much wall clock time
+/// * `APPLEPIE_FUZZ_INPUT` - `<vaddr>:<max size>`, where to write each input
+///   in the page table CPU 0 is using in the snapshot. Inputs are truncated
+///   to `max size`. Without this the guest can only get the input with the
+///   `HC_GET_INPUT` hypercall
+/// * `APPLEPIE_FUZZ_SEED` - File holding an initial input to add to the corpus
+/// * `APPLEPIE_FUZZ_CORPUS` - Corpus directory, `corpus` if not set. Inputs
+///   which find new coverage are saved here, and everything in it is run once
+///   at startup before mutating anything
+/// * `APPLEPIE_FUZZ_RNG_SEED` - Seed for the mutator, otherwise one is picked
+///   from the TSC. Runs with the same seed and inputs mutate the same way
+/// * `APPLEPIE_FUZZ_EXCEPTIONS` - Comma separated list of exceptions which are
+///   crashes, as names (`pf`, `gp`, `ud`, `bp`, ...) or vector numbers. These
+///   are trapped by the hypervisor, so every one of them ends the case even if
+///   the guest would have handled it
+/// * `APPLEPIE_FUZZ_CRASHES` - Crashes directory, `crashes` if not set. The
+///   first input of every crash bucket is saved here
+/// * `APPLEPIE_FUZZ_TIMEOUT` - Comma separated list of per-case budgets. A
+///   case which runs over any of them before meeting an end condition is a
+///   hang. `time:<seconds>` is wall clock time, `vm_cycles:<n>` is TSC cycles
+///   spent in the hypervisor by all processors and `emulated:<n>` is
+///   instructions (or chains) emulated in Bochs. `time:10` if not set
+/// * `APPLEPIE_FUZZ_HANGS` - Hangs directory, `hangs` if not set. The first
+///   input to hang at every final RIP is saved here
+/// * `APPLEPIE_FUZZ_MINIMIZE` - Path of a crashing input to minimize instead
+///   of fuzzing. Candidates which crash in the same bucket as the input are
+///   kept, the smallest one is saved as `<path>.min` and the steps which got
+///   there as `<path>.min.log`
+/// * `APPLEPIE_FUZZ_COVERAGE` - What counts as new coverage, `offsets` if not
+///   set. `offsets` is new module offsets, `edges` also tracks AFL style edges
+///   between offsets with bucketed hit counts, so new paths through known
+///   code and known edges taken a new number of times are interesting too
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::ffi::OsString;
+use crate::time;
+use crate::mutator::{Mutator, Rng};
+use crate::corpus::Corpus;
+use crate::crash::{self, Crash, Crashes};
+use crate::minimize::Minimizer;
+use crate::edges::EdgeMap;
+
+/// Environment variable holding the end conditions
+const FUZZ_END_ENV_VAR: &str = "APPLEPIE_FUZZ_END";
+
+/// Environment variable holding where to write inputs
+const FUZZ_INPUT_ENV_VAR: &str = "APPLEPIE_FUZZ_INPUT";
+
+/// Environment variable holding the path of the initial input
+const FUZZ_SEED_ENV_VAR: &str = "APPLEPIE_FUZZ_SEED";
+
+/// Environment variable holding the corpus directory
+const FUZZ_CORPUS_ENV_VAR: &str = "APPLEPIE_FUZZ_CORPUS";
+
+/// Corpus directory if none was given
+const DEFAULT_CORPUS_DIR: &str = "corpus";
+
+/// Environment variable holding the mutator seed
+const FUZZ_RNG_SEED_ENV_VAR: &str = "APPLEPIE_FUZZ_RNG_SEED";
+
+/// Environment variable holding the exceptions which are crashes
+const FUZZ_EXCEPTIONS_ENV_VAR: &str = "APPLEPIE_FUZZ_EXCEPTIONS";
+
+/// Environment variable holding the crashes directory
+const FUZZ_CRASHES_ENV_VAR: &str = "APPLEPIE_FUZZ_CRASHES";
+
+/// Crashes directory if none was given
+const DEFAULT_CRASHES_DIR: &str = "crashes";
+
+/// Environment variable holding the per-case budgets
+const FUZZ_TIMEOUT_ENV_VAR: &str = "APPLEPIE_FUZZ_TIMEOUT";
+
+/// Wall clock budget of a case in seconds if no budgets were given
+const DEFAULT_TIMEOUT: f64 = 10.0;
+
+/// Environment variable holding the hangs directory
+const FUZZ_HANGS_ENV_VAR: &str = "APPLEPIE_FUZZ_HANGS";
+
+/// Hangs directory if none was given
+const DEFAULT_HANGS_DIR: &str = "hangs";
+
+/// Environment variable holding the path of the input to minimize
+const FUZZ_MINIMIZE_ENV_VAR: &str = "APPLEPIE_FUZZ_MINIMIZE";
+
+/// Environment variable holding the coverage mode
+const FUZZ_COVERAGE_ENV_VAR: &str = "APPLEPIE_FUZZ_COVERAGE";
+
+/// Largest input we generate if there's no input buffer limiting the size
+const DEFAULT_MAX_INPUT_SIZE: usize = 64 * 1024;
+
+/// Condition which ends a fuzz case
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseEnd {
+    /// A processor executed this RIP. This uses DR0 on every processor
+    Rip(u64),
+
+    /// The guest reported a result or a crash with a hypercall
+    Hypercall,
+
+    /// This many Bochs instructions of guest time passed. Time in the
+    /// hypervisor is converted to instructions at the Bochs IPS, the same way
+    /// devices are stepped
+    Instructions(u64),
+
+    /// This many seconds of wall clock time passed
+    Time(f64),
+}
+
+/// Per-case budget. Running over one of these means the case hung
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Budget {
+    /// Seconds of wall clock time
+    Time(f64),
+
+    /// TSC cycles spent in the hypervisor, summed over all processors
+    VmCycles(u64),
+
+    /// Instructions (or chains) emulated in Bochs, summed over all processors
+    Emulated(u64),
+}
+
+/// What counts as new coverage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoverageMode {
+    /// Module offsets never seen before
+    Offsets,
+
+    /// New offsets, plus edges never taken before or taken a new number of
+    /// times
+    Edges,
+}
+
+/// Fuzzing configuration
+#[derive(Clone, Debug)]
+pub struct FuzzConfig {
+    /// Conditions which end a case
+    pub ends: Vec<CaseEnd>,
+
+    /// Virtual address and maximum size of the input buffer in the guest
+    pub input: Option<(usize, usize)>,
+
+    /// Initial input
+    pub seed: Vec<u8>,
+
+    /// Corpus directory
+    pub corpus_dir: PathBuf,
+
+    /// Seed for the random number generator picking mutations
+    pub rng_seed: u64,
+
+    /// Bitmap of exception vectors which are crashes
+    pub exceptions: u64,
+
+    /// Crashes directory
+    pub crashes_dir: PathBuf,
+
+    /// Budgets of every case
+    pub budgets: Vec<Budget>,
+
+    /// Hangs directory
+    pub hangs_dir: PathBuf,
+
+    /// Path of the crashing input to minimize, if we're minimizing
+    pub minimize: Option<PathBuf>,
+
+    /// What counts as new coverage
+    pub coverage: CoverageMode,
+}
+
+/// Parse a number as hex if it's `0x` prefixed, otherwise as decimal
+pub fn parse_num(value: &str) -> Result<u64, String> {
+    let ret = if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+    ret.map_err(|_| format!("Invalid number `{}`", value))
+}
+
+/// Parse a single case end condition
+fn parse_end(end: &str) -> Result<CaseEnd, String> {
+    let mut split = end.trim().splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some("rip"), Some(rip)) => Ok(CaseEnd::Rip(parse_num(rip)?)),
+        (Some("hypercall"), None) => Ok(CaseEnd::Hypercall),
+        (Some("instructions"), Some(num)) =>
+            Ok(CaseEnd::Instructions(parse_num(num)?)),
+        (Some("time"), Some(secs)) => secs.parse().map(CaseEnd::Time)
+            .map_err(|_| format!("Invalid time `{}`", secs)),
+        _ => Err(format!("Unknown case end condition `{}`", end)),
+    }
+}
+
+/// Parse a single per-case budget
+fn parse_budget(budget: &str) -> Result<Budget, String> {
+    let mut split = budget.trim().splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some("time"), Some(secs)) => secs.parse().map(Budget::Time)
+            .map_err(|_| format!("Invalid time `{}`", secs)),
+        (Some("vm_cycles"), Some(num)) =>
+            Ok(Budget::VmCycles(parse_num(num)?)),
+        (Some("emulated"), Some(num)) =>
+            Ok(Budget::Emulated(parse_num(num)?)),
+        _ => Err(format!("Unknown budget `{}`", budget)),
+    }
+}
+
+impl FuzzConfig {
+    /// Get the fuzzing configuration from the environment. Returns `Ok(None)`
+    /// if fuzzing wasn't requested
+    pub fn from_env() -> Result<Option<Self>, String> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Get the fuzzing configuration from the variables `var` looks up by
+    /// name. Returns `Ok(None)` if fuzzing wasn't requested
+    fn from_vars<F>(var: F) -> Result<Option<Self>, String>
+            where F: Fn(&str) -> Option<String> {
+        let ends = match var(FUZZ_END_ENV_VAR) {
+            Some(ends) => ends,
+            None       => return Ok(None),
+        };
+        let ends = ends.split(',').map(parse_end)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Only one RIP can be watched as it takes a debug register
+        if ends.iter().filter(|x| match x {
+                CaseEnd::Rip(_) => true, _ => false }).count() > 1 {
+            return Err("Only one `rip` end condition is supported".into());
+        }
+
+        let input = match var(FUZZ_INPUT_ENV_VAR) {
+            Some(input) => {
+                let mut split = input.splitn(2, ':');
+                match (split.next(), split.next()) {
+                    (Some(vaddr), Some(size)) => Some((
+                        parse_num(vaddr)? as usize,
+                        parse_num(size)? as usize)),
+                    _ => return Err(format!(
+                        "{} must be `<vaddr>:<max size>`", FUZZ_INPUT_ENV_VAR)),
+                }
+            }
+            None => None,
+        };
+
+        let seed = match var(FUZZ_SEED_ENV_VAR) {
+            Some(path) => std::fs::read(&path)
+                .map_err(|x| format!("Failed to read seed {}: {}", path, x))?,
+            None => Vec::new(),
+        };
+
+        let corpus_dir = var(FUZZ_CORPUS_ENV_VAR)
+            .unwrap_or_else(|| DEFAULT_CORPUS_DIR.into()).into();
+
+        let rng_seed = match var(FUZZ_RNG_SEED_ENV_VAR) {
+            Some(rng_seed) => parse_num(&rng_seed)?,
+            None => time::rdtsc(),
+        };
+
+        let exceptions = match var(FUZZ_EXCEPTIONS_ENV_VAR) {
+            Some(exceptions) => crash::parse_exceptions(&exceptions)?,
+            None => 0,
+        };
+
+        let crashes_dir = var(FUZZ_CRASHES_ENV_VAR)
+            .unwrap_or_else(|| DEFAULT_CRASHES_DIR.into()).into();
+
+        let budgets = match var(FUZZ_TIMEOUT_ENV_VAR) {
+            Some(budgets) => budgets.split(',').map(parse_budget)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![Budget::Time(DEFAULT_TIMEOUT)],
+        };
+
+        let hangs_dir = var(FUZZ_HANGS_ENV_VAR)
+            .unwrap_or_else(|| DEFAULT_HANGS_DIR.into()).into();
+
+        let minimize = var(FUZZ_MINIMIZE_ENV_VAR).map(PathBuf::from);
+
+        let coverage = match var(FUZZ_COVERAGE_ENV_VAR).as_ref()
+                .map(|x| x.as_str()) {
+            Some("offsets") | None => CoverageMode::Offsets,
+            Some("edges") => CoverageMode::Edges,
+            Some(mode) => return Err(format!(
+                "Unknown coverage mode `{}`", mode)),
+        };
+
+        Ok(Some(FuzzConfig {
+            ends, input, seed, corpus_dir, rng_seed, exceptions, crashes_dir,
+            budgets, hangs_dir, minimize, coverage,
+        }))
+    }
+
+    /// Get the RIP which ends a case, if any
+    pub fn end_rip(&self) -> Option<u64> {
+        self.ends.iter().filter_map(|x| match x {
+            CaseEnd::Rip(rip) => Some(*rip),
+            _ => None,
+        }).next()
+    }
+}
+
+/// Fuzzer state which lives across fuzz cases
+pub struct Fuzzer {
+    /// Configuration
+    pub config: FuzzConfig,
+
+    /// Random number generator which hands out a mutator seed for every case
+    rng: Rng,
+
+    /// Inputs which find new coverage
+    pub corpus: Corpus,
+
+    /// Crashes found so far
+    pub crashes: Crashes,
+
+    /// Hangs found so far, bucketed by their final RIP
+    pub hangs: Crashes,
+
+    /// Address of `nt!KeBugCheckEx`, which gets a breakpoint in DR1
+    pub bugcheck_bp: Option<u64>,
+
+    /// Address of `ntdll!KiUserExceptionDispatcher`, which gets a breakpoint
+    /// in DR2
+    pub user_exception_bp: Option<u64>,
+
+    /// Minimizer, if we're minimizing a crash rather than fuzzing
+    minimizer: Option<Minimizer>,
+
+    /// Edge hit counts, if we're in edge coverage mode
+    pub edges: Option<EdgeMap>,
+
+    /// Corpus entries which still have to be run as-is before we start
+    /// mutating
+    replay: VecDeque<usize>,
+
+    /// Corpus entry the current input was mutated from, `None` if the current
+    /// input is a corpus entry being replayed
+    parent: Option<usize>,
+
+    /// Corpus entry the mutator could splice from in the current case, `None`
+    /// if the current input is a corpus entry being replayed
+    splice: Option<usize>,
+
+    /// Mutator seed of the current case. The input of this case can be
+    /// recreated by mutating the parent input with this seed (and splicing
+    /// from the same corpus entry)
+    pub case_seed: u64,
+
+    /// TSC value when fuzzing started
+    pub start: u64,
+
+    /// TSC value when the current case started
+    pub case_start: u64,
+
+    /// Bochs instructions of guest time in the current case
+    pub case_instructions: u64,
+
+    /// TSC cycles spent in the hypervisor in the current case
+    pub case_vm_cycles: u64,
+
+    /// Instructions (or chains) emulated in Bochs in the current case
+    pub case_emulated: u64,
+
+    /// Set once a processor executed the end RIP
+    pub end_rip_hit: bool,
+
+    /// First crash in the current case. A crash always ends the case
+    pub crash: Option<Crash>,
+
+    /// Budget the current case ran over, if it hung
+    pub hang: Option<Budget>,
+}
+
+impl Fuzzer {
+    /// Create a new fuzzer with `config`. This loads the corpus and adds the
+    /// initial input to it
+    pub fn new(config: FuzzConfig) -> Result<Self, String> {
+        let mut corpus = Corpus::load(&config.corpus_dir).map_err(|x|
+            format!("Failed to load corpus {}: {}",
+                config.corpus_dir.display(), x))?;
+
+        let crashes = Crashes::load(&config.crashes_dir).map_err(|x|
+            format!("Failed to load crashes {}: {}",
+                config.crashes_dir.display(), x))?;
+
+        let hangs = Crashes::load(&config.hangs_dir).map_err(|x|
+            format!("Failed to load hangs {}: {}",
+                config.hangs_dir.display(), x))?;
+
+        let minimizer = match &config.minimize {
+            Some(path) => Some(Minimizer::new(std::fs::read(path).map_err(|x|
+                format!("Failed to read {}: {}", path.display(), x))?)),
+            None => None,
+        };
+
+        let edges = match config.coverage {
+            CoverageMode::Offsets => None,
+            CoverageMode::Edges   => Some(EdgeMap::new()),
+        };
+
+        // Make sure there's always something to mutate
+        if !config.seed.is_empty() || corpus.len() == 0 {
+            corpus.add(&config.seed, &[], &[]).map_err(|x|
+                format!("Failed to add seed to corpus: {}", x))?;
+        }
+
+        Ok(Fuzzer {
+            rng:               Rng::new(config.rng_seed),
+            config,
+            replay:            (0..corpus.len()).collect(),
+            corpus,
+            crashes,
+            hangs,
+            bugcheck_bp:       None,
+            user_exception_bp: None,
+            minimizer,
+            edges,
+            parent:            None,
+            splice:            None,
+            case_seed:         0,
+            start:             time::rdtsc(),
+            case_start:        0,
+            case_instructions: 0,
+            case_vm_cycles:    0,
+            case_emulated:     0,
+            end_rip_hit:       false,
+            crash:             None,
+            hang:              None,
+        })
+    }
+
+    /// Get the input for the next case. Every corpus entry is run as-is
+    /// first, after that every case gets a mutation of a corpus entry
+    pub fn next_input(&mut self) -> Vec<u8> {
+        self.parent    = None;
+        self.splice    = None;
+        self.case_seed = 0;
+
+        if let Some(minimizer) = &self.minimizer {
+            return minimizer.candidate();
+        }
+
+        if let Some(idx) = self.replay.pop_front() {
+            return self.corpus.get(idx).to_vec();
+        }
+
+        let max_size = self.config.input.map(|x| x.1)
+            .unwrap_or(DEFAULT_MAX_INPUT_SIZE);
+
+        let parent = self.corpus.pick(&mut self.rng);
+        let splice = self.rng.index(self.corpus.len());
+        self.parent    = Some(parent);
+        self.splice    = Some(splice);
+        self.case_seed = self.rng.rand();
+
+        Mutator::new(self.case_seed, max_size).mutate(
+            self.corpus.get(parent), Some(self.corpus.get(splice)))
+    }
+
+    /// Record that the current case with `input` found new `coverage` as
+    /// (module, offset) and new `edges` as (edge, bucket). The input is saved
+    /// to the corpus and its parent gets credit for it
+    pub fn found_coverage(&mut self, input: &[u8],
+            coverage: &[(String, usize)], edges: &[(usize, u8)]) {
+        match self.corpus.add(input, coverage, edges) {
+            Ok(true) => {
+                if let Some(parent) = self.parent {
+                    self.corpus.credit(parent,
+                        (coverage.len() + edges.len()) as u64);
+                }
+                print!("New corpus entry {:016x} with {} new offsets and {} \
+                        new edge buckets, {} entries\n",
+                    crate::corpus::hash(input), coverage.len(), edges.len(),
+                    self.corpus.len());
+            }
+            Ok(false) => {}
+            Err(err) => print!("Failed to save corpus entry: {}\n", err),
+        }
+    }
+
+    /// Describe where `input`, the input of the current case, came from. For
+    /// mutations this is everything needed to make the input again
+    fn origin(&self, input: &[u8]) -> String {
+        let hash = |idx| crate::corpus::hash(self.corpus.get(idx));
+        match (self.parent, self.splice) {
+            (Some(parent), Some(splice)) => format!("Mutated from {:016x} \
+                with seed {:#x}, splicing from {:016x}", hash(parent),
+                self.case_seed, hash(splice)),
+            _ if self.minimizing() => "Minimization candidate".into(),
+            _ => format!("Corpus entry {:016x} as-is",
+                crate::corpus::hash(input)),
+        }
+    }
+
+    /// Record the crash of the current case with `input`, if there was one.
+    /// The input is saved if it's the first one in its bucket
+    pub fn found_crash(&mut self, input: &[u8]) {
+        let crash = match self.crash.as_ref() {
+            Some(crash) => crash,
+            None => return,
+        };
+
+        let origin = self.origin(input);
+        match self.crashes.add(crash, input, &origin) {
+            Ok(true) => print!("New crash at {:#x}, {} unique: {}\n",
+                crash.addr, self.crashes.unique(), crash.bucket()),
+            Ok(false) => {}
+            Err(err) => print!("Failed to save crash: {}\n", err),
+        }
+    }
+
+    /// Record that the current case with `input` hung, `hang` holds the
+    /// final RIP. The input is saved if it's the first one to hang there
+    pub fn found_hang(&mut self, input: &[u8], hang: &Crash) {
+        let origin = self.origin(input);
+        match self.hangs.add(hang, input, &origin) {
+            Ok(true) => print!("New hang over {:?}, {} unique: {}\n",
+                self.hang.unwrap(), self.hangs.unique(), hang.bucket()),
+            Ok(false) => {}
+            Err(err) => print!("Failed to save hang: {}\n", err),
+        }
+    }
+
+    /// Returns `true` if we're minimizing a crash rather than fuzzing
+    pub fn minimizing(&self) -> bool {
+        self.minimizer.is_some()
+    }
+
+    /// Hand the crash bucket of the current case to the minimizer. Returns
+    /// `true` once minimization is over, the smallest reproducer and the log
+    /// are saved by then
+    pub fn minimize_case(&mut self) -> bool {
+        let bucket = self.crash.as_ref().map(|x| x.bucket());
+        let minimizer = self.minimizer.as_mut().unwrap();
+        minimizer.report(bucket.as_ref().map(|x| x.as_str()));
+        if !minimizer.done() { return false; }
+
+        let path = self.config.minimize.as_ref().unwrap();
+        let mut output = OsString::from(path);
+        output.push(".min");
+        let mut log = output.clone();
+        log.push(".log");
+
+        let mut steps = minimizer.log().join("\n");
+        steps.push('\n');
+        if let Err(err) = std::fs::write(&output, minimizer.best())
+                .and_then(|_| std::fs::write(&log, steps)) {
+            print!("Failed to save minimized input: {}\n", err);
+        }
+
+        print!("Minimized {} to {} bytes in {}\n", path.display(),
+            minimizer.best().len(), PathBuf::from(output).display());
+        true
+    }
+
+    /// Reset the per-case state for a new case
+    pub fn begin_case(&mut self) {
+        self.case_start        = time::rdtsc();
+        self.case_instructions = 0;
+        self.case_vm_cycles    = 0;
+        self.case_emulated     = 0;
+        self.end_rip_hit       = false;
+        self.crash             = None;
+        self.hang              = None;
+
+        if let Some(edges) = self.edges.as_mut() {
+            edges.begin_case();
+        }
+    }
+
+    /// Check if the current case is over. `tickrate` is the TSC rate and
+    /// `guest_reported` is set if the guest reported a result or crash.
+    ///
+    /// Cases which run over budget before meeting an end condition are over
+    /// as well, the budget is saved in `hang`
+    pub fn case_finished(&mut self, tickrate: f64, guest_reported: bool)
+            -> bool {
+        if self.crash.is_some() || self.end_met(tickrate, guest_reported) {
+            return true;
+        }
+
+        let elapsed = time::rdtsc().saturating_sub(self.case_start);
+        self.hang = self.config.budgets.iter().cloned().find(|budget| {
+            match *budget {
+                Budget::Time(secs) => elapsed as f64 / tickrate >= secs,
+                Budget::VmCycles(max) => self.case_vm_cycles >= max,
+                Budget::Emulated(max) => self.case_emulated >= max,
+            }
+        });
+        self.hang.is_some()
+    }
+
+    /// Check if any of the end conditions of the current case was met
+    fn end_met(&self, tickrate: f64, guest_reported: bool) -> bool {
+        self.config.ends.iter().any(|end| match *end {
+            CaseEnd::Rip(_) => self.end_rip_hit,
+            CaseEnd::Hypercall => guest_reported,
+            CaseEnd::Instructions(budget) => self.case_instructions >= budget,
+            CaseEnd::Time(secs) => {
+                let elapsed = time::rdtsc().saturating_sub(self.case_start);
+                elapsed as f64 / tickrate >= secs
+            }
+        })
+    }
+}
+
+/// Create a fuzzer with `config` whose directories are all in a fresh
+/// temporary directory named after `name`, holding `corpus` as its corpus
+#[cfg(test)]
+pub fn test_fuzzer(name: &str, mut config: FuzzConfig, corpus: &[&[u8]])
+        -> (Fuzzer, PathBuf) {
+    let dir = std::env::temp_dir().join(
+        format!("applepie_fuzz_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("corpus")).unwrap();
+    for input in corpus {
+        std::fs::write(dir.join("corpus")
+            .join(format!("{:016x}", crate::corpus::hash(input))), input)
+            .unwrap();
+    }
+
+    config.corpus_dir  = dir.join("corpus");
+    config.crashes_dir = dir.join("crashes");
+    config.hangs_dir   = dir.join("hangs");
+    (Fuzzer::new(config).unwrap(), dir)
+}
+
+/// Get a configuration from the variables in `vars`
+#[cfg(test)]
+pub fn test_config(vars: &[(&str, &str)]) -> Result<Option<FuzzConfig>, String> {
+    FuzzConfig::from_vars(|name| vars.iter().find(|x| x.0 == name)
+        .map(|x| x.1.to_string()))
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse_num("0x1f"), Ok(0x1f));
+    assert_eq!(parse_num("31"), Ok(31));
+    assert!(parse_num("1f").is_err());
+    assert!(parse_num("0x").is_err());
+
+    assert_eq!(parse_end(" rip:0xfffff80000001000"),
+        Ok(CaseEnd::Rip(0xfffff80000001000)));
+    assert_eq!(parse_end("hypercall"), Ok(CaseEnd::Hypercall));
+    assert_eq!(parse_end("instructions:1000"),
+        Ok(CaseEnd::Instructions(1000)));
+    assert_eq!(parse_end("time:0.5"), Ok(CaseEnd::Time(0.5)));
+    assert!(parse_end("hypercall:1").is_err());
+    assert!(parse_end("rip").is_err());
+    assert!(parse_end("time:soon").is_err());
+
+    assert_eq!(parse_budget("time:10"), Ok(Budget::Time(10.)));
+    assert_eq!(parse_budget("vm_cycles:0x100"), Ok(Budget::VmCycles(0x100)));
+    assert_eq!(parse_budget("emulated:5"), Ok(Budget::Emulated(5)));
+    assert!(parse_budget("instructions:5").is_err());
+}
+
+#[test]
+fn test_config_from_vars() {
+    assert!(test_config(&[]).unwrap().is_none());
+
+    let config = test_config(&[
+        (FUZZ_END_ENV_VAR, "rip:0x1000,hypercall"),
+        (FUZZ_INPUT_ENV_VAR, "0x20000:64"),
+        (FUZZ_RNG_SEED_ENV_VAR, "0x1234"),
+        (FUZZ_EXCEPTIONS_ENV_VAR, "pf,gp"),
+        (FUZZ_TIMEOUT_ENV_VAR, "time:5,emulated:100"),
+        (FUZZ_COVERAGE_ENV_VAR, "edges"),
+    ]).unwrap().unwrap();
+    assert_eq!(config.ends, [CaseEnd::Rip(0x1000), CaseEnd::Hypercall]);
+    assert_eq!(config.end_rip(), Some(0x1000));
+    assert_eq!(config.input, Some((0x20000, 64)));
+    assert_eq!(config.rng_seed, 0x1234);
+    assert_eq!(config.exceptions, (1 << 14) | (1 << 13));
+    assert_eq!(config.budgets, [Budget::Time(5.), Budget::Emulated(100)]);
+    assert_eq!(config.coverage, CoverageMode::Edges);
+    assert_eq!(config.corpus_dir, PathBuf::from(DEFAULT_CORPUS_DIR));
+    assert!(config.seed.is_empty() && config.minimize.is_none());
+
+    // Defaults
+    let config = test_config(&[(FUZZ_END_ENV_VAR, "hypercall")])
+        .unwrap().unwrap();
+    assert_eq!(config.end_rip(), None);
+    assert_eq!(config.input, None);
+    assert_eq!(config.budgets, [Budget::Time(DEFAULT_TIMEOUT)]);
+    assert_eq!(config.coverage, CoverageMode::Offsets);
+
+    for vars in &[
+        [(FUZZ_END_ENV_VAR, "rip:0x1000,rip:0x2000"), ("", "")],
+        [(FUZZ_END_ENV_VAR, "hypercall"), (FUZZ_INPUT_ENV_VAR, "0x1000")],
+        [(FUZZ_END_ENV_VAR, "hypercall"), (FUZZ_COVERAGE_ENV_VAR, "paths")],
+        [(FUZZ_END_ENV_VAR, "hypercall"), (FUZZ_TIMEOUT_ENV_VAR, "forever")],
+    ] {
+        assert!(test_config(vars).is_err());
+    }
+}
+
+#[test]
+fn test_case_finished() {
+    let mut config = test_config(&[
+        (FUZZ_END_ENV_VAR, "rip:0x1000,instructions:100"),
+        (FUZZ_TIMEOUT_ENV_VAR, "vm_cycles:50,emulated:20,time:1"),
+    ]).unwrap().unwrap();
+    let (mut fuzzer, dir) = test_fuzzer("finished", config.clone(), &[]);
+
+    // Far too fast a TSC for any wall clock time to pass
+    let tickrate = 1e30;
+    fuzzer.begin_case();
+    assert!(!fuzzer.case_finished(tickrate, true));
+    assert_eq!(fuzzer.hang, None);
+
+    fuzzer.end_rip_hit = true;
+    assert!(fuzzer.case_finished(tickrate, false));
+    assert_eq!(fuzzer.hang, None);
+
+    fuzzer.begin_case();
+    fuzzer.case_instructions = 100;
+    assert!(fuzzer.case_finished(tickrate, false));
+
+    fuzzer.begin_case();
+    fuzzer.crash = Some(Crash {
+        kind:    crash::CrashKind::Guest,
+        code:    0,
+        addr:    0,
+        frames:  Vec::new(),
+        context: String::new(),
+    });
+    assert!(fuzzer.case_finished(tickrate, false));
+
+    // Running over a budget is a hang, unless the case also ended
+    fuzzer.begin_case();
+    fuzzer.case_emulated = 20;
+    assert!(fuzzer.case_finished(tickrate, false));
+    assert_eq!(fuzzer.hang, Some(Budget::Emulated(20)));
+    fuzzer.begin_case();
+    fuzzer.case_vm_cycles = 50;
+    fuzzer.end_rip_hit = true;
+    assert!(fuzzer.case_finished(tickrate, false));
+    assert_eq!(fuzzer.hang, None);
+
+    // With a TSC this slow any tick is past the time budget
+    fuzzer.begin_case();
+    assert!(fuzzer.case_finished(1e-30, false));
+    assert_eq!(fuzzer.hang, Some(Budget::Time(1.)));
+
+    // Guest reports only end cases with a hypercall end
+    config.ends = vec![CaseEnd::Hypercall, CaseEnd::Time(1.)];
+    fuzzer.config = config;
+    fuzzer.begin_case();
+    assert!(fuzzer.end_met(tickrate, true));
+    assert!(fuzzer.end_met(1e-30, false));
+    assert!(!fuzzer.end_met(tickrate, false));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_next_input() {
+    let config = test_config(&[
+        (FUZZ_END_ENV_VAR, "hypercall"),
+        (FUZZ_INPUT_ENV_VAR, "0x1000:16"),
+        (FUZZ_RNG_SEED_ENV_VAR, "1"),
+    ]).unwrap().unwrap();
+    let corpus: &[&[u8]] = &[b"first entry", b"second entry"];
+    let (mut fuzzer, dir) = test_fuzzer("next_input", config, corpus);
+
+    // An empty corpus gets the empty seed, ours has entries already
+    assert_eq!(fuzzer.corpus.len(), 2);
+
+    // Every entry is run as-is first, in corpus order
+    for idx in 0..2 {
+        let input = fuzzer.next_input();
+        assert_eq!(input, fuzzer.corpus.get(idx));
+        assert_eq!((fuzzer.parent, fuzzer.case_seed), (None, 0));
+        assert_eq!(fuzzer.origin(&input), format!("Corpus entry {:016x} as-is",
+            crate::corpus::hash(&input)));
+    }
+
+    // Then mutations of entries, no bigger than the input buffer, which can
+    // be made again from the parent, seed and splice input
+    for _ in 0..100 {
+        let input = fuzzer.next_input();
+        assert!(input.len() <= 16);
+
+        let (parent, splice) = (fuzzer.parent.unwrap(),
+            fuzzer.splice.unwrap());
+        assert_eq!(Mutator::new(fuzzer.case_seed, 16).mutate(
+            fuzzer.corpus.get(parent), Some(fuzzer.corpus.get(splice))), input);
+    }
+
+    // Crashes say where their input came from
+    let input = fuzzer.next_input();
+    fuzzer.crash = Some(Crash {
+        kind:    crash::CrashKind::Guest,
+        code:    0x1234,
+        addr:    0,
+        frames:  vec!["target.exe+0x10".into()],
+        context: "rip=0\n".into(),
+    });
+    fuzzer.found_crash(&input);
+    let bucket = fuzzer.crash.as_ref().unwrap().bucket();
+    let info = std::fs::read_to_string(dir.join("crashes").join(format!(
+        "{:016x}.txt", crate::corpus::hash(bucket.as_bytes())))).unwrap();
+    assert_eq!(info, format!("{}\nMutated from {:016x} with seed {:#x}, \
+        splicing from {:016x}\n\nrip=0\n", bucket,
+        crate::corpus::hash(fuzzer.corpus.get(fuzzer.parent.unwrap())),
+        fuzzer.case_seed,
+        crate::corpus::hash(fuzzer.corpus.get(fuzzer.splice.unwrap()))));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,119 @@
+/// Guest to host hypercall ABI
+///
+/// A guest makes a hypercall by running CPUID with `HYPERCALL_LEAF` in EAX and
+/// the operation in the upper 32 bits of RAX. Arguments are passed in RCX,
+/// RDX, RSI and RDI. Once handled EBX holds `HYPERCALL_SIGNATURE`, RAX holds
+/// one of the `HC_STATUS_*` values and RCX holds the return value.
+///
+/// CPUIDs run while Bochs is emulating never exit to us, instead Bochs
+/// answers them with its usual CPUID data. Guests must repeat the CPUID until
+/// EBX holds the signature, by then the hypervisor has handled the call.
+///
+/// This only uses `core` so guest code can share it.
+
+/// CPUID leaf reserved for hypercalls ("APIE"). This is in the range reserved
+/// for hypervisors so no real CPU will ever use it
+pub const HYPERCALL_LEAF: u32 = 0x41504945;
+
+/// Value in EBX after a hypercall has been handled ("apie" in memory)
+pub const HYPERCALL_SIGNATURE: u32 = 0x65697061;
+
+/// Print a string. RCX is the address and RDX the length of the string
+pub const HC_PRINT: u32 = 1;
+
+/// Longest string printed by a single `HC_PRINT`, the rest is dropped
+pub const HYPERCALL_MAX_PRINT: u64 = 4096;
+
+/// Take a snapshot of the guest, resuming it returns from this hypercall
+pub const HC_SNAPSHOT: u32 = 2;
+
+/// Copy the current fuzz input into the guest. RCX is the address and RDX the
+/// size of the buffer. Returns the full length of the input, which may be
+/// more than the amount copied
+pub const HC_GET_INPUT: u32 = 3;
+
+/// Report a crash. RCX is a crash code and RDX the faulting address
+pub const HC_REPORT_CRASH: u32 = 4;
+
+/// Report the result of a test case in RCX
+pub const HC_REPORT_RESULT: u32 = 5;
+
+/// The hypercall was handled
+pub const HC_STATUS_SUCCESS: u64 = 0;
+
+/// The operation is not known
+pub const HC_STATUS_INVALID_OPCODE: u64 = 1;
+
+/// A guest buffer could not be accessed
+pub const HC_STATUS_BAD_ADDRESS: u64 = 2;
+
+/// The operation isn't possible right now
+pub const HC_STATUS_UNSUPPORTED: u64 = 3;
+
+/// A decoded hypercall
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hypercall {
+    Print { addr: u64, len: u64 },
+    Snapshot,
+    GetInput { addr: u64, size: u64 },
+    ReportCrash { code: u64, addr: u64 },
+    ReportResult { result: u64 },
+
+    /// Hypercall with an operation we don't know
+    Invalid { opcode: u32 },
+}
+
+impl Hypercall {
+    /// Decode a CPUID into a hypercall. `rax` is the full RAX the CPUID was
+    /// run with and `args` are RCX, RDX, RSI and RDI. Returns `None` if this
+    /// is a normal CPUID
+    pub fn decode(rax: u64, args: [u64; 4]) -> Option<Hypercall> {
+        if rax as u32 != HYPERCALL_LEAF {
+            return None;
+        }
+
+        let opcode = (rax >> 32) as u32;
+        Some(match opcode {
+            HC_PRINT =>
+                Hypercall::Print { addr: args[0], len: args[1] },
+            HC_SNAPSHOT =>
+                Hypercall::Snapshot,
+            HC_GET_INPUT =>
+                Hypercall::GetInput { addr: args[0], size: args[1] },
+            HC_REPORT_CRASH =>
+                Hypercall::ReportCrash { code: args[0], addr: args[1] },
+            HC_REPORT_RESULT =>
+                Hypercall::ReportResult { result: args[0] },
+            _ => Hypercall::Invalid { opcode },
+        })
+    }
+}
+
+#[test]
+fn test_decode() {
+    let args = [0x1000, 0x20, 0x30, 0x40];
+    let call = |opcode: u32| ((opcode as u64) << 32) | HYPERCALL_LEAF as u64;
+
+    // Normal CPUIDs, including other leaves with a hypercall opcode on top
+    assert_eq!(Hypercall::decode(0, args), None);
+    assert_eq!(Hypercall::decode(((HC_PRINT as u64) << 32) | 0x40000000,
+        args), None);
+
+    assert_eq!(Hypercall::decode(call(HC_PRINT), args),
+        Some(Hypercall::Print { addr: 0x1000, len: 0x20 }));
+    assert_eq!(Hypercall::decode(call(HC_SNAPSHOT), args),
+        Some(Hypercall::Snapshot));
+    assert_eq!(Hypercall::decode(call(HC_GET_INPUT), args),
+        Some(Hypercall::GetInput { addr: 0x1000, size: 0x20 }));
+    assert_eq!(Hypercall::decode(call(HC_REPORT_CRASH), args),
+        Some(Hypercall::ReportCrash { code: 0x1000, addr: 0x20 }));
+    assert_eq!(Hypercall::decode(call(HC_REPORT_RESULT), args),
+        Some(Hypercall::ReportResult { result: 0x1000 }));
+
+    // Unknown opcodes, including 0 which is what a plain `cpuid` of the
+    // leaf looks like
+    assert_eq!(Hypercall::decode(call(0), args),
+        Some(Hypercall::Invalid { opcode: 0 }));
+    assert_eq!(Hypercall::decode(call(0xdead), args),
+        Some(Hypercall::Invalid { opcode: 0xdead }));
+}
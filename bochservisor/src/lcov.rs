@@ -0,0 +1,140 @@
+/// lcov coverage files
+///
+/// lcov's tracefile format is what `genhtml` and most editor and CI coverage
+/// tooling read. Private PDBs map the code of every source line to an offset in
+/// the module, a line's code runs until the next offset with a line or the end
+/// of the function it's in, whichever comes first. A line is hit if any offset
+/// in its code was covered. We only know whether something was covered, not
+/// how often, so hit counts are always 0 or 1.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// A single module worth of coverage
+pub struct Module<'a> {
+    /// SizeOfImage from the PE header
+    pub size: u32,
+
+    /// Source lines of the module as `(offset, file, line)`, sorted by offset
+    pub lines: &'a [(u64, String, u64)],
+
+    /// Symbols of the module as `(offset, name, size)`, sorted by offset
+    pub symbols: &'a [(u64, String, u64)],
+
+    /// Bitmap of covered offsets, one bit per byte of the image
+    pub bitmap: &'a [u8],
+}
+
+impl<'a> Module<'a> {
+    /// Get the end of the symbol `offset` is in, `None` if it's in none
+    fn symbol_end(&self, offset: u64) -> Option<u64> {
+        let ii = match self.symbols.binary_search_by_key(&offset, |x| x.0) {
+            Ok(ii)  => ii,
+            Err(0)  => return None,
+            Err(ii) => ii - 1,
+        };
+
+        let (start, _, size) = &self.symbols[ii];
+        Some(start + size).filter(|&end| offset < end)
+    }
+
+    /// Returns `true` if any offset in `start..end` was covered
+    fn covered(&self, start: u64, end: u64) -> bool {
+        let end = std::cmp::min(end, self.size as u64);
+        (start..end).any(|offset| {
+            self.bitmap.get(offset as usize / 8)
+                .map(|x| x & (1 << (offset % 8)) != 0) == Some(true)
+        })
+    }
+}
+
+/// Write the line coverage of `modules` to `out` as an lcov tracefile.
+/// Modules without source lines are skipped
+pub fn write<W: Write>(mut out: W, modules: &[Module]) -> io::Result<()> {
+    // Whether each line was hit, by file and line number
+    let mut files: BTreeMap<&str, BTreeMap<u64, bool>> = BTreeMap::new();
+
+    for module in modules {
+        let lines = module.lines;
+        let mut ii = 0;
+        while ii < lines.len() {
+            // Every line starting at this offset shares the same code
+            let start = lines[ii].0;
+            let group = lines[ii..].iter().take_while(|x| x.0 == start)
+                .count();
+
+            // Code past the end of the function belongs to no line. Without a
+            // symbol the last line only gets its first byte
+            let next = lines.get(ii + group).map(|x| x.0);
+            let end = match (next, module.symbol_end(start)) {
+                (Some(next), Some(func)) => std::cmp::min(next, func),
+                (Some(next), None)       => next,
+                (None, Some(func))       => func,
+                (None, None)             => start + 1,
+            };
+
+            let hit = module.covered(start, end);
+            for (_, file, line) in &lines[ii..ii + group] {
+                *files.entry(file).or_default().entry(*line)
+                    .or_insert(false) |= hit;
+            }
+            ii += group;
+        }
+    }
+
+    for (file, lines) in files {
+        write!(out, "TN:\nSF:{}\n", file)?;
+        for (line, &hit) in &lines {
+            write!(out, "DA:{},{}\n", line, hit as u8)?;
+        }
+        write!(out, "LF:{}\nLH:{}\nend_of_record\n", lines.len(),
+            lines.values().filter(|&&x| x).count())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_lcov() {
+    let lines = [
+        (0x10, "a.c".to_string(), 3),
+        (0x14, "a.c".to_string(), 4),
+        (0x14, "b.h".to_string(), 9),
+        (0x20, "a.c".to_string(), 3),
+        (0x30, "a.c".to_string(), 5),
+    ];
+
+    let symbols = [
+        (0x10, "foo".to_string(), 0x18),
+        (0x30, "bar".to_string(), 0x8),
+    ];
+
+    // Offsets 0x1a, 0x2c and 0x3f are covered, the last two are past the
+    // end of a function so they don't count towards any line
+    let mut bitmap = [0u8; 8];
+    bitmap[3] = 1 << 2;
+    bitmap[5] = 1 << 4;
+    bitmap[7] = 1 << 7;
+    let modules = [
+        Module { size: 0x40, lines: &lines, symbols: &symbols,
+            bitmap: &bitmap },
+        Module { size: 0x1000, lines: &[], symbols: &[], bitmap: &[0xff] },
+    ];
+
+    let mut out = Vec::new();
+    write(&mut out, &modules).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "\
+        TN:\nSF:a.c\nDA:3,0\nDA:4,1\nDA:5,0\nLF:3\nLH:1\nend_of_record\n\
+        TN:\nSF:b.h\nDA:9,1\nLF:1\nLH:1\nend_of_record\n");
+
+    // Without symbols lines run until the next one, and the last line is
+    // only its first byte
+    let modules = [
+        Module { size: 0x40, lines: &lines, symbols: &[], bitmap: &bitmap },
+    ];
+    let mut out = Vec::new();
+    write(&mut out, &modules).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "\
+        TN:\nSF:a.c\nDA:3,1\nDA:4,1\nDA:5,0\nLF:3\nLH:2\nend_of_record\n\
+        TN:\nSF:b.h\nDA:9,1\nLF:1\nLH:1\nend_of_record\n");
+}
@@ -2,30 +2,74 @@
 #![allow(non_upper_case_globals)]
 
 pub mod whvp;
+pub mod backend;
+pub mod context;
 pub mod time;
 pub mod virtmem;
 pub mod win32;
 pub mod symdumper;
 pub mod symloader;
 pub mod disk;
+pub mod memreader;
+pub mod cpustate;
+pub mod bochs_snapshot;
+pub mod crashdump;
+pub mod elfcore;
+pub mod hypercall;
+pub mod fuzz;
+pub mod mutator;
+pub mod corpus;
+pub mod crash;
+pub mod minimize;
+pub mod drcov;
+pub mod lcov;
+pub mod report;
+pub mod covdb;
+pub mod edges;
+pub mod procfilter;
+pub mod bpcov;
+pub mod blocks;
+mod x86;
+pub mod snapshot;
 
 use std::cell::{Cell, RefCell};
 use std::collections::{HashMap};
-use crate::whvp::{Whvp, WhvpContext};
+use crate::whvp::Whvp;
 use crate::whvp::{PERM_READ, PERM_WRITE, PERM_EXECUTE};
-use whvp_bindings::winhvplatform::*;
-use crate::win32::{get_modlist, find_kernel_modlist};
+use crate::backend::{HypervisorBackend, SoftwareBackend, BackendError};
+use crate::backend::{VmExit, VmExitReason, VpExit, spawn_runners};
+use crate::context::VpContext;
+use crate::win32::{get_modlist, find_kernel_modlist, get_current_process};
+use crate::win32::ProcessOffsets;
 use crate::symloader::Symbols;
+use crate::snapshot::Snapshot;
+use crate::memreader::{MemReader, MemoryRegion};
+use crate::cpustate::CpuState;
+use crate::virtmem::Paging;
+use crate::crashdump::{CrashDump, MANUALLY_INITIATED_CRASH};
+use crate::elfcore::{ElfCore, CoreLayout};
+use crate::hypercall::*;
+use crate::fuzz::{Fuzzer, FuzzConfig};
+use crate::crash::{Crash, CrashKind};
+use crate::procfilter::ProcessFilter;
+use crate::bpcov::Breakpoints;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver};
 use crate::win32::{ModuleList};
 use std::fs::File;
 use std::io::Write;
 use std::time::SystemTime;
 use std::ffi::CString;
+use std::borrow::Cow;
+use std::path::Path;
 
 /// Number of instructions to step in emulation mode after a vmexit
 const EMULATE_STEPS: u64 = 250;
 
+/// Run everything in Bochs with the `SoftwareBackend` rather than using WHVP.
+/// This is very slow but doesn't need Hyper-V
+const SOFTWARE_BACKEND: bool = false;
+
 /// Disables coverage entirely if this is `true`
 /// This helps a lot with performance if you're not concerned with coverage info
 const COVERAGE_DISABLE: bool = true;
@@ -33,9 +77,58 @@ const COVERAGE_DISABLE: bool = true;
 /// Logs coverage using symbols to the file `coverage.txt`.
 const LOG_COVERAGE_SYMBOLS: bool = false;
 
+/// File the coverage is saved to in drcov format every time it's reported,
+/// for loading into Lighthouse and other drcov tools. It's put in the same
+/// directory as the coverage database
+const DRCOV_FILENAME: &str = "coverage.drcov";
+
+/// Environment variable holding the path to save line coverage to as an lcov
+/// tracefile every time coverage is reported. This needs private symbols, and
+/// isn't saved if not set
+const LCOV_ENV_VAR: &str = "APPLEPIE_COVERAGE_LCOV";
+
+/// Environment variable holding the path to save an HTML coverage report to
+/// every time coverage is reported. Not saved if not set
+const REPORT_ENV_VAR: &str = "APPLEPIE_COVERAGE_REPORT";
+
+/// Environment variable holding the path of a coverage database to compare
+/// the HTML coverage report against, functions not covered in it are marked
+/// as new
+const BASELINE_ENV_VAR: &str = "APPLEPIE_COVERAGE_BASELINE";
+
+/// Environment variable holding the path of the coverage database, which is
+/// loaded at startup and merged with our coverage every time it's reported.
+/// `coverage.db` if not set
+const COVERAGE_DB_ENV_VAR: &str = "APPLEPIE_COVERAGE_DB";
+
 /// Maximum amount of instructions to emulate at a given time
 const MAX_EMULATE: u64 = 1000;
 
+/// Number of hypervisor errors in a row we tolerate on a single VP before
+/// giving up. Every error is followed by a bit of emulation in Bochs, which
+/// usually gets the guest out of whatever state upset the hypervisor
+const MAX_CONSECUTIVE_ERRORS: u64 = 32;
+
+/// Environment variable holding the path of an applepie snapshot file to boot
+/// into. This replaces the state Bochs booted with on the first entry to the
+/// CPU loop, and the snapshot is then what we restore to
+const SNAPSHOT_ENV_VAR: &str = "APPLEPIE_SNAPSHOT";
+
+/// Name of the applepie snapshot file saved in every snapshot folder
+const SNAPSHOT_FILE_NAME: &str = "applepie.snap";
+
+/// Name of the Windows crash dump saved in snapshot folders of Windows guests
+const CRASH_DUMP_FILE_NAME: &str = "MEMORY.DMP";
+
+/// Name of the ELF core saved in every snapshot folder
+const ELF_CORE_FILE_NAME: &str = "guest.core";
+
+/// Environment variable picking the memory layout of the ELF core, `virtual`
+/// for what the page table in use when the snapshot was taken maps, or
+/// `physical` for guest physical memory. Virtual if not set, physical when
+/// the guest isn't using 4-level long mode paging
+const CORE_LAYOUT_ENV_VAR: &str = "APPLEPIE_CORE_LAYOUT";
+
 /// Discard reads/writes to the framebuffer when in the hypervisor. This breaks
 /// screen updates but gives a performance boost if you only care about RDP/SSH
 /// into the guest
@@ -49,21 +142,23 @@ const BX_EXECUTE: i32 = 2;
 /// Routines passed by Bochs to use for manipulating the Bochs state
 #[repr(C)]
 pub struct BochsRoutines {
-    /// Set the Bochs context to the `context` provided
-    set_context: extern fn(context: &WhvpContext),
+    /// Set the Bochs context of processor `cpu` to the `context` provided
+    set_context: extern fn(cpu: u32, context: &VpContext),
 
-    /// Get the Bochs context into the `context` provided
-    get_context: extern fn(context: &mut WhvpContext),
+    /// Get the Bochs context of processor `cpu` into the `context` provided
+    get_context: extern fn(cpu: u32, context: &mut VpContext),
 
     /// Step the device emulation portion of Bochs by `steps`. For example if
     /// ips=1000000 in your bochsrc and you pass 1000000 as `steps`, this will
-    /// effectively emulate 1 second of hardware/timer/interrupts
-    step_device: extern fn(steps: u64),
+    /// effectively emulate 1 second of hardware/timer/interrupts.
+    ///
+    /// Only async events (interrupts) for processor `cpu` are handled
+    step_device: extern fn(cpu: u32, steps: u64),
 
-    /// Step the CPU by `steps`. Depending on the Bochs optimization features
-    /// this either steps `steps` instructions, or `steps` 'chains' which are
-    /// Bochs's linked instructions (similar to a basic block)
-    step_cpu: extern fn(steps: u64),
+    /// Step processor `cpu` by `steps`. Depending on the Bochs optimization
+    /// features this either steps `steps` instructions, or `steps` 'chains'
+    /// which are Bochs's linked instructions (similar to a basic block)
+    step_cpu: extern fn(cpu: u32, steps: u64),
 
     /// Get the backing address of a physical address `addr` with an access type
     /// `typ` from Bochs. The access type should be a combination of the
@@ -71,11 +166,12 @@ pub struct BochsRoutines {
     get_memory_backing: extern fn(addr: u64, typ: i32) -> usize,
 
     // Get the CPUID result from Bochs for a given leaf:subleaf combination
-    cpuid: extern fn(leaf: u32, subleaf: u32, eax: &mut u32, ebx: &mut u32,
-        ecx: &mut u32, edx: &mut u32),
+    // on processor `cpu`
+    cpuid: extern fn(cpu: u32, leaf: u32, subleaf: u32, eax: &mut u32,
+        ebx: &mut u32, ecx: &mut u32, edx: &mut u32),
 
-    /// Write an MSR `value` to the MSR specified by `index`
-    write_msr: extern fn(index: u32, value: u64),
+    /// Write an MSR `value` to the MSR specified by `index` on processor `cpu`
+    write_msr: extern fn(cpu: u32, index: u32, value: u64),
 
     /// Notify devices that a restore just occured. This does things like
     /// redraw the screen, check CPU state is valid, and such.
@@ -86,41 +182,27 @@ pub struct BochsRoutines {
 
     /// Take a Bochs snapshot and save it to `folder_name`
     take_snapshot: extern fn(folder_name: *const i8) -> !,
-}
-
-/// Named structure for tracking memory regions in Bochs
-/// 
-/// This is just for convience
-struct MemoryRegion {
-    /// Physical address of the base of this memory region
-    paddr: usize,
-
-    /// Pointer to memory which represents this region in bochs
-    backing: usize,
 
-    /// Permissions allowed on this region
-    /// These are the WHVP constants: `PERM_READ`, `PERM_WRITE`, `PERM_EXECUTE`
-    perms: i32,
-
-    /// Size of the memory region
-    size: usize,
+    /// Get the Bochs activity state of processor `cpu`. Zero means the
+    /// processor is active, anything else means it's halted or waiting for a
+    /// SIPI
+    activity_state: extern fn(cpu: u32) -> u32,
 }
 
+/// Bitmask of the VPs which are currently running in the hypervisor, bit `n`
+/// is set while VP `n` is running
 static KICKER_ACTIVE: AtomicUsize = AtomicUsize::new(0);
 
-/// Kicker thread. This thread kicks the WHVP partition approx. 1000 times per
-/// second to give us an opportunity to step a bit in Bochs and emulate devices
-/// and potentially deliver interrupts.
+/// Kicker thread. This thread kicks every running VP in the WHVP partition
+/// approx. 1000 times per second to give us an opportunity to step a bit in
+/// Bochs and emulate devices and potentially deliver interrupts.
 /// 
 /// This is _really_ gross but I don't see anything in the WHVP API that has
 /// an alternative.
 /// 
 /// Further we use a busyloop here instead of a Sleep() so we can get a higher
 /// frequency kick (to get 1000/second this seems necessary).
-fn kicker(handle: usize) {
-    // Cast the handle
-    let handle = handle as WHV_PARTITION_HANDLE;
-
+fn kicker(cancel: Box<dyn Fn(u32) + Send>) {
     // Determine this processors TSC rate
     let tickrate = time::calibrate_tsc();
 
@@ -138,62 +220,36 @@ fn kicker(handle: usize) {
 
         // Potentially the kicker was deactivated by this point, so skip the
         // cancel
-        if KICKER_ACTIVE.load(Ordering::SeqCst) == 0 { continue; }
+        let active = KICKER_ACTIVE.load(Ordering::SeqCst);
+        if active == 0 { continue; }
 
-        // Kick the hypervisor to cause it to exit
-        unsafe { WHvCancelRunVirtualProcessor(handle, 0, 0); }
+        // Kick all running VPs to cause them to exit
+        for vp in 0..std::mem::size_of::<usize>() * 8 {
+            if (active & (1 << vp)) != 0 {
+                cancel(vp as u32);
+            }
+        }
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
-enum VmExitReason {
-    None,
-    MemoryAccess,
-    IoPortAccess,
-    UnrecoverableException,
-    InvalidVpRegisterValue,
-    UnsupportedFeature,
-    InterruptWindow,
-    Halt,
-    ApicEoi,
-    MsrAccess,
-    Cpuid,
-    Exception,
-    Canceled,
+/// Give up after hypervisor error `err`. We can't unwind through Bochs so just
+/// report what happened and exit
+fn fatal_hypervisor_error(err: &BackendError) -> ! {
+    print!("Fatal hypervisor error: {}\n", err);
+    std::process::exit(-1);
 }
 
-impl VmExitReason {
-    fn from_whvp(whvp_reason: WHV_RUN_VP_EXIT_REASON) -> Self {
-        match whvp_reason {
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonNone =>
-                VmExitReason::None,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonMemoryAccess =>
-                VmExitReason::MemoryAccess,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64IoPortAccess =>
-                VmExitReason::IoPortAccess,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonUnrecoverableException =>
-                VmExitReason::UnrecoverableException,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonInvalidVpRegisterValue =>
-                VmExitReason::InvalidVpRegisterValue,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonUnsupportedFeature =>
-                VmExitReason::UnsupportedFeature,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64InterruptWindow =>
-                VmExitReason::InterruptWindow,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Halt =>
-                VmExitReason::Halt,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64ApicEoi =>
-                VmExitReason::ApicEoi,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64MsrAccess =>
-                VmExitReason::MsrAccess,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Cpuid =>
-                VmExitReason::Cpuid,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonException =>
-                VmExitReason::Exception,
-            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonCanceled =>
-                VmExitReason::Canceled,
-            _ => panic!("Invalid vm exit reason {}\n", whvp_reason),
-        }
+/// Account for the failed hypervisor call `err` on `vpstate`. Transient errors
+/// are survived by the caller emulating in Bochs for a bit and then trying the
+/// hypervisor again. Anything else, or too many errors in a row, is fatal
+fn hypervisor_error(vpstate: &mut VirtualProcessor, err: &BackendError) {
+    vpstate.consecutive_errors += 1;
+    if !err.is_transient() ||
+            vpstate.consecutive_errors > MAX_CONSECUTIVE_ERRORS {
+        fatal_hypervisor_error(err);
     }
+
+    vpstate.stats.hypervisor_errors += 1;
 }
 
 /// All kinds of statistics for tracking what we're doing
@@ -207,6 +263,51 @@ struct Statistics {
 
     /// Number of fuzz cases
     num_fuzz_cases: u64,
+
+    /// Total cycles spent restoring the guest between fuzz cases
+    restore_cycles: u64,
+
+    /// Number of hypercalls handled
+    hypercalls: u64,
+}
+
+/// Statistics for a single virtual processor
+#[derive(Default, Debug)]
+struct VpStatistics {
+    /// Estimated number of cycles spent inside of the hypervisor executing
+    vm_elapsed: u64,
+
+    /// Number of instructions (or chains) emulated in Bochs
+    emulated: u64,
+
+    /// VM exit reason frequencies
+    vmexits: HashMap<VmExitReason, u64>,
+
+    /// Number of failed hypervisor calls we recovered from
+    hypervisor_errors: u64,
+}
+
+/// State for a single virtual processor
+struct VirtualProcessor {
+    /// Requests to the run thread of this VP to run it in the hypervisor
+    run_request: Sender<()>,
+
+    /// Set while this VP is in the hypervisor. The Bochs state for this
+    /// processor is stale while this is set
+    running: bool,
+
+    /// TSC value when this VP was last handed to its run thread
+    run_start: u64,
+
+    /// Number of instructions to emulate on this VP next time it's handled
+    emulating: u64,
+
+    /// Number of hypervisor errors in a row on this VP. Reset whenever the VP
+    /// makes it through the hypervisor successfully
+    consecutive_errors: u64,
+
+    /// Statistics
+    stats: VpStatistics,
 }
 
 /// A single module worth of coverage information
@@ -232,8 +333,8 @@ struct Page([u8; 4096]);
 // the start
 #[derive(Default)]
 struct PersistState {
-    /// WHVP API context
-    hypervisor: Option<Whvp>,
+    /// Hypervisor backend running the guest
+    hypervisor: Option<Box<dyn HypervisorBackend>>,
 
     /// Cached tickrate for the TSC on this processor
     tickrate: Option<f64>,
@@ -245,14 +346,18 @@ struct PersistState {
     /// uptime
     start: u64,
 
-    /// Estimated number of cycles spent inside of the hypervisor executing
-    vm_elapsed: u64,
-
     /// Last TSC value when Bochs device state was synced with the wall clock
     last_sync_cycles: u64,
 
-    /// VM exit reason frequencies
-    vmexits: HashMap<VmExitReason, u64>,
+    /// State of all virtual processors, indexed by VP number
+    vps: Vec<VirtualProcessor>,
+
+    /// Exits reported by the VP run threads as (VP number, exit)
+    vm_exits: Option<Receiver<VpExit>>,
+
+    /// VP which most recently exited the hypervisor. This is the VP the CPU
+    /// loop is currently working on
+    current_vp: u32,
 
     /// Memory reader for physical and virtual access
     memory: MemReader,
@@ -260,6 +365,16 @@ struct PersistState {
     /// Code coverage information per module
     coverage: Vec<Option<CoverageEntry>>,
 
+    /// Filter restricting coverage to a target process, if one was requested
+    process_filter: Option<ProcessFilter>,
+
+    /// Offsets of the process structures of the guest's Windows build, looked
+    /// up at startup when there's a process filter
+    process_offsets: Option<ProcessOffsets>,
+
+    /// Coverage breakpoints, if breakpoint coverage was requested
+    breakpoints: Option<Breakpoints>,
+
     /// Symbols
     symbols: Symbols,
 
@@ -275,262 +390,35 @@ struct PersistState {
     /// Statistics
     stats: Statistics,
 
-    /// Number of instructions to emulate next CPU loop iteration
-    emulating: u64,
-
     /// Normal framebuffer backing memory 0xa0000-0xbffff
     normal_fb: Vec<Page>,
 
     /// Linear framebuffer backing memory 0xe0000000-0xe0ffffff
     linear_fb: Vec<Page>,
-}
 
-thread_local! {
-    /// Thread locals. I really hate this but it's the only way to survive the
-    /// re-entry due to Bochs using `longjmp()`
-    static PERSIST: RefCell<PersistState> = RefCell::new(Default::default());
-}
+    /// Input handed to the guest by the `HC_GET_INPUT` hypercall
+    fuzz_input: Vec<u8>,
 
-#[derive(Default)]
-pub struct MemReader {
-    /// List of all of the memory regions mapped into the guest physical space
-    regions: Vec<MemoryRegion>
-}
-
-macro_rules! read_virt_declare {
-    ($name:ident, $vt:ty) => (
-        pub fn $name(&mut self, cr3: usize, addr: usize) -> Result<$vt, ()> {
-            let mut val = [0u8; std::mem::size_of::<$vt>()];
-            if self.read_virt(cr3, addr, &mut val) == val.len() {
-                Ok(<($vt)>::from_ne_bytes(val))
-            } else {
-                Err(())
-            }
-        }
-    )
-}
-
-impl MemReader {
-    /// Create a new memory reader based on a list of memory regions
-    fn new(memory_regions: Vec<MemoryRegion>) -> Self {
-        MemReader { regions: memory_regions }
-    }
+    /// Set once the guest reported a crash or result in the current case.
+    /// Crashes are recorded by the fuzzer as they're reported
+    guest_reported: bool,
 
-    /// Read physical memory at `paddr` into an output buffer. Returns number
-    /// of bytes read, this may be smaller than `output.len()` on partial reads
-    pub fn read_phys(&mut self, paddr: usize, output: &mut [u8]) -> usize {
-        // Sanity check
-        assert!(output.len() > 0, "Output buffer was zero size");
-
-        // Track number of bytes read
-        let mut bread = 0usize;
-
-        while bread < output.len() {
-            let mut matched_something = false;
-            
-            for mr in &self.regions {
-                // Check if this address falls in this region
-                if paddr >= mr.paddr {
-                    // Compute offset and remainder of region
-                    let offset = paddr - mr.paddr;
-                    let remain = mr.size.saturating_sub(offset);
-
-                    // Nothing in this region for us
-                    if remain <= 0 { continue; }
-
-                    // Convert to Rust slice
-                    let region = unsafe {
-                        std::slice::from_raw_parts(
-                            mr.backing as *const u8, mr.size)
-                    };
-
-                    // Compute bytes to copy
-                    let remain = std::cmp::min(output.len() - bread, remain);
-
-                    // Copy bytes
-                    output[bread..bread+remain].copy_from_slice(
-                        &region[offset..offset+remain]);
-
-                    // Update read amount
-                    bread += remain;
-                    matched_something = true;
-
-                    if bread >= output.len() {
-                        assert!(bread == output.len(), "Whoa we overshot");
-                        return bread;
-                    }
-                }
-            }
-
-            // Failed to find a region that contains this byte, bail out
-            if !matched_something { break; }
-        }
+    /// Fuzzer, if we're fuzzing
+    fuzzer: Option<Fuzzer>,
 
-        assert!(bread < output.len(), "Success path shouldn't go here");
+    /// Module and offset of all the new coverage found in the current fuzz
+    /// case
+    case_coverage: Vec<(String, usize)>,
 
-        bread
-    }
-
-    /// Write physical memory at `paddr` from an input buffer. Returns number
-    /// of bytes written, this may be smaller than `input.len()` on partial
-    /// writes
-    pub fn write_phys(&mut self, paddr: usize, input: &[u8]) -> usize {
-        // Sanity check
-        assert!(input.len() > 0, "Input buffer was zero size");
-
-        // Track number of bytes read
-        let mut bread = 0usize;
-
-        while bread < input.len() {
-            let mut matched_something = false;
-            
-            for mr in &self.regions {
-                // Check if this address falls in this region
-                if paddr >= mr.paddr {
-                    // Compute offset and remainder of region
-                    let offset = paddr - mr.paddr;
-                    let remain = mr.size.saturating_sub(offset);
-
-                    // Nothing in this region for us
-                    if remain <= 0 { continue; }
-
-                    // Convert to Rust slice
-                    let region = unsafe {
-                        std::slice::from_raw_parts_mut(
-                            mr.backing as *mut u8, mr.size)
-                    };
-
-                    // Compute bytes to copy
-                    let remain = std::cmp::min(input.len() - bread, remain);
-
-                    // Copy bytes
-                    region[offset..offset+remain].copy_from_slice(
-                        &input[bread..bread+remain]);
-
-                    // Update read amount
-                    bread += remain;
-                    matched_something = true;
-
-                    if bread >= input.len() {
-                        assert!(bread == input.len(), "Whoa we overshot");
-                        return bread;
-                    }
-                }
-            }
-
-            // Failed to find a region that contains this byte, bail out
-            if !matched_something { break; }
-        }
-
-        assert!(bread < input.len(), "Success path shouldn't go here");
-
-        bread
-    }
-
-    /// Read virtual memory at `vaddr` using page table `cr3` into `buf`.
-    /// Returns number of bytes read (can be less than `buf.len()` on error)
-    pub fn read_virt(&mut self, cr3: usize, vaddr: usize,
-                     buf: &mut [u8]) -> usize
-    {
-        // Cached physical translation
-        let mut guest_phys = 0;
-
-        // Go through each byte
-        for offset in 0..buf.len() {
-            // Update translation on new pages
-            if (guest_phys & 0xfff) == 0 {
-                // Translate vaddr to paddr
-                let mut guest_pt = unsafe {
-                    virtmem::PageTable::from_existing(cr3 as *mut u64, self)
-                };
-                guest_phys = match guest_pt.virt_to_phys_dirty(
-                        (vaddr + offset) as u64, false) {
-                    Ok(Some((phys, _))) => phys,
-                    _                   => return offset,
-                };
-            }
-
-            // Read one byte from memory
-            if self.read_phys(guest_phys as usize,
-                    &mut buf[offset..offset+1]) != 1 {
-                // Failed to read, return bytes read to this point
-                return offset;
-            }
-
-            // Update physical pointer
-            guest_phys += 1;
-        }
-
-        // Return bytes read
-        buf.len()
-    }
-
-    /// Writes virtual memory at `vaddr` using page table `cr3` from `buf`.
-    /// Returns number of bytes written (can be less than `buf.len()` on error)
-    pub fn write_virt(&mut self, cr3: usize, vaddr: usize,
-                      buf: &[u8]) -> usize
-    {
-        // Cached physical translation
-        let mut guest_phys = 0;
-
-        // Go through each byte
-        for offset in 0..buf.len() {
-            // Update translation on new pages
-            if (guest_phys & 0xfff) == 0 {
-                // Translate vaddr to paddr
-                let mut guest_pt = unsafe {
-                    virtmem::PageTable::from_existing(cr3 as *mut u64, self)
-                };
-                guest_phys = match guest_pt.virt_to_phys_dirty(
-                        (vaddr + offset) as u64, false) {
-                    Ok(Some((phys, _))) => phys,
-                    _                   => return offset,
-                };
-            }
-
-            // Read one byte from memory
-            if self.write_phys(guest_phys as usize,
-                    &buf[offset..offset+1]) != 1 {
-                // Failed to read, return bytes read to this point
-                return offset;
-            }
-
-            // Update physical pointer
-            guest_phys += 1;
-        }
-
-        // Return bytes read
-        buf.len()
-    }
-
-    read_virt_declare!(read_virt_u8, u8);
-    read_virt_declare!(read_virt_u16, u16);
-    read_virt_declare!(read_virt_u32, u32);
-    read_virt_declare!(read_virt_u64, u64);
-    read_virt_declare!(read_virt_usize, usize);
+    /// Physical pages we wrote to ourselves. These don't show up in any dirty
+    /// list so they're restored explicitly
+    host_dirty: Vec<usize>,
 }
 
-impl virtmem::PhysMem for MemReader {
-    fn alloc_page(&mut self) -> Option<*mut u8> {
-        panic!("Alloc page not supported");
-    }
-
-    fn read_phys_int(&mut self, addr: *mut u64) -> Result<u64, &'static str> {
-        let mut buf = [0u8; 8];
-        if self.read_phys(addr as usize, &mut buf) != buf.len() {
-            return Err("Failed to read physical memory");
-        }
-        Ok(u64::from_ne_bytes(buf))
-    }
-
-    fn write_phys(&mut self, _addr: *mut u64,
-            _val: u64) ->Result<(), &'static str> {
-        panic!("write_phys not supported");
-    }
-
-    fn probe_vaddr(&mut self, _addr: usize, _length: usize) -> bool {
-        panic!("probe_vaddr not supported");
-    }
+thread_local! {
+    /// Thread locals. I really hate this but it's the only way to survive the
+    /// re-entry due to Bochs using `longjmp()`
+    static PERSIST: RefCell<PersistState> = RefCell::new(Default::default());
 }
 
 /// Dump the coverage table to the console
@@ -578,9 +466,185 @@ fn dump_coverage(coverage: &Vec<Option<CoverageEntry>>) {
     print!("Coverage total:              {:10}\n", sum);
 }
 
+/// Merge the coverage database at `path` into the coverage table, then save
+/// the merged table back to it. Returns the number of offsets the database
+/// added to the table
+fn sync_coverage_db(coverage: &mut Vec<Option<CoverageEntry>>, path: &str)
+        -> std::io::Result<u64> {
+    let invalid = |err: String| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path, err))
+    };
+
+    let mut added = 0;
+
+    // Merge in the database if there is one yet
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound =>
+            Vec::new(),
+        Err(err) => return Err(err),
+    };
+    if !data.is_empty() {
+        for module in covdb::parse(&data).map_err(invalid)? {
+            // Look up the ordinal this module has in this process
+            let modinfo = win32::ModuleInfo::new(module.name.into(),
+                module.timestamp, module.size);
+            let ordinal = modinfo.ordinal() as usize;
+
+            while ordinal >= coverage.len() {
+                coverage.push(None);
+            }
+
+            let covent = coverage[ordinal].get_or_insert_with(|| {
+                CoverageEntry {
+                    bitmap: vec![0u8; (module.size as usize + 7) & !7],
+                    unique: 0,
+                }
+            });
+
+            let new = covdb::merge(&mut covent.bitmap, module.bitmap);
+            covent.unique += new;
+            added += new;
+        }
+    }
+
+    // Save the merged table
+    let modinfos = covered_modules(coverage);
+
+    let modules: Vec<covdb::Module> = modinfos.iter().map(|(modinfo, bitmap)| {
+        covdb::Module {
+            name:      modinfo.name(),
+            timestamp: modinfo.time(),
+            size:      modinfo.size(),
+            bitmap,
+        }
+    }).collect();
+
+    // Write to a temporary file first so other workers never see a partially
+    // written database
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    std::fs::write(&tmp, covdb::serialize(&modules))?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(added)
+}
+
+/// Get the path of the coverage database
+fn coverage_db_path() -> String {
+    std::env::var(COVERAGE_DB_ENV_VAR)
+        .unwrap_or_else(|_| "coverage.db".into())
+}
+
+/// Get the path of the drcov file, next to the coverage database
+fn drcov_path() -> std::path::PathBuf {
+    Path::new(&coverage_db_path()).with_file_name(DRCOV_FILENAME)
+}
+
+/// Get the module info and coverage bitmap of every module in `coverage` with
+/// coverage
+fn covered_modules(coverage: &[Option<CoverageEntry>])
+        -> Vec<(win32::ModuleInfo, &[u8])> {
+    coverage.iter().enumerate().filter_map(|(ordinal, entry)| {
+        entry.as_ref().map(|entry| {
+            (win32::ordinal_to_modinfo(ordinal as win32::Ordinal)
+                .expect("Got coverage on ordinal that doesn't exist!?"),
+             entry.bitmap.as_slice())
+        })
+    }).collect()
+}
+
+/// Save the coverage table to `path` as a drcov file
+fn save_drcov(coverage: &Vec<Option<CoverageEntry>>, path: &Path)
+        -> std::io::Result<()> {
+    let modinfos = covered_modules(coverage);
+
+    let modules: Vec<drcov::Module> = modinfos.iter().map(|(modinfo, bitmap)| {
+        drcov::Module {
+            name:      modinfo.name(),
+            size:      modinfo.size(),
+            timestamp: modinfo.time(),
+            bitmap,
+        }
+    }).collect();
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    drcov::write(file, &modules)
+}
+
+/// Save the line coverage of every covered module with private symbols to
+/// `path` as an lcov tracefile
+fn save_lcov(persist: &mut PersistState, path: &str) -> std::io::Result<()> {
+    let modinfos = covered_modules(&persist.coverage);
+
+    // Make sure the symbols are loaded, this only downloads them once
+    let symbols = &mut persist.symbols;
+    for (modinfo, _) in &modinfos {
+        let _ = symbols.load_win32(modinfo);
+    }
+
+    let modules: Vec<lcov::Module> = modinfos.iter()
+        .filter_map(|(modinfo, bitmap)| {
+            symbols.source_lines(modinfo).map(|lines| lcov::Module {
+                size:    modinfo.size(),
+                lines,
+                symbols: symbols.symbols(modinfo),
+                bitmap,
+            })
+        }).collect();
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    lcov::write(file, &modules)
+}
+
+/// Save an HTML coverage report to `path`, comparing against the coverage
+/// database at `baseline` if there is one
+fn save_report(persist: &mut PersistState, path: &str,
+        baseline: Option<&str>) -> std::io::Result<()> {
+    let mut baseline_data = Vec::new();
+    if let Some(path) = baseline {
+        baseline_data = std::fs::read(path)?;
+    }
+    let baseline_modules = match baseline {
+        Some(path) => covdb::parse(&baseline_data).map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path, err))
+        })?,
+        None => Vec::new(),
+    };
+
+    let modinfos = covered_modules(&persist.coverage);
+
+    let breakpoints = persist.breakpoints.as_ref();
+    let modules: Vec<report::Module> = modinfos.iter()
+        .map(|(modinfo, bitmap)| report::Module {
+            name:      modinfo.name(),
+            size:      modinfo.size(),
+            timestamp: modinfo.time(),
+            bitmap,
+            baseline:  baseline_modules.iter().find(|x|
+                    x.name == modinfo.name() &&
+                    x.timestamp == modinfo.time() && x.size == modinfo.size())
+                .map(|x| x.bitmap),
+            blocks:    breakpoints.and_then(|x| x.blocks(modinfo)),
+        }).collect();
+
+    // Strip the offset off of `module!function+0x1234`
+    let symbols = &mut persist.symbols;
+    let function = |idx: usize, offset: u32| {
+        symbols.resolve(&modinfos[idx].0, offset as usize).map(|mut sym| {
+            if let Some(plus) = sym.rfind("+0x") { sym.truncate(plus); }
+            sym
+        })
+    };
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    report::write(file, &modules, baseline, function)
+}
+
 /// Types for all shadow data types used in snapshots
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ShadowType {
   Data,    // bx_shadow_data_c, raw data pointer and a size
   Fileptr, // bx_shadow_filedata_c, FILE**
@@ -599,6 +663,13 @@ pub enum ShadowType {
 
 /// This represents the state of a given device
 struct DeviceState {
+    /// Full name Bochs registered this state with. When contiguous states are
+    /// merged this is the name of the first one
+    name: String,
+
+    /// Type of the state
+    typ: ShadowType,
+
     /// Address to the memory in Bochs which holds this state
     addr: usize,
 
@@ -622,6 +693,10 @@ thread_local! {
     /// This is locked when the first call to the Bochs CPU loop is invoked,
     /// which prevents us from registering new state when running.
     static DEVICE_STATE_LOCKED: Cell<bool> = Cell::new(false);
+
+    /// Address of the original memory if we booted from an applepie snapshot
+    /// file rather than a Bochs snapshot, otherwise zero
+    static SNAPSHOT_MEMORY_BASE: Cell<usize> = Cell::new(0);
 }
 
 /// Convert a null-terminated C string to a Rust string
@@ -648,7 +723,7 @@ unsafe fn string_from_cstring(cstring: *const u8) -> Option<String> {
 /// but it's nice to have
 #[no_mangle]
 pub extern "C" fn register_state(name: *const u8, label: *const u8,
-        data: usize, size: usize, _typ: ShadowType) {           
+        data: usize, size: usize, typ: ShadowType) {           
     // Ensure device state is not locked for editing
     DEVICE_STATE_LOCKED.with(|locked| {
         assert!(locked.get() == false, "Device state change during lock")
@@ -676,19 +751,160 @@ pub extern "C" fn register_state(name: *const u8, label: *const u8,
 
         // Save it to the list of device state!
         x.push(DeviceState {
+            name: name.unwrap_or_default(), typ,
             addr: data, len: size, original: None
         });
     });
 }
 
+/// Get the PE file of `module` from the symbol server
+fn module_image_file(module: &win32::ModuleInfo) -> Option<Vec<u8>> {
+    let file = symdumper::download_symbol(module.name(), module.time(),
+        module.size()).ok()?;
+    std::fs::read(file).ok()
+}
+
+/// Rewalk the module list of a processor using page table `cr3` in `paging`
+/// mode and make it the module list cache, placing coverage breakpoints in
+/// any modules which need them. Returns `false` if the walk failed
+fn walk_modules(persist: &mut PersistState, paging: Paging, cr3: usize,
+        fs_base: usize, gs_base: usize, cs: u16) -> bool {
+    persist.stats.module_list_walks += 1;
+    let ml = match get_modlist(&mut persist.memory, cr3, paging, fs_base,
+            gs_base, cs, persist.kernel_module_list) {
+        Ok(ml) => ml,
+        Err(_) => return false,
+    };
+
+    if let Some(breakpoints) = persist.breakpoints.as_mut() {
+        for module in ml.modules() {
+            breakpoints.place(&mut persist.memory, paging, cr3,
+                module.info(), module.base());
+        }
+    }
+
+    //print!("Updating module list cache\n");
+    persist.module_list_cache = ml;
+    true
+}
+
+/// Record that `offset` in `module` was executed. Returns `true` if that's new
+/// coverage
+fn record_coverage(persist: &mut PersistState, module: &win32::ModuleInfo,
+        offset: usize) -> bool {
+    let coverage = &mut persist.coverage;
+    let mut new_coverage = false;
+
+    let size    = module.size() as usize;
+    let ordinal = module.ordinal() as usize;
+
+    // Every event counts towards edges, not just new offsets
+    if let Some(edges) =
+            persist.fuzzer.as_mut().and_then(|x| x.edges.as_mut()) {
+        edges.hit(persist.current_vp as usize,
+            edges::location(module.name(), offset));
+    }
+
+    // Make sure there are enough entries in the coverage list for our
+    // ordinal to be valid
+    while ordinal >= coverage.len() {
+        coverage.push(None);
+    }
+
+    // The above loop makes sure the ordinal is a valid index into
+    // coverage but it is still potentially a None value.
+    if coverage[ordinal].is_none() {
+        // Get the image size rounded up to the nearest byte boundary
+        let imagesize = (size + 7) & !7;
+
+        let covent = CoverageEntry {
+            bitmap: vec![0u8; imagesize],
+            unique: 0,
+        };
+
+        coverage[ordinal] = Some(covent);
+    }
+
+    // Get a mutable reference to this corresponding coverage entry
+    let covent = coverage[ordinal].as_mut().unwrap();
+
+    // Calculate bitmap offsets
+    let byte = offset / 8;
+    let bit  = offset % 8;
+
+    // Check if we've seen this offset before
+    if (covent.bitmap[byte] & (1 << bit)) == 0 {
+        // Record that we got new coverage so we can return that
+        // information to the caller
+        new_coverage = true;
+
+        // Update bitmap
+        covent.bitmap[byte] |= 1 << bit;
+
+        // Update unique count
+        covent.unique += 1;
+
+        // Remember what this fuzz case found for the corpus
+        if persist.fuzzer.is_some() {
+            persist.case_coverage.push(
+                (module.name().to_string(), offset));
+        }
+
+        // Only use symbol coverage if requested
+        if LOG_COVERAGE_SYMBOLS {
+            // Try to look up the symbol for this new coverage
+            if let Some(sym) = persist.symbols.resolve(module, offset) {
+                // Create the log file if it's not already open
+                if persist.coverage_log_file.is_none() {
+                    persist.coverage_log_file = Some(
+                        File::create("coverage.txt")
+                            .expect("Failed to open coverage output")
+                    );
+                }
+
+                // Write the symbol to the log file
+                let clf = persist.coverage_log_file.as_mut().unwrap();
+                clf.write(format!("{}\n", sym).as_bytes())
+                    .expect("Failed to write coverage entry");
+                clf.flush().expect("Failed to flush coverage file");
+            }
+        }
+    }
+
+    new_coverage
+}
+
+/// Returns `true` if coverage from a processor running with page table `cr3`,
+/// `gs_base` and code selector `cs` passes the process filter. `lma` is set
+/// if the processor is in long mode
+fn process_allowed(persist: &mut PersistState, cr3: usize, lma: bool,
+        gs_base: usize, cs: u16) -> bool {
+    let filter = match persist.process_filter.as_mut() {
+        Some(filter) => filter,
+        None => return true,
+    };
+
+    let memory  = &mut persist.memory;
+    let offsets = persist.process_offsets;
+    filter.allowed(cr3, cs & 3 == 0, || {
+        offsets.and_then(|offsets|
+            get_current_process(memory, &offsets, cr3, lma, gs_base).ok())
+    })
+}
+
 #[no_mangle]
-/// Callback for handling coverage events
-pub extern "C" fn report_coverage(cr3: usize, lma: bool, gs_base: usize,
-        cs: u16, rip: usize, _rsp: usize) -> bool {
+/// Callback for handling coverage events. `cr3` is the raw CR3, it's masked
+/// for whichever paging mode `cr0`, `cr4` and `lma` say we're in
+pub extern "C" fn report_coverage(cr0: u64, cr3: u64, cr4: u64, lma: bool,
+        fs_base: usize, gs_base: usize, cs: u16, rip: usize,
+        _rsp: usize) -> bool {
     if COVERAGE_DISABLE { return false; }
 
-    // Fast path, we only collect 64-bit coverage right now
-    if !lma { return false; }
+    // Legacy, PAE and long mode (including compatibility mode) all work, but
+    // without paging there are no module lists to work with
+    let paging = Paging::new(cr0, cr4, lma);
+    if paging == Paging::Disabled { return false; }
+    let cr3 = paging.table_root(cr3) as usize;
 
     // Obtain the thread local
     PERSIST.with(|x| {
@@ -697,109 +913,98 @@ pub extern "C" fn report_coverage(cr3: usize, lma: bool, gs_base: usize,
 
         persist.stats.coverage_callbacks += 1;
 
-        let mut new_coverage = false;
-
-        // Quick and dirty coverage
-        let kml = persist.kernel_module_list;
+        // Drop coverage from processes other than the target process
+        if !process_allowed(persist, cr3, lma, gs_base, cs) { return false; }
 
-        // Split structure references to help with borrowck
-        let mlc      = &mut persist.module_list_cache;
-        let coverage = &mut persist.coverage;
-        let memory   = &mut persist.memory;
-        let stats    = &mut persist.stats;
+        // Get the module offset for this RIP. If it didn't resolve from the
+        // cache, rewalk the module list to check for updates
+        if persist.module_list_cache.get_modoff(rip).0.is_none() &&
+                !walk_modules(persist, paging, cr3, fs_base, gs_base, cs) {
+            // Couldn't resolve module and couldn't update module list
+            // we can't do anything at this point
+            return false;
+        }
 
-        // Get the module offset for this RIP
-        let mut cached = mlc.get_modoff(rip);
+        // The module list cache is moved out while the coverage is recorded,
+        // so the module can be borrowed from it alongside everything else
+        let mlc = std::mem::take(&mut persist.module_list_cache);
+        let new_coverage = match mlc.get_modoff(rip) {
+            (Some(module), offset) => record_coverage(persist, module, offset),
+            _ => false, // Unknown module
+        };
+        persist.module_list_cache = mlc;
 
-        if cached.0.is_none() {
-            // Module didn't resolve from the cache, rewalk the module list
-            // to check for updates
-            stats.module_list_walks += 1;
-            if let Ok(ml) = get_modlist(memory, cr3, lma, gs_base, cs, kml) {
-                //print!("Updating module list cache\n");
-                *mlc = ml;
-                cached = mlc.get_modoff(rip);
-            } else {
-                // Couldn't resolve module and couldn't update module list
-                // we can't do anything at this point
-                return false;
+        // If we got new coverage, emulate for longer
+        if new_coverage {
+            let current_vp = persist.current_vp as usize;
+            if let Some(vp) = persist.vps.get_mut(current_vp) {
+                vp.emulating += 100;
             }
         }
 
-        // If we were able to resolve the module, report the coverage
-        if let (Some(module), offset) = cached {
-            let size    = module.size() as usize;
-            let ordinal = module.ordinal() as usize;
-
-            // Make sure there are enough entries in the coverage list for our
-            // ordinal to be valid
-            while ordinal >= coverage.len() {
-                coverage.push(None);
-            }
+        new_coverage
+    })
+}
 
-            // The above loop makes sure the ordinal is a valid index into
-            // coverage but it is still potentially a None value.
-            if coverage[ordinal].is_none() {
-                // Get the image size rounded up to the nearest byte boundary
-                let imagesize = (size + 7) & !7;
+/// Returns `true` if any kind of coverage is being collected
+fn coverage_enabled(persist: &PersistState) -> bool {
+    !COVERAGE_DISABLE || persist.breakpoints.is_some()
+}
 
-                let covent = CoverageEntry {
-                    bitmap: vec![0u8; imagesize],
-                    unique: 0,
-                };
+/// Place coverage breakpoints in the modules of a processor in `state` if it's
+/// running somewhere the module list cache doesn't know about
+fn sync_breakpoints(persist: &mut PersistState, state: &CpuState) {
+    let rip = state.linear_rip() as usize;
+    if persist.module_list_cache.get_modoff(rip).0.is_some() { return; }
 
-                coverage[ordinal] = Some(covent);
-            }
+    let paging = state.paging();
+    if paging == Paging::Disabled { return; }
+    walk_modules(persist, paging, state.cr3() as usize, state.fs.base as usize,
+        state.gs.base as usize, state.cs.selector);
+}
 
-            // Get a mutable reference to this corresponding coverage entry
-            let covent = coverage[ordinal].as_mut().unwrap();
-
-            // Calculate bitmap offsets
-            let byte = offset / 8;
-            let bit  = offset % 8;
-
-            // Check if we've seen this offset before
-            if (covent.bitmap[byte] & (1 << bit)) == 0 {
-                // Record that we got new coverage so we can return that
-                // information to the caller
-                new_coverage = true;
-
-                // Update bitmap
-                covent.bitmap[byte] |= 1 << bit;
-
-                // Update unique count
-                covent.unique += 1;
-
-                // Only use symbol coverage if requested
-                if LOG_COVERAGE_SYMBOLS {
-                    // Try to look up the symbol for this new coverage
-                    if let Some(sym) = persist.symbols.resolve(module, offset) {
-                        // Create the log file if it's not already open
-                        if persist.coverage_log_file.is_none() {
-                            persist.coverage_log_file = Some(
-                                File::create("coverage.txt")
-                                    .expect("Failed to open coverage output")
-                            );
-                        }
+/// Handle an `int3` at `rip` of a processor using page table `cr3` in `paging`
+/// mode, with `gs_base` and code selector `cs`. Returns `true` if it was one
+/// of our coverage breakpoints, in which case the original byte is back and
+/// the block is recorded if it passes the process filter
+fn hit_breakpoint(persist: &mut PersistState, paging: Paging, cr3: usize,
+        gs_base: usize, cs: u16, rip: usize) -> bool {
+    let hit = match persist.breakpoints.as_mut() {
+        Some(breakpoints) =>
+            breakpoints.hit(&mut persist.memory, paging, cr3, rip),
+        None => None,
+    };
 
-                        // Write the symbol to the log file
-                        let clf = persist.coverage_log_file.as_mut().unwrap();
-                        clf.write(format!("{}\n", sym).as_bytes())
-                            .expect("Failed to write coverage entry");
-                        clf.flush().expect("Failed to flush coverage file");
-                    }
-                }
+    match hit {
+        Some((ordinal, base, offset)) => {
+            // Other processes running the block don't count, it gets a
+            // breakpoint again for the target to hit
+            let lma = paging == Paging::Long;
+            if !process_allowed(persist, cr3, lma, gs_base, cs) {
+                persist.breakpoints.as_mut().unwrap()
+                    .requeue(ordinal, base, offset);
+                return true;
             }
-        } else {
-            // Unknown module
+            if let Some(module) = win32::ordinal_to_modinfo(ordinal) {
+                record_coverage(persist, &module, offset as usize);
+            }
+            true
         }
+        None => false,
+    }
+}
 
-        // If we got new coverage, emulate for longer
-        if new_coverage {
-            persist.emulating += 100;
-        }
+#[no_mangle]
+/// Callback for Bochs executing an `int3` at `rip`. Returns `true` if it was
+/// one of our coverage breakpoints, in which case Bochs runs the instruction
+/// it replaced instead
+pub extern "C" fn report_breakpoint(cr0: u64, cr3: u64, cr4: u64, lma: bool,
+        gs_base: usize, cs: u16, rip: usize) -> bool {
+    let paging = Paging::new(cr0, cr4, lma);
+    let cr3 = paging.table_root(cr3) as usize;
 
-        new_coverage
+    PERSIST.with(|x| {
+        hit_breakpoint(&mut *x.borrow_mut(), paging, cr3, gs_base, cs, rip)
     })
 }
 
@@ -905,12 +1110,36 @@ fn restore(orig_memory: &[u8], memory: &mut [u8],
         // WHVP dev to optimize their dirty list API. I requested to get 10k
         // per second for querying an empty dirty list. Hopefully we get that :D
         // I've got some workaround ideas for this
-        persist.hypervisor.as_mut().unwrap().get_dirty_list(
+        let dirty = persist.hypervisor.as_mut().unwrap().get_dirty_list(
             dirty_bits_l1, dirty_bits_l2);
+        if dirty.is_err() {
+            // We don't know what the hypervisor dirtied, so restore all of it.
+            // Slow, but it beats killing a fuzzer over a flaky query
+            for ent in dirty_bits_l1.iter_mut() { *ent = !0; }
+            for ent in dirty_bits_l2.iter_mut() { *ent = !0; }
+        }
+
+        // Mark the pages we wrote ourselves as dirty
+        for paddr in persist.host_dirty.drain(..) {
+            let l1 = paddr / (1024 * 1024);
+            let l2 = paddr / 4096;
+            if let (Some(l1ent), Some(l2ent)) = (
+                    dirty_bits_l1.get_mut(l1 / 64),
+                    dirty_bits_l2.get_mut(l2 / 64)) {
+                *l1ent |= 1 << (l1 % 64);
+                *l2ent |= 1 << (l2 % 64);
+            }
+        }
 
         // Restore memory
         reset_dirty_pages(orig_memory, memory, dirty_bits_l1, dirty_bits_l2);
 
+        // The restore wiped out any coverage breakpoints in dirty pages
+        let persist = &mut *persist;
+        if let Some(breakpoints) = persist.breakpoints.as_mut() {
+            breakpoints.reapply(&mut persist.memory);
+        }
+
         // Restore disk
         disk::vdisk_discard_changes();
 
@@ -941,16 +1170,647 @@ fn _benchmark_restore(orig_memory: &[u8], memory: &mut [u8],
     }
 }
 
+/// Force every VP out of the hypervisor and sync their state back into Bochs.
+/// This is needed before anything which looks at the Bochs state of every
+/// processor, like taking a snapshot.
+///
+/// The exits this causes are thrown away, whatever caused them just happens
+/// again when the VP is next run
+fn stop_all_vps(persist: &mut PersistState, routines: &BochsRoutines) {
+    let cancel = persist.hypervisor.as_ref().unwrap().canceller();
+
+    while persist.vps.iter().any(|vp| vp.running) {
+        // Kick everything which is still running
+        if let Some(cancel) = &cancel {
+            for (ii, vp) in persist.vps.iter().enumerate() {
+                if vp.running { cancel(ii as u32); }
+            }
+        }
+
+        // Wait for a VP to exit
+        let (vp, _) = persist.vm_exits.as_ref().unwrap().recv()
+            .expect("VP run threads went away");
+        KICKER_ACTIVE.fetch_and(!(1 << vp), Ordering::SeqCst);
+        persist.vps[vp as usize].running = false;
+
+        // Sync hypervisor register state to Bochs register state
+        let context = persist.hypervisor.as_ref().unwrap().get_context(vp)
+            .unwrap_or_else(|err| fatal_hypervisor_error(&err));
+        (routines.set_context)(vp, &context);
+    }
+}
+
+/// Device states which get saved in applepie snapshots. File pointers are
+/// only meaningful in the process which registered them, so they're skipped
+fn snapshot_devices<'a>(devices: &'a [DeviceState])
+        -> impl Iterator<Item = &'a DeviceState> {
+    devices.iter().filter(|x| x.typ != ShadowType::Fileptr)
+}
+
+/// Capture the guest state into a `Snapshot`. Every processor must be out of
+/// the hypervisor with its state synced into Bochs, see `stop_all_vps`
+///
+/// Device state must not have been merged yet, as the names and order of the
+/// device states are what we match on when booting the snapshot
+fn capture_snapshot<'a>(routines: &BochsRoutines, num_cpus: u32,
+        memory: &'a [u8]) -> Snapshot<'a> {
+    // Grab the current contents of every device state
+    let devices = DEVICE_STATE.with(|x| {
+        snapshot_devices(&x.borrow()).map(|devstate| {
+            let sliced = unsafe {
+                std::slice::from_raw_parts(
+                    devstate.addr as *const u8, devstate.len)
+            };
+            (devstate.name.clone(), sliced.to_vec())
+        }).collect()
+    });
+
+    // Grab the state of every processor
+    let contexts = (0..num_cpus).map(|cpu| {
+        let mut context = VpContext::default();
+        (routines.get_context)(cpu, &mut context);
+        context
+    }).collect();
+
+    Snapshot {
+        memory: Cow::Borrowed(memory),
+        devices,
+        contexts,
+        disk: disk::vdisk_changed_sectors(),
+    }
+}
+
+/// Write `data` to guest virtual memory at `vaddr` using page table `cr3`.
+/// Returns number of bytes written.
+///
+/// Neither Bochs nor the hypervisor see these writes, so the physical pages
+/// written are recorded in `host_dirty` to be restored with everything else
+fn write_guest_virt(memory: &mut MemReader, host_dirty: &mut Vec<usize>,
+        cr3: usize, vaddr: usize, data: &[u8]) -> usize {
+    let written = memory.write_virt(cr3, vaddr, data);
+
+    let mut page = vaddr & !0xfff;
+    while page < vaddr + written {
+        let mut table = unsafe {
+            virtmem::PageTable::from_existing(cr3 as *mut u64, memory)
+        };
+        if let Ok(Some((paddr, _))) =
+                table.virt_to_phys_dirty(page as u64, false) {
+            host_dirty.push(paddr as usize & !0xfff);
+        }
+        page += 4096;
+    }
+
+    written
+}
+
+/// Handle hypercall `call` made by the processor with state `context`. The
+/// results are written to `context` for the caller to write back.
+///
+/// Snapshots never return so they're taken by the caller once the results
+/// are written back, this only reports whether it's possible. `live` is set
+/// when we're running live rather than fuzzing from a snapshot, which is
+/// the only time snapshots can be taken
+fn handle_hypercall(persist: &mut PersistState, context: &mut VpContext,
+        call: Hypercall, live: bool) {
+    let cr3 = context.cr3() as usize;
+    let mut retval = 0u64;
+
+    let status = match call {
+        Hypercall::Print { addr, len } => {
+            let mut buf = vec![0u8; std::cmp::min(len, HYPERCALL_MAX_PRINT)
+                as usize];
+            if buf.is_empty() || persist.memory.read_virt(cr3, addr as usize,
+                    &mut buf) == buf.len() {
+                print!("Guest: {}\n", String::from_utf8_lossy(&buf));
+                HC_STATUS_SUCCESS
+            } else {
+                HC_STATUS_BAD_ADDRESS
+            }
+        }
+        Hypercall::Snapshot => {
+            if live { HC_STATUS_SUCCESS } else { HC_STATUS_UNSUPPORTED }
+        }
+        Hypercall::GetInput { addr, size } => {
+            let input = &persist.fuzz_input;
+            let to_copy = std::cmp::min(size as usize, input.len());
+            retval = input.len() as u64;
+            if to_copy == 0 || write_guest_virt(&mut persist.memory,
+                    &mut persist.host_dirty, cr3, addr as usize,
+                    &input[..to_copy]) == to_copy {
+                HC_STATUS_SUCCESS
+            } else {
+                HC_STATUS_BAD_ADDRESS
+            }
+        }
+        Hypercall::ReportCrash { code, addr } => {
+            print!("Guest reported crash {:#x} at {:#x}\n", code, addr);
+            persist.guest_reported = true;
+            if persist.fuzzer.is_some() {
+                let rsp = unsafe { context.rsp.Reg64 };
+                record_crash(persist, context, CrashKind::Guest, code, addr,
+                    rsp);
+            }
+            HC_STATUS_SUCCESS
+        }
+        Hypercall::ReportResult { result } => {
+            // Every case reports one, only print them when running live
+            if persist.fuzzer.is_none() {
+                print!("Guest reported result {:#x}\n", result);
+            }
+            persist.guest_reported = true;
+            HC_STATUS_SUCCESS
+        }
+        Hypercall::Invalid { opcode } => {
+            print!("Guest made unknown hypercall {}\n", opcode);
+            HC_STATUS_INVALID_OPCODE
+        }
+    };
+
+    persist.stats.hypercalls += 1;
+
+    context.rax.Reg64 = status;
+    context.rbx.Reg64 = HYPERCALL_SIGNATURE as u64;
+    context.rcx.Reg64 = retval;
+}
+
+/// Start a new fuzz case from the snapshot state. Every processor must be out
+/// of the hypervisor and the guest restored.
+///
+/// This picks the next input and writes it into the guest if an input buffer
+/// is configured, re-arms coverage, and puts the end RIP breakpoint on every
+/// processor
+fn start_fuzz_case(persist: &mut PersistState, routines: &BochsRoutines,
+        num_cpus: u32) {
+    let fuzzer = persist.fuzzer.as_mut().unwrap();
+    fuzzer.begin_case();
+    persist.fuzz_input     = fuzzer.next_input();
+    persist.guest_reported = false;
+
+    // Modules loaded during the last case are gone now, drop them from the
+    // module list cache so coverage doesn't land on them
+    persist.module_list_cache = ModuleList::default();
+    persist.case_coverage.clear();
+    if let Some(filter) = persist.process_filter.as_mut() {
+        filter.reset();
+    }
+
+    for vp in persist.vps.iter_mut() {
+        vp.emulating = 0;
+    }
+
+    let mut context = VpContext::default();
+
+    // Write the input into the guest buffer
+    if let Some((vaddr, max_size)) = fuzzer.config.input {
+        (routines.get_context)(0, &mut context);
+
+        let input = &persist.fuzz_input[..std::cmp::min(max_size,
+            persist.fuzz_input.len())];
+        if !input.is_empty() && write_guest_virt(&mut persist.memory,
+                &mut persist.host_dirty, context.cr3() as usize, vaddr,
+                input) != input.len() {
+            print!("Fuzz input buffer at {:#x} is not mapped\n", vaddr);
+            std::process::exit(-1);
+        }
+    }
+
+    // Break on the end RIP with DR0 and on the crash handlers with DR1 and
+    // DR2. These are all execute breakpoints
+    let breakpoints = [fuzzer.config.end_rip(), fuzzer.bugcheck_bp,
+        fuzzer.user_exception_bp];
+    if breakpoints.iter().any(|x| x.is_some()) {
+        for cpu in 0..num_cpus {
+            (routines.get_context)(cpu, &mut context);
+            let mut dr7 = unsafe { context.dr7.Reg64 };
+            for (ii, addr) in breakpoints.iter().enumerate() {
+                let addr = match addr {
+                    Some(addr) => *addr,
+                    None => continue,
+                };
+                match ii {
+                    0 => context.dr0.Reg64 = addr,
+                    1 => context.dr1.Reg64 = addr,
+                    _ => context.dr2.Reg64 = addr,
+                }
+
+                // Local enable with a zero type and length, which is execute
+                dr7 &= !(0xf << (16 + ii * 4)) & !(3 << (ii * 2));
+                dr7 |= 1 << (ii * 2);
+            }
+            context.dr7.Reg64 = dr7;
+            context.dr6.Reg64 = 0xffff0ff0;
+            (routines.set_context)(cpu, &context);
+        }
+    }
+}
+
+/// Symbolize `addr` with the modules in `modlist`. This is `module!symbol+off`
+/// if we have symbols, otherwise `module+off`
+fn symbolize(symbols: &mut Symbols, modlist: &ModuleList, addr: usize)
+        -> String {
+    match modlist.get_modoff(addr) {
+        (Some(module), offset) => symbols.resolve(module, offset)
+            .unwrap_or_else(|| modlist.get_modoff_string(addr)),
+        _ => modlist.get_modoff_string(addr),
+    }
+}
+
+/// Find the address of `module!symbol` in `modlist`
+fn find_symbol(symbols: &mut Symbols, modlist: &ModuleList, module: &str,
+        symbol: &str) -> Option<u64> {
+    modlist.modules().iter()
+        .find(|x| x.info().name().eq_ignore_ascii_case(module))
+        .and_then(|x| symbols.lookup(x.info(), symbol)
+            .map(|offset| (x.base() + offset) as u64))
+}
+
+/// Find `nt!KeBugCheckEx` and `ntdll!KiUserExceptionDispatcher` so crashes in
+/// Windows guests can be caught with breakpoints. The kernel modules come from
+/// `nt!PsLoadedModuleList`, ntdll from the PEB of any processor which was in
+/// user mode when the snapshot was taken
+fn resolve_crash_breakpoints(persist: &mut PersistState,
+        routines: &BochsRoutines, num_cpus: u32) {
+    let mut context = VpContext::default();
+
+    for cpu in 0..num_cpus {
+        (routines.get_context)(cpu, &mut context);
+        let state = CpuState::from(&context);
+        let cr3   = state.cr3() as usize;
+
+        if persist.kernel_module_list.is_none() {
+            persist.kernel_module_list = find_kernel_modlist(cr3,
+                state.paging(), state.kpcr() as usize, 0,
+                &mut persist.memory).ok();
+        }
+
+        let fuzzer = persist.fuzzer.as_mut().unwrap();
+
+        if fuzzer.bugcheck_bp.is_none() {
+            if let Ok(ml) = get_modlist(&mut persist.memory, cr3,
+                    state.paging(), state.kpcr() as usize,
+                    state.kpcr() as usize, 0, persist.kernel_module_list) {
+                fuzzer.bugcheck_bp = find_symbol(&mut persist.symbols, &ml,
+                    "ntoskrnl.exe", "KeBugCheckEx");
+            }
+        }
+
+        if fuzzer.user_exception_bp.is_none() && state.cpl() == 3 {
+            if let Ok(ml) = get_modlist(&mut persist.memory, cr3,
+                    state.paging(), state.fs.base as usize,
+                    state.gs.base as usize, state.cs.selector, None) {
+                fuzzer.user_exception_bp = find_symbol(&mut persist.symbols,
+                    &ml, "ntdll.dll", "KiUserExceptionDispatcher");
+            }
+        }
+    }
+
+    let fuzzer = persist.fuzzer.as_ref().unwrap();
+    print!("Crash breakpoints: nt!KeBugCheckEx {:x?} | \
+            ntdll!KiUserExceptionDispatcher {:x?}\n",
+        fuzzer.bugcheck_bp, fuzzer.user_exception_bp);
+}
+
+/// Record a crash in the current fuzz case found on the processor with state
+/// `context`. `code` depends on the `kind` of crash, `addr` is the faulting
+/// instruction, and `rsp` is the stack to look for return addresses on.
+///
+/// Only the first crash of a case is kept, anything after it is likely fallout
+/// of the first one
+fn record_crash(persist: &mut PersistState, context: &VpContext,
+        kind: CrashKind, code: u64, addr: u64, rsp: u64) {
+    if persist.fuzzer.as_ref().unwrap().crash.is_some() { return; }
+
+    let state = CpuState::from(context);
+    let cr3   = state.cr3() as usize;
+
+    // Modules of whatever mode the processor is in
+    let modlist = get_modlist(&mut persist.memory, cr3, state.paging(),
+        state.fs.base as usize, state.gs.base as usize, state.cs.selector,
+        persist.kernel_module_list).unwrap_or_default();
+
+    let symbols = &mut persist.symbols;
+    let memory  = &mut persist.memory;
+
+    // Scan the stack for return addresses. Anything which points into a
+    // module right after a call instruction is probably one
+    let mut frames = vec![symbolize(symbols, &modlist, addr as usize)];
+    for slot in 0..crash::CRASH_STACK_SCAN {
+        if frames.len() >= crash::CRASH_FRAMES { break; }
+
+        let ret = match memory.read_virt_usize(cr3, rsp as usize + slot * 8) {
+            Ok(ret) => ret,
+            Err(_)  => break,
+        };
+
+        let mut prefix = [0u8; 7];
+        if modlist.get_modoff(ret).0.is_none() ||
+                memory.read_virt(cr3, ret.wrapping_sub(prefix.len()),
+                    &mut prefix) != prefix.len() ||
+                !crash::is_return_address(&prefix) {
+            continue;
+        }
+
+        frames.push(symbolize(symbols, &modlist, ret));
+    }
+
+    persist.fuzzer.as_mut().unwrap().crash = Some(Crash {
+        kind, code, addr, frames,
+        context: format!("{}", state),
+    });
+}
+
+/// Check which of the breakpoints set by `start_fuzz_case` fired on the
+/// processor with state `context`, based on DR6
+fn check_breakpoints(persist: &mut PersistState, context: &VpContext) {
+    let dr6 = unsafe { context.dr6.Reg64 };
+    let cr3 = context.cr3() as usize;
+    let rsp = unsafe { context.rsp.Reg64 };
+
+    let fuzzer = match persist.fuzzer.as_mut() {
+        Some(fuzzer) => fuzzer,
+        None => return,
+    };
+
+    if (dr6 & 1) != 0 && fuzzer.config.end_rip().is_some() {
+        fuzzer.end_rip_hit = true;
+    }
+
+    let bugcheck       = (dr6 & 2) != 0 && fuzzer.bugcheck_bp.is_some();
+    let user_exception = (dr6 & 4) != 0 && fuzzer.user_exception_bp.is_some();
+
+    // KeBugCheckEx(code, param1, param2, param3, param4), the last parameter
+    // is on the stack past the return address and the home space
+    if bugcheck {
+        let memory = &mut persist.memory;
+        let ret    = memory.read_virt_u64(cr3, rsp as usize).unwrap_or(0);
+        let param4 = memory.read_virt_u64(cr3, rsp as usize + 0x28)
+            .unwrap_or(0);
+        let (code, params) = unsafe {
+            (context.rcx.Reg64, [context.rdx.Reg64, context.r8.Reg64,
+                context.r9.Reg64, param4])
+        };
+
+        let addr = crash::bugcheck_address(code, params).unwrap_or(ret);
+        record_crash(persist, context, CrashKind::BugCheck, code, addr,
+            rsp + 8);
+    }
+
+    // KiUserExceptionDispatcher is entered with the CONTEXT and
+    // EXCEPTION_RECORD of the exception on the stack
+    if user_exception {
+        let memory = &mut persist.memory;
+        let record = rsp as usize + crash::USER_EXCEPTION_RECORD;
+        let code   = memory.read_virt_u32(cr3, record);
+        let addr   = memory.read_virt_u64(cr3,
+            record + crash::EXCEPTION_RECORD_ADDRESS);
+        let stack  = memory.read_virt_u64(cr3,
+            rsp as usize + crash::CONTEXT_RSP);
+
+        if let (Ok(code), Ok(addr), Ok(stack)) = (code, addr, stack) {
+            if crash::is_crash_status(code) {
+                record_crash(persist, context, CrashKind::UserException,
+                    code as u64, addr, stack);
+            }
+        }
+    }
+}
+
+/// Handle the breakpoints set by `start_fuzz_case` which Bochs stopped
+/// stepping on. Bochs puts them in DR6 of `context` like a #DB exit would,
+/// see `step_cpu()` in Bochs. Returns `true` if there were any, in which case
+/// they're cleared from DR6 and `context` has to be written back
+fn emulated_breakpoints(persist: &mut PersistState, context: &mut VpContext)
+        -> bool {
+    if unsafe { context.dr6.Reg64 } & 0xf == 0 { return false; }
+
+    check_breakpoints(persist, context);
+    unsafe { context.dr6.Reg64 &= !0xf; }
+    true
+}
+
+/// Check for fuzzing breakpoints Bochs stopped on while stepping processor
+/// `vp`
+fn sync_emulated_breakpoints(persist: &mut PersistState,
+        routines: &BochsRoutines, vp: u32) {
+    if persist.fuzzer.is_none() { return; }
+
+    let mut context = VpContext::default();
+    (routines.get_context)(vp, &mut context);
+    if emulated_breakpoints(persist, &mut context) {
+        (routines.set_context)(vp, &context);
+    }
+}
+
+/// Save the current fuzz case as a hang. Hangs are bucketed by the final RIP
+/// of the processor we were working on. Every processor must be out of the
+/// hypervisor
+fn record_hang(persist: &mut PersistState, routines: &BochsRoutines) {
+    let mut context = VpContext::default();
+    (routines.get_context)(persist.current_vp, &mut context);
+    let state = CpuState::from(&context);
+
+    let modlist = get_modlist(&mut persist.memory, state.cr3() as usize,
+        state.paging(), state.fs.base as usize, state.gs.base as usize,
+        state.cs.selector, persist.kernel_module_list).unwrap_or_default();
+
+    let hang = Crash {
+        kind:    CrashKind::Hang,
+        code:    0,
+        addr:    context.rip(),
+        frames:  vec![modlist.get_modoff_string(context.rip() as usize)],
+        context: format!("{}", state),
+    };
+    persist.fuzzer.as_mut().unwrap().found_hang(&persist.fuzz_input, &hang);
+}
+
+/// Finish the current fuzz case. Inputs which found new coverage go into the
+/// corpus, and crashes and hangs are bucketed. Every processor must be out of
+/// the hypervisor
+fn end_fuzz_case(persist: &mut PersistState, routines: &BochsRoutines) {
+    persist.stats.num_fuzz_cases += 1;
+
+    // Minimizing only cares about which bucket the case crashed in
+    if persist.fuzzer.as_ref().unwrap().minimizing() {
+        if persist.fuzzer.as_mut().unwrap().minimize_case() {
+            std::process::exit(0);
+        }
+        return;
+    }
+
+    persist.fuzzer.as_mut().unwrap().found_crash(&persist.fuzz_input);
+    if persist.fuzzer.as_ref().unwrap().hang.is_some() {
+        record_hang(persist, routines);
+    }
+
+    let fuzzer = persist.fuzzer.as_mut().unwrap();
+    let new_edges = fuzzer.edges.as_mut().map(|x| x.finish_case())
+        .unwrap_or_default();
+    if !persist.case_coverage.is_empty() || !new_edges.is_empty() {
+        fuzzer.found_coverage(&persist.fuzz_input, &persist.case_coverage,
+            &new_edges);
+    }
+}
+
+/// Take a snapshot of the guest into a new `snapshot_<time>` folder. Along
+/// with the Bochs snapshot this saves our own snapshot file, an ELF core, and
+/// a crash dump for Windows guests. The Bochs snapshot never returns
+fn take_snapshot(persist: &mut PersistState, routines: &BochsRoutines,
+        num_cpus: u32, memory: &[u8], vp: u32) -> ! {
+    // The processor which asked for the snapshot
+    let mut context = VpContext::default();
+    (routines.get_context)(vp, &mut context);
+
+    let uptime_since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+    // Construct a filename for the folder
+    let snapshot_folder_name = format!("snapshot_{:?}", uptime_since_epoch);
+    let folder = Path::new(&snapshot_folder_name);
+
+    print!("Taking snapshot to: {}\n", snapshot_folder_name);
+
+    // Get the state of every processor into Bochs
+    stop_all_vps(persist, routines);
+
+    // Snapshots shouldn't have our coverage breakpoints in them
+    if let Some(breakpoints) = persist.breakpoints.as_mut() {
+        breakpoints.remove_all(&mut persist.memory);
+    }
+
+    // Make snapshot folder
+    std::fs::create_dir(folder).expect("Snapshot already exists");
+
+    // Save our own snapshot file first, as the Bochs snapshot never returns.
+    // We're running live so the disk is non-volatile, the disk image itself
+    // holds the disk state
+    capture_snapshot(routines, num_cpus, memory)
+        .save(folder.join(SNAPSHOT_FILE_NAME))
+        .expect("Failed to save snapshot");
+
+    // Save an ELF core so any guest can be looked at with gdb and friends
+    let cpu_states: Vec<CpuState> = (0..num_cpus).map(|cpu| {
+        let mut context = VpContext::default();
+        (routines.get_context)(cpu, &mut context);
+        CpuState::from(&context)
+    }).collect();
+    let cpu_state = CpuState::from(&context);
+    let layout = match std::env::var(CORE_LAYOUT_ENV_VAR).as_ref()
+            .map(|x| x.as_str()) {
+        Ok("physical") => Ok(CoreLayout::Physical),
+        Ok("virtual") | Err(_) => {
+            if cpu_state.paging() == Paging::Long && !cpu_state.la57() {
+                Ok(CoreLayout::Virtual(cpu_state.cr3() as usize))
+            } else {
+                Ok(CoreLayout::Physical)
+            }
+        }
+        Ok(layout) => Err(format!("Unknown core layout `{}`", layout)),
+    };
+    let core = layout.and_then(|layout| {
+        ElfCore { cpus: &cpu_states, layout }
+            .save(&mut persist.memory, folder.join(ELF_CORE_FILE_NAME))
+            .map_err(|x| x.to_string())
+    });
+    if let Err(err) = core {
+        print!("Failed to save ELF core: {}\n", err);
+    }
+
+    // Save a crash dump so the snapshot can be opened in WinDbg. This needs
+    // the kernel module list so it's only done for Windows guests, and the
+    // dump writer only knows 64-bit Windows
+    let kml = persist.kernel_module_list.or_else(|| {
+        find_kernel_modlist(cpu_state.cr3() as usize, cpu_state.paging(),
+            cpu_state.kpcr() as usize, 0, &mut persist.memory).ok()
+    }).filter(|_| cpu_state.lma());
+    if let Some(kml) = kml {
+        let dump = CrashDump {
+            cpu:      &cpu_state,
+            num_cpus,
+            ps_loaded_module_list: kml,
+            bugcheck: (MANUALLY_INITIATED_CRASH, [0; 4]),
+        };
+        if let Err(err) = dump.save(&mut persist.memory,
+                folder.join(CRASH_DUMP_FILE_NAME)) {
+            print!("Failed to save crash dump: {}\n", err);
+        }
+    }
+
+    // Cause a Bochs snapshot!
+    let folder_name_cstr = CString::new(snapshot_folder_name.as_str())
+        .expect("Couldn't convert to cstring");
+    (routines.take_snapshot)(folder_name_cstr.as_ptr());
+}
+
+/// Boot into the applepie snapshot at `path`. This replaces guest memory,
+/// device state, processor state, and disk contents with the ones in the
+/// snapshot.
+///
+/// Returns the address of the snapshot memory, which lives forever and is the
+/// original memory we restore to
+fn boot_snapshot(routines: &BochsRoutines, num_cpus: u32, memory: &mut [u8],
+        path: &str) -> usize {
+    print!("Booting from snapshot: {}\n", path);
+
+    let snapshot = Snapshot::load(path).expect("Failed to load snapshot");
+    assert!(snapshot.memory.len() == memory.len(),
+        "Snapshot memory size does not match Bochs memory size");
+    assert!(snapshot.contexts.len() == num_cpus as usize,
+        "Snapshot processor count does not match Bochs processor count");
+
+    // Restore all device states. These must line up exactly with what Bochs
+    // registered, which is the case as long as the Bochs build and config
+    // match the ones the snapshot was taken with
+    DEVICE_STATE.with(|x| {
+        let x = x.borrow();
+        let mut saved = snapshot.devices.iter();
+
+        for devstate in snapshot_devices(&x) {
+            let (name, data) = saved.next()
+                .expect("Snapshot is missing device state");
+            assert!(*name == devstate.name && data.len() == devstate.len,
+                "Snapshot device state mismatch for {}", devstate.name);
+
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(),
+                    devstate.addr as *mut u8, devstate.len);
+            }
+        }
+
+        assert!(saved.next().is_none(), "Snapshot has extra device state");
+    });
+
+    // Restore memory and disk
+    memory.copy_from_slice(&snapshot.memory);
+    disk::vdisk_load_snapshot(&snapshot.disk);
+
+    // Let devices pick up their new state. This is only safe to do once, which
+    // is fine as we only boot a snapshot once
+    (routines.after_restore)();
+
+    // Restore processors last so nothing above clobbers them
+    for (cpu, context) in snapshot.contexts.iter().enumerate() {
+        (routines.set_context)(cpu as u32, context);
+    }
+
+    Box::leak(snapshot.memory.into_owned().into_boxed_slice()).as_ptr() as usize
+}
+
 /// Rust CPU loop for Bochs which uses both emulation and hypervisor for running
 /// a guest
 #[no_mangle]
-pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
-        dirty_bits_l1: usize, dirty_bits_l2: usize, bochs_memory_base: usize,
-        original_memory_base: usize) {
+pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, num_cpus: u32,
+        pmem_size: u64, dirty_bits_l1: usize, dirty_bits_l2: usize,
+        bochs_memory_base: usize, original_memory_base: usize) {
     /// Make sure the physical memory size reported by Bochs is sane
     assert!(pmem_size & 0xfff == 0,
         "Physical memory size was not 4 KiB aligned");
 
+    // Every VP needs a bit in `KICKER_ACTIVE`
+    assert!(num_cpus > 0 &&
+        (num_cpus as usize) <= std::mem::size_of::<usize>() * 8,
+        "Unsupported number of processors {}", num_cpus);
+
     // Lock further device state registration
     let first_run = !DEVICE_STATE_LOCKED.with(|locked| locked.replace(true));
 
@@ -960,6 +1820,22 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
             pmem_size as usize)
     };
 
+    // Boot into an applepie snapshot file if one was requested and Bochs
+    // didn't already restore a snapshot of its own
+    if first_run && original_memory_base == 0 {
+        if let Ok(path) = std::env::var(SNAPSHOT_ENV_VAR) {
+            let base = boot_snapshot(routines, num_cpus, memory, &path);
+            SNAPSHOT_MEMORY_BASE.with(|x| x.set(base));
+        }
+    }
+
+    // If we booted from an applepie snapshot that's our original memory
+    let original_memory_base = if original_memory_base == 0 {
+        SNAPSHOT_MEMORY_BASE.with(|x| x.get())
+    } else {
+        original_memory_base
+    };
+
     // Get slice of original memory state if we're restoring from a snapshot.
     // If we're not in snapshot mode this will be `None`.
     let orig_memory = if original_memory_base > 0 { unsafe {
@@ -1066,19 +1942,46 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
         let mut persist = x.borrow_mut();
 
         // Create a context to be used for all register sync operations
-        let mut context = WhvpContext::default();
+        let mut context = VpContext::default();
 
         // Cache the TSC rate if it's not already been cached
         if persist.tickrate.is_none() {
             persist.tickrate = Some(time::calibrate_tsc());
         }
 
+        // Get the fuzzing configuration if it was requested. This needs a
+        // snapshot to restore to, which we only have once we've saved the
+        // original device state. The hypervisor needs to know which
+        // exceptions are crashes, so this is done before creating it
+        let fuzz_config = if first_run && orig_memory.is_some() {
+            FuzzConfig::from_env().unwrap_or_else(|err| {
+                print!("Invalid fuzzing configuration: {}\n", err);
+                std::process::exit(-1);
+            })
+        } else {
+            None
+        };
+
         // Run first-time initialization of the hypervisor and other context
         if persist.hypervisor.is_none() {
             print!("Creating hypervisor!\n");
 
+            persist.breakpoints = Breakpoints::from_env(module_image_file);
+
             // Create a new hypervisor :)
-            let mut new_hyp = Whvp::new();
+            let mut new_hyp: Box<dyn HypervisorBackend> = if SOFTWARE_BACKEND {
+                Box::new(SoftwareBackend::new(num_cpus))
+            } else {
+                // #DB is always trapped for breakpoints, and #BP for coverage
+                // breakpoints if there are any
+                let mut exceptions = (1 << crash::DEBUG_VECTOR) |
+                    fuzz_config.as_ref().map(|x| x.exceptions).unwrap_or(0);
+                if persist.breakpoints.is_some() {
+                    exceptions |= 1 << crash::BREAKPOINT_VECTOR;
+                }
+                Box::new(Whvp::new(num_cpus, exceptions)
+                    .unwrap_or_else(|err| fatal_hypervisor_error(&err)))
+            };
 
             // Memory regions of (paddr, backing memory, size in bytes)
             let mut mem_regions: Vec<MemoryRegion> = Vec::new();
@@ -1197,7 +2100,8 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                 };
 
                 // Map the memory :)
-                new_hyp.map_memory(mr.paddr, sliced, mr.perms);
+                new_hyp.map_memory(mr.paddr, sliced, mr.perms)
+                    .unwrap_or_else(|err| fatal_hypervisor_error(&err));
             }
 
             if DEVNULL_FRAMEBUFFERS {
@@ -1223,7 +2127,8 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                         persist.normal_fb.as_mut_ptr() as *mut u8,
                         std::mem::size_of_val(persist.normal_fb.as_slice()))
                 };
-                new_hyp.map_memory(0xa0000, sliced, PERM_READ | PERM_WRITE);
+                new_hyp.map_memory(0xa0000, sliced, PERM_READ | PERM_WRITE)
+                    .unwrap_or_else(|err| fatal_hypervisor_error(&err));
 
                 // Map in linear framebuffer
                 assert!(std::mem::size_of_val(
@@ -1233,14 +2138,30 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                         persist.linear_fb.as_mut_ptr() as *mut u8,
                         std::mem::size_of_val(persist.linear_fb.as_slice()))
                 };
-                new_hyp.map_memory(0xe0000000, sliced, PERM_READ | PERM_WRITE);
+                new_hyp.map_memory(0xe0000000, sliced, PERM_READ | PERM_WRITE)
+                    .unwrap_or_else(|err| fatal_hypervisor_error(&err));
             }
 
-            // Get the raw handle for this partition and create the kicker
-            // thread which is responsible for on an interval causing VMEXITS
-            // which gives us a chance to deliver interrupts
-            let handle = new_hyp.handle() as usize;
-            std::thread::spawn(move || kicker(handle));
+            // Create the kicker thread which is responsible for on an interval
+            // causing VMEXITS which gives us a chance to deliver interrupts
+            if let Some(cancel) = new_hyp.canceller() {
+                std::thread::spawn(move || kicker(cancel));
+            }
+
+            // Create a run thread for every VP. They all report their exits
+            // back to us over the same channel
+            let (run_senders, exit_receiver) = spawn_runners(&*new_hyp);
+            for run_sender in run_senders {
+                persist.vps.push(VirtualProcessor {
+                    run_request: run_sender,
+                    running:     false,
+                    run_start:   0,
+                    emulating:   0,
+                    consecutive_errors: 0,
+                    stats:       Default::default(),
+                });
+            }
+            persist.vm_exits = Some(exit_receiver);
 
             // Save the hypervisor into the persitent storage
             persist.hypervisor = Some(new_hyp);
@@ -1255,16 +2176,67 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
             // Save the time of hypervisor creation
             persist.start = time::rdtsc();
 
-            // Initialize VM cycle count
-            persist.vm_elapsed = 0;
-
             // Record the TSC value for the last time Bochs device state was
             // synced with the wall clock
             persist.last_sync_cycles = time::rdtsc();
+
+            // Pick up the coverage of earlier runs
+            if coverage_enabled(&persist) {
+                let path = coverage_db_path();
+                match sync_coverage_db(&mut persist.coverage, &path) {
+                    Ok(added) => print!("Loaded {} coverage offsets from {}\n",
+                        added, path),
+                    Err(err) => {
+                        print!("Failed to load coverage database: {}\n", err);
+                        std::process::exit(-1);
+                    }
+                }
+
+                persist.process_filter = ProcessFilter::from_env()
+                    .unwrap_or_else(|err| {
+                        print!("{}\n", err);
+                        std::process::exit(-1);
+                    });
+
+                // Reading processes with the wrong offsets would silently
+                // filter out everything, so give up on builds we don't know
+                if persist.process_filter.is_some() {
+                    let mut context = VpContext::default();
+                    (routines.get_context)(0, &mut context);
+                    let cr3 = CpuState::from(&context).cr3() as usize;
+                    let offsets = ProcessOffsets::from_guest(
+                            &mut persist.memory, cr3)
+                        .unwrap_or_else(|err| {
+                            print!("{}\n", err);
+                            std::process::exit(-1);
+                        });
+                    persist.process_offsets = Some(offsets);
+                }
+            }
+        }
+
+        // Start fuzzing if it was requested
+        if let Some(config) = fuzz_config {
+            print!("Fuzzing with {:?}, RNG seed {:#x}\n",
+                config.ends, config.rng_seed);
+            let fuzzer = Fuzzer::new(config).unwrap_or_else(|err| {
+                print!("{}\n", err);
+                std::process::exit(-1);
+            });
+            print!("Loaded {} corpus entries, {} known crashes\n",
+                fuzzer.corpus.len(), fuzzer.crashes.unique());
+            persist.fuzzer = Some(fuzzer);
+            resolve_crash_breakpoints(&mut persist, routines, num_cpus);
+            start_fuzz_case(&mut persist, routines, num_cpus);
         }
 
         // We expect on reentry that we try the hypervisor first
-        persist.emulating = 0;
+        for vp in persist.vps.iter_mut() {
+            vp.emulating = 0;
+        }
+
+        // VP we're working on, this is the one which most recently exited
+        let mut vp = persist.current_vp;
 
         loop {
             {
@@ -1296,7 +2268,34 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
 
                 // Tick devices along in Bochs to emulate the time that has
                 // passed
-                (routines.step_device)(elapsed_adj_cycles);
+                (routines.step_device)(vp, elapsed_adj_cycles);
+
+                // This is also how much guest time the fuzz case has used
+                if let Some(fuzzer) = persist.fuzzer.as_mut() {
+                    fuzzer.case_instructions += elapsed_adj_cycles;
+                }
+            }
+
+            // If the fuzz case is over, restore the guest and start the next
+            let tickrate = persist.tickrate.unwrap();
+            let reported = persist.guest_reported;
+            if persist.fuzzer.as_mut().map(|x| x.case_finished(
+                    tickrate, reported)) == Some(true) {
+                stop_all_vps(&mut persist, routines);
+                end_fuzz_case(&mut persist, routines);
+
+                let restore_start = time::rdtsc();
+                std::mem::drop(persist);
+                restore(orig_memory.as_ref().unwrap(), memory, dirty_bits_l1,
+                    dirty_bits_l2);
+                persist = x.borrow_mut();
+                persist.stats.restore_cycles += time::rdtsc() - restore_start;
+
+                start_fuzz_case(&mut persist, routines, num_cpus);
+
+                // Don't charge the restore to the next case
+                persist.last_sync_cycles = time::rdtsc();
+                continue;
             }
 
             // If the TSC is past the future report time, it's time to do our
@@ -1305,31 +2304,88 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                 // Compute the total number of cycles elapsed since start
                 let total_cycles = time::rdtsc() - persist.start;
 
+                // Total cycles spent in the hypervisor by all VPs
+                let vm_elapsed: u64 =
+                    persist.vps.iter().map(|x| x.stats.vm_elapsed).sum();
+
                 // Print some stats
                 print!("VM run percentage {:8.6} | Uptime {:14.6}\n",
-                    persist.vm_elapsed as f64 / total_cycles as f64,
+                    vm_elapsed as f64 /
+                        (total_cycles * persist.vps.len() as u64) as f64,
                     total_cycles as f64 / persist.tickrate.unwrap());
 
+                if coverage_enabled(&persist) {
+                    if let Err(err) = sync_coverage_db(&mut persist.coverage,
+                            &coverage_db_path()) {
+                        print!("Failed to save coverage database: {}\n", err);
+                    }
+                }
+
                 dump_coverage(&persist.coverage);
+                if let Some(breakpoints) = persist.breakpoints.as_ref() {
+                    print!("Coverage breakpoints {:10} placed | {:10} hit | \
+                            {:10} pending\n", breakpoints.placed,
+                        breakpoints.hits, breakpoints.pending());
+                }
+                if persist.coverage.iter().any(|x| x.is_some()) {
+                    let path = drcov_path();
+                    if let Err(err) = save_drcov(&persist.coverage, &path) {
+                        print!("Failed to save {}: {}\n", path.display(),
+                            err);
+                    }
 
-                // Print vmexit reason frequencies
-                print!("{:#?}\n", persist.vmexits);
+                    if let Ok(path) = std::env::var(LCOV_ENV_VAR) {
+                        if let Err(err) = save_lcov(&mut persist, &path) {
+                            print!("Failed to save {}: {}\n", path, err);
+                        }
+                    }
+
+                    if let Ok(path) = std::env::var(REPORT_ENV_VAR) {
+                        let baseline = std::env::var(BASELINE_ENV_VAR).ok();
+                        if let Err(err) = save_report(&mut persist, &path,
+                                baseline.as_ref().map(|x| x.as_str())) {
+                            print!("Failed to save {}: {}\n", path, err);
+                        }
+                    }
+                }
+
+                // Print per-VP statistics, including vmexit reason frequencies
+                for (ii, vpstate) in persist.vps.iter().enumerate() {
+                    print!("VP {} run percentage {:8.6}\n{:#?}\n", ii,
+                        vpstate.stats.vm_elapsed as f64 / total_cycles as f64,
+                        vpstate.stats);
+                }
 
                 // Print statistics
                 print!("{:#?}\n", persist.stats);
 
+                if let Some(fuzzer) = persist.fuzzer.as_ref() {
+                    let tickrate = persist.tickrate.unwrap();
+                    let cases    = persist.stats.num_fuzz_cases;
+                    let elapsed  = (time::rdtsc() - fuzzer.start) as f64 /
+                        tickrate;
+                    let restore  = persist.stats.restore_cycles as f64 /
+                        tickrate / std::cmp::max(cases, 1) as f64;
+                    print!("Fuzz cases {:10} | {:10.2} cases/second | \
+                            restore latency {:10.2} us | \
+                            crashes {} ({} unique) | hangs {} ({} unique)\n",
+                        cases, cases as f64 / elapsed, restore * 1000000.0,
+                        fuzzer.crashes.total, fuzzer.crashes.unique(),
+                        fuzzer.hangs.total, fuzzer.hangs.unique());
+                    if let Some(edges) = fuzzer.edges.as_ref() {
+                        print!("Edges taken {:10}\n", edges.edges);
+                    }
+                }
+
                 // Attempt to find the nt!PsLoadedModuleList
                 if persist.kernel_module_list.is_none() {
                     // Get information about the guest state
-                    let cr3 = context.cr3() as usize;
-                    let lma =
-                        (unsafe { context.efer.Reg64 } & (1 << 10)) != 0;
-                    let kernel_gs = unsafe { context.gs.Segment.Base as usize };
-                    let cs        = unsafe { context.cs.Segment.Selector };
+                    let state = CpuState::from(&context);
 
                     persist.kernel_module_list =
-                        find_kernel_modlist(cr3, lma, kernel_gs, cs,
-                        &mut persist.memory).ok();
+                        find_kernel_modlist(state.cr3() as usize,
+                        state.paging(), state.kpcr() as usize,
+                        state.cs.selector, &mut persist.memory).ok();
                 }
 
                 // Update the next report time
@@ -1338,75 +2394,148 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
             }
 
             // If we're requesting emulation, step using Bochs
-            persist.emulating = std::cmp::min(MAX_EMULATE, persist.emulating);
-            if persist.emulating > 0 {
-                let emu = persist.emulating;
-
+            let emu = std::cmp::min(MAX_EMULATE,
+                persist.vps[vp as usize].emulating);
+            persist.vps[vp as usize].emulating = emu;
+            if emu > 0 {
                 // Emulate instructions with Bochs!
                 std::mem::drop(persist);
-                (routines.step_cpu)(emu);
+                (routines.step_cpu)(vp, emu);
                 persist = x.borrow_mut();
+                sync_emulated_breakpoints(&mut persist, routines, vp);
 
                 // Subtract the amount we just emulated from the emulating
                 // number.
                 // We don't zero it because coverage could cause this to update
-                persist.emulating = persist.emulating.checked_sub(emu)
+                let vpstate = &mut persist.vps[vp as usize];
+                vpstate.emulating = vpstate.emulating.checked_sub(emu)
                     .expect("Underflow on emulating");
+                vpstate.stats.emulated += emu;
+
+                if let Some(fuzzer) = persist.fuzzer.as_mut() {
+                    fuzzer.case_emulated += emu;
+                }
                 continue;
             }
 
-            // Sync bochs register state to hypervisor register state
-            (routines.get_context)(&mut context);
-            persist.hypervisor.as_mut().unwrap().set_context(&context);
+            // Put every VP which isn't already running into the hypervisor
+            for ii in 0..persist.vps.len() as u32 {
+                if persist.vps[ii as usize].running { continue; }
+
+                // Processors which are halted or waiting for a SIPI are left
+                // in Bochs, it knows when to wake them up
+                if (routines.activity_state)(ii) != 0 {
+                    std::mem::drop(persist);
+                    (routines.step_cpu)(ii, 1);
+                    persist = x.borrow_mut();
+                    sync_emulated_breakpoints(&mut persist, routines, ii);
+                    continue;
+                }
 
-            // Get the current TSC
-            let vmstart_cycles = time::rdtsc();
+                // Sync bochs register state to hypervisor register state
+                (routines.get_context)(ii, &mut context);
+                if let Err(err) = persist.hypervisor.as_mut().unwrap()
+                        .set_context(ii, &context) {
+                    // The hypervisor doesn't like this state, let Bochs run
+                    // the processor for a bit and try again later
+                    hypervisor_error(&mut persist.vps[ii as usize], &err);
+                    std::mem::drop(persist);
+                    (routines.step_cpu)(ii, EMULATE_STEPS);
+                    persist = x.borrow_mut();
+                    sync_emulated_breakpoints(&mut persist, routines, ii);
+                    continue;
+                }
+
+                // Hand the VP to its run thread to run it until exit!
+                KICKER_ACTIVE.fetch_or(1 << ii, Ordering::SeqCst);
+                let vpstate = &mut persist.vps[ii as usize];
+                vpstate.running   = true;
+                vpstate.run_start = time::rdtsc();
+                vpstate.run_request.send(())
+                    .expect("VP run thread went away");
+            }
 
-            // Run the hypervisor until exit!
-            KICKER_ACTIVE.store(1, Ordering::SeqCst);
-            let vmexit = persist.hypervisor.as_mut().unwrap().run();
-            KICKER_ACTIVE.store(0, Ordering::SeqCst);
+            // If nothing is in the hypervisor then everything is waiting on
+            // Bochs, go step some more
+            if !persist.vps.iter().any(|x| x.running) { continue; }
 
-            // Compute number of cycles spent in the call to `vm.run()`
-            let elapsed = time::rdtsc() - vmstart_cycles;
+            // Wait for any VP to exit, that's the VP we work on now
+            let (exited, vmexit) = persist.vm_exits.as_ref().unwrap().recv()
+                .expect("VP run threads went away");
+            KICKER_ACTIVE.fetch_and(!(1 << exited), Ordering::SeqCst);
+            vp = exited;
+            persist.current_vp = vp;
+
+            // Compute number of cycles since the VP was handed to its run
+            // thread
+            let elapsed =
+                time::rdtsc().saturating_sub(persist.vps[vp as usize].run_start);
 
             // Subtract off the API overhead of the call to approximate the
             // number of cycles elapsed inside of the VM itself. Saturating to
             // handle some noise and potential integer underflow.
             let vm_run_time = elapsed.saturating_sub(
-                persist.hypervisor.as_mut().unwrap().overhead());
+                persist.hypervisor.as_ref().unwrap().overhead());
 
             // Update statistics about number of cycles spent in the hypervisor
-            persist.vm_elapsed += vm_run_time;
+            let vpstate = &mut persist.vps[vp as usize];
+            vpstate.running = false;
+            vpstate.stats.vm_elapsed += vm_run_time;
 
-            // Sync hypervisor register state to Bochs register state
-            context = persist.hypervisor.as_mut().unwrap().get_context();
-            (routines.set_context)(&context);
+            if let Some(fuzzer) = persist.fuzzer.as_mut() {
+                fuzzer.case_vm_cycles += vm_run_time;
+            }
+
+            // Sync hypervisor register state to Bochs register state. If we
+            // can't get the state Bochs would carry on from a stale state, so
+            // there's no recovering from this
+            context = persist.hypervisor.as_ref().unwrap().get_context(vp)
+                .unwrap_or_else(|err| fatal_hypervisor_error(&err));
+            (routines.set_context)(vp, &context);
+
+            // Check if the run itself failed. If it did, emulate for a bit and
+            // give the hypervisor another shot
+            let vmexit = match vmexit {
+                Ok(vmexit) => {
+                    persist.vps[vp as usize].consecutive_errors = 0;
+                    vmexit
+                }
+                Err(err) => {
+                    let vpstate = &mut persist.vps[vp as usize];
+                    hypervisor_error(vpstate, &err);
+                    vpstate.emulating += EMULATE_STEPS;
+                    continue;
+                }
+            };
 
             if !COVERAGE_DISABLE {
                 std::mem::drop(persist);
 
-                let cr3 = context.cr3() as usize;
-                let lma = (unsafe { context.efer.Reg64 } & (1 << 10)) != 0;
-                let gs_base = unsafe { context.gs.Segment.Base } as usize;
-                let cs = unsafe { context.cs.Segment.Selector };
-                let rip = context.rip() as usize;
-                let rsp = unsafe { context.rsp.Reg64 as usize };
+                let state = CpuState::from(&context);
 
-                report_coverage(cr3, lma, gs_base, cs, rip, rsp);
+                report_coverage(state.cr0, state.cr3, state.cr4, state.lma(),
+                    state.fs.base as usize, state.gs.base as usize,
+                    state.cs.selector, state.linear_rip() as usize,
+                    state.rsp as usize);
                 persist = x.borrow_mut();
             }
 
-            // Record the exit reason frequencies
-            let vmer = VmExitReason::from_whvp(vmexit.ExitReason);
+            // Place coverage breakpoints in any modules we haven't seen yet
+            if persist.breakpoints.is_some() {
+                sync_breakpoints(&mut persist, &CpuState::from(&context));
+            }
+
+            // Record the exit reason frequencies for this VP
+            let vmer: VmExitReason = vmexit.reason();
+            let vmexits = &mut persist.vps[vp as usize].stats.vmexits;
 
             // Insert the reason if it's not already tracked
-            if !persist.vmexits.contains_key(&vmer) {
-                persist.vmexits.insert(vmer, 0);
+            if !vmexits.contains_key(&vmer) {
+                vmexits.insert(vmer, 0);
             }
 
             // Update frequency
-            *persist.vmexits.get_mut(&vmer).unwrap() += 1;
+            *vmexits.get_mut(&vmer).unwrap() += 1;
 
             if false {
                 std::mem::drop(persist);
@@ -1419,27 +2548,71 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
             }
 
             // Determine the reason the hypervisor exited
-            match vmexit.ExitReason {
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonMemoryAccess => {
-                    /*let ma = unsafe { &vmexit.__bindgen_anon_1.MemoryAccess };
-                    print!("Mem access GVA {:x} GPA {:x} RIP {:x}\n",
-                        ma.Gva, ma.Gpa, context.rip());*/
+            match vmexit {
+                VmExit::MemoryAccess { .. } => {
+                    /*print!("Mem access {:x?} RIP {:x}\n",
+                        vmexit, context.rip());*/
 
                     // Emulate MMIO by emulating using Bochs for a bit
                     // Note this is tunable but 100 seems to by far be the best
                     // mix between performance and latency. <10 is unusable.
                     // >1000 introduces latency
                     // (cursor stutters when moving, etc)
-                    persist.emulating += EMULATE_STEPS;
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonException => {
+                VmExit::Exception { vector, error_code: _error_code } => {
+                    // Coverage breakpoints are removed and the instruction
+                    // they replaced is run. Breakpoints of the guest's own
+                    // which aren't crashes are left pending for the guest
+                    if vector == crash::BREAKPOINT_VECTOR &&
+                            persist.breakpoints.is_some() {
+                        let state = CpuState::from(&context);
+                        if hit_breakpoint(&mut persist, state.paging(),
+                                state.cr3() as usize, state.gs.base as usize,
+                                state.cs.selector, context.rip() as usize) {
+                            if let Err(err) = persist.hypervisor.as_mut()
+                                    .unwrap().clear_pending_exception(vp) {
+                                let vpstate = &mut persist.vps[vp as usize];
+                                hypervisor_error(vpstate, &err);
+                                vpstate.emulating += EMULATE_STEPS;
+                                continue;
+                            }
+                            (routines.set_context)(vp, &context);
+                            continue;
+                        }
+
+                        let crashes = persist.fuzzer.as_ref().map(|x|
+                            x.config.exceptions &
+                                (1 << crash::BREAKPOINT_VECTOR) != 0);
+                        if crashes != Some(true) { continue; }
+                    }
+
                     // Only take snapshots when running live
                     if orig_memory.is_some() {
-                        persist.hypervisor.as_mut().unwrap().clear_pending_exception();
+                        if vector == crash::DEBUG_VECTOR {
+                            // DR0 holds the end RIP of fuzz cases, DR1 and
+                            // DR2 the crash breakpoints
+                            check_breakpoints(&mut persist, &context);
+                        } else if persist.fuzzer.is_some() {
+                            // Anything else was trapped as a crash. The case
+                            // ends before this VP runs again
+                            let rsp = unsafe { context.rsp.Reg64 };
+                            record_crash(&mut persist, &context,
+                                CrashKind::Exception, vector as u64,
+                                context.rip(), rsp);
+                        }
+
+                        if let Err(err) = persist.hypervisor.as_mut().unwrap()
+                                .clear_pending_exception(vp) {
+                            let vpstate = &mut persist.vps[vp as usize];
+                            hypervisor_error(vpstate, &err);
+                            vpstate.emulating += EMULATE_STEPS;
+                            continue;
+                        }
                         context.dr6.Reg64 = 1 << 16;
                         unsafe { context.rflags.Reg64 |= 1 << 16; }
-                        (routines.set_context)(&context);
+                        (routines.set_context)(vp, &context);
 
                         //persist.hypervisor.as_mut().unwrap().deliver_exception(1, None);
                         continue;
@@ -1447,10 +2620,7 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
 
                     const MAGIC_BREAKPOINT_VALUE: u64 = 0x7b3c3638;
 
-                    let exception =
-                        unsafe { &vmexit.__bindgen_anon_1.VpException };
-
-                    if exception.ExceptionType == 1 {
+                    if vector == 1 {
                         // a #DB debug exception occured
                         let dr0 = unsafe { context.dr0.Reg64 };
                         let dr1 = unsafe { context.dr1.Reg64 };
@@ -1482,26 +2652,8 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                                 dr1 == MAGIC_BREAKPOINT_VALUE ||
                                 dr2 == MAGIC_BREAKPOINT_VALUE ||
                                 dr3 == MAGIC_BREAKPOINT_VALUE) {
-                            let uptime_since_epoch =
-                                SystemTime::now().duration_since(
-                                    SystemTime::UNIX_EPOCH).unwrap();
-
-                            // Construct a filename for the folder
-                            let snapshot_folder_name =
-                                format!("snapshot_{:?}", uptime_since_epoch);
-
-                            print!("Taking snapshot to: {}\n",
-                                snapshot_folder_name);
-
-                            // Make snapshot folder
-                            std::fs::create_dir(&snapshot_folder_name)
-                                .expect("Snapshot already exists");
-
-                            // Cause a Bochs snapshot!
-                            let folder_name_cstr =
-                                CString::new(snapshot_folder_name)
-                                .expect("Couldn't convert to cstring");
-                            (routines.take_snapshot)(folder_name_cstr.as_ptr());
+                            take_snapshot(&mut *persist, routines,
+                                num_cpus, memory, vp);
                         } else {
                             // We should handle re-injecting the #DB exception
                             continue;
@@ -1511,77 +2663,91 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                         print!("Debug exception happened\n");
 
                         // Inject the exception that was supposed to happen
-                        unsafe {
-                            if exception.ExceptionInfo
-                                    .__bindgen_anon_1.ErrorCodeValid() != 0 {
-                                persist.hypervisor.as_mut().unwrap()
-                                    .deliver_exception(exception.ExceptionType,
-                                        Some(exception.ErrorCode));
-                            } else {
-                                persist.hypervisor.as_mut().unwrap()
-                                    .deliver_exception(
-                                        exception.ExceptionType, None);
-                            }
+                        persist.hypervisor.as_mut().unwrap()
+                            .deliver_exception(vp, vector, _error_code);
 
-                            print!("{}\n", context);
-                        }
+                        print!("{}\n", context);
 
                         persist.hypervisor.as_mut().unwrap().test_exception();
 
-                        assert!(persist.emulating == 0,
+                        assert!(persist.vps[vp as usize].emulating == 0,
                             "Shouldn't be emulating during exception");
                         continue;*/
                     } else {
                         panic!("Unhandled exception vmexit");
                     }
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64IoPortAccess => {
+                VmExit::IoPortAccess { .. } => {
                     // Emulate I/O by emulating using Bochs for a bit
                     // Note this is tunable but 100 seems to by far be the best
                     // mix between performance and latency. <10 is unusable.
                     // >1000 introduces latency
                     // (cursor stutters when moving, etc)
-                    persist.emulating += EMULATE_STEPS;
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Halt => {
+                VmExit::Halt => {
                     // Emulate halts by emulating using Bochs for a bit
-                    persist.emulating += 1;
+                    persist.vps[vp as usize].emulating += 1;
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonCanceled => {
+                VmExit::Canceled => {
                     // Check if rflags.IF=0
                     if (unsafe { context.rflags.Reg64 } & (1 << 9)) == 0 {
                         // If interrupts are disabled, request to be notified
                         // next time they are enabled so we can potentially
                         // deliver interrupts
-                        persist.hypervisor.as_mut().unwrap()
-                            .register_interrupt_window();
+                        // If this fails we just don't get notified, and the
+                        // interrupt gets delivered on a later exit instead
+                        if let Err(err) = persist.hypervisor.as_mut().unwrap()
+                                .register_interrupt_window(vp) {
+                            hypervisor_error(
+                                &mut persist.vps[vp as usize], &err);
+                        }
                     }
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonInvalidVpRegisterValue => {
+                VmExit::InvalidVpRegisterValue => {
                     // This was observed in Windows 7, however if we emulate a
                     // bit the issue seems to go away. So that's our "solution".
                     // Not sure which state is going bad here, or if it's some
                     // CPUID/MSR desync issue with Bochs
                     //print!("Warning: Invalid VP state, emulating for a bit\n");
-                    persist.emulating += EMULATE_STEPS;
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Cpuid => {
-                    // Manually handle CPUIDs
-                    let cpuid = unsafe { &vmexit.__bindgen_anon_1.CpuidAccess };
+                VmExit::Cpuid { leaf, subleaf, default, instruction_length } => {
+                    // Check if this is a hypercall rather than a real CPUID
+                    let args = unsafe { [context.rcx.Reg64,
+                        context.rdx.Reg64, context.rsi.Reg64,
+                        context.rdi.Reg64] };
+                    if let Some(call) = Hypercall::decode(leaf, args) {
+                        let live = orig_memory.is_none();
+                        handle_hypercall(&mut *persist, &mut context, call,
+                            live);
+
+                        // Return to after the cpuid instruction
+                        unsafe {
+                            context.rip.Reg64 += instruction_length as u64;
+                        }
+                        (routines.set_context)(vp, &context);
+
+                        // Snapshot with the hypercall completed, so resuming
+                        // the snapshot returns from the hypercall
+                        if call == Hypercall::Snapshot && live {
+                            take_snapshot(&mut *persist, routines, num_cpus,
+                                memory, vp);
+                        }
+                        continue;
+                    }
 
-                    // Get the leaf and subleaf the MSR is trying to access
-                    let leaf    = cpuid.Rax;
-                    let subleaf = cpuid.Rcx;
+                    // Manually handle CPUIDs
 
                     // Get the ones that Hyper-V would have returned inside the
                     // VM
-                    let rax = cpuid.DefaultResultRax;
-                    let rbx = cpuid.DefaultResultRbx;
-                    let mut rcx = cpuid.DefaultResultRcx;
-                    let rdx = cpuid.DefaultResultRdx;
+                    let rax = default[0];
+                    let rbx = default[1];
+                    let mut rcx = default[2];
+                    let rdx = default[3];
 
                     // Modify cpuid info
                     match (leaf, subleaf) {
@@ -1602,26 +2768,30 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
 
                     // Advance RIP past the cpuid instruction
                     unsafe {
-                        context.rip.Reg64 +=
-                            vmexit.VpContext.InstructionLength() as u64;
+                        context.rip.Reg64 += instruction_length as u64;
                     }
                     
                     // Write out the context and reenter the VM
-                    (routines.set_context)(&context);
+                    (routines.set_context)(vp, &context);
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64InterruptWindow => {
+                VmExit::InterruptWindow => {
                     // We got an interrupt window! Well we don't have to do
                     // anything as now Bochs knows it can deliver async events
                     // and will when we `step_device()`
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonUnrecoverableException => {
-                    persist.emulating += EMULATE_STEPS;
+                VmExit::UnrecoverableException => {
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
                     continue;
                 }
-                WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64MsrAccess => {
+                VmExit::MsrAccess { .. } => {
                     // Handle MSR read/writes
-                    persist.emulating += EMULATE_STEPS;
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
+                    continue;
+                }
+                VmExit::UnsupportedFeature => {
+                    // The backend can't run the guest right now, Bochs can
+                    persist.vps[vp as usize].emulating += EMULATE_STEPS;
                     continue;
                 }
                 _ => {
@@ -1630,9 +2800,34 @@ pub extern "C" fn bochs_cpu_loop(routines: &BochsRoutines, pmem_size: u64,
                     // common for a while until we test more and more OSes under
                     // this hypervisor.
                     print!("{}\n", context);
-                    panic!("Unhandled VM exit reason {}", vmexit.ExitReason);
+                    panic!("Unhandled VM exit reason {:?}", vmexit);
                 }
             }
         }
     });
 }
+
+#[test]
+fn test_emulated_breakpoints() {
+    let config = fuzz::test_config(&[("APPLEPIE_FUZZ_END", "rip:0x1000")])
+        .unwrap().unwrap();
+    let (fuzzer, dir) = fuzz::test_fuzzer("emulated", config, &[]);
+    let mut persist = PersistState {
+        fuzzer: Some(fuzzer),
+        ..Default::default()
+    };
+    persist.fuzzer.as_mut().unwrap().begin_case();
+
+    // Nothing hit
+    let mut context = VpContext::default();
+    context.dr6.Reg64 = 0xffff0ff0;
+    assert!(!emulated_breakpoints(&mut persist, &mut context));
+
+    // Bochs stopped on the end RIP, which ends the case like a #DB exit
+    context.dr6.Reg64 = 0xffff0ff1;
+    assert!(emulated_breakpoints(&mut persist, &mut context));
+    assert_eq!(unsafe { context.dr6.Reg64 }, 0xffff0ff0);
+    assert!(persist.fuzzer.as_mut().unwrap().case_finished(1e30, false));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,346 @@
+/// Guest physical and virtual memory access
+///
+/// This only needs a list of the guest physical memory regions and where they
+/// live in our address space. It doesn't care if that's the live Bochs memory
+/// or a snapshot loaded from disk.
+
+use crate::virtmem::{self, Paging};
+
+/// Named structure for tracking memory regions in Bochs
+/// 
+/// This is just for convience
+pub struct MemoryRegion {
+    /// Physical address of the base of this memory region
+    pub paddr: usize,
+
+    /// Pointer to memory which represents this region in bochs
+    pub backing: usize,
+
+    /// Permissions allowed on this region
+    /// These are the WHVP constants: `PERM_READ`, `PERM_WRITE`, `PERM_EXECUTE`
+    pub perms: i32,
+
+    /// Size of the memory region
+    pub size: usize,
+}
+
+
+#[derive(Default)]
+pub struct MemReader {
+    /// List of all of the memory regions mapped into the guest physical space
+    regions: Vec<MemoryRegion>
+}
+
+macro_rules! read_virt_declare {
+    ($name:ident, $vt:ty) => (
+        pub fn $name(&mut self, cr3: usize, addr: usize) -> Result<$vt, ()> {
+            let mut val = [0u8; std::mem::size_of::<$vt>()];
+            if self.read_virt(cr3, addr, &mut val) == val.len() {
+                Ok(<$vt>::from_ne_bytes(val))
+            } else {
+                Err(())
+            }
+        }
+    )
+}
+
+impl MemReader {
+    /// Create a new memory reader based on a list of memory regions
+    pub fn new(memory_regions: Vec<MemoryRegion>) -> Self {
+        MemReader { regions: memory_regions }
+    }
+
+    /// Get the memory regions this reader accesses
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// Read physical memory at `paddr` into an output buffer. Returns number
+    /// of bytes read, this may be smaller than `output.len()` on partial reads
+    pub fn read_phys(&mut self, paddr: usize, output: &mut [u8]) -> usize {
+        // Sanity check
+        assert!(output.len() > 0, "Output buffer was zero size");
+
+        // Track number of bytes read
+        let mut bread = 0usize;
+
+        while bread < output.len() {
+            let mut matched_something = false;
+            
+            for mr in &self.regions {
+                // Check if this address falls in this region
+                if paddr >= mr.paddr {
+                    // Compute offset and remainder of region
+                    let offset = paddr - mr.paddr;
+                    let remain = mr.size.saturating_sub(offset);
+
+                    // Nothing in this region for us
+                    if remain <= 0 { continue; }
+
+                    // Convert to Rust slice
+                    let region = unsafe {
+                        std::slice::from_raw_parts(
+                            mr.backing as *const u8, mr.size)
+                    };
+
+                    // Compute bytes to copy
+                    let remain = std::cmp::min(output.len() - bread, remain);
+
+                    // Copy bytes
+                    output[bread..bread+remain].copy_from_slice(
+                        &region[offset..offset+remain]);
+
+                    // Update read amount
+                    bread += remain;
+                    matched_something = true;
+
+                    if bread >= output.len() {
+                        assert!(bread == output.len(), "Whoa we overshot");
+                        return bread;
+                    }
+                }
+            }
+
+            // Failed to find a region that contains this byte, bail out
+            if !matched_something { break; }
+        }
+
+        assert!(bread < output.len(), "Success path shouldn't go here");
+
+        bread
+    }
+
+    /// Write physical memory at `paddr` from an input buffer. Returns number
+    /// of bytes written, this may be smaller than `input.len()` on partial
+    /// writes
+    pub fn write_phys(&mut self, paddr: usize, input: &[u8]) -> usize {
+        // Sanity check
+        assert!(input.len() > 0, "Input buffer was zero size");
+
+        // Track number of bytes read
+        let mut bread = 0usize;
+
+        while bread < input.len() {
+            let mut matched_something = false;
+            
+            for mr in &self.regions {
+                // Check if this address falls in this region
+                if paddr >= mr.paddr {
+                    // Compute offset and remainder of region
+                    let offset = paddr - mr.paddr;
+                    let remain = mr.size.saturating_sub(offset);
+
+                    // Nothing in this region for us
+                    if remain <= 0 { continue; }
+
+                    // Convert to Rust slice
+                    let region = unsafe {
+                        std::slice::from_raw_parts_mut(
+                            mr.backing as *mut u8, mr.size)
+                    };
+
+                    // Compute bytes to copy
+                    let remain = std::cmp::min(input.len() - bread, remain);
+
+                    // Copy bytes
+                    region[offset..offset+remain].copy_from_slice(
+                        &input[bread..bread+remain]);
+
+                    // Update read amount
+                    bread += remain;
+                    matched_something = true;
+
+                    if bread >= input.len() {
+                        assert!(bread == input.len(), "Whoa we overshot");
+                        return bread;
+                    }
+                }
+            }
+
+            // Failed to find a region that contains this byte, bail out
+            if !matched_something { break; }
+        }
+
+        assert!(bread < input.len(), "Success path shouldn't go here");
+
+        bread
+    }
+
+    /// Read virtual memory at `vaddr` using long mode page table `cr3` into
+    /// `buf`. Returns number of bytes read (can be less than `buf.len()` on
+    /// error)
+    pub fn read_virt(&mut self, cr3: usize, vaddr: usize,
+                     buf: &mut [u8]) -> usize
+    {
+        self.read_virt_paging(Paging::Long, cr3, vaddr, buf)
+    }
+
+    /// Translate `vaddr` to a physical address using page table `cr3` of a
+    /// processor in `paging` mode. `cr3` must already be masked with
+    /// `Paging::table_root`
+    pub fn translate(&mut self, paging: Paging, cr3: usize,
+                     vaddr: usize) -> Option<usize>
+    {
+        let mut guest_pt = unsafe {
+            virtmem::PageTable::from_existing(cr3 as *mut u64, self)
+        };
+        match guest_pt.virt_to_phys_paging(paging, vaddr as u64) {
+            Ok(Some((phys, _))) => Some(phys as usize),
+            _                   => None,
+        }
+    }
+
+    /// Read virtual memory at `vaddr` using page table `cr3` of a processor
+    /// in `paging` mode into `buf`. `cr3` must already be masked with
+    /// `Paging::table_root`. Returns number of bytes read (can be less than
+    /// `buf.len()` on error)
+    pub fn read_virt_paging(&mut self, paging: Paging, cr3: usize,
+                            vaddr: usize, buf: &mut [u8]) -> usize
+    {
+        // Cached physical translation
+        let mut guest_phys = 0;
+
+        // Go through each byte
+        for offset in 0..buf.len() {
+            // Update translation on new pages
+            if (guest_phys & 0xfff) == 0 {
+                // Translate vaddr to paddr
+                guest_phys = match self.translate(paging, cr3, vaddr + offset) {
+                    Some(phys) => phys,
+                    None       => return offset,
+                };
+            }
+
+            // Read one byte from memory
+            if self.read_phys(guest_phys,
+                    &mut buf[offset..offset+1]) != 1 {
+                // Failed to read, return bytes read to this point
+                return offset;
+            }
+
+            // Update physical pointer
+            guest_phys += 1;
+        }
+
+        // Return bytes read
+        buf.len()
+    }
+
+    /// Writes virtual memory at `vaddr` using page table `cr3` from `buf`.
+    /// Returns number of bytes written (can be less than `buf.len()` on error)
+    pub fn write_virt(&mut self, cr3: usize, vaddr: usize,
+                      buf: &[u8]) -> usize
+    {
+        // Cached physical translation
+        let mut guest_phys = 0;
+
+        // Go through each byte
+        for offset in 0..buf.len() {
+            // Update translation on new pages
+            if (guest_phys & 0xfff) == 0 {
+                // Translate vaddr to paddr
+                let mut guest_pt = unsafe {
+                    virtmem::PageTable::from_existing(cr3 as *mut u64, self)
+                };
+                guest_phys = match guest_pt.virt_to_phys_dirty(
+                        (vaddr + offset) as u64, false) {
+                    Ok(Some((phys, _))) => phys,
+                    _                   => return offset,
+                };
+            }
+
+            // Read one byte from memory
+            if self.write_phys(guest_phys as usize,
+                    &buf[offset..offset+1]) != 1 {
+                // Failed to read, return bytes read to this point
+                return offset;
+            }
+
+            // Update physical pointer
+            guest_phys += 1;
+        }
+
+        // Return bytes read
+        buf.len()
+    }
+
+    read_virt_declare!(read_virt_u8, u8);
+    read_virt_declare!(read_virt_u16, u16);
+    read_virt_declare!(read_virt_u32, u32);
+    read_virt_declare!(read_virt_u64, u64);
+    read_virt_declare!(read_virt_usize, usize);
+}
+
+impl virtmem::PhysMem for MemReader {
+    fn alloc_page(&mut self) -> Option<*mut u8> {
+        panic!("Alloc page not supported");
+    }
+
+    fn read_phys_int(&mut self, addr: *mut u64) -> Result<u64, &'static str> {
+        let mut buf = [0u8; 8];
+        if self.read_phys(addr as usize, &mut buf) != buf.len() {
+            return Err("Failed to read physical memory");
+        }
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    fn write_phys(&mut self, _addr: *mut u64,
+            _val: u64) ->Result<(), &'static str> {
+        panic!("write_phys not supported");
+    }
+
+    fn probe_vaddr(&mut self, _addr: usize, _length: usize) -> bool {
+        panic!("probe_vaddr not supported");
+    }
+}
+
+#[test]
+fn test_read_virt_paging() {
+    let mut memory = vec![0u8; 0x8000];
+    let mut put = |paddr: usize, val: u64, size: usize| {
+        memory[paddr..paddr + size].copy_from_slice(&val.to_le_bytes()[..size]);
+    };
+
+    // Legacy, 0x00401234 through PDE 1 and PTE 1, 0x80005234 through a 4 MiB
+    // page at physical 0 in PDE 0x200
+    put(0x1004, 0x2001, 4);
+    put(0x2004, 0x5001, 4);
+    put(0x1800, 0x0081, 4);
+
+    // PAE with a PDPT which isn't page aligned, 0x40201234 through PDPTE 1,
+    // PDE 1 and PTE 1
+    put(0x3028, 0x4001, 8);
+    put(0x4008, 0x6001, 8);
+    put(0x6008, 0x5001, 8);
+
+    put(0x5234, 0xdeadbeef, 4);
+
+    let mut reader = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: memory.as_ptr() as usize,
+        perms:   0,
+        size:    memory.len(),
+    }]);
+
+    let mut read = |paging: Paging, cr3: u64, vaddr: usize| {
+        let mut buf = [0u8; 4];
+        let cr3 = paging.table_root(cr3) as usize;
+        if reader.read_virt_paging(paging, cr3, vaddr, &mut buf) == 4 {
+            Some(u32::from_le_bytes(buf))
+        } else {
+            None
+        }
+    };
+
+    assert_eq!(read(Paging::Legacy, 0x1018, 0x00401234), Some(0xdeadbeef));
+    assert_eq!(read(Paging::Legacy, 0x1000, 0x80005234), Some(0xdeadbeef));
+    assert_eq!(read(Paging::Legacy, 0x1000, 0x00801234), None);
+    assert_eq!(read(Paging::Pae, 0x3038, 0x40201234), Some(0xdeadbeef));
+    assert_eq!(read(Paging::Pae, 0x3020, 0x00201234), None);
+    assert_eq!(read(Paging::Disabled, 0, 0x5234), Some(0xdeadbeef));
+
+    assert_eq!(Paging::new(1 << 31, 1 << 5, true), Paging::Long);
+    assert_eq!(Paging::new(1 << 31, 1 << 5, false), Paging::Pae);
+    assert_eq!(Paging::new(1 << 31, 0, false), Paging::Legacy);
+    assert_eq!(Paging::new(0, 0, false), Paging::Disabled);
+}
@@ -0,0 +1,217 @@
+/// Crash input minimization
+///
+/// The minimizer hands out candidate inputs one at a time and is told the
+/// crash bucket each one ended up in. The first candidate is the input as-is,
+/// which sets the bucket every later candidate has to reproduce. After that
+/// it removes chunks of the input, halving the chunk size every pass, and then
+/// zeroes the bytes which are left one by one.
+///
+/// This only decides what to try next, running the candidates is up to the
+/// fuzz loop. So the tests run anywhere.
+
+/// Stage the minimizer is in
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    /// Running the original input to find the bucket to reproduce
+    Initial,
+
+    /// Removing `chunk` bytes at `offset`. `changed` is set if anything was
+    /// removed since the chunk size was last reset
+    Remove { chunk: usize, offset: usize, changed: bool },
+
+    /// Zeroing the byte at `offset`
+    Zero { offset: usize },
+
+    /// Nothing left to try
+    Done,
+}
+
+/// Crash input minimizer
+pub struct Minimizer {
+    /// Smallest input which reproduces the bucket so far
+    best: Vec<u8>,
+
+    /// Bucket every candidate has to reproduce
+    bucket: Option<String>,
+
+    /// What we're trying right now
+    stage: Stage,
+
+    /// Log of every accepted step
+    log: Vec<String>,
+}
+
+impl Minimizer {
+    /// Create a new minimizer for `input`
+    pub fn new(input: Vec<u8>) -> Self {
+        Minimizer {
+            best:   input,
+            bucket: None,
+            stage:  Stage::Initial,
+            log:    Vec::new(),
+        }
+    }
+
+    /// Get the smallest reproducer so far
+    pub fn best(&self) -> &[u8] {
+        &self.best
+    }
+
+    /// Get the bucket the minimizer is reproducing, once it's known
+    pub fn bucket(&self) -> Option<&str> {
+        self.bucket.as_ref().map(|x| x.as_str())
+    }
+
+    /// Get the log of every accepted step
+    pub fn log(&self) -> &[String] {
+        &self.log
+    }
+
+    /// Returns `true` once there's nothing left to try
+    pub fn done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// Get the next candidate to run. This keeps returning the same candidate
+    /// until `report()` is called, and the best input once we're done
+    pub fn candidate(&self) -> Vec<u8> {
+        let mut candidate = self.best.clone();
+        match self.stage {
+            Stage::Remove { chunk, offset, .. } => {
+                let end = std::cmp::min(offset + chunk, candidate.len());
+                candidate.drain(offset..end);
+            }
+            Stage::Zero { offset } => candidate[offset] = 0,
+            Stage::Initial | Stage::Done => {}
+        }
+        candidate
+    }
+
+    /// Report the `bucket` the current candidate crashed in, `None` if it
+    /// didn't crash
+    pub fn report(&mut self, bucket: Option<&str>) {
+        let candidate = self.candidate();
+
+        let reproduced = match (&self.bucket, bucket) {
+            (Some(expected), Some(bucket)) => expected == bucket,
+            _ => false,
+        };
+
+        self.stage = match self.stage {
+            Stage::Initial => {
+                // Nothing to minimize if the input doesn't crash
+                match bucket {
+                    Some(bucket) => {
+                        self.log.push(format!("Reproducing {} with {} bytes",
+                            bucket, self.best.len()));
+                        self.bucket = Some(bucket.into());
+                        self.first_removal()
+                    }
+                    None => {
+                        self.log.push("Input does not crash".into());
+                        Stage::Done
+                    }
+                }
+            }
+            Stage::Remove { chunk, offset, changed } => {
+                if reproduced {
+                    self.log.push(format!(
+                        "Removed {} bytes at {:#x}, {} bytes left",
+                        self.best.len() - candidate.len(), offset,
+                        candidate.len()));
+                    self.best = candidate;
+
+                    // Try the same offset again, it holds new data now
+                    self.next_removal(chunk, offset, true)
+                } else {
+                    self.next_removal(chunk, offset + chunk, changed)
+                }
+            }
+            Stage::Zero { offset } => {
+                if reproduced {
+                    self.log.push(format!("Zeroed byte at {:#x}", offset));
+                    self.best = candidate;
+                }
+                self.next_zero(offset + 1)
+            }
+            Stage::Done => Stage::Done,
+        };
+    }
+
+    /// Start removing chunks of half the input
+    fn first_removal(&self) -> Stage {
+        let chunk = std::cmp::max(1, self.best.len() / 2);
+        self.next_removal(chunk, 0, false)
+    }
+
+    /// Get the next removal at or after `offset` with `chunk` sized chunks.
+    /// Once a pass with single bytes is done this starts over if anything was
+    /// removed, otherwise it moves on to zeroing
+    fn next_removal(&self, chunk: usize, offset: usize, changed: bool)
+            -> Stage {
+        if offset < self.best.len() {
+            return Stage::Remove { chunk, offset, changed };
+        }
+
+        if chunk > 1 {
+            self.next_removal(chunk / 2, 0, changed)
+        } else if changed {
+            self.first_removal()
+        } else {
+            self.next_zero(0)
+        }
+    }
+
+    /// Get the next byte at or after `offset` which isn't zero yet
+    fn next_zero(&self, offset: usize) -> Stage {
+        match self.best.iter().skip(offset).position(|&x| x != 0) {
+            Some(ii) => Stage::Zero { offset: offset + ii },
+            None => Stage::Done,
+        }
+    }
+}
+
+/// Run the minimizer on `input` with a fake target which crashes when
+/// `crashes` returns `true`. Returns the minimizer and the number of runs
+#[cfg(test)]
+fn minimize_with<F: Fn(&[u8]) -> bool>(input: &[u8], crashes: F)
+        -> (Minimizer, usize) {
+    let mut minimizer = Minimizer::new(input.to_vec());
+    let mut runs = 0;
+    while !minimizer.done() {
+        let crashed = crashes(&minimizer.candidate());
+        minimizer.report(if crashed { Some("bucket") } else { None });
+        runs += 1;
+        assert!(runs < 100000, "Minimizer didn't finish");
+    }
+    (minimizer, runs)
+}
+
+#[test]
+fn test_minimize_removes_chunks() {
+    let mut input = vec![0x41u8; 1000];
+    input[700..703].copy_from_slice(b"BUG");
+    let (minimizer, _) = minimize_with(&input,
+        |x| x.windows(3).any(|x| x == b"BUG"));
+    assert_eq!(minimizer.best(), b"BUG");
+    assert_eq!(minimizer.bucket(), Some("bucket"));
+    assert!(!minimizer.log().is_empty());
+}
+
+#[test]
+fn test_minimize_zeroes_bytes() {
+    // The bug needs to be at offset 2, so the bytes in front of it can only
+    // be zeroed and everything after it removed
+    let (minimizer, _) = minimize_with(b"xxBUGxx",
+        |x| x.len() >= 5 && &x[2..5] == b"BUG");
+    assert_eq!(minimizer.best(), b"\0\0BUG");
+}
+
+#[test]
+fn test_minimize_no_crash() {
+    let (minimizer, runs) = minimize_with(b"fine",
+        |x| x.windows(3).any(|x| x == b"BUG"));
+    assert_eq!(runs, 1);
+    assert_eq!(minimizer.best(), b"fine");
+    assert_eq!(minimizer.bucket(), None);
+}
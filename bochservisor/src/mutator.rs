@@ -0,0 +1,344 @@
+/// Input mutation for snapshot fuzzing
+///
+/// Every mutation is driven by a small seedable PRNG, so a mutated input can
+/// always be recreated from the seed and the parent input (and the splice
+/// input, if one was used). This is pure Rust with no hypervisor dependencies
+/// so the tests run anywhere.
+
+/// Interesting 8-bit values, these are sign extended for larger values
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+
+/// Interesting 16-bit values
+const INTERESTING_16: [i16; 10] =
+    [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+
+/// Interesting 32-bit values
+const INTERESTING_32: [i32; 8] = [-2147483648, -100663046, -32769, 32768,
+    65535, 65536, 100663045, 2147483647];
+
+/// Largest value added to or subtracted from integers
+const ARITH_MAX: u64 = 35;
+
+/// Largest number of mutations stacked by havoc is `1 << HAVOC_MAX_POW2`
+const HAVOC_MAX_POW2: u64 = 7;
+
+/// Largest block inserted, deleted, copied or spliced
+const BLOCK_MAX: usize = 1024;
+
+/// xorshift64 pseudo random number generator. Fast and good enough for
+/// picking mutations
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a new RNG from `seed`. Any seed is fine, including zero
+    pub fn new(seed: u64) -> Self {
+        // Run the seed through splitmix64 so nearby seeds give unrelated
+        // streams and a zero seed doesn't get xorshift stuck
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        Rng(if z == 0 { 1 } else { z })
+    }
+
+    /// Get the next random 64-bit number
+    pub fn rand(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Get a random number in `0..max`. `max` must be non-zero
+    pub fn below(&mut self, max: u64) -> u64 {
+        assert!(max > 0, "Random range must not be empty");
+        self.rand() % max
+    }
+
+    /// Get a random index into something `len` long. `len` must be non-zero
+    pub fn index(&mut self, len: usize) -> usize {
+        self.below(len as u64) as usize
+    }
+}
+
+/// A single mutation strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Flip a single bit
+    BitFlip,
+
+    /// Invert a byte
+    ByteFlip,
+
+    /// Replace a byte with a random value
+    RandomByte,
+
+    /// Add or subtract a small value from an 8, 16, 32 or 64-bit integer of
+    /// either endianness
+    Arithmetic,
+
+    /// Replace an 8, 16 or 32-bit integer with an interesting value
+    Interesting,
+
+    /// Insert a block of random bytes or of a repeated byte
+    InsertBlock,
+
+    /// Delete a block of bytes
+    DeleteBlock,
+
+    /// Copy a block of the input over another part of it
+    CopyBlock,
+
+    /// Overwrite or insert a block from another input
+    Splice,
+}
+
+/// Every strategy, havoc picks from these
+const STRATEGIES: [Strategy; 9] = [
+    Strategy::BitFlip, Strategy::ByteFlip, Strategy::RandomByte,
+    Strategy::Arithmetic, Strategy::Interesting, Strategy::InsertBlock,
+    Strategy::DeleteBlock, Strategy::CopyBlock, Strategy::Splice,
+];
+
+/// Mutator for inputs
+pub struct Mutator {
+    /// Random number generator driving every decision
+    rng: Rng,
+
+    /// Inputs never grow past this size
+    max_size: usize,
+}
+
+impl Mutator {
+    /// Create a new mutator seeded with `seed` which keeps inputs at most
+    /// `max_size` bytes
+    pub fn new(seed: u64, max_size: usize) -> Self {
+        Mutator { rng: Rng::new(seed), max_size }
+    }
+
+    /// Create a mutated copy of `parent`. This applies a havoc stack of
+    /// randomly picked strategies. `splice` is another input to take blocks
+    /// from, if there is one
+    pub fn mutate(&mut self, parent: &[u8], splice: Option<&[u8]>) -> Vec<u8> {
+        let mut input = parent.to_vec();
+        input.truncate(self.max_size);
+
+        let stack = 1 << (1 + self.rng.below(HAVOC_MAX_POW2));
+        for _ in 0..stack {
+            let strategy = STRATEGIES[self.rng.index(STRATEGIES.len())];
+            self.apply(strategy, &mut input, splice);
+        }
+
+        input
+    }
+
+    /// Apply a single mutation `strategy` to `input`. Strategies which can't
+    /// work on this input (like flipping a bit in an empty input) do nothing
+    pub fn apply(&mut self, strategy: Strategy, input: &mut Vec<u8>,
+            splice: Option<&[u8]>) {
+        let rng = &mut self.rng;
+
+        match strategy {
+            Strategy::BitFlip => {
+                if input.is_empty() { return; }
+                let idx = rng.index(input.len());
+                input[idx] ^= 1 << rng.below(8);
+            }
+            Strategy::ByteFlip => {
+                if input.is_empty() { return; }
+                let idx = rng.index(input.len());
+                input[idx] ^= 0xff;
+            }
+            Strategy::RandomByte => {
+                if input.is_empty() { return; }
+                let idx = rng.index(input.len());
+                input[idx] = rng.rand() as u8;
+            }
+            Strategy::Arithmetic => {
+                let size  = 1 << rng.below(4);
+                let delta = 1 + rng.below(ARITH_MAX);
+                let subtract = rng.below(2) == 0;
+                let big_endian = rng.below(2) == 0;
+                if let Some(val) = read_int(rng, input, size, big_endian) {
+                    let (off, val) = val;
+                    let val = if subtract {
+                        val.wrapping_sub(delta)
+                    } else {
+                        val.wrapping_add(delta)
+                    };
+                    write_int(input, off, size, big_endian, val);
+                }
+            }
+            Strategy::Interesting => {
+                let size = 1 << rng.below(3);
+                let big_endian = rng.below(2) == 0;
+                let val = match size {
+                    1 => INTERESTING_8[rng.index(INTERESTING_8.len())] as u64,
+                    2 => INTERESTING_16[rng.index(INTERESTING_16.len())]
+                        as u64,
+                    _ => INTERESTING_32[rng.index(INTERESTING_32.len())]
+                        as u64,
+                };
+                if let Some((off, _)) = read_int(rng, input, size, big_endian) {
+                    write_int(input, off, size, big_endian, val);
+                }
+            }
+            Strategy::InsertBlock => {
+                let room = self.max_size.saturating_sub(input.len());
+                if room == 0 { return; }
+                let len = 1 + rng.index(std::cmp::min(room, BLOCK_MAX));
+                let at  = rng.index(input.len() + 1);
+                let block: Vec<u8> = if rng.below(2) == 0 {
+                    (0..len).map(|_| rng.rand() as u8).collect()
+                } else {
+                    vec![rng.rand() as u8; len]
+                };
+                input.splice(at..at, block);
+            }
+            Strategy::DeleteBlock => {
+                // Never delete everything, an empty input has nothing left
+                // to mutate
+                if input.len() < 2 { return; }
+                let len = 1 + rng.index(
+                    std::cmp::min(input.len() - 1, BLOCK_MAX));
+                let at  = rng.index(input.len() - len + 1);
+                input.drain(at..at + len);
+            }
+            Strategy::CopyBlock => {
+                if input.len() < 2 { return; }
+                let len  = 1 + rng.index(
+                    std::cmp::min(input.len() - 1, BLOCK_MAX));
+                let from = rng.index(input.len() - len + 1);
+                let to   = rng.index(input.len() - len + 1);
+                input.copy_within(from..from + len, to);
+            }
+            Strategy::Splice => {
+                let other = match splice {
+                    Some(other) if !other.is_empty() => other,
+                    _ => return,
+                };
+                let len  = 1 + rng.index(std::cmp::min(other.len(), BLOCK_MAX));
+                let from = rng.index(other.len() - len + 1);
+                let block = &other[from..from + len];
+
+                // Either overwrite part of the input or insert the block
+                if input.len() >= len && rng.below(2) == 0 {
+                    let to = rng.index(input.len() - len + 1);
+                    input[to..to + len].copy_from_slice(block);
+                } else if input.len() + len <= self.max_size {
+                    let at = rng.index(input.len() + 1);
+                    input.splice(at..at, block.iter().cloned());
+                }
+            }
+        }
+    }
+}
+
+/// Pick a random `size` byte integer in `input`. Returns its offset and value
+fn read_int(rng: &mut Rng, input: &[u8], size: usize,
+        big_endian: bool) -> Option<(usize, u64)> {
+    if input.len() < size { return None; }
+    let off = rng.index(input.len() - size + 1);
+
+    let mut bytes = [0u8; 8];
+    bytes[..size].copy_from_slice(&input[off..off + size]);
+    if big_endian { bytes[..size].reverse(); }
+    Some((off, u64::from_le_bytes(bytes)))
+}
+
+/// Write the low `size` bytes of `val` to `input` at `off`
+fn write_int(input: &mut [u8], off: usize, size: usize, big_endian: bool,
+        val: u64) {
+    let mut bytes = val.to_le_bytes();
+    if big_endian { bytes[..size].reverse(); }
+    input[off..off + size].copy_from_slice(&bytes[..size]);
+}
+
+#[test]
+fn test_rng_deterministic() {
+    let mut a = Rng::new(1234);
+    let mut b = Rng::new(1234);
+    let mut c = Rng::new(1235);
+    let a: Vec<u64> = (0..64).map(|_| a.rand()).collect();
+    let b: Vec<u64> = (0..64).map(|_| b.rand()).collect();
+    let c: Vec<u64> = (0..64).map(|_| c.rand()).collect();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+
+    // Zero must not get stuck
+    let mut z = Rng::new(0);
+    assert!((0..64).any(|_| z.rand() != 0));
+}
+
+#[test]
+fn test_mutate_reproducible() {
+    let parent: Vec<u8> = (0..200).map(|x| x as u8).collect();
+    let splice = b"spliced in from another input";
+
+    for seed in 0..256 {
+        let a = Mutator::new(seed, 4096).mutate(&parent, Some(splice));
+        let b = Mutator::new(seed, 4096).mutate(&parent, Some(splice));
+        assert_eq!(a, b, "Seed {} wasn't reproducible", seed);
+    }
+}
+
+#[test]
+fn test_mutate_respects_max_size() {
+    let parent = vec![0x41u8; 100];
+    let mut mutator = Mutator::new(0x1337, 128);
+    for _ in 0..1000 {
+        assert!(mutator.mutate(&parent, Some(&parent)).len() <= 128);
+    }
+
+    // Parents which are too large get truncated
+    assert!(mutator.mutate(&[0u8; 1000], None).len() <= 128);
+}
+
+#[test]
+fn test_strategies_on_small_inputs() {
+    // Nothing should panic no matter how small the input is
+    let mut mutator = Mutator::new(5, 64);
+    for &strategy in STRATEGIES.iter() {
+        for len in 0..16 {
+            for _ in 0..64 {
+                let mut input = vec![0u8; len];
+                mutator.apply(strategy, &mut input, Some(&[1, 2, 3]));
+                mutator.apply(strategy, &mut input, Some(&[]));
+                mutator.apply(strategy, &mut input, None);
+                assert!(input.len() <= 64);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_strategies_change_input() {
+    let mut mutator = Mutator::new(99, 4096);
+    let parent: Vec<u8> = (0..64).collect();
+    let other  = vec![0xaau8; 64];
+
+    for &strategy in STRATEGIES.iter() {
+        // Some single applications are no-ops (copying a block onto itself),
+        // but not every time
+        let changed = (0..64).any(|_| {
+            let mut input = parent.clone();
+            mutator.apply(strategy, &mut input, Some(&other));
+            input != parent
+        });
+        assert!(changed, "{:?} never changed the input", strategy);
+    }
+}
+
+#[test]
+fn test_ints() {
+    let mut rng = Rng::new(0);
+    let mut input = vec![0x12, 0x34];
+    let (off, val) = read_int(&mut rng, &input, 2, true).unwrap();
+    assert_eq!((off, val), (0, 0x1234));
+    write_int(&mut input, off, 2, true, val + 1);
+    assert_eq!(input, [0x12, 0x35]);
+    write_int(&mut input, 0, 2, false, 0xbeef);
+    assert_eq!(input, [0xef, 0xbe]);
+    assert!(read_int(&mut rng, &input, 4, false).is_none());
+}
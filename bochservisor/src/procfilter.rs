@@ -0,0 +1,212 @@
+/// Per-process coverage filtering
+///
+/// With a target process configured only coverage from that process counts,
+/// which keeps other processes and services running in the guest out of the
+/// coverage and the fuzzer's idea of what's interesting.
+///
+/// Processes are identified by their page tables. The first time kernel code
+/// runs with a page table we haven't seen yet, the current process is read
+/// from `_KPCR.Prcb.CurrentThread` and both its kernel and KVA shadow user page
+/// tables are remembered. User mode code can't be identified on its own, as
+/// the kernel isn't mapped with KVA shadowing, so it's skipped until the
+/// process has been seen in kernel mode. This only works on 64-bit Windows.
+///
+/// Environment variables:
+///
+/// * `APPLEPIE_COVERAGE_PROCESS` - Process to collect coverage from,
+///   `name:<image>` matches the image name without case (only the first 15
+///   characters are kept by Windows), `pid:<n>` the process ID and
+///   `cr3:<addr>` the page table of the process
+/// * `APPLEPIE_COVERAGE_KERNEL` - Kernel coverage to keep, `all` if not set.
+///   `all` keeps all of it, `target` only while the target process is
+///   current and `none` drops it
+
+use std::collections::HashMap;
+use crate::fuzz::parse_num;
+use crate::win32::ProcessInfo;
+
+/// Environment variable holding the process to collect coverage from
+const PROCESS_ENV_VAR: &str = "APPLEPIE_COVERAGE_PROCESS";
+
+/// Environment variable holding which kernel coverage to keep
+const KERNEL_ENV_VAR: &str = "APPLEPIE_COVERAGE_KERNEL";
+
+/// Maximum length of `_EPROCESS.ImageFileName`
+const IMAGE_NAME_LEN: usize = 15;
+
+/// Process to collect coverage from
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    /// Image name, lowercase and truncated like `_EPROCESS.ImageFileName`
+    Name(String),
+
+    /// Process ID
+    Pid(usize),
+
+    /// Page table of the process with the PCID bits masked off
+    Cr3(usize),
+}
+
+/// Which kernel coverage to keep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelCoverage {
+    /// Keep all kernel coverage
+    All,
+
+    /// Keep kernel coverage only while the target process is current
+    Target,
+
+    /// Drop all kernel coverage
+    None,
+}
+
+/// Coverage filter for a target process
+pub struct ProcessFilter {
+    /// Process to collect coverage from
+    target: Target,
+
+    /// Which kernel coverage to keep
+    kernel: KernelCoverage,
+
+    /// Page tables we've identified, and whether they belong to the target
+    known: HashMap<usize, bool>,
+
+    /// Set once the target process has been found and reported
+    found: bool,
+}
+
+impl ProcessFilter {
+    /// Create a new filter for `target`
+    pub fn new(target: Target, kernel: KernelCoverage) -> Self {
+        ProcessFilter { target, kernel, known: HashMap::new(), found: false }
+    }
+
+    /// Get the process filter from the environment. Returns `Ok(None)` if no
+    /// target process was requested
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let target = match std::env::var(PROCESS_ENV_VAR) {
+            Ok(target) => parse_target(&target)?,
+            Err(_)     => return Ok(None),
+        };
+
+        let kernel = match std::env::var(KERNEL_ENV_VAR).as_ref()
+                .map(|x| x.as_str()) {
+            Ok("all") | Err(_) => KernelCoverage::All,
+            Ok("target") => KernelCoverage::Target,
+            Ok("none")   => KernelCoverage::None,
+            Ok(kernel) => return Err(format!(
+                "Unknown kernel coverage `{}`", kernel)),
+        };
+
+        Ok(Some(ProcessFilter::new(target, kernel)))
+    }
+
+    /// Forget every page table we've identified. Processes from an earlier
+    /// fuzz case are gone and their page tables may be reused
+    pub fn reset(&mut self) {
+        self.known.clear();
+    }
+
+    /// Returns `true` if `process` is the target process
+    fn matches(&self, process: &ProcessInfo) -> bool {
+        match &self.target {
+            Target::Name(name) => process.name.to_lowercase() == *name,
+            Target::Pid(pid)   => process.pid == *pid,
+            Target::Cr3(cr3)   =>
+                process.cr3 == *cr3 || process.user_cr3 == *cr3,
+        }
+    }
+
+    /// Remember that `cr3` belongs to `process`. Returns `true` if it's the
+    /// target process
+    fn learn(&mut self, cr3: usize, process: &ProcessInfo) -> bool {
+        let target = self.matches(process);
+        self.known.insert(cr3, target);
+        self.known.insert(process.cr3, target);
+        if process.user_cr3 != 0 {
+            self.known.insert(process.user_cr3, target);
+        }
+
+        if target && !self.found {
+            print!("Collecting coverage from {} (pid {}, cr3 {:#x})\n",
+                process.name, process.pid, process.cr3);
+            self.found = true;
+        }
+
+        target
+    }
+
+    /// Returns `true` if coverage at page table `cr3` should be kept.
+    /// `kernel` is set if the coverage is from kernel mode, where `lookup`
+    /// is used to get the current process if `cr3` isn't known yet
+    pub fn allowed<F>(&mut self, cr3: usize, kernel: bool, lookup: F) -> bool
+            where F: FnOnce() -> Option<ProcessInfo> {
+        if kernel {
+            match self.kernel {
+                KernelCoverage::All    => return true,
+                KernelCoverage::None   => return false,
+                KernelCoverage::Target => {}
+            }
+        }
+
+        if let Some(&target) = self.known.get(&cr3) {
+            return target;
+        }
+
+        // Only kernel mode can tell us which process this is
+        if !kernel { return false; }
+        match lookup() {
+            Some(process) => self.learn(cr3, &process),
+            None => false,
+        }
+    }
+}
+
+/// Parse a target process
+fn parse_target(target: &str) -> Result<Target, String> {
+    let mut split = target.splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some("name"), Some(name)) => Ok(Target::Name(
+            name.chars().take(IMAGE_NAME_LEN).collect::<String>()
+                .to_lowercase())),
+        (Some("pid"), Some(pid)) => Ok(Target::Pid(parse_num(pid)? as usize)),
+        (Some("cr3"), Some(cr3)) => Ok(Target::Cr3(
+            parse_num(cr3)? as usize & 0xFFFFFFFFFF000)),
+        _ => Err(format!("{} must be `name:<image>`, `pid:<n>` or \
+            `cr3:<addr>`", PROCESS_ENV_VAR)),
+    }
+}
+
+#[test]
+fn test_process_filter() {
+    assert_eq!(parse_target("name:VeryLongImageName.exe").unwrap(),
+        Target::Name("verylongimagena".into()));
+    assert_eq!(parse_target("cr3:0x1aa002").unwrap(), Target::Cr3(0x1aa000));
+    assert!(parse_target("tid:4").is_err());
+
+    let process = |pid, cr3, name: &str| ProcessInfo {
+        eprocess: 0, pid, cr3, user_cr3: cr3 + 0x1000, name: name.into(),
+    };
+    let target = process(0x1234, 0x10000, "VeryLongImageNa");
+    let other  = process(4, 0x20000, "System");
+
+    let mut filter = ProcessFilter::new(
+        parse_target("name:verylongimagename.exe").unwrap(),
+        KernelCoverage::Target);
+
+    // User mode is skipped until the process is seen in kernel mode, after
+    // which its KVA shadow page table is known too
+    assert!(!filter.allowed(0x11000, false, || panic!()));
+    assert!(filter.allowed(0x10000, true, || Some(target.clone())));
+    assert!(filter.allowed(0x11000, false, || panic!()));
+    assert!(!filter.allowed(0x20000, true, || Some(other.clone())));
+    assert!(!filter.allowed(0x21000, false, || panic!()));
+
+    filter.reset();
+    assert!(!filter.allowed(0x10000, true, || None));
+
+    let mut filter = ProcessFilter::new(Target::Pid(4), KernelCoverage::None);
+    assert!(!filter.allowed(0x20000, true, || panic!()));
+    filter.kernel = KernelCoverage::All;
+    assert!(filter.allowed(0x10000, true, || panic!()));
+}
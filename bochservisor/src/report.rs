@@ -0,0 +1,195 @@
+/// HTML coverage reports
+///
+/// A single self-contained HTML file, no scripts or external resources, so it
+/// can be archived or mailed around as-is. It has a summary of every module
+/// with coverage, and per module the functions which were hit along with how
+/// many covered offsets (or blocks, when we have the module's block list) fell
+/// in each. With a baseline, functions which weren't covered in it are marked
+/// as new so progress over a campaign is easy to review.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use crate::drcov;
+
+/// Name functions are grouped under when an offset has no symbol
+const NO_SYMBOL: &str = "<no symbol>";
+
+/// A single module worth of coverage
+pub struct Module<'a> {
+    /// Name of the module
+    pub name: &'a str,
+
+    /// SizeOfImage from the PE header
+    pub size: u32,
+
+    /// TimeDateStamp from the PE header
+    pub timestamp: u32,
+
+    /// Bitmap of covered offsets, one bit per byte of the image
+    pub bitmap: &'a [u8],
+
+    /// Bitmap of offsets covered in the baseline, `None` if the module isn't
+    /// in it
+    pub baseline: Option<&'a [u8]>,
+
+    /// Offset of every basic block in the module, `None` if unknown
+    pub blocks: Option<&'a [u32]>,
+}
+
+impl<'a> Module<'a> {
+    /// Get the covered offsets of the module in `bitmap`. When the blocks are
+    /// known only block starts count
+    fn covered(&self, bitmap: &'a [u8]) -> Vec<u32> {
+        let module = drcov::Module {
+            name:      self.name,
+            size:      self.size,
+            timestamp: self.timestamp,
+            bitmap,
+        };
+
+        match self.blocks {
+            Some(blocks) => module.offsets()
+                .filter(|x| blocks.binary_search(x).is_ok()).collect(),
+            None => module.offsets().collect(),
+        }
+    }
+}
+
+/// Escape `text` for use in HTML
+fn escape(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for chr in text.chars() {
+        match chr {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            _   => ret.push(chr),
+        }
+    }
+    ret
+}
+
+/// Write an HTML report of `modules` to `out`. `function` resolves an offset
+/// in the module with the given index to the name of the function it's in.
+/// `baseline` is the name of the baseline, if there is one
+pub fn write<W, F>(mut out: W, modules: &[Module], baseline: Option<&str>,
+        mut function: F) -> io::Result<()>
+        where W: Write, F: FnMut(usize, u32) -> Option<String> {
+    write!(out, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>Coverage report</title>\n<style>\n\
+        body {{ font-family: sans-serif; margin: 2em; }}\n\
+        table {{ border-collapse: collapse; }}\n\
+        th, td {{ border: 1px solid #ccc; padding: 2px 8px; }}\n\
+        td {{ font-family: monospace; }}\n\
+        td.num {{ text-align: right; }}\n\
+        tr.new td {{ background: #dfd; }}\n\
+        summary {{ cursor: pointer; margin-top: 1em; }}\n\
+        </style>\n</head>\n<body>\n<h1>Coverage report</h1>\n")?;
+    if let Some(baseline) = baseline {
+        write!(out, "<p>Functions not covered in <code>{}</code> are \
+            highlighted as new.</p>\n", escape(baseline))?;
+    }
+
+    // Hits per function, and which functions are new, for every module
+    let mut summaries = Vec::new();
+    for (idx, module) in modules.iter().enumerate() {
+        let covered = module.covered(module.bitmap);
+
+        let mut hits: BTreeMap<String, u64> = BTreeMap::new();
+        for &offset in &covered {
+            let name = function(idx, offset)
+                .unwrap_or_else(|| NO_SYMBOL.to_string());
+            *hits.entry(name).or_default() += 1;
+        }
+
+        let mut new = BTreeSet::new();
+        if baseline.is_some() {
+            let old: BTreeSet<String> = module.baseline.map(|bitmap| {
+                module.covered(bitmap).into_iter()
+                    .filter_map(|offset| function(idx, offset)).collect()
+            }).unwrap_or_default();
+            new = hits.keys().filter(|x| x.as_str() != NO_SYMBOL &&
+                !old.contains(*x)).cloned().collect();
+        }
+
+        summaries.push((covered.len(), hits, new));
+    }
+
+    let unit = |module: &Module| {
+        if module.blocks.is_some() { "blocks" } else { "offsets" }
+    };
+
+    write!(out, "<h2>Modules</h2>\n<table>\n<tr><th>Module</th>\
+        <th>Covered</th><th>Total blocks</th><th>Percent</th>\
+        <th>Functions</th><th>New functions</th></tr>\n")?;
+    for (module, (covered, hits, new)) in modules.iter().zip(&summaries) {
+        let (total, percent) = match module.blocks {
+            Some(blocks) if !blocks.is_empty() => (blocks.len().to_string(),
+                format!("{:.2}%",
+                    *covered as f64 * 100. / blocks.len() as f64)),
+            _ => ("-".to_string(), "-".to_string()),
+        };
+        write!(out, "<tr><td><a href=\"#{name}\">{name}</a></td>\
+            <td class=\"num\">{} {}</td><td class=\"num\">{}</td>\
+            <td class=\"num\">{}</td><td class=\"num\">{}</td>\
+            <td class=\"num\">{}</td></tr>\n", covered, unit(module), total,
+            percent, hits.len(), new.len(), name = escape(module.name))?;
+    }
+    write!(out, "</table>\n")?;
+
+    for (module, (_, hits, new)) in modules.iter().zip(&summaries) {
+        write!(out, "<details id=\"{}\">\n<summary>{} ({:08x} {:x}), {} \
+            functions</summary>\n<table>\n<tr><th>Function</th>\
+            <th>Covered {}</th></tr>\n", escape(module.name),
+            escape(module.name), module.timestamp, module.size, hits.len(),
+            unit(module))?;
+        for (name, count) in hits {
+            let class = if new.contains(name) { " class=\"new\"" } else { "" };
+            write!(out, "<tr{}><td>{}</td><td class=\"num\">{}</td></tr>\n",
+                class, escape(name), count)?;
+        }
+        write!(out, "</table>\n</details>\n")?;
+    }
+
+    write!(out, "</body>\n</html>\n")?;
+    Ok(())
+}
+
+#[test]
+fn test_write_report() {
+    let blocks = [0x0, 0x4, 0x8, 0x10];
+    let modules = [
+        Module { name: "a<b>.sys", size: 0x20, timestamp: 0x1234,
+            bitmap: &[0x13, 0x01, 0, 0], baseline: Some(&[0x01]),
+            blocks: Some(&blocks) },
+        Module { name: "c.dll", size: 0x10, timestamp: 0,
+            bitmap: &[0x01, 0x80], baseline: None, blocks: None },
+    ];
+
+    let function = |idx: usize, offset: u32| match (idx, offset) {
+        (0, 0x0..=0x7) => Some("a!Foo".to_string()),
+        (0, _)         => Some("a!Bar".to_string()),
+        (_, 0x0)       => Some("c!Baz".to_string()),
+        _              => None,
+    };
+
+    let mut out = Vec::new();
+    write(&mut out, &modules, Some("base.db"), function).unwrap();
+    let html = String::from_utf8(out).unwrap();
+
+    // Offset 0x1 isn't a block start, so 3 of the 4 blocks are covered
+    assert!(html.contains("<td><a href=\"#a&lt;b&gt;.sys\">a&lt;b&gt;.sys</a>\
+        </td><td class=\"num\">3 blocks</td><td class=\"num\">4</td>\
+        <td class=\"num\">75.00%</td><td class=\"num\">2</td>\
+        <td class=\"num\">1</td>"));
+    assert!(html.contains("<tr class=\"new\"><td>a!Bar</td>\
+        <td class=\"num\">1</td>"));
+    assert!(html.contains("<tr><td>a!Foo</td><td class=\"num\">2</td>"));
+
+    // Everything in a module missing from the baseline is new, offsets
+    // without a symbol never are
+    assert!(html.contains("<tr class=\"new\"><td>c!Baz</td>"));
+    assert!(html.contains("<tr><td>&lt;no symbol&gt;</td>\
+        <td class=\"num\">1</td>"));
+}
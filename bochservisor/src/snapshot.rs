@@ -0,0 +1,373 @@
+/// applepie snapshot files
+///
+/// A single file holding everything needed to resume a guest: physical memory,
+/// Bochs device state, the register state of every processor, and the sectors
+/// the guest wrote to the virtual disk. These are written and read entirely in
+/// Rust so a worker can boot straight into a snapshot without Bochs parsing its
+/// snapshot folder.
+///
+/// Layout (all integers little endian):
+///
+/// ```text
+/// header:  magic "APSNAP\0\0", version u32, context size u32
+/// memory:  size u64, then one tag u8 per 4 KiB page:
+///              PAGE_ZERO  no data, page is all zeros
+///              PAGE_DATA  4096 bytes of page contents follow
+///              PAGE_DUP   u32 index of an earlier page with the same contents
+/// devices: count u32, then per device name len u32, name, data len u64, data
+/// cpus:    count u32, then a raw `VpContext` per processor
+/// disk:    count u64, then per sector the sector number u64 and 512 bytes
+/// ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use crate::context::VpContext;
+
+/// Magic at the start of every snapshot file
+const SNAPSHOT_MAGIC: &[u8; 8] = b"APSNAP\0\0";
+
+/// Version of the snapshot format. Bump this on any layout change
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Page tags for the memory section
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+const PAGE_DUP:  u8 = 2;
+
+/// Size of a page for the memory section
+const PAGE_SIZE: usize = 4096;
+
+/// Size of a disk sector
+const SECTOR_SIZE: usize = 512;
+
+/// Everything that makes up a snapshot of the guest
+///
+/// Memory is a `Cow` so a snapshot of a running guest can be saved without
+/// making a copy of all of guest memory
+pub struct Snapshot<'a> {
+    /// Guest physical memory starting at physical address 0
+    pub memory: Cow<'a, [u8]>,
+
+    /// Bochs device state as (registered name, raw state)
+    pub devices: Vec<(String, Vec<u8>)>,
+
+    /// Register state for each processor, indexed by processor number
+    pub contexts: Vec<VpContext>,
+
+    /// Disk sectors which differ from the backing disk image as
+    /// (sector number, contents)
+    pub disk: Vec<(u64, [u8; SECTOR_SIZE])>,
+}
+
+/// Create an `InvalidData` error with `msg`
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn write_u8<W: Write>(w: &mut W, val: u8) -> io::Result<()> {
+    w.write_all(&[val])
+}
+
+fn write_u32<W: Write>(w: &mut W, val: u32) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+fn write_u64<W: Write>(w: &mut W, val: u64) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+/// Reader which keeps track of how many bytes are left in the snapshot, so
+/// lengths read from it can be checked before anything is allocated for them
+struct Bounded<'a, R: Read> {
+    inner:     &'a mut R,
+    remaining: u64,
+}
+
+impl<'a, R: Read> Bounded<'a, R> {
+    /// Make sure there are at least `len` bytes left for `what`, returning
+    /// `len` as a `usize`
+    fn check(&self, len: u64, what: &str) -> io::Result<usize> {
+        if len > self.remaining {
+            return Err(invalid(&format!("{} is larger than the snapshot",
+                what)));
+        }
+        Ok(len as usize)
+    }
+}
+
+impl<'a, R: Read> Read for Bounded<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bread = self.inner.read(buf)?;
+        self.remaining = self.remaining.saturating_sub(bread as u64);
+        Ok(bread)
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl<'a> Snapshot<'a> {
+    /// Write the snapshot to the file at `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.serialize(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Read a snapshot from the file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot<'static>> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Snapshot::deserialize(&mut BufReader::new(file), size)
+    }
+
+    /// Serialize the snapshot into `w`
+    pub fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        assert!(self.memory.len() % PAGE_SIZE == 0,
+            "Snapshot memory is not 4 KiB aligned");
+
+        // Header
+        w.write_all(SNAPSHOT_MAGIC)?;
+        write_u32(w, SNAPSHOT_VERSION)?;
+        write_u32(w, std::mem::size_of::<VpContext>() as u32)?;
+
+        // Memory. Pages we've already written are tracked by a hash of their
+        // contents, the hash only finds candidates and the actual contents
+        // are always compared
+        let zero_page = [0u8; PAGE_SIZE];
+        let mut written: HashMap<u64, Vec<u32>> = HashMap::new();
+        write_u64(w, self.memory.len() as u64)?;
+        'next_page: for (idx, page) in
+                self.memory.chunks(PAGE_SIZE).enumerate() {
+            if page == &zero_page[..] {
+                write_u8(w, PAGE_ZERO)?;
+                continue;
+            }
+
+            let mut hasher = DefaultHasher::new();
+            hasher.write(page);
+            let candidates = written.entry(hasher.finish())
+                .or_insert_with(Vec::new);
+
+            for &cand in candidates.iter() {
+                let cand_page = &self.memory[cand as usize * PAGE_SIZE..
+                    (cand as usize + 1) * PAGE_SIZE];
+                if cand_page == page {
+                    write_u8(w, PAGE_DUP)?;
+                    write_u32(w, cand)?;
+                    continue 'next_page;
+                }
+            }
+
+            write_u8(w, PAGE_DATA)?;
+            w.write_all(page)?;
+            candidates.push(idx as u32);
+        }
+
+        // Devices
+        write_u32(w, self.devices.len() as u32)?;
+        for (name, data) in &self.devices {
+            write_u32(w, name.len() as u32)?;
+            w.write_all(name.as_bytes())?;
+            write_u64(w, data.len() as u64)?;
+            w.write_all(data)?;
+        }
+
+        // Processors
+        write_u32(w, self.contexts.len() as u32)?;
+        for context in &self.contexts {
+            let raw = unsafe {
+                std::slice::from_raw_parts(
+                    context as *const VpContext as *const u8,
+                    std::mem::size_of::<VpContext>())
+            };
+            w.write_all(raw)?;
+        }
+
+        // Disk
+        write_u64(w, self.disk.len() as u64)?;
+        for (sector, data) in &self.disk {
+            write_u64(w, *sector)?;
+            w.write_all(data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deserialize a snapshot from `r`, which holds `size` bytes. Lengths in
+    /// the snapshot are checked against what's left before anything is
+    /// allocated for them
+    pub fn deserialize<R: Read>(r: &mut R, size: u64)
+            -> io::Result<Snapshot<'static>> {
+        let r = &mut Bounded { inner: r, remaining: size };
+
+        // Header
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid("Not an applepie snapshot"));
+        }
+        if read_u32(r)? != SNAPSHOT_VERSION {
+            return Err(invalid("Unsupported snapshot version"));
+        }
+        if read_u32(r)? != std::mem::size_of::<VpContext>() as u32 {
+            return Err(invalid("Snapshot context size mismatch"));
+        }
+
+        // Memory
+        let memory_size = read_u64(r)?;
+        if memory_size % PAGE_SIZE as u64 != 0 {
+            return Err(invalid("Snapshot memory is not 4 KiB aligned"));
+        }
+
+        // Every page has at least its tag in the file
+        let memory_size = r.check(memory_size / PAGE_SIZE as u64,
+            "Snapshot memory")? * PAGE_SIZE;
+        let mut memory = vec![0u8; memory_size];
+        for idx in 0..memory_size / PAGE_SIZE {
+            let offset = idx * PAGE_SIZE;
+            match read_u8(r)? {
+                PAGE_ZERO => {}
+                PAGE_DATA => {
+                    r.read_exact(&mut memory[offset..offset + PAGE_SIZE])?;
+                }
+                PAGE_DUP => {
+                    let orig = read_u32(r)? as usize;
+                    if orig >= idx {
+                        return Err(invalid("Duplicate page refers forward"));
+                    }
+                    memory.copy_within(orig * PAGE_SIZE..
+                        (orig + 1) * PAGE_SIZE, offset);
+                }
+                _ => return Err(invalid("Invalid page tag")),
+            }
+        }
+
+        // Devices
+        let mut devices = Vec::new();
+        for _ in 0..read_u32(r)? {
+            let name_len = read_u32(r)?;
+            let mut name = vec![0u8; r.check(name_len as u64, "Device name")?];
+            r.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid("Device name is not UTF-8"))?;
+
+            let data_len = read_u64(r)?;
+            let mut data = vec![0u8; r.check(data_len, "Device state")?];
+            r.read_exact(&mut data)?;
+            devices.push((name, data));
+        }
+
+        // Processors
+        let mut contexts = Vec::new();
+        for _ in 0..read_u32(r)? {
+            let mut context = VpContext::default();
+            let raw = unsafe {
+                std::slice::from_raw_parts_mut(
+                    &mut context as *mut VpContext as *mut u8,
+                    std::mem::size_of::<VpContext>())
+            };
+            r.read_exact(raw)?;
+            contexts.push(context);
+        }
+
+        // Disk
+        let mut disk = Vec::new();
+        for _ in 0..read_u64(r)? {
+            let sector = read_u64(r)?;
+            let mut data = [0u8; SECTOR_SIZE];
+            r.read_exact(&mut data)?;
+            disk.push((sector, data));
+        }
+
+        Ok(Snapshot { memory: Cow::Owned(memory), devices, contexts, disk })
+    }
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    // A zero page, two copies of the same page, and a unique page
+    let mut memory = vec![0u8; 4 * PAGE_SIZE];
+    memory[PAGE_SIZE..2 * PAGE_SIZE].iter_mut().for_each(|x| *x = 0x41);
+    memory[2 * PAGE_SIZE..3 * PAGE_SIZE].iter_mut().for_each(|x| *x = 0x41);
+    memory[3 * PAGE_SIZE] = 0x42;
+
+    let mut contexts = vec![VpContext::default(); 2];
+    contexts[1].rip.Reg64 = 0x1234;
+    let snapshot = Snapshot {
+        memory:  Cow::Owned(memory),
+        devices: vec![("pic".to_string(), vec![1, 2, 3]),
+            ("cmos".to_string(), vec![])],
+        contexts,
+        disk:    vec![(7, [0x55; SECTOR_SIZE])],
+    };
+
+    let mut out = Vec::new();
+    snapshot.serialize(&mut out).unwrap();
+    let loaded = Snapshot::deserialize(&mut &out[..], out.len() as u64)
+        .unwrap();
+
+    assert!(loaded.memory == snapshot.memory);
+    assert_eq!(loaded.devices, snapshot.devices);
+    assert_eq!(loaded.contexts.len(), 2);
+    assert_eq!(unsafe { loaded.contexts[1].rip.Reg64 }, 0x1234);
+    assert_eq!(loaded.disk.len(), 1);
+    assert_eq!(loaded.disk[0].0, 7);
+    assert!(loaded.disk[0].1[..] == [0x55; SECTOR_SIZE][..]);
+
+    // The duplicate page is stored as a reference to the first copy
+    assert!(out.len() < 3 * PAGE_SIZE);
+}
+
+#[test]
+fn test_snapshot_truncated() {
+    let snapshot = Snapshot {
+        memory:   Cow::Owned(vec![0x41; PAGE_SIZE]),
+        devices:  vec![("pic".to_string(), vec![1, 2, 3])],
+        contexts: vec![VpContext::default()],
+        disk:     vec![],
+    };
+    let mut out = Vec::new();
+    snapshot.serialize(&mut out).unwrap();
+
+    // Every truncation fails cleanly
+    for len in 0..out.len() {
+        assert!(Snapshot::deserialize(&mut &out[..len], len as u64).is_err());
+    }
+
+    // Lengths past the end of the file are rejected up front. The device
+    // state length follows the 3 byte name of the only device
+    let name = out.windows(3).position(|x| x == b"pic").unwrap();
+    let mut huge = out.clone();
+    huge[name + 3..name + 11].copy_from_slice(&u64::MAX.to_le_bytes());
+    let err = Snapshot::deserialize(&mut &huge[..], huge.len() as u64)
+        .err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // Same for the size of memory
+    let mut huge = out.clone();
+    huge[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    let err = Snapshot::deserialize(&mut &huge[..], huge.len() as u64)
+        .err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
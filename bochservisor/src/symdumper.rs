@@ -302,7 +302,7 @@ pub fn get_symbols_from_module(module: &ModuleInfo)
 /// it's TimeDateStamp and SizeOfImage from it's PE header
 /// 
 /// Returns a string containing a filename of the downloaded module
-fn download_symbol(module_name: &str, timedatestamp: u32, sizeofimage: u32)
+pub fn download_symbol(module_name: &str, timedatestamp: u32, sizeofimage: u32)
         -> std::io::Result<String> {
     let mut dir = std::env::temp_dir();
     dir.push("applepie_manifest");
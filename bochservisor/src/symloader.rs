@@ -12,7 +12,7 @@ pub struct Symbols {
 impl Symbols {
     /// Load the symbols for a module `module_name` with TimeDateStamp and
     /// SizeOfImage from their PE header
-    fn load_win32(&mut self, module: &ModuleInfo) -> Result<(), std::io::Error>{
+    pub fn load_win32(&mut self, module: &ModuleInfo) -> Result<(), std::io::Error>{
         // Already loaded
         if self.modules.contains_key(module) { return Ok(()); }
 
@@ -30,6 +30,35 @@ impl Symbols {
         Ok(())
     }
 
+    /// Look up the offset of symbol `name` in `module`
+    pub fn lookup(&mut self, module: &ModuleInfo, name: &str)
+            -> Option<usize> {
+        // Attempt to load symbols for this module
+        if self.load_win32(module).is_err() {
+            return None;
+        }
+
+        self.modules.get(module).and_then(|context| {
+            context.symbols.iter().find(|x| x.1 == name)
+                .map(|x| x.0 as usize)
+        })
+    }
+
+    /// Get the source lines of `module` as `(offset, file, line)`, sorted by
+    /// offset. Only private symbols have these, and only once they're loaded
+    pub fn source_lines(&self, module: &ModuleInfo)
+            -> Option<&[(u64, String, u64)]> {
+        self.modules.get(module).map(|x| x.sourceline.as_slice())
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Get the symbols of `module` as `(offset, name, size)`, sorted by
+    /// offset. Empty until they're loaded
+    pub fn symbols(&self, module: &ModuleInfo) -> &[(u64, String, u64)] {
+        self.modules.get(module).map(|x| x.symbols.as_slice())
+            .unwrap_or(&[])
+    }
+
     /// Lookup a symbol based on a module and offset
     pub fn resolve(&mut self, module: &ModuleInfo, offset: usize)
             -> Option<String> {
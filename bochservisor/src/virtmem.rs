@@ -27,6 +27,51 @@ pub fn canonicalize_address(addr: u64) -> u64
     addr as u64
 }
 
+/// Paging mode of a processor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Paging {
+    /// Paging is off, virtual addresses are physical addresses
+    Disabled,
+
+    /// 32-bit paging. Two levels of 1024 entry tables with 4 byte entries,
+    /// and 4 MiB large pages
+    Legacy,
+
+    /// PAE paging. A 4 entry PDPT followed by two levels of 512 entry tables,
+    /// and 2 MiB large pages
+    Pae,
+
+    /// 4-level long mode paging. Compatibility mode code uses this as well
+    Long,
+}
+
+impl Paging {
+    /// Get the paging mode from CR0, CR4 and EFER.LMA
+    pub fn new(cr0: u64, cr4: u64, lma: bool) -> Self {
+        if (cr0 & (1 << 31)) == 0 {
+            Paging::Disabled
+        } else if lma {
+            Paging::Long
+        } else if (cr4 & (1 << 5)) != 0 {
+            Paging::Pae
+        } else {
+            Paging::Legacy
+        }
+    }
+
+    /// Get the physical address of the root of the page table from `cr3`,
+    /// with the PCID and reserved bits masked off. PAE page tables are only
+    /// 32 byte aligned
+    pub fn table_root(&self, cr3: u64) -> u64 {
+        match self {
+            Paging::Disabled => 0,
+            Paging::Legacy   => cr3 & 0xFFFFF000,
+            Paging::Pae      => cr3 & 0xFFFFFFE0,
+            Paging::Long     => cr3 & 0xFFFFFFFFFF000,
+        }
+    }
+}
+
 /// Bits for raw page tables and page table entries
 #[repr(u64)]
 pub enum PTBits {
@@ -158,6 +203,156 @@ impl<'a, T: 'a + PhysMem> PageTable<'a, T>
         self.virt_to_phys_dirty(vaddr, false)
     }
 
+    /// Translate a virtual address to a physical address using this page table
+    /// with the `paging` mode of a processor rather than assuming long mode
+    ///
+    /// Return a tuple of (physical address, page size)
+    pub fn virt_to_phys_paging(&mut self, paging: Paging, vaddr: u64) ->
+        Result<Option<(u64, u64)>, &'static str>
+    {
+        match paging {
+            Paging::Disabled => Ok(Some((vaddr, 0x1000))),
+            Paging::Legacy   => self.virt_to_phys_legacy(vaddr),
+            Paging::Pae      => self.virt_to_phys_pae(vaddr),
+            Paging::Long     => self.virt_to_phys(vaddr),
+        }
+    }
+
+    /// Translate a virtual address with 32-bit paging
+    fn virt_to_phys_legacy(&mut self, vaddr: u64) ->
+        Result<Option<(u64, u64)>, &'static str>
+    {
+        if vaddr > 0xFFFFFFFF {
+            return Err("Virtual address to virt_to_phys() not 32-bit");
+        }
+
+        unsafe {
+            /* Entries are 4 bytes, the upper half of the read is the next
+             * entry
+             */
+            let pde = self.physmem.read_phys_int((self.backing as *mut u32)
+                .offset(((vaddr >> 22) & 0x3ff) as isize) as *mut u64)?
+                & 0xFFFFFFFF;
+            if (pde & PTBits::Present as u64) == 0 {
+                return Ok(None);
+            }
+
+            /* 4 MiB page */
+            if (pde & PTBits::PageSize as u64) != 0 {
+                return Ok(Some(((pde & 0xFFC00000) + (vaddr & 0x3FFFFF),
+                               0x400000)));
+            }
+
+            let table = (pde & 0xFFFFF000) as *mut u32;
+            let pte = self.physmem.read_phys_int(table
+                .offset(((vaddr >> 12) & 0x3ff) as isize) as *mut u64)?
+                & 0xFFFFFFFF;
+            if (pte & PTBits::Present as u64) == 0 {
+                return Ok(None);
+            }
+
+            Ok(Some(((pte & 0xFFFFF000) + (vaddr & 0xfff), 0x1000)))
+        }
+    }
+
+    /// Translate a virtual address with PAE paging
+    fn virt_to_phys_pae(&mut self, vaddr: u64) ->
+        Result<Option<(u64, u64)>, &'static str>
+    {
+        if vaddr > 0xFFFFFFFF {
+            return Err("Virtual address to virt_to_phys() not 32-bit");
+        }
+
+        unsafe {
+            let pdpte = self.physmem.read_phys_int(
+                self.backing.offset(((vaddr >> 30) & 3) as isize))?;
+            if (pdpte & PTBits::Present as u64) == 0 {
+                return Ok(None);
+            }
+
+            let pde = self.physmem.read_phys_int(
+                ((pdpte & 0xFFFFFFFFFF000) as *mut u64)
+                .offset(((vaddr >> 21) & 0x1ff) as isize))?;
+            if (pde & PTBits::Present as u64) == 0 {
+                return Ok(None);
+            }
+
+            /* 2 MiB page */
+            if (pde & PTBits::PageSize as u64) != 0 {
+                return Ok(Some(((pde & 0xFFFFFFFE00000) + (vaddr & 0x1FFFFF),
+                               0x200000)));
+            }
+
+            let pte = self.physmem.read_phys_int(
+                ((pde & 0xFFFFFFFFFF000) as *mut u64)
+                .offset(((vaddr >> 12) & 0x1ff) as isize))?;
+            if (pte & PTBits::Present as u64) == 0 {
+                return Ok(None);
+            }
+
+            Ok(Some(((pte & 0xFFFFFFFFFF000) + (vaddr & 0xfff), 0x1000)))
+        }
+    }
+
+    /// Invoke a closure on each mapping present in this page table, including
+    /// large pages. The closure is given the canonical virtual address, the
+    /// physical address, the size of the mapping, and the permissions as
+    /// (writable, executable) with every level of the table accounted for.
+    ///
+    /// `la57` is CR4.LA57 of the processor using the table. 5-level tables
+    /// aren't supported, walking one as 4-level would give bogus mappings
+    pub fn for_each_mapping<F>(&mut self, la57: bool, mut func: F)
+        -> Result<(), &'static str>
+        where F: FnMut(u64, u64, u64, bool, bool)
+    {
+        if la57 {
+            return Err("5-level paging is not supported");
+        }
+
+        unsafe {
+            self.walk_mappings(self.backing, 0, 0, true, true, &mut func)
+        }
+    }
+
+    /// Recursive helper for `for_each_mapping`, walks the table at `table`
+    /// which is at `depth` (0 for the PML4) and maps starting at `vaddr`
+    unsafe fn walk_mappings<F>(&mut self, table: *mut u64, depth: u32,
+                               vaddr: u64, writable: bool, executable: bool,
+                               func: &mut F) -> Result<(), &'static str>
+        where F: FnMut(u64, u64, u64, bool, bool)
+    {
+        /* Number of bits of virtual address translated below this level */
+        let shift = 39 - depth * 9;
+
+        for idx in 0..512u64 {
+            let entry = self.physmem.read_phys_int(table.offset(idx as isize))?;
+            if (entry & PTBits::Present as u64) == 0 { continue; }
+
+            let vaddr = canonicalize_address(vaddr | (idx << shift));
+            let writable = writable &&
+                (entry & PTBits::Writable as u64) != 0;
+            let executable = executable &&
+                (entry & PTBits::ExecuteDisable as u64) == 0;
+
+            /* PTEs and large pages are the mappings, everything else is a
+             * pointer to the next level
+             */
+            let leaf = depth == 3 ||
+                (depth > 0 && (entry & PTBits::PageSize as u64) != 0);
+
+            if leaf {
+                let size  = 1u64 << shift;
+                let paddr = entry & 0xFFFFFFFFFF000 & !(size - 1);
+                func(vaddr, paddr, size, writable, executable);
+            } else {
+                self.walk_mappings((entry & 0xFFFFFFFFFF000) as *mut u64,
+                    depth + 1, vaddr, writable, executable, func)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Invoke a closure on each page present in this page table. Optionally
     /// if `dirty_only` is true, the closure will only be invoked for dirty
     /// pages.
@@ -233,3 +428,59 @@ impl<'a, T: 'a + PhysMem> PageTable<'a, T>
         Ok(())
     }
 }
+
+#[test]
+fn test_for_each_mapping() {
+    use crate::memreader::{MemReader, MemoryRegion};
+
+    let mut memory = vec![0u8; 9 * 4096];
+    let mut put = |table: usize, idx: usize, entry: u64| {
+        let offset = table + idx * 8;
+        memory[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+    };
+
+    let p  = PTBits::Present as u64;
+    let w  = PTBits::Writable as u64;
+    let ps = PTBits::PageSize as u64;
+    let nx = PTBits::ExecuteDisable as u64;
+
+    // PML4 at 0x1000, the low PDPT at 0x2000 with a 1 GiB page, the PD at
+    // 0x3000 with a 2 MiB page and a read-only PT, the PT at 0x4000, and a
+    // high PDPT at 0x7000 with a 1 GiB page at the top of the address space
+    put(0x1000, 0, 0x2000 | p | w);
+    put(0x1000, 0x1ff, 0x7000 | p | w);
+    put(0x2000, 0, 0x3000 | p | w);
+    put(0x2000, 1, 0x40000000 | p | w | ps | nx);
+    put(0x3000, 0, 0x4000 | p);
+    put(0x3000, 1, 0x200000 | p | w | ps);
+    put(0x3000, 2, 0x400000 | w | ps);
+    put(0x4000, 0, 0x5000 | p | w);
+    put(0x4000, 1, 0x6000 | p | w | nx);
+    put(0x7000, 0x1ff, 0x80000000 | p | w | ps);
+
+    let mut reader = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: memory.as_ptr() as usize,
+        perms:   0,
+        size:    memory.len(),
+    }]);
+    let mut table = unsafe {
+        PageTable::from_existing(0x1000 as *mut u64, &mut reader)
+    };
+
+    let mut mappings = Vec::new();
+    table.for_each_mapping(false, |vaddr, paddr, size, writable, executable| {
+        mappings.push((vaddr, paddr, size, writable, executable));
+    }).unwrap();
+    assert_eq!(mappings, [
+        (0x0, 0x5000, 0x1000, false, true),
+        (0x1000, 0x6000, 0x1000, false, false),
+        (0x200000, 0x200000, 0x200000, true, true),
+        (0x40000000, 0x40000000, 0x40000000, true, false),
+        (0xffffffffc0000000, 0x80000000, 0x40000000, true, true),
+    ]);
+
+    assert!(table.for_each_mapping(true, |_, _, _, _, _| {
+        panic!("Walked a 5-level table");
+    }).is_err());
+}
@@ -4,7 +4,11 @@
 /// There isn't much anything special here, just a lot of FFI
 
 use std::ffi::c_void;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use crate::time;
+use crate::backend::{HypervisorBackend, VmExit, BackendError};
+use crate::context::{VpContext, RegisterValue};
 use whvp_bindings::winhvplatform::*;
 
 // Force a dependency on winhvplatform.lib to make sure we link against it
@@ -17,6 +21,91 @@ pub const PERM_WRITE:   i32 = WHV_MAP_GPA_RANGE_FLAGS_WHvMapGpaRangeFlagWrite;
 pub const PERM_EXECUTE: i32 = WHV_MAP_GPA_RANGE_FLAGS_WHvMapGpaRangeFlagExecute;
 pub const PERM_DIRTY:   i32 = WHV_MAP_GPA_RANGE_FLAGS_WHvMapGpaRangeFlagTrackDirtyPages;
 
+// HRESULTs we know how to describe. The WHV_E_* codes come from
+// WinHvPlatformDefs.h
+const E_NOTIMPL:      u32 = 0x80004001;
+const E_FAIL:         u32 = 0x80004005;
+const E_ACCESSDENIED: u32 = 0x80070005;
+const E_OUTOFMEMORY:  u32 = 0x8007000e;
+const E_INVALIDARG:   u32 = 0x80070057;
+const WHV_E_UNKNOWN_CAPABILITY:            u32 = 0x80370300;
+const WHV_E_INSUFFICIENT_BUFFER:           u32 = 0x80370301;
+const WHV_E_UNKNOWN_PROPERTY:              u32 = 0x80370302;
+const WHV_E_UNSUPPORTED_HYPERVISOR_CONFIG: u32 = 0x80370303;
+const WHV_E_INVALID_PARTITION_CONFIG:      u32 = 0x80370304;
+const WHV_E_GPA_RANGE_NOT_FOUND:           u32 = 0x80370305;
+const WHV_E_VP_ALREADY_EXISTS:             u32 = 0x80370306;
+const WHV_E_VP_DOES_NOT_EXIST:             u32 = 0x80370307;
+const WHV_E_INVALID_VP_STATE:              u32 = 0x80370308;
+const WHV_E_INVALID_VP_REGISTER_NAME:      u32 = 0x80370309;
+
+/// Convert the HRESULT `res` returned from `api` into a `Result`
+fn check(api: &'static str, res: HRESULT) -> Result<(), BackendError> {
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(BackendError {
+            api,
+            code:        res,
+            description: description(res),
+            transient:   is_transient(res),
+        })
+    }
+}
+
+/// Get an error for `api` which returned success but not what we asked for,
+/// described by `description`
+fn failure(api: &'static str, description: &'static str) -> BackendError {
+    BackendError {
+        api,
+        code:        E_FAIL as HRESULT,
+        description: Some(description),
+        transient:   false,
+    }
+}
+
+/// Get a human readable description of `hresult`, if we know it
+fn description(hresult: HRESULT) -> Option<&'static str> {
+    Some(match hresult as u32 {
+        E_NOTIMPL      => "Not implemented",
+        E_FAIL         => "Unspecified failure",
+        E_ACCESSDENIED => "Windows Hypervisor Platform now requires Admin \
+            access, please rerun this application as Administrator!",
+        E_OUTOFMEMORY  => "Out of memory",
+        E_INVALIDARG   => "Invalid argument",
+        WHV_E_UNKNOWN_CAPABILITY            => "Unknown capability",
+        WHV_E_INSUFFICIENT_BUFFER           => "Insufficient buffer",
+        WHV_E_UNKNOWN_PROPERTY              => "Unknown partition property",
+        WHV_E_UNSUPPORTED_HYPERVISOR_CONFIG =>
+            "Unsupported hypervisor configuration",
+        WHV_E_INVALID_PARTITION_CONFIG      =>
+            "Invalid partition configuration",
+        WHV_E_GPA_RANGE_NOT_FOUND           => "GPA range not found",
+        WHV_E_VP_ALREADY_EXISTS             =>
+            "Virtual processor already exists",
+        WHV_E_VP_DOES_NOT_EXIST             =>
+            "Virtual processor does not exist",
+        WHV_E_INVALID_VP_STATE              =>
+            "Invalid virtual processor state",
+        WHV_E_INVALID_VP_REGISTER_NAME      =>
+            "Invalid virtual processor register name",
+        _ => return None,
+    })
+}
+
+/// Returns `true` if trying again (possibly after emulating for a bit) has a
+/// chance of working after `hresult`. Errors like missing Admin access,
+/// missing features, or a bad partition will never go away on their own.
+fn is_transient(hresult: HRESULT) -> bool {
+    match hresult as u32 {
+        E_NOTIMPL | E_ACCESSDENIED | WHV_E_UNKNOWN_CAPABILITY |
+        WHV_E_UNKNOWN_PROPERTY | WHV_E_UNSUPPORTED_HYPERVISOR_CONFIG |
+        WHV_E_INVALID_PARTITION_CONFIG | WHV_E_VP_DOES_NOT_EXIST |
+        WHV_E_INVALID_VP_REGISTER_NAME => false,
+        _ => true,
+    }
+}
+
 /// Get the identifier for this processor
 fn get_cpu_string() -> String {
     let mut buf = [0u8; 48];
@@ -53,7 +142,7 @@ fn get_cpu_string() -> String {
 }
 
 /// Entire context name list for a processor using all possible fields WHVP
-/// allows access to
+/// allows access to, in the order of the fields of `VpContext`
 /// 
 /// DO NOT CHANGE WITHOUT CHANGING THE C VERSION IN BOCHS!!!
 const WHVP_CONTEXT_NAMES: &[i32] = &[
@@ -147,308 +236,6 @@ const WHVP_CONTEXT_NAMES: &[i32] = &[
     WHV_REGISTER_NAME_WHvX64RegisterXCr0,
 ];
 
-/// Entire context structure for a processor using all possible fields WHVP
-/// allows access to
-/// 
-/// It seems internally there's some alignment requirements.
-/// We force this by using a 64-byte alignment
-/// 
-/// DO NOT CHANGE WITHOUT CHANGING THE C VERSION IN BOCHS!!!
-/// This structure crosses FFI boundaries!
-#[repr(C, align(64))]
-pub struct WhvpContext {
-    pub rax: WHV_REGISTER_VALUE,
-    pub rcx: WHV_REGISTER_VALUE,
-    pub rdx: WHV_REGISTER_VALUE,
-    pub rbx: WHV_REGISTER_VALUE,
-    pub rsp: WHV_REGISTER_VALUE,
-    pub rbp: WHV_REGISTER_VALUE,
-    pub rsi: WHV_REGISTER_VALUE,
-    pub rdi: WHV_REGISTER_VALUE,
-    pub r8:  WHV_REGISTER_VALUE,
-    pub r9:  WHV_REGISTER_VALUE,
-    pub r10: WHV_REGISTER_VALUE,
-    pub r11: WHV_REGISTER_VALUE,
-    pub r12: WHV_REGISTER_VALUE,
-    pub r13: WHV_REGISTER_VALUE,
-    pub r14: WHV_REGISTER_VALUE,
-    pub r15: WHV_REGISTER_VALUE,
-    pub rip: WHV_REGISTER_VALUE,
-
-    pub rflags: WHV_REGISTER_VALUE,
-
-    pub es: WHV_REGISTER_VALUE,
-    pub cs: WHV_REGISTER_VALUE,
-    pub ss: WHV_REGISTER_VALUE,
-    pub ds: WHV_REGISTER_VALUE,
-    pub fs: WHV_REGISTER_VALUE,
-    pub gs: WHV_REGISTER_VALUE,
-
-    pub ldtr: WHV_REGISTER_VALUE,
-    pub tr:   WHV_REGISTER_VALUE,
-    pub idtr: WHV_REGISTER_VALUE,
-    pub gdtr: WHV_REGISTER_VALUE,
-
-    pub cr0: WHV_REGISTER_VALUE,
-    pub cr2: WHV_REGISTER_VALUE,
-    pub cr3: WHV_REGISTER_VALUE,
-    pub cr4: WHV_REGISTER_VALUE,
-    pub cr8: WHV_REGISTER_VALUE,
-
-    pub dr0: WHV_REGISTER_VALUE,
-    pub dr1: WHV_REGISTER_VALUE,
-    pub dr2: WHV_REGISTER_VALUE,
-    pub dr3: WHV_REGISTER_VALUE,
-    pub dr6: WHV_REGISTER_VALUE,
-    pub dr7: WHV_REGISTER_VALUE,
-
-    pub xmm0: WHV_REGISTER_VALUE,
-    pub xmm1: WHV_REGISTER_VALUE,
-    pub xmm2: WHV_REGISTER_VALUE,
-    pub xmm3: WHV_REGISTER_VALUE,
-    pub xmm4: WHV_REGISTER_VALUE,
-    pub xmm5: WHV_REGISTER_VALUE,
-    pub xmm6: WHV_REGISTER_VALUE,
-    pub xmm7: WHV_REGISTER_VALUE,
-    pub xmm8: WHV_REGISTER_VALUE,
-    pub xmm9: WHV_REGISTER_VALUE,
-    pub xmm10: WHV_REGISTER_VALUE,
-    pub xmm11: WHV_REGISTER_VALUE,
-    pub xmm12: WHV_REGISTER_VALUE,
-    pub xmm13: WHV_REGISTER_VALUE,
-    pub xmm14: WHV_REGISTER_VALUE,
-    pub xmm15: WHV_REGISTER_VALUE,
-
-    pub st0: WHV_REGISTER_VALUE,
-    pub st1: WHV_REGISTER_VALUE,
-    pub st2: WHV_REGISTER_VALUE,
-    pub st3: WHV_REGISTER_VALUE,
-    pub st4: WHV_REGISTER_VALUE,
-    pub st5: WHV_REGISTER_VALUE,
-    pub st6: WHV_REGISTER_VALUE,
-    pub st7: WHV_REGISTER_VALUE,
-
-    pub fp_control:  WHV_REGISTER_VALUE,
-    pub xmm_control: WHV_REGISTER_VALUE,
-
-    pub tsc: WHV_REGISTER_VALUE,
-    pub efer: WHV_REGISTER_VALUE,
-    pub kernel_gs_base: WHV_REGISTER_VALUE,
-    pub apic_base: WHV_REGISTER_VALUE,
-    pub pat: WHV_REGISTER_VALUE,
-    pub sysenter_cs: WHV_REGISTER_VALUE,
-    pub sysenter_eip: WHV_REGISTER_VALUE,
-    pub sysenter_esp: WHV_REGISTER_VALUE,
-    pub star: WHV_REGISTER_VALUE,
-    pub lstar: WHV_REGISTER_VALUE,
-    pub cstar: WHV_REGISTER_VALUE,
-    pub sfmask: WHV_REGISTER_VALUE,
-
-    pub tsc_aux: WHV_REGISTER_VALUE,
-    //pub spec_ctrl: WHV_REGISTER_VALUE, not yet supported by Windows 17763
-    //pub pred_cmd: WHV_REGISTER_VALUE, not yet supported by Windows 17763
-    //pub apic_id: WHV_REGISTER_VALUE, not yet supported by Windows 17763
-    //pub apic_version: WHV_REGISTER_VALUE, not yet supported by Windows 17763
-    //pub pending_interruption: WHV_REGISTER_VALUE,
-    //pub interrupt_state: WHV_REGISTER_VALUE,
-    //pub pending_event: WHV_REGISTER_VALUE,
-    //pub deliverability_notifications: WHV_REGISTER_VALUE,
-    //pub internal_activity_state: WHV_REGISTER_VALUE, unknown type
-
-    pub xcr0: WHV_REGISTER_VALUE,
-}
-
-impl WhvpContext {
-    /// Gets the linear address for RIP
-    pub fn rip(&self) -> u64 {
-        unsafe { self.cs.Segment.Base.wrapping_add(self.rip.Reg64) }
-    }
-
-    /// Gets the CR3 for the VM with the reserved and PCID bits masked off
-    pub fn cr3(&self) -> u64 {
-        unsafe { self.cr3.Reg64 & 0xFFFFFFFFFF000 }
-    }
-}
-
-impl Default for WhvpContext {
-    /// Returns a zeroed out context structure
-    fn default() -> Self {
-        unsafe { std::mem::zeroed() }
-    }
-}
-
-impl std::fmt::Display for WhvpContext {
-    /// Pretty prints the entire WHVP state available
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        unsafe {
-            write!(f,
-                "rax {:016x} rcx {:016x} rdx {:016x} rbx {:016x}\n\
-                 rsp {:016x} rbp {:016x} rsi {:016x} rdi {:016x}\n\
-                 r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}\n\
-                 r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}\n\
-                 rip {:04x}:{:016x} (linear {:016x})\n\
-                 rfl {:016x}\n\
-                 es  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 cs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 ss  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 ds  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 fs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 gs  {:04x} base {:016x} limit {:08x} attr {:04x}\n\
-                 ldtr base {:016x} limit {:04x} attr {:04x}\n\
-                 tr   base {:016x} limit {:04x} attr {:04x}\n\
-                 idtr base {:016x} limit {:04x}\n\
-                 gdtr base {:016x} limit {:04x}\n\
-                 tsc {:016x} tsc aux {:016x} efer {:016x} kernel gs {:016x}\n\
-                 apic base {:016x} pat {:016x}\n\
-                 sysenter eip {:04x}:{:08x} esp {:08x}\n\
-                 star {:016x} lstar {:016x} cstar {:016x} sfmask {:016x}\n\
-                 cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}\n\
-                 cr8 {:016x}\n\
-                 dr0 {:016x} dr1 {:016x} dr2 {:016x} dr3 {:016x}\n\
-                 dr6 {:016x} dr7 {:016x}\n\
-                 xcr0 {:016x}\n\
-                 xmm control {:08x} mask {:08x}\n\
-                 xmm0  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm1  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm2  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm3  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm4  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm5  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm6  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm7  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm8  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm9  {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm10 {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm11 {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm12 {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm13 {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm14 {:08x} {:08x} {:08x} {:08x}\n\
-                 xmm15 {:08x} {:08x} {:08x} {:08x}\n\
-                 fp control {:04x} status {:04x} tag {:02x} last op {:04x}\n\
-                 fp0 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp1 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp2 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp3 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp4 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp5 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp6 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 fp7 mantissa {:016x} exponent {:04x} sign {:02x}\n\
-                 ",
-                 /*
-                 interrupt pending {:x} type {:x} deliver error {:x}\n    \
-                     inst len {:x} nested {:x} vector {:04x}\n    \
-                     error code {:08x}\n\
-                 interrupt state shadow {:x} nmi masked {:x}\n\
-                 pending event {:08x}\n\
-                 deliverability nmi {:x} interrupt {:x} priority {:x}\n\*/
-                self.rax.Reg64, self.rcx.Reg64, self.rdx.Reg64,
-                self.rbx.Reg64, self.rsp.Reg64, self.rbp.Reg64,
-                self.rsi.Reg64, self.rdi.Reg64, self.r8.Reg64,
-                self.r9.Reg64,  self.r10.Reg64, self.r11.Reg64,
-                self.r12.Reg64, self.r13.Reg64, self.r14.Reg64,
-                self.r15.Reg64,
-                self.cs.Segment.Selector, self.rip.Reg64,
-                self.rip.Reg64.wrapping_add(self.cs.Segment.Base),
-                self.rflags.Reg64,
-                self.es.Segment.Selector, self.es.Segment.Base,
-                self.es.Segment.Limit, self.es.Segment.__bindgen_anon_1.Attributes,
-                self.cs.Segment.Selector, self.cs.Segment.Base,
-                self.cs.Segment.Limit, self.cs.Segment.__bindgen_anon_1.Attributes,
-                self.ss.Segment.Selector, self.ss.Segment.Base,
-                self.ss.Segment.Limit, self.ss.Segment.__bindgen_anon_1.Attributes,
-                self.ds.Segment.Selector, self.ds.Segment.Base,
-                self.ds.Segment.Limit, self.ds.Segment.__bindgen_anon_1.Attributes,
-                self.fs.Segment.Selector, self.fs.Segment.Base,
-                self.fs.Segment.Limit, self.fs.Segment.__bindgen_anon_1.Attributes,
-                self.gs.Segment.Selector, self.gs.Segment.Base,
-                self.gs.Segment.Limit, self.gs.Segment.__bindgen_anon_1.Attributes,
-                self.ldtr.Segment.Base, self.ldtr.Segment.Limit,
-                self.ldtr.Segment.__bindgen_anon_1.Attributes,
-                self.tr.Segment.Base, self.tr.Segment.Limit,
-                self.tr.Segment.__bindgen_anon_1.Attributes,
-                self.idtr.Table.Base, self.idtr.Table.Limit,
-                self.gdtr.Table.Base, self.gdtr.Table.Limit,
-                self.tsc.Reg64, self.tsc_aux.Reg64, self.efer.Reg64,
-                self.kernel_gs_base.Reg64,
-                self.apic_base.Reg64, self.pat.Reg64,
-                self.sysenter_cs.Reg64, self.sysenter_eip.Reg64,
-                self.sysenter_esp.Reg64,
-                self.star.Reg64, self.lstar.Reg64, self.cstar.Reg64,
-                self.sfmask.Reg64,
-                self.cr0.Reg64, self.cr2.Reg64, self.cr3.Reg64,
-                self.cr4.Reg64, self.cr8.Reg64, self.dr0.Reg64,
-                self.dr1.Reg64, self.dr2.Reg64, self.dr3.Reg64,
-                self.dr6.Reg64, self.dr7.Reg64,
-                self.xcr0.Reg64,
-                self.xmm_control.XmmControlStatus.__bindgen_anon_1.XmmStatusControl,
-                self.xmm_control.XmmControlStatus.__bindgen_anon_1.XmmStatusControlMask,
-                // Missing LastFpRdp and LastFpDp depending on mode
-                self.xmm0.Reg128.Dword[0], self.xmm0.Reg128.Dword[1],
-                self.xmm0.Reg128.Dword[2], self.xmm0.Reg128.Dword[3],
-                self.xmm1.Reg128.Dword[0], self.xmm1.Reg128.Dword[1],
-                self.xmm1.Reg128.Dword[2], self.xmm1.Reg128.Dword[3],
-                self.xmm2.Reg128.Dword[0], self.xmm2.Reg128.Dword[1],
-                self.xmm2.Reg128.Dword[2], self.xmm2.Reg128.Dword[3],
-                self.xmm3.Reg128.Dword[0], self.xmm3.Reg128.Dword[1],
-                self.xmm3.Reg128.Dword[2], self.xmm3.Reg128.Dword[3],
-                self.xmm4.Reg128.Dword[0], self.xmm4.Reg128.Dword[1],
-                self.xmm4.Reg128.Dword[2], self.xmm4.Reg128.Dword[3],
-                self.xmm5.Reg128.Dword[0], self.xmm5.Reg128.Dword[1],
-                self.xmm5.Reg128.Dword[2], self.xmm5.Reg128.Dword[3],
-                self.xmm6.Reg128.Dword[0], self.xmm6.Reg128.Dword[1],
-                self.xmm6.Reg128.Dword[2], self.xmm6.Reg128.Dword[3],
-                self.xmm7.Reg128.Dword[0], self.xmm7.Reg128.Dword[1],
-                self.xmm7.Reg128.Dword[2], self.xmm7.Reg128.Dword[3],
-                self.xmm8.Reg128.Dword[0], self.xmm8.Reg128.Dword[1],
-                self.xmm8.Reg128.Dword[2], self.xmm8.Reg128.Dword[3],
-                self.xmm9.Reg128.Dword[0], self.xmm9.Reg128.Dword[1],
-                self.xmm9.Reg128.Dword[2], self.xmm9.Reg128.Dword[3],
-                self.xmm10.Reg128.Dword[0], self.xmm10.Reg128.Dword[1],
-                self.xmm10.Reg128.Dword[2], self.xmm10.Reg128.Dword[3],
-                self.xmm11.Reg128.Dword[0], self.xmm11.Reg128.Dword[1],
-                self.xmm11.Reg128.Dword[2], self.xmm11.Reg128.Dword[3],
-                self.xmm12.Reg128.Dword[0], self.xmm12.Reg128.Dword[1],
-                self.xmm12.Reg128.Dword[2], self.xmm12.Reg128.Dword[3],
-                self.xmm13.Reg128.Dword[0], self.xmm13.Reg128.Dword[1],
-                self.xmm13.Reg128.Dword[2], self.xmm13.Reg128.Dword[3],
-                self.xmm14.Reg128.Dword[0], self.xmm14.Reg128.Dword[1],
-                self.xmm14.Reg128.Dword[2], self.xmm14.Reg128.Dword[3],
-                self.xmm15.Reg128.Dword[0], self.xmm15.Reg128.Dword[1],
-                self.xmm15.Reg128.Dword[2], self.xmm15.Reg128.Dword[3],
-                self.fp_control.FpControlStatus.__bindgen_anon_1.FpControl,
-                self.fp_control.FpControlStatus.__bindgen_anon_1.FpStatus,
-                self.fp_control.FpControlStatus.__bindgen_anon_1.FpTag,
-                self.fp_control.FpControlStatus.__bindgen_anon_1.LastFpOp,
-                // TODO: Missing LastFpRip/LastFpEip based on processor mode
-                self.st0.Fp.__bindgen_anon_1.Mantissa,
-                self.st0.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st0.Fp.__bindgen_anon_1.Sign(),
-                self.st1.Fp.__bindgen_anon_1.Mantissa,
-                self.st1.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st1.Fp.__bindgen_anon_1.Sign(),
-                self.st2.Fp.__bindgen_anon_1.Mantissa,
-                self.st2.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st2.Fp.__bindgen_anon_1.Sign(),
-                self.st3.Fp.__bindgen_anon_1.Mantissa,
-                self.st3.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st3.Fp.__bindgen_anon_1.Sign(),
-                self.st4.Fp.__bindgen_anon_1.Mantissa,
-                self.st4.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st4.Fp.__bindgen_anon_1.Sign(),
-                self.st5.Fp.__bindgen_anon_1.Mantissa,
-                self.st5.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st5.Fp.__bindgen_anon_1.Sign(),
-                self.st6.Fp.__bindgen_anon_1.Mantissa,
-                self.st6.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st6.Fp.__bindgen_anon_1.Sign(),
-                self.st7.Fp.__bindgen_anon_1.Mantissa,
-                self.st7.Fp.__bindgen_anon_1.BiasedExponent(),
-                self.st7.Fp.__bindgen_anon_1.Sign(),
-            )
-        }
-    }
-}
-
 /// Structure representing an instance of a hypervisor using the WHVP API
 pub struct Whvp {
     /// The raw partition used to manage the partition with the WHVP API
@@ -481,12 +268,39 @@ pub struct Whvp {
     /// Tracks if memory can be dirty. This is set when we run the guest, and
     /// cleared when we clear dirty memory. It's an optimization for not doing
     /// anything to clear dirty memory when we haven't run the hypervisor.
-    memory_dirty: bool,
+    ///
+    /// This is shared with the runners handed out by `runner()` as they run
+    /// the guest on other threads
+    memory_dirty: Arc<AtomicBool>,
+}
+
+/// Run virtual processor `vp` in `partition` until exit, returning the exit
+/// context
+fn run_vp(partition: WHV_PARTITION_HANDLE, vp: u32)
+        -> Result<WHV_RUN_VP_EXIT_CONTEXT, BackendError> {
+    let mut context: WHV_RUN_VP_EXIT_CONTEXT =
+        unsafe { std::mem::zeroed() };
+    let res = unsafe { WHvRunVirtualProcessor(partition, vp,
+        &mut context as *mut WHV_RUN_VP_EXIT_CONTEXT as *mut c_void,
+        std::mem::size_of_val(&context) as u32) };
+    check("WHvRunVirtualProcessor", res)?;
+    Ok(context)
 }
 
 impl Whvp {
-    /// Create a new WHVP instance with one processor
-    pub fn new() -> Self {
+    /// Create a new WHVP instance with `num_vps` processors. Every exception
+    /// vector `n` with bit `n` set in `exception_bitmap` causes a VM exit
+    pub fn new(num_vps: u32, exception_bitmap: u64)
+            -> Result<Self, BackendError> {
+        assert!(num_vps > 0, "Cannot create a partition with no processors");
+
+        // `VpContext` is handed to WHVP as an array of `WHV_REGISTER_VALUE`s
+        assert!(std::mem::size_of::<RegisterValue>() ==
+            std::mem::size_of::<WHV_REGISTER_VALUE>() &&
+            std::mem::size_of::<VpContext>() >= WHVP_CONTEXT_NAMES.len() *
+            std::mem::size_of::<WHV_REGISTER_VALUE>(),
+            "VpContext doesn't match the WHVP register layout");
+
         // Print the CPU model string
         print!("Processor model string: {}\n", get_cpu_string());
 
@@ -498,11 +312,15 @@ impl Whvp {
             &mut present_check as *mut BOOL as *mut c_void,
             std::mem::size_of_val(&present_check) as u32,
             &mut bread) };
-        assert!(res == 0, "WHvGetCapability() error: {:#x}", res);
-        assert!(bread == std::mem::size_of_val(&present_check) as u32,
-            "Failed to get WHvCapabilityCodeHypervisorPresent");
-        assert!(present_check != 0,
-            "Hypervisor not present, enable it in Windows features");
+        check("WHvGetCapability", res)?;
+        if bread != std::mem::size_of_val(&present_check) as u32 {
+            return Err(failure("WHvGetCapability",
+                "Failed to get WHvCapabilityCodeHypervisorPresent"));
+        }
+        if present_check == 0 {
+            return Err(failure("WHvGetCapability",
+                "Hypervisor not present, enable it in Windows features"));
+        }
 
         // Get WHVP features
         let mut whvp_features: WHV_CAPABILITY_FEATURES =
@@ -513,9 +331,11 @@ impl Whvp {
             &mut whvp_features as *mut WHV_CAPABILITY_FEATURES as *mut c_void,
             std::mem::size_of_val(&whvp_features) as u32,
             &mut bread) };
-        assert!(res == 0, "WHvGetCapability() error: {:#x}", res);
-        assert!(bread == std::mem::size_of_val(&whvp_features) as u32,
-            "Failed to get WHvCapabilityCodeFeatures");
+        check("WHvGetCapability", res)?;
+        if bread != std::mem::size_of_val(&whvp_features) as u32 {
+            return Err(failure("WHvGetCapability",
+                "Failed to get WHvCapabilityCodeFeatures"));
+        }
 
         // Display the feature set for WHVP
         unsafe {
@@ -541,9 +361,11 @@ impl Whvp {
             &mut proc_features as *mut WHV_PROCESSOR_FEATURES as *mut c_void,
             std::mem::size_of_val(&proc_features) as u32,
             &mut bread) };
-        assert!(res == 0, "WHvGetCapability() error: {:#x}", res);
-        assert!(bread == std::mem::size_of_val(&proc_features) as u32,
-            "Failed to get WHvCapabilityCodeProcessorFeatures");
+        check("WHvGetCapability", res)?;
+        if bread != std::mem::size_of_val(&proc_features) as u32 {
+            return Err(failure("WHvGetCapability",
+                "Failed to get WHvCapabilityCodeProcessorFeatures"));
+        }
 
         unsafe {
             print!("Processor detected with features:\n\
@@ -663,9 +485,11 @@ impl Whvp {
                 &mut tmp as *mut WHV_PROCESSOR_XSAVE_FEATURES as *mut c_void,
                 std::mem::size_of_val(&tmp) as u32,
                 &mut bread) };
-            assert!(res == 0, "WHvGetCapability() error: {:#x}", res);
-            assert!(bread == std::mem::size_of_val(&tmp) as u32,
-                "Failed to get WHvCapabilityCodeProcessorXsaveFeatures");
+            check("WHvGetCapability", res)?;
+            if bread != std::mem::size_of_val(&tmp) as u32 {
+                return Err(failure("WHvGetCapability",
+                    "Failed to get WHvCapabilityCodeProcessorXsaveFeatures"));
+            }
 
             unsafe {
                 print!("Processor detected with features:\n\
@@ -723,10 +547,7 @@ impl Whvp {
         // Create a new WHVP partition
         let mut partition: WHV_PARTITION_HANDLE = std::ptr::null_mut();
         let res = unsafe { WHvCreatePartition(&mut partition) };
-        if res == 0x80070005u32 as i32 {
-            panic!("Windows Hypervisor Platform now requires Admin access, please rerun this application as Administrator!")
-        }
-        assert!(res == 0, "WHvCreatePartition() error: {:#x}", res);
+        check("WHvCreatePartition", res)?;
 
         // Create the partition object now which will make a destructor if we
         // bail out of subsequent API calls
@@ -739,17 +560,17 @@ impl Whvp {
             xsave_features,
             memory_regions: Vec::new(),
             dirty_bitmap_tmp: vec![0u64; (4*1024*1024*1024) / (4096 * 64)],
-            memory_dirty: false,
+            memory_dirty: Arc::new(AtomicBool::new(false)),
         };
 
-        // Register that we only want one processor
-        let proc_count = 1u32;
+        // Register the number of processors we want
+        let proc_count = num_vps;
         let res = unsafe { WHvSetPartitionProperty(partition,
             WHV_PARTITION_PROPERTY_CODE_WHvPartitionPropertyCodeProcessorCount,
             &proc_count as *const u32 as *const c_void,
             std::mem::size_of_val(&proc_count) as u32)
         };
-        assert!(res == 0, "WHvSetPartitionProperty() error: {:#x}", res);
+        check("WHvSetPartitionProperty", res)?;
 
         // _HV_X64_INTERRUPT_CONTROLLER_STATE
         // APIC emulation
@@ -760,7 +581,7 @@ impl Whvp {
             &apic_mode as *const i32 as *const c_void,
             std::mem::size_of_val(&apic_mode) as u32)
         };
-        assert!(res == 0, "WHvSetPartitionProperty() error: {:#x}", res);*/
+        check("WHvSetPartitionProperty", res)?;*/
 
         // Enable vmexits on certain events
         let mut vmexits: WHV_EXTENDED_VM_EXITS = unsafe { std::mem::zeroed() };
@@ -774,38 +595,39 @@ impl Whvp {
             &vmexits as *const WHV_EXTENDED_VM_EXITS as *const c_void,
             std::mem::size_of_val(&vmexits) as u32)
         };
-        assert!(res == 0, "WHvSetPartitionProperty() error: {:#x}", res);
+        check("WHvSetPartitionProperty", res)?;
 
-        // Set the vmexit bitmap
-        let vmexit_bitmap: u64 = 1 << 1;
+        // Set the exception vmexit bitmap
         let res = unsafe { WHvSetPartitionProperty(partition,
             WHV_PARTITION_PROPERTY_CODE_WHvPartitionPropertyCodeExceptionExitBitmap,
-            &vmexit_bitmap as *const u64 as *const c_void,
-            std::mem::size_of_val(&vmexit_bitmap) as u32)
+            &exception_bitmap as *const u64 as *const c_void,
+            std::mem::size_of_val(&exception_bitmap) as u32)
         };
-        assert!(res == 0, "WHvSetPartitionProperty() error: {:#x}", res);
+        check("WHvSetPartitionProperty", res)?;
 
         // Setup the partition, not sure what this does but it's just how the
         // API works
         let res = unsafe { WHvSetupPartition(partition) };
-        assert!(res == 0, "WHvSetupPartition() error: {:#x}", res);
+        check("WHvSetupPartition", res)?;
 
-        // Create a single virtual processor
-        let res = unsafe { WHvCreateVirtualProcessor(partition, 0, 0) };
-        assert!(res == 0, "WHvCreateVirtualProcessor() error: {:#x}", res);
-        ret.virtual_processors.push(0);
+        // Create all of the virtual processors
+        for vp in 0..num_vps {
+            let res = unsafe { WHvCreateVirtualProcessor(partition, vp, 0) };
+            check("WHvCreateVirtualProcessor", res)?;
+            ret.virtual_processors.push(vp);
+        }
 
         // Time the approximate overhead of running a VM. We use this to get
         // a more accurate estimate of how many cycles actually executed inside
         // the hypervisor rather than just the API and context switches.
         for _ in 0..10000 {
             let start = time::rdtsc();
-            ret.run();
+            ret.run(0)?;
             let elapsed = time::rdtsc() - start;
             ret.vm_run_overhead = std::cmp::min(ret.vm_run_overhead, elapsed);
         }
 
-        ret
+        Ok(ret)
     }
 
     /// Get the raw WHVP handle
@@ -813,6 +635,11 @@ impl Whvp {
         self.partition
     }
 
+    /// Get the number of virtual processors in this partition
+    pub fn num_vps(&self) -> u32 {
+        self.virtual_processors.len() as u32
+    }
+
     /// Gets the number of cycles of overhead for a VM entry.
     /// 
     /// This can be used to more accurately estimate the amount of cycles spent
@@ -825,7 +652,8 @@ impl Whvp {
     /// Request that the hypervisor exits as soon as it's back in an
     /// interruptable state. This allows us to get the guest into a state where
     /// we can deliver things like timer interrupts.
-    pub fn register_interrupt_window(&mut self) {
+    pub fn register_interrupt_window(&mut self, vp: u32)
+            -> Result<(), BackendError> {
         // List of names, in this case just the
         // RegisterDeliverabilityNotifications will be changed.
         const REGINT_NAMES: &[i32] = &[
@@ -839,11 +667,10 @@ impl Whvp {
                 .__bindgen_anon_1.set_InterruptNotification(1);
 
             // Call the API to apply the changes
-            let res = WHvSetVirtualProcessorRegisters(self.partition, 0,
+            let res = WHvSetVirtualProcessorRegisters(self.partition, vp,
                 REGINT_NAMES.as_ptr(), REGINT_NAMES.len() as u32,
                 &reg_value as *const WHV_REGISTER_VALUE);
-            assert!(res == 0,
-                "WHvSetVirtualProcessorRegisters() error: {:#x}", res);
+            check("WHvSetVirtualProcessorRegisters", res)
         }
     }
 
@@ -851,7 +678,8 @@ impl Whvp {
     /// memory pointed to by `backing` for the size of `backing.len()` using
     /// `perm`. `perm` is a bitwise or-ed combination `PERM_READ`,
     /// `PERM_WRITE`, and `PERM_EXECUTE`.
-    pub fn map_memory(&mut self, addr: usize, backing: &mut [u8], perm: i32) {
+    pub fn map_memory(&mut self, addr: usize, backing: &mut [u8], perm: i32)
+            -> Result<(), BackendError> {
         // Make sure everything looks sane about this new mapping
         assert!(addr & 0xfff == 0,
             "Cannot map page-unaligned memory");
@@ -866,10 +694,11 @@ impl Whvp {
         let res = unsafe { WHvMapGpaRange(self.partition,
             backing.as_mut_ptr() as *mut c_void, addr as u64,
             backing.len() as u64, perm | PERM_DIRTY) };
-        assert!(res == 0, "WHvMapGpaRange() error: {:#x}", res);
+        check("WHvMapGpaRange", res)?;
 
         // Save that we mapped this memory region
         self.memory_regions.push((addr, backing.len()));
+        Ok(())
     }
 
     // benchmarking
@@ -877,9 +706,9 @@ impl Whvp {
     // <run benchmark>
     // xperf -d trace.etl
     pub fn get_dirty_list(&mut self, dirty_bits_l1: &mut [u64],
-            dirty_bits_l2: &mut [u64]) {
+            dirty_bits_l2: &mut [u64]) -> Result<(), BackendError> {
         // Nothing to do if we haven't run the hypervisor since the last reset
-        if !self.memory_dirty { return; }
+        if !self.memory_dirty.load(Ordering::SeqCst) { return Ok(()); }
         
         unsafe {
             let bitmap = &mut self.dirty_bitmap_tmp;
@@ -900,8 +729,7 @@ impl Whvp {
                 let res = WHvQueryGpaRangeDirtyBitmap(self.partition,
                     paddr as u64, size as u64, bitmap.as_mut_ptr(),
                     std::mem::size_of_val(bitmap.as_slice()) as u32);
-                assert!(res == 0,
-                    "WHvQueryGpaRangeDirtyBitmap() error: {:#x}", res);
+                check("WHvQueryGpaRangeDirtyBitmap", res)?;
 
                 let qwords_in_map = size / (4096 * 64);
                 for (ii, qword) in bitmap[..qwords_in_map]
@@ -928,28 +756,24 @@ impl Whvp {
         }
 
         // Memory can no longer be dirty as we've restored it
-        self.memory_dirty = false;
+        self.memory_dirty.store(false, Ordering::SeqCst);
+        Ok(())
     }
 
-    // Run the hypervisor until exit, returning the exit context
-    pub fn run(&mut self) -> WHV_RUN_VP_EXIT_CONTEXT {
-        let mut context: WHV_RUN_VP_EXIT_CONTEXT =
-            unsafe { std::mem::zeroed() };
-        let res = unsafe { WHvRunVirtualProcessor(self.partition, 0,
-            &mut context as *mut WHV_RUN_VP_EXIT_CONTEXT as *mut c_void,
-            std::mem::size_of_val(&context) as u32) };
-        assert!(res == 0, "WHvRunVirtualProcessor() error: {:#x}", res);
-
-        // Mark that memory may be dirty
-        self.memory_dirty = true;
+    // Run virtual processor `vp` until exit, returning the exit context
+    pub fn run(&mut self, vp: u32)
+            -> Result<WHV_RUN_VP_EXIT_CONTEXT, BackendError> {
+        // Mark that memory may be dirty, even a failed run may have run the
+        // guest for a bit
+        self.memory_dirty.store(true, Ordering::SeqCst);
 
-        context
+        run_vp(self.partition, vp)
     }
 
-    // Get the entire WHVP context structure from the hypervisor
-    pub fn get_context(&self) -> WhvpContext {
+    // Get the entire WHVP context structure of `vp` from the hypervisor
+    pub fn get_context(&self, vp: u32) -> Result<VpContext, BackendError> {
         // Make room for the context
-        let mut ret: WhvpContext = unsafe { std::mem::zeroed() };
+        let mut ret: VpContext = unsafe { std::mem::zeroed() };
 
         // Check if xsave is supported
         let xsave_supported = self.xsave_features.map(|x| {
@@ -967,15 +791,17 @@ impl Whvp {
         };
 
         // Get the state
-        let res = unsafe { WHvGetVirtualProcessorRegisters(self.partition, 0,
+        let res = unsafe { WHvGetVirtualProcessorRegisters(self.partition, vp,
             WHVP_CONTEXT_NAMES.as_ptr(), names as u32,
-            &mut ret as *mut WhvpContext as *mut WHV_REGISTER_VALUE) };
-        assert!(res == 0, "WHvGetVirtualProcessorRegisters() error: {:#x}", res);
-        ret
+            &mut ret as *mut VpContext as *mut WHV_REGISTER_VALUE) };
+        check("WHvGetVirtualProcessorRegisters", res)?;
+        Ok(ret)
     }
 
     // Commit the entire WHVP context structure state to the hypervisor state
-    pub fn set_context(&mut self, context: &WhvpContext) {
+    // of `vp`
+    pub fn set_context(&mut self, vp: u32, context: &VpContext)
+            -> Result<(), BackendError> {
         // Check if xsave is supported
         let xsave_supported = self.xsave_features.map(|x| {
             unsafe { x.__bindgen_anon_1.XsaveSupport() != 0 }
@@ -992,25 +818,30 @@ impl Whvp {
         };
 
         // Apply the state
-        let res = unsafe { WHvSetVirtualProcessorRegisters(self.partition, 0,
+        let res = unsafe { WHvSetVirtualProcessorRegisters(self.partition, vp,
             WHVP_CONTEXT_NAMES.as_ptr(), names as u32,
-            context as *const WhvpContext as *const WHV_REGISTER_VALUE) };
-
-        if res != 0 {
-            print!("Likely unsupported virtual processor register\n");
-            print!("Please open a ticket with your CPU string:\n");
-            print!("    CPU string: \"{}\"\n", get_cpu_string());
-
-            assert!(res == 0,
-                "WHvSetVirtualProcessorRegisters() error: {:#x}\n{}",
-                res, context);
+            context as *const VpContext as *const WHV_REGISTER_VALUE) };
+
+        let ret = check("WHvSetVirtualProcessorRegisters", res);
+        if let Err(err) = ret {
+            // Only complain about registers if it's not something which could
+            // go away on its own
+            if !err.is_transient() {
+                print!("Likely unsupported virtual processor register\n");
+                print!("Please open a ticket with your CPU string:\n");
+                print!("    CPU string: \"{}\"\n", get_cpu_string());
+                print!("{}\n", context);
+            }
         }
+
+        ret
     }
 
-    /// Request that an exception is delivered to the guest based on `vector`
+    /// Request that an exception is delivered to `vp` based on `vector`
     /// and `error_code`. If `error_code` is `None` then no error code will
     /// be pushed onto the stack for the exception
-    pub fn deliver_exception(&mut self, vector: u8, error_code: Option<u32>) {
+    pub fn deliver_exception(&mut self, vp: u32, vector: u8,
+            error_code: Option<u32>) -> Result<(), BackendError> {
         // List of names
         const REGINT_NAMES: &[i32] = &[
             WHV_REGISTER_NAME_WHvRegisterPendingEvent
@@ -1020,19 +851,23 @@ impl Whvp {
             unsafe { std::mem::zeroed() };
 
         // Get current exception event state
-        let res = unsafe { WHvGetVirtualProcessorRegisters(self.partition, 0,
+        let res = unsafe { WHvGetVirtualProcessorRegisters(self.partition, vp,
             REGINT_NAMES.as_ptr(), REGINT_NAMES.len() as u32,
             &mut event as *mut WHV_X64_PENDING_EXCEPTION_EVENT as
             *mut WHV_REGISTER_VALUE) };
-        assert!(res == 0,
-            "WHvGetVirtualProcessorRegisters() error: {:#x}",
-            res);
+        check("WHvGetVirtualProcessorRegisters", res)?;
+
+        // There's only room for one pending exception. It goes away once the
+        // processor runs and takes it, so this is worth trying again
+        if unsafe { event.__bindgen_anon_1.EventPending() != 0 } {
+            return Err(BackendError {
+                transient: true,
+                ..failure("WHvSetVirtualProcessorRegisters",
+                    "Can't deliver exception when one is already pending")
+            });
+        }
 
         unsafe {
-            // Make sure there's not already a pending exception event
-            assert!(event.__bindgen_anon_1.EventPending() == 0,
-                "Can't deliver exception when one is already pending");
-
             event.__bindgen_anon_1.set_EventPending(1);
             event.__bindgen_anon_1.set_EventType(
                 WHV_X64_PENDING_EVENT_TYPE_WHvX64PendingEventException as u32);
@@ -1043,17 +878,16 @@ impl Whvp {
             event.__bindgen_anon_1.ExceptionParameter = 0; // What is this?
 
             let res = WHvSetVirtualProcessorRegisters(self.partition,
-                0, REGINT_NAMES.as_ptr(), REGINT_NAMES.len() as u32,
+                vp, REGINT_NAMES.as_ptr(), REGINT_NAMES.len() as u32,
                 &event as *const WHV_X64_PENDING_EXCEPTION_EVENT as
                 *const WHV_REGISTER_VALUE);
-            assert!(res == 0,
-                "WHvSetVirtualProcessorRegisters() error: {:#x}",
-                res);
+            check("WHvSetVirtualProcessorRegisters", res)
         }
     }
 
-    /// Clear a pending exception
-    pub fn clear_pending_exception(&mut self) {
+    /// Clear a pending exception on `vp`
+    pub fn clear_pending_exception(&mut self, vp: u32)
+            -> Result<(), BackendError> {
         // List of names
         const REGINT_NAMES: &[i32] = &[
             WHV_REGISTER_NAME_WHvRegisterPendingEvent
@@ -1062,12 +896,149 @@ impl Whvp {
         let event: WHV_X64_PENDING_EXCEPTION_EVENT =
             unsafe { std::mem::zeroed() };
 
-        let res = unsafe { WHvSetVirtualProcessorRegisters(self.partition, 0,
+        let res = unsafe { WHvSetVirtualProcessorRegisters(self.partition, vp,
             REGINT_NAMES.as_ptr(), REGINT_NAMES.len() as u32,
             &event as *const WHV_X64_PENDING_EXCEPTION_EVENT as *const WHV_REGISTER_VALUE) };
-        assert!(res == 0,
-            "WHvSetVirtualProcessorRegisters() error: {:#x}",
-            res);
+        check("WHvSetVirtualProcessorRegisters", res)
+    }
+}
+
+/// Convert a raw WHVP exit context into a backend-neutral `VmExit`
+fn exit_from_whvp(exit: &WHV_RUN_VP_EXIT_CONTEXT) -> VmExit {
+    unsafe {
+        match exit.ExitReason {
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonNone => VmExit::None,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonMemoryAccess => {
+                let ma = &exit.__bindgen_anon_1.MemoryAccess;
+                VmExit::MemoryAccess { gpa: ma.Gpa, gva: ma.Gva }
+            }
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64IoPortAccess => {
+                VmExit::IoPortAccess {
+                    port: exit.__bindgen_anon_1.IoPortAccess.PortNumber
+                }
+            }
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonUnrecoverableException =>
+                VmExit::UnrecoverableException,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonInvalidVpRegisterValue =>
+                VmExit::InvalidVpRegisterValue,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonUnsupportedFeature =>
+                VmExit::UnsupportedFeature,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64InterruptWindow =>
+                VmExit::InterruptWindow,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Halt => VmExit::Halt,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64ApicEoi =>
+                VmExit::ApicEoi,
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64MsrAccess => {
+                VmExit::MsrAccess {
+                    msr: exit.__bindgen_anon_1.MsrAccess.MsrNumber
+                }
+            }
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonX64Cpuid => {
+                let cpuid = &exit.__bindgen_anon_1.CpuidAccess;
+                VmExit::Cpuid {
+                    leaf:    cpuid.Rax,
+                    subleaf: cpuid.Rcx,
+                    default: [
+                        cpuid.DefaultResultRax,
+                        cpuid.DefaultResultRbx,
+                        cpuid.DefaultResultRcx,
+                        cpuid.DefaultResultRdx,
+                    ],
+                    instruction_length:
+                        exit.VpContext.InstructionLength() as u8,
+                }
+            }
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonException => {
+                let exception = &exit.__bindgen_anon_1.VpException;
+                let error_code = if exception.ExceptionInfo
+                        .__bindgen_anon_1.ErrorCodeValid() != 0 {
+                    Some(exception.ErrorCode)
+                } else {
+                    None
+                };
+
+                VmExit::Exception {
+                    vector: exception.ExceptionType,
+                    error_code,
+                }
+            }
+            WHV_RUN_VP_EXIT_REASON_WHvRunVpExitReasonCanceled =>
+                VmExit::Canceled,
+            _ => panic!("Invalid vm exit reason {}\n", exit.ExitReason),
+        }
+    }
+}
+
+impl HypervisorBackend for Whvp {
+    fn num_vps(&self) -> u32 {
+        Whvp::num_vps(self)
+    }
+
+    fn map_memory(&mut self, paddr: usize, backing: &mut [u8], perms: i32)
+            -> Result<(), BackendError> {
+        Whvp::map_memory(self, paddr, backing, perms)
+    }
+
+    fn runner(&self, vp: u32)
+            -> Box<dyn Fn() -> Result<VmExit, BackendError> + Send> {
+        assert!(vp < self.num_vps(), "Invalid virtual processor {}", vp);
+
+        // Raw handles aren't `Send`, so smuggle it through as an integer
+        let handle       = self.partition as usize;
+        let memory_dirty = self.memory_dirty.clone();
+
+        Box::new(move || {
+            // Mark that memory may be dirty, even a failed run may have run
+            // the guest for a bit
+            memory_dirty.store(true, Ordering::SeqCst);
+
+            let exit = run_vp(handle as WHV_PARTITION_HANDLE, vp)?;
+            Ok(exit_from_whvp(&exit))
+        })
+    }
+
+    fn get_context(&self, vp: u32) -> Result<VpContext, BackendError> {
+        Whvp::get_context(self, vp)
+    }
+
+    fn set_context(&mut self, vp: u32, context: &VpContext)
+            -> Result<(), BackendError> {
+        Whvp::set_context(self, vp, context)
+    }
+
+    fn get_dirty_list(&mut self, dirty_bits_l1: &mut [u64],
+            dirty_bits_l2: &mut [u64]) -> Result<(), BackendError> {
+        Whvp::get_dirty_list(self, dirty_bits_l1, dirty_bits_l2)
+    }
+
+    fn register_interrupt_window(&mut self, vp: u32)
+            -> Result<(), BackendError> {
+        Whvp::register_interrupt_window(self, vp)
+    }
+
+    fn deliver_exception(&mut self, vp: u32, vector: u8,
+            error_code: Option<u32>) -> Result<(), BackendError> {
+        Whvp::deliver_exception(self, vp, vector, error_code)
+    }
+
+    fn clear_pending_exception(&mut self, vp: u32) -> Result<(), BackendError> {
+        Whvp::clear_pending_exception(self, vp)
+    }
+
+    fn overhead(&self) -> u64 {
+        Whvp::overhead(self)
+    }
+
+    fn canceller(&self) -> Option<Box<dyn Fn(u32) + Send>> {
+        // Raw handles aren't `Send`, so smuggle it through as an integer
+        let handle = self.partition as usize;
+
+        // Nothing useful to do if a cancel fails, the kicker will just try
+        // again on the next kick
+        Some(Box::new(move |vp| unsafe {
+            WHvCancelRunVirtualProcessor(
+                handle as WHV_PARTITION_HANDLE, vp, 0);
+        }))
     }
 }
 
@@ -1075,13 +1046,18 @@ impl Drop for Whvp {
     /// Drop everything related to the WHVP API we registered
     fn drop(&mut self) {
         // Delete all virtual processors
+        // We can't do anything about errors here, so just report them
         for &pid in &self.virtual_processors {
             let res = unsafe { WHvDeleteVirtualProcessor(self.partition, pid) };
-            assert!(res == 0, "WHvDeleteVirtualProcessor() error: {:#x}", res);
+            if let Err(err) = check("WHvDeleteVirtualProcessor", res) {
+                print!("{}\n", err);
+            }
         }
 
         // Delete the partition itself
         let res = unsafe { WHvDeletePartition(self.partition) };
-        assert!(res == 0, "WHvDeletePartition() error: {:#x}", res);
+        if let Err(err) = check("WHvDeletePartition", res) {
+            print!("{}\n", err);
+        }
     }
 }
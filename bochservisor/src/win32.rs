@@ -1,4 +1,5 @@
-use crate::MemReader;
+use crate::memreader::MemReader;
+use crate::virtmem::Paging;
 use std::fmt::Write;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -92,6 +93,17 @@ pub struct ModuleEntry {
     len: usize,
 }
 
+impl ModuleEntry {
+    #[inline]
+    pub fn info(&self) -> &ModuleInfo { &self.info }
+
+    #[inline]
+    pub fn base(&self) -> usize { self.base }
+
+    #[inline]
+    pub fn len(&self) -> usize { self.len }
+}
+
 /// Group of modules
 #[derive(Debug, Default)]
 pub struct ModuleList {
@@ -110,6 +122,11 @@ impl ModuleList {
         self.modules.push(module);
     }
 
+    /// Get all modules in the list, sorted by base address
+    pub fn modules(&self) -> &[ModuleEntry] {
+        &self.modules
+    }
+
     /// Get the module offset representation of a virtual address
     pub fn get_modoff(&self, vaddr: usize) -> (Option<&ModuleInfo>, usize) {
         let search = self.modules
@@ -155,58 +172,151 @@ impl ModuleList {
     }
 }
 
-/// Get a list of all modules for the current running process
-/// Currently only for user-mode applications
-/// On failure may return a 0 sized module list
-fn get_modlist_user<'a>(modlist: &mut ModuleList,
-        cr3: usize, lma: bool, gs_base: usize, cs: u16,
-        memory: &mut MemReader) -> Result<(), ()> {
-    // Make sure we have a GS, we're in userspace, and we're also 64-bit
-    if !(gs_base != 0 && lma && (cs & 3) == 3) {
-        return Err(());
+/// Offsets of the fields we use in a `_LDR_DATA_TABLE_ENTRY` (user mode) or a
+/// `nt!_KLDR_DATA_TABLE_ENTRY` (kernel). Both start with `InLoadOrderLinks`
+struct LdrLayout {
+    /// Size of a pointer, 4 for 32-bit structures and 8 for 64-bit ones
+    ptr_size: usize,
+
+    /// `DllBase`
+    dll_base: usize,
+
+    /// `SizeOfImage`
+    size_of_image: usize,
+
+    /// `BaseDllName`, a `UNICODE_STRING`
+    base_dll_name: usize,
+
+    /// `TimeDateStamp`
+    time_date_stamp: usize,
+}
+
+/// 64-bit `_LDR_DATA_TABLE_ENTRY`
+const LDR64: LdrLayout = LdrLayout {
+    ptr_size: 8, dll_base: 0x30, size_of_image: 0x40, base_dll_name: 0x58,
+    time_date_stamp: 0x80,
+};
+
+/// 32-bit `_LDR_DATA_TABLE_ENTRY`, used by 32-bit Windows and WoW64 processes
+const LDR32: LdrLayout = LdrLayout {
+    ptr_size: 4, dll_base: 0x18, size_of_image: 0x20, base_dll_name: 0x2c,
+    time_date_stamp: 0x44,
+};
+
+/// 64-bit `nt!_KLDR_DATA_TABLE_ENTRY`
+const KLDR64: LdrLayout = LdrLayout {
+    ptr_size: 8, dll_base: 0x30, size_of_image: 0x40, base_dll_name: 0x58,
+    time_date_stamp: 0x9c,
+};
+
+/// 32-bit `nt!_KLDR_DATA_TABLE_ENTRY`
+const KLDR32: LdrLayout = LdrLayout {
+    ptr_size: 4, dll_base: 0x18, size_of_image: 0x20, base_dll_name: 0x2c,
+    time_date_stamp: 0x58,
+};
+
+/// Offsets used to get from a TEB to the module list, for 64-bit and 32-bit
+/// structures. `_TEB.ProcessEnvironmentBlock`, `_PEB.Ldr` and
+/// `_PEB_LDR_DATA.InLoadOrderModuleList`
+const TEB64_PEB: usize = 0x60;
+const TEB32_PEB: usize = 0x30;
+const PEB64_LDR: usize = 0x18;
+const PEB32_LDR: usize = 0x0c;
+const LDR_DATA64_LOAD_ORDER: usize = 0x10;
+const LDR_DATA32_LOAD_ORDER: usize = 0x0c;
+
+/// Offset of `_TEB.WowTebOffset` in a 64-bit TEB. For threads of WoW64
+/// processes this is the offset from the 64-bit TEB to the 32-bit one
+const TEB64_WOW_TEB_OFFSET: usize = 0x180c;
+
+/// Offset of `_KPCR.KdVersionBlock` in a 32-bit KPCR, which points to a
+/// `_DBGKD_GET_VERSION64` on the boot processor
+const KPCR32_KD_VERSION_BLOCK: usize = 0x34;
+
+/// Offset of `PsLoadedModuleList` in a `_DBGKD_GET_VERSION64`
+const KD_VERSION_PS_LOADED_MODULE_LIST: usize = 0x18;
+
+/// Virtual address space of a processor, in whatever paging mode it's in
+struct AddressSpace<'a> {
+    memory: &'a mut MemReader,
+    paging: Paging,
+    cr3:    usize,
+}
+
+impl<'a> AddressSpace<'a> {
+    /// Read virtual memory at `vaddr` into `buf`. Returns number of bytes read
+    fn read(&mut self, vaddr: usize, buf: &mut [u8]) -> usize {
+        self.memory.read_virt_paging(self.paging, self.cr3, vaddr, buf)
     }
 
-    // Look up the PEB from the TEB
-    let peb_ptr = memory.read_virt_usize(cr3, gs_base + 0x60)?;
+    /// Read a `u16` at `vaddr`
+    fn read_u16(&mut self, vaddr: usize) -> Result<u16, ()> {
+        let mut val = [0u8; 2];
+        if self.read(vaddr, &mut val) != val.len() { return Err(()); }
+        Ok(u16::from_le_bytes(val))
+    }
+
+    /// Read a `u32` at `vaddr`
+    fn read_u32(&mut self, vaddr: usize) -> Result<u32, ()> {
+        let mut val = [0u8; 4];
+        if self.read(vaddr, &mut val) != val.len() { return Err(()); }
+        Ok(u32::from_le_bytes(val))
+    }
 
-    // Get the _PEB_LDR_DATA structure pointer 
-    let peb_ldr_ptr = memory.read_virt_usize(cr3, peb_ptr + 0x18)?;
+    /// Read a `ptr_size` byte pointer at `vaddr`
+    fn read_ptr(&mut self, vaddr: usize, ptr_size: usize)
+            -> Result<usize, ()> {
+        if ptr_size == 4 {
+            return self.read_u32(vaddr).map(|x| x as usize);
+        }
+
+        let mut val = [0u8; 8];
+        if self.read(vaddr, &mut val) != val.len() { return Err(()); }
+        Ok(u64::from_le_bytes(val) as usize)
+    }
+}
+
+/// Walk the list of `_LDR_DATA_TABLE_ENTRY`s or `nt!_KLDR_DATA_TABLE_ENTRY`s
+/// with list head `head`, adding every module to `modlist`
+fn walk_ldr_list(modlist: &mut ModuleList, space: &mut AddressSpace,
+        head: usize, layout: &LdrLayout) -> Result<(), ()> {
+    let ptr_size = layout.ptr_size;
 
     // Get the first pointer to the InLoadOrderModuleList
-    // This type is of _LDR_DATA_TABLE_ENTRY
-    let mut flink = memory.read_virt_usize(cr3, peb_ldr_ptr + 0x10)?;
-    let blink     = memory.read_virt_usize(cr3, peb_ldr_ptr + 0x18)?;
+    let mut flink = space.read_ptr(head, ptr_size)?;
+    let blink     = space.read_ptr(head + ptr_size, ptr_size)?;
 
-    // This should never happen
-    assert!(blink != 0, "No blink");
+    // A list head which isn't set up yet, speculative walks run into these
+    if blink == 0 { return Err(()); }
 
     // Loop while we have entries in the list
     while flink != 0 {
         // Get base and length
-        let base = memory.read_virt_usize(cr3, flink + 0x30)?;
-        let len  = memory.read_virt_u32(cr3, flink + 0x40)? as usize;
+        let base = space.read_ptr(flink + layout.dll_base, ptr_size)?;
+        let len  = space.read_u32(flink + layout.size_of_image)? as usize;
 
         // Get the name length and pointer
-        let namelen = memory.read_virt_u16(cr3, flink + 0x58)? as usize;
-        let nameptr = memory.read_virt_usize(cr3, flink + 0x60)?;
+        let namelen = space.read_u16(flink + layout.base_dll_name)? as usize;
+        let nameptr = space.read_ptr(
+            flink + layout.base_dll_name + ptr_size, ptr_size)?;
 
         // Get the module information
-        let time_date_stamp = memory.read_virt_u32(cr3, flink + 0x80)?;
-        let size_of_image   = memory.read_virt_u32(cr3, flink + 0x40)?;
+        let time_date_stamp = space.read_u32(flink + layout.time_date_stamp)?;
+        let size_of_image   = len as u32;
 
         // Skip this entry if it doesn't seem sane
         if nameptr == 0 || namelen == 0 || (namelen % 2) != 0 {
             if flink == blink { break; }
-            flink = memory.read_virt_usize(cr3, flink)?;
+            flink = space.read_ptr(flink, ptr_size)?;
             continue;
         }
-        
+
         // Make room and read the UTF-16 name
         let mut name = vec![0u8; namelen];
-        if memory.read_virt(cr3, nameptr, &mut name) != namelen {
+        if space.read(nameptr, &mut name) != namelen {
             // Name might be paged out, skip entry
             if flink == blink { break; }
-            flink = memory.read_virt_usize(cr3, flink)?;
+            flink = space.read_ptr(flink, ptr_size)?;
             continue;
         }
 
@@ -226,63 +336,148 @@ fn get_modlist_user<'a>(modlist: &mut ModuleList,
 
         // Go to the next module
         if flink == blink { break; }
-        flink = memory.read_virt_usize(cr3, flink)?;
+        flink = space.read_ptr(flink, ptr_size)?;
+    }
+
+    Ok(())
+}
+
+/// Walk the 32-bit module list of the process with the 32-bit TEB `teb`
+fn get_modlist_user32(modlist: &mut ModuleList, space: &mut AddressSpace,
+        teb: usize) -> Result<(), ()> {
+    let peb = space.read_ptr(teb + TEB32_PEB, 4)?;
+    let ldr = space.read_ptr(peb + PEB32_LDR, 4)?;
+    walk_ldr_list(modlist, space, ldr + LDR_DATA32_LOAD_ORDER, &LDR32)
+}
+
+/// Get a list of all modules for the current running process
+/// Currently only for user-mode applications
+/// On failure may return a 0 sized module list
+///
+/// In long mode the 64-bit TEB is in GS. WoW64 processes also have a 32-bit
+/// TEB with the modules of the 32-bit side, which are added as well. 32-bit
+/// Windows has the TEB in FS
+fn get_modlist_user(modlist: &mut ModuleList,
+        cr3: usize, paging: Paging, fs_base: usize, gs_base: usize, cs: u16,
+        memory: &mut MemReader) -> Result<(), ()> {
+    // Make sure we're in userspace with paging on
+    if (cs & 3) != 3 || paging == Paging::Disabled {
+        return Err(());
+    }
+
+    let mut space = AddressSpace { memory, paging, cr3 };
+
+    // 32-bit Windows
+    if paging != Paging::Long {
+        if fs_base == 0 { return Err(()); }
+        return get_modlist_user32(modlist, &mut space, fs_base);
+    }
+
+    // Make sure we have a GS
+    if gs_base == 0 {
+        return Err(());
+    }
+
+    // Look up the PEB from the TEB
+    let peb = space.read_ptr(gs_base + TEB64_PEB, 8)?;
+
+    // Get the _PEB_LDR_DATA structure pointer
+    let ldr = space.read_ptr(peb + PEB64_LDR, 8)?;
+
+    walk_ldr_list(modlist, &mut space, ldr + LDR_DATA64_LOAD_ORDER, &LDR64)?;
+
+    // Add the 32-bit modules of WoW64 processes. These might not be set up
+    // yet early on in the process, that's fine as we have the 64-bit ones
+    let wow_teb_offset = space.read_u32(gs_base + TEB64_WOW_TEB_OFFSET)
+        .unwrap_or(0) as i32;
+    if wow_teb_offset != 0 {
+        let teb32 = gs_base.wrapping_add(wow_teb_offset as isize as usize);
+        let _ = get_modlist_user32(modlist, &mut space, teb32);
     }
 
     Ok(())
 }
 
-// Find the address of the `nt!PsLoadedModuleList` global
-pub fn find_kernel_modlist(cr3: usize, lma: bool, kernel_gs: usize, cs: u16,
+/// Check if `list_addr` looks like `nt!PsLoadedModuleList`. The first entry in
+/// it is always `ntoskrnl.exe`
+fn is_kernel_modlist(space: &mut AddressSpace, list_addr: usize,
+        layout: &LdrLayout) -> bool {
+    let ptr_size = layout.ptr_size;
+
+    // Attempt to read a pointer from this location
+    let flink = match space.read_ptr(list_addr, ptr_size) {
+        Ok(flink) => flink,
+        Err(_)    => return false,
+    };
+
+    // _KLDR_DATA_TABLE_ENTRY.InLoadOrderLinks.Blink
+    // If the blink pointer doesn't reference the base of the list this
+    // cannot be the module list
+    if space.read_ptr(flink + ptr_size, ptr_size) != Ok(list_addr) {
+        return false;
+    }
+
+    // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Length
+    let size = space.read_u16(flink + layout.base_dll_name);
+
+    // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Buffer
+    let nameptr = space.read_ptr(flink + layout.base_dll_name + ptr_size,
+        ptr_size);
+
+    // Make sure the length is 0x18 and all reads succeeded
+    if let (Ok(0x18), Ok(nameptr)) = (size, nameptr) {
+        // Make room to read the name
+        let mut buf = [0u8; 0x18];
+
+        // Read the name, it has to be UTF-16 'ntoskrnl.exe'
+        return space.read(nameptr, &mut buf) == 0x18 &&
+            &buf == b"n\0t\0o\0s\0k\0r\0n\0l\0.\0e\0x\0e\0";
+    }
+
+    false
+}
+
+// Find the address of the `nt!PsLoadedModuleList` global. `kpcr` is the
+// kernel GS base in long mode, and the kernel FS base on 32-bit Windows
+pub fn find_kernel_modlist(cr3: usize, paging: Paging, kpcr: usize, cs: u16,
         memory: &mut MemReader) -> Result<usize, ()> {
-    // Make sure we have a GS, and we're also 64-bit and in kernel mode
-    if !(lma && (cs & 3) == 0 && (kernel_gs & (1 << 63)) != 0) {
+    // Make sure we have a KPCR and we're in kernel mode
+    if (cs & 3) != 0 || kpcr == 0 {
         return Err(());
     }
 
-    // Search virtual memory in the kernel starting at GS_BASE for 64 MiB.
-    // We search for something that looks like nt!PsLoadedModuleList which
-    // contains entries of type nt!_KLDR_DATA_TABLE_ENTRY
-    //
-    // The first entry in the list should always be 'ntoskrnl.exe' so we
-    // search for that
+    let mut space = AddressSpace { memory, paging, cr3 };
     let mut found: Option<usize> = None;
 
-    // Walk through memory
-    for offset in (0..64 * 1024 * 1024).step_by(8) {
-        let list_addr = kernel_gs + offset;
-
-        // Attempt to read a pointer from this location
-        if let Ok(flink) = memory.read_virt_usize(cr3, list_addr) {
-            // _KLDR_DATA_TABLE_ENTRY.InLoadOrderLinks.Blink
-            let blink = memory.read_virt_usize(cr3, flink + 0x08);
-
-            // If the blink pointer doesn't reference the base of the list this
-            // cannot be the module list
-            if blink != Ok(list_addr) { continue; }
-
-            // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Length
-            let size = memory.read_virt_u16(cr3, flink + 0x58);
-
-            // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Buffer
-            let nameptr = memory.read_virt_usize(cr3, flink + 0x60);
-
-            // Make sure the length is 0x18 and all reads succeeded
-            if let (Ok(0x18), Ok(nameptr)) = (size, nameptr) {
-                // Make room to read the name
-                let mut buf = [0u8; 0x18];
-
-                // Read the name
-                if memory.read_virt(cr3, nameptr, &mut buf) == 0x18 {
-                    // If it's UTF-16 'ntoskrnl.exe' then we found
-                    // our list!
-                    if &buf == b"n\0t\0o\0s\0k\0r\0n\0l\0.\0e\0x\0e\0" {
-                        found = Some(list_addr);
-                        break;
-                    }
+    match paging {
+        Paging::Long => {
+            // Kernel addresses have the top bit set
+            if (kpcr & (1 << 63)) == 0 { return Err(()); }
+
+            // Search virtual memory in the kernel starting at GS_BASE for
+            // 64 MiB. We search for something that looks like
+            // nt!PsLoadedModuleList which contains entries of type
+            // nt!_KLDR_DATA_TABLE_ENTRY
+            for offset in (0..64 * 1024 * 1024).step_by(8) {
+                let list_addr = kpcr + offset;
+                if is_kernel_modlist(&mut space, list_addr, &KLDR64) {
+                    found = Some(list_addr);
+                    break;
                 }
             }
         }
+        Paging::Legacy | Paging::Pae => {
+            // The KPCR of the boot processor points to the debugger version
+            // block, which has the address of the list. It's a 64-bit field
+            // but only the low half is used
+            let kd = space.read_ptr(kpcr + KPCR32_KD_VERSION_BLOCK, 4)?;
+            let list_addr = space.read_ptr(
+                kd + KD_VERSION_PS_LOADED_MODULE_LIST, 4)?;
+            if kd != 0 && is_kernel_modlist(&mut space, list_addr, &KLDR32) {
+                found = Some(list_addr);
+            }
+        }
+        Paging::Disabled => return Err(()),
     }
 
     if let Some(kml) = found {
@@ -299,95 +494,366 @@ pub fn find_kernel_modlist(cr3: usize, lma: bool, kernel_gs: usize, cs: u16,
 /// Dump it with a debugger with:
 /// `!list -x "dt" -a "nt!_KLDR_DATA_TABLE_ENTRY" nt!PsLoadedModuleList`
 /// The type for this list is `nt!_KLDR_DATA_TABLE_ENTRY`
-fn get_modlist_kernel<'a>(modlist: &mut ModuleList,
-        cr3: usize, lma: bool, cs: u16,
+fn get_modlist_kernel(modlist: &mut ModuleList,
+        cr3: usize, paging: Paging, cs: u16,
         memory: &mut MemReader, plml_ptr: usize) -> Result<(), ()> {
-    // Make sure we're in long mode and in ring0
-    if !(lma && (cs & 3) == 0) {
+    // Make sure we're in ring0 with paging on
+    if (cs & 3) != 0 || paging == Paging::Disabled {
         return Err(());
     }
 
-    // Get the first pointer to the InLoadOrderModuleList
-    // This type is of _KLDR_DATA_TABLE_ENTRY
-    let mut flink = memory.read_virt_usize(cr3, plml_ptr)?;
-    let blink     = memory.read_virt_usize(cr3, plml_ptr + 0x8)?;
+    let layout = if paging == Paging::Long { &KLDR64 } else { &KLDR32 };
+    walk_ldr_list(modlist, &mut AddressSpace { memory, paging, cr3 },
+        plml_ptr, layout)
+}
 
-    // This should never happen
-    assert!(blink != 0, "No blink");
+/// Walk the module list for the current operating context. `cr3` must be
+/// masked for the `paging` mode with `Paging::table_root`
+pub fn get_modlist(memory: &mut MemReader,
+        cr3: usize, paging: Paging, fs_base: usize, gs_base: usize, cs: u16,
+        plml_ptr: Option<usize>) -> Result<ModuleList, ()> {
 
-    // Loop while we have entries in the list
-    while flink != 0 {
-        // Get base and length
-        let base = memory.read_virt_usize(cr3, flink + 0x30)?;
-        let len  = memory.read_virt_u32(cr3, flink + 0x40)? as usize;
+    // Create the module list we will return
+    let mut ret = ModuleList::new();
 
-        // Get the name length and pointer
-        let namelen = memory.read_virt_u16(cr3, flink + 0x58)? as usize;
-        let nameptr = memory.read_virt_usize(cr3, flink + 0x60)?;
+    // Check which CPL we're at
+    if (cs & 3) == 3 {
+        // ring3
+        get_modlist_user(&mut ret, cr3, paging, fs_base, gs_base, cs,
+            memory)?;
+    } else if plml_ptr.is_some() {
+        // kernel
+        get_modlist_kernel(&mut ret, cr3, paging, cs, memory,
+            plml_ptr.unwrap())?;
+    } else {
+        return Err(());
+    }
 
-        // Get the module information
-        let time_date_stamp = memory.read_virt_u32(cr3, flink + 0x9c)?;
-        let size_of_image   = memory.read_virt_u32(cr3, flink + 0x40)?;
+    // Sort listing so we can binary search by module base. WoW64 processes
+    // have their main image in both the 64-bit and 32-bit lists
+    ret.modules.sort_by_key(|x| x.base);
+    ret.modules.dedup_by_key(|x| x.base);
 
-        // Skip this entry if it doesn't seem sane
-        if nameptr == 0 || namelen == 0 || (namelen % 2) != 0 {
-            if flink == blink { break; }
-            flink = memory.read_virt_usize(cr3, flink)?;
-            continue;
+    Ok(ret)
+}
+
+/// Environment variable with the process structure offsets to use, for builds
+/// we don't know the offsets of. A comma separated list of numbers in the
+/// order of the fields of `ProcessOffsets`
+const PROCESS_OFFSETS_ENV_VAR: &str = "APPLEPIE_PROCESS_OFFSETS";
+
+/// Address of `KUSER_SHARED_DATA` in the kernel, and where it's mapped in user
+/// mode for page tables which don't map the kernel
+const KUSER_SHARED_DATA:      usize = 0xfffff78000000000;
+const KUSER_SHARED_DATA_USER: usize = 0x7ffe0000;
+
+/// Offset of `KUSER_SHARED_DATA.NtBuildNumber`
+const KUSER_NT_BUILD_NUMBER: usize = 0x260;
+
+/// Offsets of the kernel structures used to walk the process list. These
+/// change between Windows builds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessOffsets {
+    /// `_KPCR.Prcb.CurrentThread`
+    pub kpcr_current_thread: usize,
+
+    /// `_KTHREAD.ApcState.Process`
+    pub kthread_process: usize,
+
+    /// `_KPROCESS.DirectoryTableBase`
+    pub directory_table: usize,
+
+    /// `_KPROCESS.UserDirectoryTableBase`
+    pub user_directory: usize,
+
+    /// `_EPROCESS.UniqueProcessId`
+    pub unique_process_id: usize,
+
+    /// `_EPROCESS.ActiveProcessLinks`
+    pub active_links: usize,
+
+    /// `_EPROCESS.ImageFileName`
+    pub image_file_name: usize,
+}
+
+/// Process structure offsets of the x64 Windows builds we know, by build
+/// number
+const KNOWN_PROCESS_OFFSETS: &[(u32, ProcessOffsets)] = &[
+    // Windows 10 1809
+    (17763, ProcessOffsets {
+        kpcr_current_thread: 0x188,
+        kthread_process:     0xb8,
+        directory_table:     0x28,
+        user_directory:      0x280,
+        unique_process_id:   0x2e0,
+        active_links:        0x2e8,
+        image_file_name:     0x450,
+    }),
+];
+
+impl ProcessOffsets {
+    /// Get the process structure offsets for the Windows build in the guest,
+    /// using `cr3` to read its build number. Offsets from the environment are
+    /// used over the known ones. Fails if the build number can't be read or
+    /// we don't know the offsets for it
+    pub fn from_guest(memory: &mut MemReader, cr3: usize)
+            -> Result<Self, String> {
+        if let Ok(offsets) = std::env::var(PROCESS_OFFSETS_ENV_VAR) {
+            return ProcessOffsets::parse(&offsets);
         }
 
-        // Make room and read the UTF-16 name
-        let mut name = vec![0u8; namelen];
-        if memory.read_virt(cr3, nameptr, &mut name) != namelen {
-            // Name might be paged out, skip entry
-            if flink == blink { break; }
-            flink = memory.read_virt_usize(cr3, flink)?;
-            continue;
+        let build = memory.read_virt_u32(cr3,
+                KUSER_SHARED_DATA + KUSER_NT_BUILD_NUMBER)
+            .or_else(|_| memory.read_virt_u32(cr3,
+                KUSER_SHARED_DATA_USER + KUSER_NT_BUILD_NUMBER))
+            .map_err(|_| "Failed to read the Windows build number of the \
+                guest".to_string())?;
+        ProcessOffsets::for_build(build)
+    }
+
+    /// Get the process structure offsets for Windows build `build`
+    pub fn for_build(build: u32) -> Result<Self, String> {
+        // The top bits flag checked builds
+        let build = build & 0xffff;
+        KNOWN_PROCESS_OFFSETS.iter().find(|x| x.0 == build)
+            .map(|x| x.1)
+            .ok_or_else(|| format!("Process structure offsets for Windows \
+                build {} are unknown, set {} to provide them", build,
+                PROCESS_OFFSETS_ENV_VAR))
+    }
+
+    /// Parse offsets from the comma separated list `offsets`
+    fn parse(offsets: &str) -> Result<Self, String> {
+        let offsets = offsets.split(',').map(|x| {
+            let x = x.trim();
+            match x.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None      => x.parse(),
+            }.map_err(|_| format!("Invalid process structure offset `{}`", x))
+        }).collect::<Result<Vec<usize>, String>>()?;
+
+        match offsets.as_slice() {
+            &[kpcr_current_thread, kthread_process, directory_table,
+                    user_directory, unique_process_id, active_links,
+                    image_file_name] => Ok(ProcessOffsets {
+                kpcr_current_thread, kthread_process, directory_table,
+                user_directory, unique_process_id, active_links,
+                image_file_name,
+            }),
+            _ => Err(format!("{} needs 7 offsets", PROCESS_OFFSETS_ENV_VAR)),
         }
+    }
+}
 
-        // Convert the module name into a UTF-8 Rust string
-        let name_utf8 = String::from_utf16(unsafe {
-            std::slice::from_raw_parts(
-                name.as_ptr() as *const u16,
-                name.len() / 2)
-        }).expect("Failed to convert to utf8");
+/// Process information from a `nt!_EPROCESS`
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    /// Virtual address of the `nt!_EPROCESS`
+    pub eprocess: usize,
 
-        // Append this to the module list
-        modlist.add_module(ModuleEntry {
-            info: ModuleInfo::new(name_utf8, time_date_stamp, size_of_image),
-            base,
-            len,
-        });
+    /// Process ID
+    pub pid: usize,
 
-        // Go to the next module
-        if flink == blink { break; }
-        flink = memory.read_virt_usize(cr3, flink)?;
+    /// Page table of the process (`_KPROCESS.DirectoryTableBase`) with the
+    /// PCID bits masked off
+    pub cr3: usize,
+
+    /// Page table used in user mode with KVA shadowing
+    /// (`_KPROCESS.UserDirectoryTableBase`), zero if there isn't one
+    pub user_cr3: usize,
+
+    /// Short image name (`_EPROCESS.ImageFileName`), at most 15 characters
+    pub name: String,
+}
+
+/// Read the process information from the `nt!_EPROCESS` at `eprocess`
+fn get_process(memory: &mut MemReader, offsets: &ProcessOffsets, cr3: usize,
+        eprocess: usize) -> Result<ProcessInfo, ()> {
+    let pid     = memory.read_virt_usize(cr3,
+        eprocess + offsets.unique_process_id)?;
+    let dirbase = memory.read_virt_usize(cr3,
+        eprocess + offsets.directory_table)?;
+    let user_dirbase = memory.read_virt_usize(cr3,
+        eprocess + offsets.user_directory).unwrap_or(0);
+
+    let mut name = [0u8; 15];
+    if memory.read_virt(cr3, eprocess + offsets.image_file_name,
+            &mut name) != name.len() {
+        return Err(());
     }
+    let namelen = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+
+    Ok(ProcessInfo {
+        eprocess,
+        pid,
+        cr3:  dirbase & 0xFFFFFFFFFF000,
+        user_cr3: user_dirbase & 0xFFFFFFFFFF000,
+        name: String::from_utf8_lossy(&name[..namelen]).into_owned(),
+    })
+}
 
-    Ok(())
+/// Get the process which is currently running on the processor with the
+/// kernel GS base `kernel_gs`, which points to the `nt!_KPCR`
+pub fn get_current_process(memory: &mut MemReader, offsets: &ProcessOffsets,
+        cr3: usize, lma: bool, kernel_gs: usize) -> Result<ProcessInfo, ()> {
+    // Make sure we have a GS and we're 64-bit
+    if !(lma && (kernel_gs & (1 << 63)) != 0) {
+        return Err(());
+    }
+
+    // _KPCR.Prcb.CurrentThread
+    let thread = memory.read_virt_usize(cr3,
+        kernel_gs + offsets.kpcr_current_thread)?;
+
+    // _KTHREAD.ApcState.Process
+    let eprocess = memory.read_virt_usize(cr3,
+        thread + offsets.kthread_process)?;
+
+    get_process(memory, offsets, cr3, eprocess)
 }
 
-/// Walk the module list for the current operating context
-pub fn get_modlist<'a>(memory: &mut MemReader,
-        cr3: usize, lma: bool, gs_base: usize, cs: u16,
-        plml_ptr: Option<usize>) -> Result<ModuleList, ()> {
+/// Walk the process list starting at the current process
+///
+/// This follows `nt!_EPROCESS.ActiveProcessLinks`, which also goes through
+/// `nt!PsActiveProcessHead`. That isn't a process, so entries which don't look
+/// like one are skipped.
+pub fn get_process_list(memory: &mut MemReader, offsets: &ProcessOffsets,
+        cr3: usize, lma: bool, kernel_gs: usize)
+        -> Result<Vec<ProcessInfo>, ()> {
+    let current = get_current_process(memory, offsets, cr3, lma, kernel_gs)?;
+
+    let start = current.eprocess + offsets.active_links;
+    let mut ret = vec![current];
+
+    let mut flink = memory.read_virt_usize(cr3, start)?;
+    while flink != start {
+        // Give up on broken lists rather than looping forever
+        if flink == 0 || ret.len() > 65536 {
+            return Err(());
+        }
 
-    // Create the module list we will return
-    let mut ret = ModuleList::new();
+        let eprocess = flink - offsets.active_links;
+        if let Ok(process) = get_process(memory, offsets, cr3, eprocess) {
+            // Processes always have a page table and a printable name, this
+            // filters out the list head
+            if process.cr3 != 0 && !process.name.is_empty() &&
+                    process.name.bytes().all(|x| x.is_ascii_graphic() ||
+                    x == b' ') {
+                ret.push(process);
+            }
+        }
 
-    // Check which CPL we're at
-    if (cs & 3) == 3 {
-        // ring3
-        get_modlist_user(&mut ret, cr3, lma, gs_base, cs, memory)?;
-    } else if plml_ptr.is_some() {
-        // kernel
-        get_modlist_kernel(&mut ret, cr3, lma, cs, memory, plml_ptr.unwrap())?;
-    } else {
-        return Err(());
+        flink = memory.read_virt_usize(cr3, flink)?;
     }
 
-    // Sort listing so we can binary search by module base
-    ret.modules.sort_by_key(|x| x.base);
+    // Sort by PID as the list starts at whatever process was running
+    ret.sort_by_key(|x| x.pid);
 
     Ok(ret)
 }
+
+#[test]
+fn test_process_list() {
+    use crate::memreader::MemoryRegion;
+
+    // Map `vaddr` to `paddr` in the long mode page table at `cr3`, taking
+    // new tables from the top of `memory`
+    fn map(memory: &mut [u8], next: &mut usize, cr3: usize, vaddr: usize,
+            paddr: usize) {
+        let mut table = cr3;
+        for &shift in &[39, 30, 21, 12] {
+            let entry = table + ((vaddr >> shift) & 0x1ff) * 8;
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&memory[entry..entry + 8]);
+            let mut val = u64::from_le_bytes(raw);
+            if shift == 12 {
+                val = paddr as u64 | 3;
+            } else if val & 1 == 0 {
+                *next -= 4096;
+                val = *next as u64 | 3;
+            }
+            memory[entry..entry + 8].copy_from_slice(&val.to_le_bytes());
+            table = (val & !0xfff) as usize;
+        }
+    }
+
+    let offsets = ProcessOffsets::for_build(17763).unwrap();
+    let kva = |paddr: usize| 0xfffff80000000000 + paddr;
+
+    // KUSER_SHARED_DATA at 0x2000, the KPCR at 0x3000, the current thread at
+    // 0x4000, and then System, target.exe, and nt!PsActiveProcessHead
+    let mut memory = vec![0u8; 32 * 4096];
+    let mut next = memory.len();
+    let cr3 = 0x1000;
+    map(&mut memory, &mut next, cr3, KUSER_SHARED_DATA, 0x2000);
+    for paddr in (0x3000..0x8000).step_by(4096) {
+        map(&mut memory, &mut next, cr3, kva(paddr), paddr);
+    }
+
+    let mut put = |paddr: usize, val: &[u8]| {
+        memory[paddr..paddr + val.len()].copy_from_slice(val);
+    };
+    put(0x2000 + KUSER_NT_BUILD_NUMBER, &(0xf0000000u32 | 17763).to_le_bytes());
+    put(0x3000 + offsets.kpcr_current_thread, &kva(0x4000).to_le_bytes());
+    put(0x4000 + offsets.kthread_process, &kva(0x6000).to_le_bytes());
+    let links = |eprocess: usize| kva(eprocess + offsets.active_links);
+    for &(eprocess, pid, dirbase, name, flink) in &[
+            (0x5000, 4usize, 0x1aa002usize, "System", links(0x6000)),
+            (0x6000, 0x1234, 0x1bb000, "target.exe", kva(0x7000)),
+    ] {
+        put(eprocess + offsets.unique_process_id, &pid.to_le_bytes());
+        put(eprocess + offsets.directory_table, &dirbase.to_le_bytes());
+        put(eprocess + offsets.active_links, &flink.to_le_bytes());
+        put(eprocess + offsets.image_file_name, name.as_bytes());
+    }
+    put(0x7000, &links(0x5000).to_le_bytes());
+
+    let mut reader = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: memory.as_ptr() as usize,
+        perms:   0,
+        size:    memory.len(),
+    }]);
+
+    assert_eq!(ProcessOffsets::from_guest(&mut reader, cr3), Ok(offsets));
+    let current = get_current_process(&mut reader, &offsets, cr3, true,
+        kva(0x3000)).unwrap();
+    assert_eq!((current.pid, current.name.as_str()), (0x1234, "target.exe"));
+
+    // The list head isn't a process
+    let list = get_process_list(&mut reader, &offsets, cr3, true,
+        kva(0x3000)).unwrap();
+    let list: Vec<_> = list.iter()
+        .map(|x| (x.pid, x.cr3, x.eprocess, x.name.as_str())).collect();
+    assert_eq!(list, [(4, 0x1aa000, kva(0x5000), "System"),
+        (0x1234, 0x1bb000, kva(0x6000), "target.exe")]);
+
+    // Builds we don't know fail rather than reading garbage
+    assert!(ProcessOffsets::for_build(19041).is_err());
+    assert_eq!(ProcessOffsets::parse(
+        "0x188, 0xb8, 0x28, 0x280, 0x2e0, 0x2e8, 1104"), Ok(offsets));
+    assert!(ProcessOffsets::parse("0x188,0xb8").is_err());
+}
+
+#[test]
+fn test_empty_ldr_list() {
+    use crate::memreader::MemoryRegion;
+
+    // The loader data of a process which isn't set up yet is all zeroes
+    let mut backing = vec![0u8; 0x1000];
+    let mut memory = MemReader::new(vec![MemoryRegion {
+        paddr:   0,
+        backing: backing.as_mut_ptr() as usize,
+        perms:   0,
+        size:    backing.len(),
+    }]);
+    let mut space = AddressSpace {
+        memory: &mut memory,
+        paging: Paging::Disabled,
+        cr3:    0,
+    };
+
+    let mut modlist = ModuleList::default();
+    assert!(walk_ldr_list(&mut modlist, &mut space, 0x100, &LDR32).is_err());
+    assert!(walk_ldr_list(&mut modlist, &mut space, 0x100, &LDR64).is_err());
+}
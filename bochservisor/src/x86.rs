@@ -0,0 +1,325 @@
+/// x86-64 instruction length and control flow decoding
+///
+/// This decodes just enough of an instruction to know how long it is and how
+/// it affects control flow, which is all basic block discovery needs. Only
+/// 64-bit mode is supported. Operands other than branch targets aren't
+/// decoded, and encodings which don't exist in 64-bit mode are invalid.
+
+/// How an instruction affects control flow. Targets are relative to the end
+/// of the instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    /// Falls through to the next instruction
+    Next,
+
+    /// Calls a relative target, or somewhere indirect
+    Call(Option<i64>),
+
+    /// Jumps to a relative target, or somewhere indirect
+    Jump(Option<i64>),
+
+    /// Either branches to a relative target or falls through
+    Branch(i64),
+
+    /// Doesn't fall through (`ret`, `int3`, `ud2`, `hlt`, ...)
+    Stop,
+}
+
+/// A decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Insn {
+    /// Length of the instruction in bytes
+    pub len: usize,
+
+    /// How the instruction affects control flow
+    pub flow: Flow,
+}
+
+/// Immediate operand of an instruction
+#[derive(Clone, Copy, PartialEq)]
+enum Imm {
+    None,
+    Byte,
+    Word,
+
+    /// Word or dword depending on the operand size
+    Z,
+
+    /// Word, dword or qword depending on the operand size
+    V,
+
+    /// Memory offset, qword or dword depending on the address size
+    Moffs,
+
+    /// Word followed by a byte (`enter`)
+    Enter,
+
+    /// Relative branch target
+    Rel8,
+    Rel32,
+}
+
+/// Maximum length of an instruction
+const MAX_INSN_LEN: usize = 15;
+
+/// Get whether one byte opcode `op` has a ModRM and its immediate, `None` if
+/// it's invalid in 64-bit mode
+fn one_byte(op: u8) -> Option<(bool, Imm)> {
+    Some(match op {
+        0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f |
+        0x37 | 0x3f | 0x40..=0x4f | 0x60 | 0x61 | 0x82 | 0x9a | 0xce |
+        0xd4 | 0xd5 | 0xd6 | 0xea => return None,
+        0x00..=0x3f => match op & 7 {
+            0..=3 => (true,  Imm::None),
+            4     => (false, Imm::Byte),
+            _     => (false, Imm::Z),
+        },
+        0x50..=0x5f => (false, Imm::None),
+        0x63        => (true,  Imm::None),
+        0x68        => (false, Imm::Z),
+        0x69        => (true,  Imm::Z),
+        0x6a        => (false, Imm::Byte),
+        0x6b        => (true,  Imm::Byte),
+        0x6c..=0x6f => (false, Imm::None),
+        0x70..=0x7f => (false, Imm::Rel8),
+        0x80 | 0x83 => (true,  Imm::Byte),
+        0x81        => (true,  Imm::Z),
+        0x84..=0x8f => (true,  Imm::None),
+        0x90..=0x9f => (false, Imm::None),
+        0xa0..=0xa3 => (false, Imm::Moffs),
+        0xa8        => (false, Imm::Byte),
+        0xa9        => (false, Imm::Z),
+        0xa4..=0xaf => (false, Imm::None),
+        0xb0..=0xb7 => (false, Imm::Byte),
+        0xb8..=0xbf => (false, Imm::V),
+        0xc0 | 0xc1 => (true,  Imm::Byte),
+        0xc2 | 0xca => (false, Imm::Word),
+        0xc6        => (true,  Imm::Byte),
+        0xc7        => (true,  Imm::Z),
+        0xc8        => (false, Imm::Enter),
+        0xcd        => (false, Imm::Byte),
+        0xc3 | 0xc9 | 0xcb | 0xcc | 0xcf => (false, Imm::None),
+        0xd0..=0xd3 => (true,  Imm::None),
+        0xd7        => (false, Imm::None),
+        0xd8..=0xdf => (true,  Imm::None),
+        0xe0..=0xe3 => (false, Imm::Rel8),
+        0xe4..=0xe7 => (false, Imm::Byte),
+        0xe8 | 0xe9 => (false, Imm::Rel32),
+        0xeb        => (false, Imm::Rel8),
+        0xec..=0xef => (false, Imm::None),
+        0xf1 | 0xf4 | 0xf5 | 0xf8..=0xfd => (false, Imm::None),
+        0xf6 | 0xf7 | 0xfe | 0xff => (true, Imm::None),
+        _ => return None,
+    })
+}
+
+/// Get whether two byte opcode `0f op` has a ModRM and its immediate, `None`
+/// if it's invalid in 64-bit mode
+fn two_byte(op: u8) -> Option<(bool, Imm)> {
+    Some(match op {
+        0x04 | 0x0a | 0x0c | 0x24..=0x27 | 0x36 | 0x39 | 0x3b..=0x3f |
+        0x7a | 0x7b | 0xa6 | 0xa7 => return None,
+        0x05..=0x09 | 0x0b | 0x0e | 0x30..=0x35 | 0x37 | 0x77 |
+        0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf => (false, Imm::None),
+        0x80..=0x8f => (false, Imm::Rel32),
+        0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 =>
+            (true, Imm::Byte),
+        _ => (true, Imm::None),
+    })
+}
+
+/// Returns `true` if VEX or EVEX opcode `op` in opcode map `map` has an
+/// immediate byte
+fn vex_imm(map: u8, op: u8) -> bool {
+    match map {
+        1 => matches!(op, 0x70..=0x73 | 0xc2 | 0xc4..=0xc6),
+        3 => true,
+        _ => false,
+    }
+}
+
+/// Get the length of the ModRM at the start of `bytes` along with its SIB and
+/// displacement. Also returns the `reg` field of the ModRM
+fn modrm(bytes: &[u8]) -> Option<(usize, u8)> {
+    let modrm = *bytes.first()?;
+    let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+    if md == 3 { return Some((1, reg)); }
+
+    // A base of 5 without a displacement is actually a disp32, RIP relative
+    // without a SIB
+    let mut len   = 1;
+    let mut base5 = rm == 5;
+    if rm == 4 {
+        base5 = *bytes.get(1)? & 7 == 5;
+        len += 1;
+    }
+
+    len += match md {
+        0 if base5 => 4,
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    };
+    Some((len, reg))
+}
+
+/// Decode the instruction at the start of `bytes`. Returns `None` if it's
+/// invalid or doesn't fit in `bytes`
+pub fn decode(bytes: &[u8]) -> Option<Insn> {
+    let mut ii = 0;
+    let mut opsize16 = false;
+    let mut addr32   = false;
+
+    // Legacy prefixes
+    loop {
+        match *bytes.get(ii)? {
+            0x66 => opsize16 = true,
+            0x67 => addr32   = true,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => {}
+            _ => break,
+        }
+        ii += 1;
+    }
+
+    // REX has to come right before the opcode
+    let mut rex_w = false;
+    if let 0x40..=0x4f = bytes[ii] {
+        rex_w = bytes[ii] & 8 != 0;
+        ii += 1;
+    }
+
+    let op = *bytes.get(ii)?;
+    ii += 1;
+
+    // Get the opcode map, the opcode in it, and whether it has a ModRM and
+    // an immediate
+    let (map, op, has_modrm, mut imm) = match op {
+        0x0f => {
+            let op2 = *bytes.get(ii)?;
+            ii += 1;
+            match op2 {
+                0x38 => { ii += 1; (2, *bytes.get(ii - 1)?, true, Imm::None) }
+                0x3a => { ii += 1; (3, *bytes.get(ii - 1)?, true, Imm::Byte) }
+                _ => {
+                    let (has_modrm, imm) = two_byte(op2)?;
+                    (1, op2, has_modrm, imm)
+                }
+            }
+        }
+        0xc4 | 0xc5 | 0x62 => {
+            // VEX and EVEX, these are always that in 64-bit mode
+            let (map, prefix_len) = match op {
+                0xc5 => (1, 1),
+                0xc4 => (*bytes.get(ii)? & 0x1f, 2),
+                _    => (*bytes.get(ii)? & 7, 3),
+            };
+            let valid = matches!(map, 1..=3) ||
+                (op == 0x62 && matches!(map, 5 | 6));
+            if !valid { return None; }
+            ii += prefix_len;
+            let op = *bytes.get(ii)?;
+            ii += 1;
+
+            // `vzeroupper` and `vzeroall` are the only ones without a ModRM
+            let has_modrm = !(map == 1 && op == 0x77 && prefix_len < 3);
+            let imm = if vex_imm(map, op) { Imm::Byte } else { Imm::None };
+            (map + 0x10, op, has_modrm, imm)
+        }
+        _ => {
+            let (has_modrm, imm) = one_byte(op)?;
+            (0, op, has_modrm, imm)
+        }
+    };
+
+    let mut reg = 0;
+    if has_modrm {
+        let (len, modrm_reg) = modrm(bytes.get(ii..)?)?;
+        reg = modrm_reg;
+        ii += len;
+    }
+
+    // `test` is the only member of its group with an immediate
+    if map == 0 && (op == 0xf6 || op == 0xf7) && reg < 2 {
+        imm = if op == 0xf6 { Imm::Byte } else { Imm::Z };
+    }
+
+    let imm_len = match imm {
+        Imm::None  => 0,
+        Imm::Byte | Imm::Rel8 => 1,
+        Imm::Word  => 2,
+        Imm::Enter => 3,
+        Imm::Rel32 => 4,
+        Imm::Z     => if opsize16 { 2 } else { 4 },
+        Imm::V     => if rex_w { 8 } else if opsize16 { 2 } else { 4 },
+        Imm::Moffs => if addr32 { 4 } else { 8 },
+    };
+    let imm_bytes = bytes.get(ii..ii + imm_len)?;
+    let len = ii + imm_len;
+    if len > MAX_INSN_LEN { return None; }
+
+    // Relative branch target
+    let rel = match imm {
+        Imm::Rel8  => imm_bytes[0] as i8 as i64,
+        Imm::Rel32 => i32::from_le_bytes(
+            [imm_bytes[0], imm_bytes[1], imm_bytes[2], imm_bytes[3]]) as i64,
+        _ => 0,
+    };
+
+    let flow = match (map, op) {
+        (0, 0x70..=0x7f) | (0, 0xe0..=0xe3) | (1, 0x80..=0x8f) =>
+            Flow::Branch(rel),
+        (0, 0xe8) => Flow::Call(Some(rel)),
+        (0, 0xe9) | (0, 0xeb) => Flow::Jump(Some(rel)),
+        (0, 0xff) if reg == 2 || reg == 3 => Flow::Call(None),
+        (0, 0xff) if reg == 4 || reg == 5 => Flow::Jump(None),
+        (0, 0xc2) | (0, 0xc3) | (0, 0xca) | (0, 0xcb) | (0, 0xcc) |
+        (0, 0xcf) | (0, 0xf4) | (1, 0x07) | (1, 0x0b) | (1, 0x35) |
+        (1, 0xb9) | (1, 0xff) => Flow::Stop,
+        _ => Flow::Next,
+    };
+
+    Some(Insn { len, flow })
+}
+
+#[test]
+fn test_decode() {
+    let check = |bytes: &[u8], flow: Flow| {
+        assert_eq!(decode(bytes), Some(Insn { len: bytes.len(), flow }),
+            "{:02x?}", bytes);
+    };
+
+    check(&[0x48, 0x8b, 0x05, 0x11, 0x22, 0x33, 0x44], Flow::Next);
+    check(&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], Flow::Next);
+    check(&[0x66, 0xb8, 0x34, 0x12], Flow::Next);
+    check(&[0x48, 0xa1, 1, 2, 3, 4, 5, 6, 7, 8], Flow::Next);
+    check(&[0x0f, 0x1f, 0x44, 0x00, 0x00], Flow::Next);
+    check(&[0xf3, 0x0f, 0x1e, 0xfa], Flow::Next);
+    check(&[0x66, 0x0f, 0x3a, 0x0f, 0xc1, 0x08], Flow::Next);
+    check(&[0x66, 0x0f, 0x38, 0x00, 0x04, 0x24], Flow::Next);
+    check(&[0xc4, 0xe2, 0x79, 0x18, 0x05, 0, 0, 0, 0], Flow::Next);
+    check(&[0xc5, 0xf8, 0x77], Flow::Next);
+    check(&[0x62, 0xf1, 0x7c, 0x48, 0x10, 0x44, 0x24, 0x01], Flow::Next);
+    check(&[0xf7, 0xc1, 0x00, 0x01, 0x00, 0x00], Flow::Next);
+    check(&[0xf6, 0xc1, 0x01], Flow::Next);
+    check(&[0xf7, 0xd8], Flow::Next);
+    check(&[0xc8, 0x10, 0x00, 0x00], Flow::Next);
+    check(&[0x8b, 0x84, 0x8d, 0x00, 0x10, 0x00, 0x00], Flow::Next);
+
+    check(&[0xe8, 0xfb, 0xff, 0xff, 0xff], Flow::Call(Some(-5)));
+    check(&[0xff, 0x15, 0x00, 0x10, 0x00, 0x00], Flow::Call(None));
+    check(&[0xeb, 0x10], Flow::Jump(Some(0x10)));
+    check(&[0xff, 0xe0], Flow::Jump(None));
+    check(&[0x74, 0xfe], Flow::Branch(-2));
+    check(&[0x0f, 0x84, 0x10, 0x00, 0x00, 0x00], Flow::Branch(0x10));
+    check(&[0xc2, 0x08, 0x00], Flow::Stop);
+    check(&[0x0f, 0x0b], Flow::Stop);
+    check(&[0xcc], Flow::Stop);
+
+    // Invalid in 64-bit mode, truncated, and too long
+    assert_eq!(decode(&[0x06]), None);
+    assert_eq!(decode(&[0xe8, 0x00, 0x00]), None);
+    let mut long = vec![0x66u8; 15];
+    long.push(0x90);
+    assert_eq!(decode(&long[1..]), Some(Insn { len: 15, flow: Flow::Next }));
+    assert_eq!(decode(&long), None);
+}
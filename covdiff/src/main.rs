@@ -0,0 +1,267 @@
+//! Coverage database diffing
+//!
+//! Loads two coverage databases and prints, per module, what's only covered
+//! in the first, only in the second, and in both. Modules are matched by name,
+//! TimeDateStamp and SizeOfImage. If a module is in both under the same name
+//! but with different builds, offsets mean nothing across the two so the
+//! functions covered are compared instead.
+//!
+//! Symbols come from the symbol server through dbghelp, which is only there
+//! on Windows. Elsewhere offsets are printed as `module+offset`, and modules
+//! from different builds can't be compared.
+
+#[path = "../../bochservisor/src/virtmem.rs"]
+#[allow(dead_code, clippy::unnecessary_cast)]
+mod virtmem;
+
+#[path = "../../bochservisor/src/memreader.rs"]
+#[allow(dead_code, clippy::empty_line_after_doc_comments, clippy::len_zero)]
+#[allow(clippy::absurd_extreme_comparisons)]
+mod memreader;
+
+#[path = "../../bochservisor/src/win32.rs"]
+#[allow(dead_code, clippy::unnecessary_cast, clippy::manual_is_multiple_of)]
+#[allow(clippy::print_with_newline)]
+mod win32;
+
+#[path = "../../bochservisor/src/covdb.rs"]
+#[allow(dead_code, clippy::empty_line_after_doc_comments)]
+mod covdb;
+
+#[path = "../../bochservisor/src/drcov.rs"]
+#[allow(dead_code, clippy::empty_line_after_doc_comments)]
+#[allow(clippy::write_with_newline)]
+mod drcov;
+
+#[cfg(windows)]
+#[path = "../../bochservisor/src/symdumper.rs"]
+#[allow(dead_code)]
+mod symdumper;
+
+#[cfg(windows)]
+#[path = "../../bochservisor/src/symloader.rs"]
+#[allow(dead_code)]
+mod symloader;
+
+use std::collections::BTreeSet;
+use crate::win32::ModuleInfo;
+
+#[cfg(windows)]
+use crate::symloader::Symbols;
+
+/// Without dbghelp nothing resolves
+#[cfg(not(windows))]
+#[derive(Default)]
+struct Symbols {}
+
+#[cfg(not(windows))]
+impl Symbols {
+    fn resolve(&mut self, _module: &ModuleInfo, _offset: usize)
+            -> Option<String> {
+        None
+    }
+}
+
+const USAGE: &str = "\
+usage: covdiff [--functions] <coverage db A> <coverage db B>
+
+Prints what's covered only in A, only in B and in both, per module.
+--functions compares the functions covered rather than offsets, which is
+always done for modules which are in both under different builds.
+";
+
+/// Print the usage and exit
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    std::process::exit(1);
+}
+
+/// Something covered in a module
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Entry {
+    /// A covered offset
+    Offset(u32),
+
+    /// A function with coverage in it
+    Function(String),
+}
+
+/// Split `a` and `b` into what's only in `a`, only in `b`, and in both
+fn diff<T: Ord + Clone>(a: &BTreeSet<T>, b: &BTreeSet<T>)
+        -> (Vec<T>, Vec<T>, Vec<T>) {
+    (a.difference(b).cloned().collect(),
+     b.difference(a).cloned().collect(),
+     a.intersection(b).cloned().collect())
+}
+
+/// Find the module in `modules` to compare `module` against, which hasn't
+/// been matched yet. Prefers the same build, then the same name
+fn find_match(module: &covdb::Module, modules: &[covdb::Module],
+        matched: &[bool]) -> Option<usize> {
+    let unmatched = || modules.iter().enumerate().filter(|(ii, _)|
+        !matched[*ii]);
+    let same_name = |x: &covdb::Module|
+        x.name.eq_ignore_ascii_case(module.name);
+    unmatched().find(|(_, x)| same_name(x) &&
+            x.timestamp == module.timestamp && x.size == module.size)
+        .or_else(|| unmatched().find(|(_, x)| same_name(x)))
+        .map(|(ii, _)| ii)
+}
+
+/// Get what's covered in `module`, as functions if `functions` is set.
+/// Returns the entries and the number of offsets without a symbol, which
+/// are left out when comparing functions
+fn entries(symbols: &mut Symbols, module: &covdb::Module, functions: bool)
+        -> (BTreeSet<Entry>, usize) {
+    let modinfo = ModuleInfo::new(module.name.into(), module.timestamp,
+        module.size);
+    let offsets = drcov::Module {
+        name:      module.name,
+        size:      module.size,
+        timestamp: module.timestamp,
+        bitmap:    module.bitmap,
+    }.offsets();
+
+    if !functions {
+        return (offsets.map(Entry::Offset).collect(), 0);
+    }
+
+    let mut ret = BTreeSet::new();
+    let mut unresolved = 0;
+    for offset in offsets {
+        match symbols.resolve(&modinfo, offset as usize) {
+            Some(mut sym) => {
+                if let Some(plus) = sym.rfind("+0x") { sym.truncate(plus); }
+                ret.insert(Entry::Function(sym));
+            }
+            None => unresolved += 1,
+        }
+    }
+    (ret, unresolved)
+}
+
+/// Print `entries` of `module` under `title`
+fn print_entries(symbols: &mut Symbols, module: &covdb::Module, title: &str,
+        entries: &[Entry]) {
+    if entries.is_empty() { return; }
+
+    let modinfo = ModuleInfo::new(module.name.into(), module.timestamp,
+        module.size);
+    println!("{}:", title);
+    for entry in entries {
+        match entry {
+            Entry::Offset(offset) => {
+                let sym = symbols.resolve(&modinfo, *offset as usize)
+                    .unwrap_or_else(|| format!("{}+0x{:x}", module.name,
+                        offset));
+                println!("    {}", sym);
+            }
+            Entry::Function(name) => println!("    {}", name),
+        }
+    }
+}
+
+/// Compare the coverage of `a` and `b`, either of which may be missing
+fn compare(symbols: &mut Symbols, a: Option<&covdb::Module>,
+        b: Option<&covdb::Module>, functions: bool) {
+    let build = |x: &covdb::Module| format!("{:08x} {:x}", x.timestamp,
+        x.size);
+
+    // Offsets are only comparable within the same build
+    let (name, functions) = match (a, b) {
+        (Some(a), Some(b)) if build(a) != build(b) => {
+            println!("== {} ({} in A, {} in B) ==\nBuilds differ, comparing \
+                functions", a.name, build(a), build(b));
+            (a.name, true)
+        }
+        (Some(x), Some(_)) => {
+            println!("== {} ({}) ==", x.name, build(x));
+            (x.name, functions)
+        }
+        (Some(x), None) | (None, Some(x)) => {
+            println!("== {} ({}) only in {} ==", x.name, build(x),
+                if a.is_some() { "A" } else { "B" });
+            (x.name, functions)
+        }
+        (None, None) => return,
+    };
+
+    let mut get = |x: Option<&covdb::Module>| x.map(|x|
+        entries(symbols, x, functions)).unwrap_or_default();
+    let (a_entries, a_unresolved) = get(a);
+    let (b_entries, b_unresolved) = get(b);
+    if a_unresolved + b_unresolved > 0 {
+        println!("{} offsets in A and {} in B have no symbol and were left \
+            out", a_unresolved, b_unresolved);
+    }
+
+    let (only_a, only_b, both) = diff(&a_entries, &b_entries);
+    let unit = if functions { "functions" } else { "offsets" };
+    println!("{}: {} {unit} only in A | {} {unit} only in B | {} {unit} in \
+        both", name, only_a.len(), only_b.len(), both.len(), unit = unit);
+
+    // Offsets print with the symbols of their own build
+    let (a, b) = (a.or(b).unwrap(), b.or(a).unwrap());
+    print_entries(symbols, a, "Only in A", &only_a);
+    print_entries(symbols, b, "Only in B", &only_b);
+    print_entries(symbols, a, "In both", &both);
+    println!();
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let functions = args.iter().any(|x| x == "--functions");
+    let paths: Vec<&String> = args.iter().filter(|x| *x != "--functions")
+        .collect();
+    if paths.len() != 2 { usage(); }
+
+    let data: Vec<Vec<u8>> = paths.iter().map(|path| {
+        std::fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path, err);
+            std::process::exit(1);
+        })
+    }).collect();
+    let dbs: Vec<Vec<covdb::Module>> = data.iter().zip(&paths)
+        .map(|(data, path)| {
+            covdb::parse(data).unwrap_or_else(|err| {
+                eprintln!("Failed to parse {}: {}", path, err);
+                std::process::exit(1);
+            })
+        }).collect();
+    let (a, b) = (&dbs[0], &dbs[1]);
+
+    let mut symbols = Symbols::default();
+    let mut matched = vec![false; b.len()];
+    for module in a {
+        let other = find_match(module, b, &matched);
+        if let Some(ii) = other { matched[ii] = true; }
+        compare(&mut symbols, Some(module), other.map(|ii| &b[ii]),
+            functions);
+    }
+    for (module, _) in b.iter().zip(&matched).filter(|(_, &x)| !x) {
+        compare(&mut symbols, None, Some(module), functions);
+    }
+}
+
+#[test]
+fn test_diff() {
+    let a: BTreeSet<u32> = [1, 2, 3].iter().cloned().collect();
+    let b: BTreeSet<u32> = [2, 3, 4, 5].iter().cloned().collect();
+    assert_eq!(diff(&a, &b), (vec![1], vec![4, 5], vec![2, 3]));
+
+    let module = |name, timestamp| covdb::Module {
+        name, timestamp, size: 0x1000, bitmap: &[],
+    };
+    let modules = [module("foo.sys", 2), module("ntdll.dll", 1),
+        module("Foo.sys", 1)];
+
+    // Same build first, then the same name, and never twice
+    let mut matched = vec![false; modules.len()];
+    assert_eq!(find_match(&module("foo.sys", 1), &modules, &matched), Some(2));
+    assert_eq!(find_match(&module("ntdll.dll", 1), &modules, &matched),
+        Some(1));
+    matched[2] = true;
+    assert_eq!(find_match(&module("FOO.SYS", 3), &modules, &matched), Some(0));
+    matched[0] = true;
+    assert_eq!(find_match(&module("foo.sys", 1), &modules, &matched), None);
+}
@@ -0,0 +1,344 @@
+//! Offline inspector for Bochs snapshot folders
+//!
+//! Loads the RAM and register state from a snapshot folder and answers
+//! questions about the guest without running anything. This doesn't depend on
+//! WHVP so it runs on any host, the modules shared with bochservisor are
+//! pulled in directly by path.
+
+#[path = "../../bochservisor/src/virtmem.rs"]
+#[allow(dead_code, clippy::unnecessary_cast)]
+mod virtmem;
+
+#[path = "../../bochservisor/src/memreader.rs"]
+#[allow(dead_code, clippy::empty_line_after_doc_comments, clippy::len_zero)]
+#[allow(clippy::absurd_extreme_comparisons)]
+mod memreader;
+
+#[path = "../../bochservisor/src/cpustate.rs"]
+#[allow(clippy::empty_line_after_doc_comments)]
+mod cpustate;
+
+#[path = "../../bochservisor/src/bochs_snapshot.rs"]
+#[allow(clippy::empty_line_after_doc_comments, clippy::manual_is_multiple_of)]
+mod bochs_snapshot;
+
+#[path = "../../bochservisor/src/crashdump.rs"]
+#[allow(clippy::empty_line_after_doc_comments, clippy::manual_div_ceil)]
+mod crashdump;
+
+#[path = "../../bochservisor/src/elfcore.rs"]
+#[allow(clippy::empty_line_after_doc_comments)]
+mod elfcore;
+
+#[path = "../../bochservisor/src/win32.rs"]
+#[allow(dead_code, clippy::unnecessary_cast, clippy::manual_is_multiple_of)]
+#[allow(clippy::print_with_newline)]
+mod win32;
+
+#[path = "../../bochservisor/src/x86.rs"]
+#[allow(clippy::empty_line_after_doc_comments)]
+mod x86;
+
+#[path = "../../bochservisor/src/blocks.rs"]
+#[allow(dead_code, clippy::empty_line_after_doc_comments)]
+mod blocks;
+
+use crate::bochs_snapshot::BochsSnapshot;
+use crate::cpustate::CpuState;
+use crate::crashdump::{CrashDump, MANUALLY_INITIATED_CRASH};
+use crate::elfcore::{ElfCore, CoreLayout};
+use crate::memreader::MemReader;
+use crate::win32::{find_kernel_modlist, get_modlist, ModuleList};
+use crate::win32::{get_current_process, get_process_list, ProcessOffsets};
+
+const USAGE: &str = "\
+usage: snapinspect <snapshot folder> [--cpu <n>] <command> [args]
+
+commands:
+    regs                      print the register state
+    processes                 list processes
+    modules                   list kernel modules, and the user modules of
+                              the current process if it's in user mode
+    read <vaddr> <size> [pid] hexdump virtual memory, using the page table
+                              of `pid` if given
+    phys <paddr> <size>       hexdump physical memory
+    blocks <module> [output]  discover the basic blocks of a loaded module
+                              and write its block list, named for the
+                              module's build if no output is given
+    dump <output>             write a Windows kernel crash dump
+    core <output> [cr3|phys]  write an ELF core with the virtual layout of
+                              the current (or given) CR3, or the physical
+                              layout
+";
+
+/// Print the usage and exit
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    std::process::exit(1);
+}
+
+/// Parse a number as hex if it's `0x` prefixed, otherwise as decimal
+fn parse_num(arg: &str) -> usize {
+    let ret = if let Some(hex) = arg.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
+    } else {
+        arg.parse()
+    };
+
+    ret.unwrap_or_else(|_| {
+        eprintln!("Invalid number `{}`", arg);
+        std::process::exit(1);
+    })
+}
+
+/// Get the process structure offsets for the Windows build in the snapshot,
+/// exiting if we don't know them
+fn process_offsets(cpu: &CpuState, memory: &mut MemReader) -> ProcessOffsets {
+    ProcessOffsets::from_guest(memory, cpu.cr3() as usize)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+}
+
+/// Get the page table to use for kernel structures
+///
+/// In user mode CR3 may be the KVA shadow page table which doesn't map most of
+/// the kernel, in that case use the kernel page table of the current process
+fn kernel_cr3(cpu: &CpuState, memory: &mut MemReader) -> usize {
+    let cr3 = cpu.cr3() as usize;
+    if cpu.cpl() == 0 {
+        return cr3;
+    }
+
+    let offsets = process_offsets(cpu, memory);
+    get_current_process(memory, &offsets, cr3, cpu.lma(),
+            cpu.kernel_gs() as usize)
+        .map(|x| x.cr3).unwrap_or(cr3)
+}
+
+/// Get the kernel module list, and the user module list if the processor was
+/// in user mode, along with the page table each list was walked with
+fn module_lists(cpu: &CpuState, memory: &mut MemReader)
+        -> Vec<(&'static str, usize, ModuleList)> {
+    let cr3 = kernel_cr3(cpu, memory);
+    let kml = find_kernel_modlist(cr3, cpu.paging(),
+        cpu.kpcr() as usize, 0, memory).ok();
+
+    let mut lists = Vec::new();
+    match get_modlist(memory, cr3, cpu.paging(), 0, 0, 0, kml) {
+        Ok(ml) => lists.push(("Kernel", cr3, ml)),
+        Err(_) => println!("Failed to walk the kernel module list"),
+    }
+
+    if cpu.cpl() == 3 {
+        let cr3 = cpu.cr3() as usize;
+        match get_modlist(memory, cr3, cpu.paging(), cpu.fs.base as usize,
+                cpu.gs.base as usize, cpu.cs.selector, None) {
+            Ok(ml) => lists.push(("User", cr3, ml)),
+            Err(_) => println!("Failed to walk the user module list"),
+        }
+    }
+
+    lists
+}
+
+/// Print the kernel module list, and the user module list if the processor
+/// was in user mode
+fn print_modules(cpu: &CpuState, memory: &mut MemReader) {
+    for (kind, _, ml) in module_lists(cpu, memory) {
+        println!("{} modules:", kind);
+        for module in ml.modules() {
+            println!("    {:016x} {:016x} {:08x} {}",
+                module.base(), module.base() + module.len(),
+                module.info().time(), module.info().name());
+        }
+    }
+}
+
+/// Hexdump `size` bytes starting at `addr`, reading bytes with `read`.
+/// Unreadable bytes are shown as `??`
+fn hexdump<F>(addr: usize, size: usize, mut read: F)
+        where F: FnMut(usize, &mut [u8]) -> usize {
+    for line in (addr..addr.saturating_add(size)).step_by(16) {
+        let len = std::cmp::min(16, addr + size - line);
+
+        let mut buf = [0u8; 16];
+        let bread = read(line, &mut buf[..len]);
+
+        let mut hex   = String::new();
+        let mut ascii = String::new();
+        for (ii, &byte) in buf[..len].iter().enumerate() {
+            if ii < bread {
+                hex.push_str(&format!("{:02x} ", byte));
+                ascii.push(if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                });
+            } else {
+                hex.push_str("?? ");
+                ascii.push('?');
+            }
+        }
+
+        println!("{:016x}: {:48} {}", line, hex, ascii);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() { usage(); }
+
+    // Pick out the processor index if it was specified
+    let mut cpu_idx = 0;
+    let mut rest = Vec::new();
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--cpu" {
+            cpu_idx = parse_num(iter.next().unwrap_or_else(|| usage()));
+        } else {
+            rest.push(arg.as_str());
+        }
+    }
+    if rest.is_empty() { usage(); }
+
+    let snapshot = BochsSnapshot::load(&args[0]).unwrap_or_else(|err| {
+        eprintln!("Failed to load snapshot {}: {}", args[0], err);
+        std::process::exit(1);
+    });
+
+    let cpu = *snapshot.cpus.get(cpu_idx).unwrap_or_else(|| {
+        eprintln!("Snapshot only has {} processors", snapshot.cpus.len());
+        std::process::exit(1);
+    });
+
+    let mut memory = snapshot.mem_reader();
+
+    match rest.as_slice() {
+        ["regs"] => {
+            print!("{}", cpu);
+        }
+        ["processes"] => {
+            let cr3 = kernel_cr3(&cpu, &mut memory);
+            let offsets = process_offsets(&cpu, &mut memory);
+            match get_process_list(&mut memory, &offsets, cr3, cpu.lma(),
+                    cpu.kernel_gs() as usize) {
+                Ok(processes) => {
+                    println!("{:>6} {:16} {:16} name", "pid", "eprocess",
+                        "cr3");
+                    for process in processes {
+                        println!("{:6} {:016x} {:016x} {}", process.pid,
+                            process.eprocess, process.cr3, process.name);
+                    }
+                }
+                Err(_) => {
+                    eprintln!("Failed to walk the process list");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ["modules"] => {
+            print_modules(&cpu, &mut memory);
+        }
+        ["read", vaddr, size, pid @ ..] => {
+            let cr3 = match pid {
+                [] => cpu.cr3() as usize,
+                [pid] => {
+                    let pid = parse_num(pid);
+                    let kcr3 = kernel_cr3(&cpu, &mut memory);
+                    let offsets = process_offsets(&cpu, &mut memory);
+                    get_process_list(&mut memory, &offsets, kcr3, cpu.lma(),
+                            cpu.kernel_gs() as usize).ok()
+                        .and_then(|x| x.into_iter().find(|x| x.pid == pid))
+                        .unwrap_or_else(|| {
+                            eprintln!("Could not find process {}", pid);
+                            std::process::exit(1);
+                        }).cr3
+                }
+                _ => usage(),
+            };
+
+            hexdump(parse_num(vaddr), parse_num(size), |addr, buf| {
+                memory.read_virt_paging(cpu.paging(), cr3, addr, buf)
+            });
+        }
+        ["phys", paddr, size] => {
+            hexdump(parse_num(paddr), parse_num(size), |addr, buf| {
+                memory.read_phys(addr, buf)
+            });
+        }
+        ["blocks", name, output @ ..] => {
+            let lists = module_lists(&cpu, &mut memory);
+            let (cr3, module) = lists.iter()
+                .flat_map(|(_, cr3, ml)|
+                    ml.modules().iter().map(move |x| (*cr3, x)))
+                .find(|(_, x)| x.info().name().eq_ignore_ascii_case(name))
+                .unwrap_or_else(|| {
+                    eprintln!("Could not find module {}", name);
+                    std::process::exit(1);
+                });
+
+            let info = module.info();
+            let output = match output {
+                []       => blocks::list_name(info.name(), info.time(),
+                    info.size()),
+                [output] => output.to_string(),
+                _        => usage(),
+            };
+
+            let blocks = blocks::read_image(&mut memory, cpu.paging(), cr3,
+                    module.base(), info.size() as usize)
+                .and_then(|image| blocks::discover(&image))
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to discover blocks for {}: {}",
+                        info.name(), err);
+                    std::process::exit(1);
+                });
+            if let Err(err) = std::fs::write(&output,
+                    blocks::serialize_list(&blocks)) {
+                eprintln!("Failed to write {}: {}", output, err);
+                std::process::exit(1);
+            }
+            println!("Wrote {} blocks to {}", blocks.len(), output);
+        }
+        ["dump", output] => {
+            // The dump header needs the kernel page table
+            let mut kcpu = cpu;
+            kcpu.cr3 = kernel_cr3(&cpu, &mut memory) as u64;
+
+            let kml = find_kernel_modlist(kcpu.cr3() as usize, kcpu.paging(),
+                    kcpu.kpcr() as usize, 0, &mut memory)
+                .unwrap_or_else(|_| {
+                    eprintln!("Could not find nt!PsLoadedModuleList");
+                    std::process::exit(1);
+                });
+
+            let dump = CrashDump {
+                cpu:      &kcpu,
+                num_cpus: snapshot.cpus.len() as u32,
+                ps_loaded_module_list: kml,
+                bugcheck: (MANUALLY_INITIATED_CRASH, [0; 4]),
+            };
+            if let Err(err) = dump.save(&mut memory, output) {
+                eprintln!("Failed to write {}: {}", output, err);
+                std::process::exit(1);
+            }
+        }
+        ["core", output, layout @ ..] => {
+            let layout = match layout {
+                []       => CoreLayout::Virtual(cpu.cr3() as usize),
+                ["phys"] => CoreLayout::Physical,
+                [cr3]    => CoreLayout::Virtual(parse_num(cr3) & !0xfff),
+                _        => usage(),
+            };
+
+            let core = ElfCore { cpus: &snapshot.cpus, layout };
+            if let Err(err) = core.save(&mut memory, output) {
+                eprintln!("Failed to write {}: {}", output, err);
+                std::process::exit(1);
+            }
+        }
+        _ => usage(),
+    }
+}